@@ -0,0 +1,83 @@
+use std::io::Write;
+
+use bstr::{BString, ByteSlice};
+
+use crate::{error::GitRwError, pack_writer, refs, shared::ObjectHash, Repository};
+
+/// Writes a version 2 bundle of the refs selected by `revs` to `writer`, see
+/// `Repository::write_bundle`. Returns the number of refs and objects written.
+pub(crate) fn write_bundle<M, W: Write>(
+    repository: &Repository<M>,
+    revs: &[&str],
+    mut writer: W,
+) -> Result<(usize, usize), GitRwError> {
+    let mut reader = repository.clone();
+    let mut prerequisites = Vec::new();
+    let mut bundle_refs: Vec<(BString, BString)> = Vec::new();
+    for rev in revs {
+        let (exclude, include) = match rev.strip_prefix('^') {
+            Some(exclude) => (Some(exclude), None),
+            None => match rev.split_once("..") {
+                Some((a, b)) => (Some(a), Some(b)),
+                None => (None, Some(*rev)),
+            },
+        };
+
+        if let Some(exclude) = exclude {
+            let exclude = if exclude.is_empty() { "HEAD" } else { exclude };
+            let commit = reader.peel_to_commit(exclude).ok_or_else(|| {
+                GitRwError::invalid(format!("{exclude} does not point to a commit"))
+            })?;
+            prerequisites.push(commit);
+        }
+
+        if let Some(include) = include {
+            let include = if include.is_empty() { "HEAD" } else { include };
+            let found = refs::find_ref(&repository.path, &repository.git_dir, include)?
+                .ok_or_else(|| {
+                    GitRwError::invalid(format!(
+                        "{include} is no ref, bundles can only contain refs"
+                    ))
+                })?;
+            bundle_refs.push(found);
+        }
+    }
+
+    if revs.is_empty() {
+        // like `git bundle create --all`, which lists HEAD after the refs, symbolic refs are
+        // left out
+        bundle_refs = refs::ref_targets(&repository.path)?;
+        bundle_refs.extend(refs::find_ref(
+            &repository.path,
+            &repository.git_dir,
+            "HEAD",
+        )?);
+        bundle_refs.retain(|(_, target)| ObjectHash::try_from(target.as_bstr()).is_ok());
+    } else if bundle_refs.is_empty() {
+        return Err(GitRwError::invalid(
+            "No refs to bundle, only excluded revisions were given",
+        ));
+    }
+
+    // the receiving repository has to contain the prerequisites already
+    writer
+        .write_all(b"# v2 git bundle\n")
+        .map_err(GitRwError::Output)?;
+    for commit in prerequisites.iter() {
+        writeln!(writer, "-{commit}").map_err(GitRwError::Output)?;
+    }
+    for (name, target) in bundle_refs.iter() {
+        writeln!(writer, "{} {}", target.as_bstr(), name.as_bstr()).map_err(GitRwError::Output)?;
+    }
+    writer.write_all(b"\n").map_err(GitRwError::Output)?;
+
+    // the pack contains exactly what the header lists, HEAD may be detached from all refs
+    let pack_revs: Vec<String> = prerequisites
+        .iter()
+        .map(|commit| format!("^{commit}"))
+        .chain(bundle_refs.iter().map(|(_, target)| target.to_string()))
+        .collect();
+    let pack_revs: Vec<&str> = pack_revs.iter().map(String::as_str).collect();
+    let objects = pack_writer::write_pack(repository, &pack_revs, writer)?;
+    Ok((bundle_refs.len(), objects))
+}
@@ -0,0 +1,79 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::objs::CommitHash;
+
+/// The original commit and the edited header fields, see `CommitEditable::edit_key`.
+type CommitKey = (CommitHash, Box<[u8]>);
+
+struct CachedCommit {
+    new_hash: CommitHash,
+    /// Commits hashed during a dry run were never written to the repository
+    written: bool,
+}
+
+/// New hashes of rewritten commits keyed by the original commit and the edited header fields,
+/// shared by all clones of a `Repository`. Rewrites that end up with the same edits for a commit
+/// as an earlier rewrite do not have to serialize and hash it again.
+pub(crate) struct CommitCache {
+    max_entries: usize,
+    commits: Mutex<FxHashMap<CommitKey, CachedCommit>>,
+    hits: AtomicUsize,
+}
+
+impl CommitCache {
+    pub(crate) fn create(max_entries: usize) -> Self {
+        CommitCache {
+            max_entries,
+            commits: Mutex::new(FxHashMap::default()),
+            hits: AtomicUsize::new(0),
+        }
+    }
+
+    /// The new hash of `base` with the edits `edit_key`. Commits that were only hashed during a
+    /// dry run do not count for rewrites that write.
+    pub(crate) fn get(
+        &self,
+        base: &CommitHash,
+        edit_key: &[u8],
+        dry_run: bool,
+    ) -> Option<CommitHash> {
+        let commits = self.commits.lock().unwrap();
+        let cached = commits.get(&(base.clone(), edit_key.into()))?;
+        if !dry_run && !cached.written {
+            return None;
+        }
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(cached.new_hash.clone())
+    }
+
+    pub(crate) fn insert(
+        &self,
+        base: CommitHash,
+        edit_key: Box<[u8]>,
+        new_hash: CommitHash,
+        dry_run: bool,
+    ) {
+        let mut commits = self.commits.lock().unwrap();
+        if commits.len() >= self.max_entries {
+            commits.clear();
+        }
+
+        commits.insert(
+            (base, edit_key),
+            CachedCommit {
+                new_hash,
+                written: !dry_run,
+            },
+        );
+    }
+
+    pub(crate) fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+}
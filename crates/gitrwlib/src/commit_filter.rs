@@ -0,0 +1,401 @@
+use std::{iter::Peekable, str::CharIndices, str::FromStr};
+
+use regex::bytes::Regex;
+
+use crate::objs::CommitBase;
+
+/// Selects commits by their fields, e.g. `author =~ 'bot@' && date < 2020-01-01`.
+///
+/// Text fields are `author`, `committer` (name and email as in `Name <email>`), `message` and
+/// `hash`, compared with `=~` and `!~` against a regex or with `==` and `!=`. Number fields are
+/// `date` (the author date), `author-date`, `committer-date` and `parents`, compared with `==`,
+/// `!=`, `<`, `<=`, `>` and `>=`. Dates are written as `YYYY-MM-DD` in UTC or as seconds since
+/// the epoch. Comparisons are combined with `&&`, `||`, `!` and parentheses, values containing
+/// spaces are quoted with `'` or `"`.
+#[derive(Clone, Debug)]
+pub struct CommitFilter {
+    expression: Expression,
+}
+
+impl CommitFilter {
+    pub fn matches(&self, commit: &CommitBase) -> bool {
+        self.expression.matches(commit)
+    }
+}
+
+impl FromStr for CommitFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?.into_iter().peekable(),
+        };
+        let expression = parser.or()?;
+        match parser.tokens.next() {
+            None => Ok(CommitFilter { expression }),
+            Some(token) => Err(format!("Unexpected {token:?}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expression {
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Text(TextField, TextComparison),
+    Number(NumberField, Operator, i64),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum TextField {
+    Author,
+    Committer,
+    Message,
+    Hash,
+}
+
+#[derive(Clone, Debug)]
+enum TextComparison {
+    Matches(Regex),
+    NotMatches(Regex),
+    Equals(Vec<u8>),
+    NotEquals(Vec<u8>),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum NumberField {
+    AuthorDate,
+    CommitterDate,
+    Parents,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Matches,
+    NotMatches,
+    Equals,
+    NotEquals,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Operator {
+    fn symbol(self) -> &'static str {
+        match self {
+            Operator::Matches => "=~",
+            Operator::NotMatches => "!~",
+            Operator::Equals => "==",
+            Operator::NotEquals => "!=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+        }
+    }
+}
+
+impl Expression {
+    fn matches(&self, commit: &CommitBase) -> bool {
+        match self {
+            Expression::And(a, b) => a.matches(commit) && b.matches(commit),
+            Expression::Or(a, b) => a.matches(commit) || b.matches(commit),
+            Expression::Not(a) => !a.matches(commit),
+            Expression::Text(field, comparison) => {
+                let hash;
+                let text: &[u8] = match field {
+                    TextField::Author => commit.author(),
+                    TextField::Committer => commit.committer(),
+                    TextField::Message => commit.message(),
+                    TextField::Hash => {
+                        hash = commit.hash.to_string();
+                        hash.as_bytes()
+                    }
+                };
+                match comparison {
+                    TextComparison::Matches(regex) => regex.is_match(text),
+                    TextComparison::NotMatches(regex) => !regex.is_match(text),
+                    TextComparison::Equals(value) => text == value.as_slice(),
+                    TextComparison::NotEquals(value) => text != value.as_slice(),
+                }
+            }
+            Expression::Number(field, operator, value) => {
+                let number = match field {
+                    NumberField::AuthorDate => commit.author_timestamp(),
+                    NumberField::CommitterDate => commit.committer_timestamp(),
+                    NumberField::Parents => Some(commit.parents.len() as i64),
+                };
+                // commits with unreadable dates match no comparison
+                let Some(number) = number else {
+                    return false;
+                };
+                match operator {
+                    Operator::Equals => number == *value,
+                    Operator::NotEquals => number != *value,
+                    Operator::Less => number < *value,
+                    Operator::LessOrEqual => number <= *value,
+                    Operator::Greater => number > *value,
+                    Operator::GreaterOrEqual => number >= *value,
+                    Operator::Matches | Operator::NotMatches => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Operator(Operator),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((position, c)) = chars.next() {
+        let next = chars.peek().map(|(_, c)| *c);
+        let token = match (c, next) {
+            (c, _) if c.is_whitespace() => continue,
+            ('(', _) => Token::Open,
+            (')', _) => Token::Close,
+            ('&', Some('&')) => pair(&mut chars, Token::And),
+            ('|', Some('|')) => pair(&mut chars, Token::Or),
+            ('=', Some('~')) => pair(&mut chars, Token::Operator(Operator::Matches)),
+            ('!', Some('~')) => pair(&mut chars, Token::Operator(Operator::NotMatches)),
+            ('=', Some('=')) => pair(&mut chars, Token::Operator(Operator::Equals)),
+            ('!', Some('=')) => pair(&mut chars, Token::Operator(Operator::NotEquals)),
+            ('<', Some('=')) => pair(&mut chars, Token::Operator(Operator::LessOrEqual)),
+            ('>', Some('=')) => pair(&mut chars, Token::Operator(Operator::GreaterOrEqual)),
+            ('<', _) => Token::Operator(Operator::Less),
+            ('>', _) => Token::Operator(Operator::Greater),
+            ('!', _) => Token::Not,
+            ('\'' | '"', _) => {
+                let end = s[position + 1..]
+                    .find(c)
+                    .ok_or_else(|| format!("Unterminated quote at position {position}"))?;
+                let value = &s[position + 1..position + 1 + end];
+                while chars.next_if(|(i, _)| *i <= position + 1 + end).is_some() {}
+                Token::Quoted(value.to_owned())
+            }
+            _ => {
+                let mut end = position + c.len_utf8();
+                while let Some((i, c)) = chars.next_if(|(_, c)| is_word_char(*c)) {
+                    end = i + c.len_utf8();
+                }
+                if !is_word_char(c) {
+                    return Err(format!("Unexpected '{c}' at position {position}"));
+                }
+                Token::Word(s[position..end].to_owned())
+            }
+        };
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn pair(chars: &mut Peekable<CharIndices>, token: Token) -> Token {
+    chars.next();
+    token
+}
+
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"()&|=!<>~'\"".contains(c)
+}
+
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut expression = self.and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut expression = self.unary()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            expression = Expression::And(Box::new(expression), Box::new(self.unary()?));
+        }
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> Result<Expression, String> {
+        match self.tokens.next() {
+            Some(Token::Not) => Ok(Expression::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expression = self.or()?;
+                match self.tokens.next() {
+                    Some(Token::Close) => Ok(expression),
+                    _ => Err(String::from("Missing ')'")),
+                }
+            }
+            Some(Token::Word(field)) => self.comparison(&field),
+            Some(token) => Err(format!("Expected a field, found {token:?}")),
+            None => Err(String::from(
+                "Expected a field, found the end of the filter",
+            )),
+        }
+    }
+
+    fn comparison(&mut self, field: &str) -> Result<Expression, String> {
+        let Some(Token::Operator(operator)) = self.tokens.next() else {
+            return Err(format!("Expected an operator after {field}"));
+        };
+        let value = match self.tokens.next() {
+            Some(Token::Word(value) | Token::Quoted(value)) => value,
+            _ => {
+                return Err(format!(
+                    "Expected a value after {field} {}",
+                    operator.symbol()
+                ))
+            }
+        };
+
+        let text_field = match field {
+            "author" => Some(TextField::Author),
+            "committer" => Some(TextField::Committer),
+            "message" => Some(TextField::Message),
+            "hash" => Some(TextField::Hash),
+            _ => None,
+        };
+        if let Some(text_field) = text_field {
+            let regex = || Regex::new(&value).map_err(|e| format!("Invalid regex '{value}': {e}"));
+            let comparison = match operator {
+                Operator::Matches => TextComparison::Matches(regex()?),
+                Operator::NotMatches => TextComparison::NotMatches(regex()?),
+                Operator::Equals => TextComparison::Equals(value.into_bytes()),
+                Operator::NotEquals => TextComparison::NotEquals(value.into_bytes()),
+                _ => return Err(format!("{field} is text and cannot be ordered")),
+            };
+            return Ok(Expression::Text(text_field, comparison));
+        }
+
+        let number_field = match field {
+            "date" | "author-date" => NumberField::AuthorDate,
+            "committer-date" => NumberField::CommitterDate,
+            "parents" => NumberField::Parents,
+            _ => {
+                return Err(format!(
+                    "Unknown field '{field}', expected one of author, committer, message, hash, date, author-date, committer-date, parents"
+                ))
+            }
+        };
+        if matches!(operator, Operator::Matches | Operator::NotMatches) {
+            return Err(format!(
+                "{field} is a number and cannot be matched with a regex"
+            ));
+        }
+
+        let number = match number_field {
+            NumberField::Parents => value.parse().ok(),
+            _ => parse_date(&value),
+        }
+        .ok_or_else(|| format!("Invalid value '{value}' for {field}"))?;
+        Ok(Expression::Number(number_field, operator, number))
+    }
+}
+
+/// Seconds since the epoch, given as such or as `YYYY-MM-DD` at midnight UTC.
+fn parse_date(value: &str) -> Option<i64> {
+    if let Ok(seconds) = value.parse() {
+        return Some(seconds);
+    }
+
+    let mut parts = value.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // days since 1970-01-01 of the proleptic Gregorian calendar, with March as the first month
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    Some(days * 24 * 60 * 60)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{objs::CommitBase, ObjectHash};
+
+    use super::{parse_date, CommitFilter};
+
+    fn commit(author: &str, parents: usize, message: &str) -> CommitBase {
+        let parents: String = (0..parents)
+            .map(|_| "parent 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n")
+            .collect();
+        let bytes = format!(
+            "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n{parents}author {author}\ncommitter C <c@x.org> 1600000000 +0200\n\n{message}\n"
+        );
+        CommitBase::create(
+            ObjectHash::from([0u8; 20]).into(),
+            bytes.into_bytes(),
+            false,
+        )
+    }
+
+    fn matches(filter: &str, commit: &CommitBase) -> bool {
+        filter.parse::<CommitFilter>().unwrap().matches(commit)
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(Some(0), parse_date("1970-01-01"));
+        assert_eq!(Some(1577836800), parse_date("2020-01-01"));
+        assert_eq!(Some(951782400), parse_date("2000-02-29"));
+        assert_eq!(Some(1600000000), parse_date("1600000000"));
+        assert_eq!(None, parse_date("2020-13-01"));
+        assert_eq!(None, parse_date("yesterday"));
+    }
+
+    #[test]
+    fn filter_expressions() {
+        let bot = commit("Bot <bot@x.org> 1500000000 +0000", 1, "Update deps");
+        let merge = commit("A <a@x.org> 1600000000 +0200", 2, "Merge 'feature'");
+
+        assert!(matches("author =~ 'bot@' && date < 2020-01-01", &bot));
+        assert!(!matches("author =~ 'bot@' && date < 2017-01-01", &bot));
+        assert!(matches("author !~ bot@ || parents > 1", &merge));
+        assert!(!matches("!(parents >= 2)", &merge));
+        assert!(matches("message =~ \"^Merge 'feature'\"", &merge));
+        assert!(matches(
+            "committer == 'C <c@x.org>' && committer-date == 1600000000",
+            &bot
+        ));
+        assert!(matches("hash =~ ^0000 && author-date != 1600000000", &bot));
+
+        for invalid in [
+            "",
+            "author",
+            "author =~",
+            "size > 3",
+            "author < 3",
+            "date =~ 2020",
+            "date < tomorrow",
+            "(parents > 1",
+            "parents > 1 parents",
+            "author =~ '[unclosed'",
+            "author =~ 'bot",
+        ] {
+            assert!(invalid.parse::<CommitFilter>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+
+use crate::{objs::CommitHash, shared::ObjectHash};
+
+const HASH_LEN: usize = 20;
+/// Tree hash, two parents and generation plus commit date.
+const COMMIT_DATA_LEN: usize = HASH_LEN + 16;
+const NO_PARENT: u32 = 0x7000_0000;
+/// Set for the second parent of octopus merges, the rest is an index into the extra edges.
+const EXTRA_EDGES: u32 = 0x8000_0000;
+
+/// A single commit-graph file, `objects/info/commit-graph` or one layer of a chain.
+struct GraphLayer {
+    mmap: Mmap,
+    fanout: usize,
+    oids: usize,
+    commit_data: usize,
+    extra_edges: Option<usize>,
+    count: usize,
+}
+
+impl GraphLayer {
+    /// `None` for anything but a valid version 1 file with SHA-1 hashes.
+    fn open(path: &Path) -> Option<GraphLayer> {
+        let file = File::open(path).ok()?;
+        let mmap = unsafe { Mmap::map(&file) }.ok()?;
+        if mmap.get(0..4)? != b"CGPH" || mmap[4] != 1 || mmap[5] != 1 {
+            return None;
+        }
+
+        let mut fanout = None;
+        let mut oids = None;
+        let mut commit_data = None;
+        let mut extra_edges = None;
+        for chunk in 0..mmap[6] as usize {
+            let entry = mmap.get(8 + chunk * 12..8 + (chunk + 1) * 12)?;
+            let offset =
+                usize::try_from(u64::from_be_bytes(entry[4..].try_into().unwrap())).ok()?;
+            match &entry[..4] {
+                b"OIDF" => fanout = Some(offset),
+                b"OIDL" => oids = Some(offset),
+                b"CDAT" => commit_data = Some(offset),
+                b"EDGE" => extra_edges = Some(offset),
+                _ => {}
+            }
+        }
+
+        let fanout = fanout?;
+        let count = read_u32(&mmap, fanout + 255 * 4)? as usize;
+        let layer = GraphLayer {
+            fanout,
+            oids: oids?,
+            commit_data: commit_data?,
+            extra_edges,
+            count,
+            mmap,
+        };
+
+        let oids_end = layer.oids + count * HASH_LEN;
+        let commit_data_end = layer.commit_data + count * COMMIT_DATA_LEN;
+        (oids_end <= layer.mmap.len() && commit_data_end <= layer.mmap.len()).then_some(layer)
+    }
+
+    fn oid(&self, index: usize) -> &[u8] {
+        let start = self.oids + index * HASH_LEN;
+        &self.mmap[start..start + HASH_LEN]
+    }
+
+    fn find(&self, hash: &[u8; HASH_LEN]) -> Option<usize> {
+        let first = hash[0] as usize;
+        let start = match first {
+            0 => 0,
+            _ => read_u32(&self.mmap, self.fanout + (first - 1) * 4)? as usize,
+        };
+        let end = (read_u32(&self.mmap, self.fanout + first * 4)? as usize).min(self.count);
+
+        let (mut low, mut high) = (start, end);
+        while low < high {
+            let middle = (low + high) / 2;
+            match self.oid(middle).cmp(hash.as_slice()) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return Some(middle),
+            }
+        }
+
+        None
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().unwrap(),
+    ))
+}
+
+/// The parents and generation numbers of commits from `objects/info/commit-graph` or a split
+/// commit-graph chain, which let history walks order commits without inflating them. Opened by
+/// `Repository::commit_graph`.
+pub struct CommitGraph {
+    /// Base layer first, the positions of a layer continue after those of the layers before
+    layers: Vec<GraphLayer>,
+}
+
+impl CommitGraph {
+    /// `None` if the repository has no commit-graph or it cannot be read, like git the single
+    /// file is preferred over a chain.
+    pub(crate) fn open(repository_path: &Path) -> Option<CommitGraph> {
+        let info = repository_path.join("objects").join("info");
+        let files: Vec<PathBuf> = if info.join("commit-graph").is_file() {
+            vec![info.join("commit-graph")]
+        } else {
+            let graphs = info.join("commit-graphs");
+            fs::read_to_string(graphs.join("commit-graph-chain"))
+                .ok()?
+                .lines()
+                .filter(|line| !line.is_empty())
+                .map(|hash| graphs.join(format!("graph-{hash}.graph")))
+                .collect()
+        };
+
+        let layers = files
+            .iter()
+            .map(|file| GraphLayer::open(file))
+            .collect::<Option<Vec<_>>>()?;
+        (!layers.is_empty()).then_some(CommitGraph { layers })
+    }
+
+    /// The parents of the commit, `None` if it is not part of the graph.
+    pub fn parents(&self, hash: &CommitHash) -> Option<Vec<CommitHash>> {
+        let (layer, data) = self.commit_data(hash)?;
+        let first = read_u32(&layer.mmap, data)?;
+        let second = read_u32(&layer.mmap, data + 4)?;
+
+        let mut parents = Vec::with_capacity(2);
+        if first != NO_PARENT {
+            parents.push(self.hash_at(first as usize)?);
+        }
+
+        if second & EXTRA_EDGES != 0 {
+            let mut edge = layer.extra_edges? + (second & !EXTRA_EDGES) as usize * 4;
+            loop {
+                let position = read_u32(&layer.mmap, edge)?;
+                parents.push(self.hash_at((position & !EXTRA_EDGES) as usize)?);
+                if position & EXTRA_EDGES != 0 {
+                    break;
+                }
+                edge += 4;
+            }
+        } else if second != NO_PARENT {
+            parents.push(self.hash_at(second as usize)?);
+        }
+
+        Some(parents)
+    }
+
+    /// The topological level of the commit: 1 for root commits, otherwise one more than the
+    /// highest level of its parents. A commit can only be an ancestor of commits with a higher
+    /// generation. `None` if the commit is not part of the graph or the graph was written
+    /// without generation numbers.
+    pub fn generation(&self, hash: &CommitHash) -> Option<u32> {
+        let (layer, data) = self.commit_data(hash)?;
+        // the upper 30 bits, the lower 2 bits belong to the commit date
+        let generation = read_u32(&layer.mmap, data + 8)? >> 2;
+        (generation != 0).then_some(generation)
+    }
+
+    /// The layer of the commit and the offset of its data after the tree hash.
+    fn commit_data(&self, hash: &CommitHash) -> Option<(&GraphLayer, usize)> {
+        let (layer, index) = self
+            .layers
+            .iter()
+            .find_map(|layer| layer.find(&hash.0.bytes).map(|index| (layer, index)))?;
+
+        Some((
+            layer,
+            layer.commit_data + index * COMMIT_DATA_LEN + HASH_LEN,
+        ))
+    }
+
+    fn hash_at(&self, mut position: usize) -> Option<CommitHash> {
+        for layer in self.layers.iter() {
+            if position < layer.count {
+                return ObjectHash::try_from(layer.oid(position))
+                    .ok()
+                    .map(Into::into);
+            }
+            position -= layer.count;
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{CommitGraph, EXTRA_EDGES, NO_PARENT};
+    use crate::{objs::CommitHash, shared::ObjectHash};
+
+    fn commit(byte: u8) -> CommitHash {
+        ObjectHash::from([byte; 20]).into()
+    }
+
+    /// A graph with a root, a commit on top of it and an octopus merge of all three before it.
+    fn graph_file() -> Vec<u8> {
+        let parents: [(u32, u32); 4] = [
+            (NO_PARENT, NO_PARENT),
+            (0, NO_PARENT),
+            (1, NO_PARENT),
+            (0, EXTRA_EDGES),
+        ];
+        let edges = [1u32, 2 | EXTRA_EDGES];
+        let generations = [1u32, 2, 3, 4];
+
+        let chunks = [
+            (b"OIDF", 256 * 4),
+            (b"OIDL", parents.len() * 20),
+            (b"CDAT", parents.len() * 36),
+            (b"EDGE", edges.len() * 4),
+        ];
+        let mut file = b"CGPH\x01\x01\x04\x00".to_vec();
+        let mut offset = 8 + (chunks.len() + 1) * 12;
+        for (id, len) in chunks {
+            file.extend_from_slice(id);
+            file.extend_from_slice(&(offset as u64).to_be_bytes());
+            offset += len;
+        }
+        file.extend_from_slice(&[0; 4]);
+        file.extend_from_slice(&(offset as u64).to_be_bytes());
+
+        for first_byte in 0..256 {
+            let count = (1..=parents.len()).filter(|&i| i <= first_byte).count() as u32;
+            file.extend_from_slice(&count.to_be_bytes());
+        }
+        for i in 1..=parents.len() {
+            file.extend_from_slice(&[i as u8; 20]);
+        }
+        for ((first, second), generation) in parents.into_iter().zip(generations) {
+            file.extend_from_slice(&[0; 20]);
+            file.extend_from_slice(&first.to_be_bytes());
+            file.extend_from_slice(&second.to_be_bytes());
+            file.extend_from_slice(&(generation << 2).to_be_bytes());
+            file.extend_from_slice(&[0; 4]);
+        }
+        for edge in edges {
+            file.extend_from_slice(&edge.to_be_bytes());
+        }
+
+        file
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn parents_from_graph() {
+        let dir = std::env::temp_dir().join(format!("gitrw-commit-graph-{}", std::process::id()));
+        fs::create_dir_all(dir.join("objects/info")).unwrap();
+        fs::write(dir.join("objects/info/commit-graph"), graph_file()).unwrap();
+        let graph = CommitGraph::open(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let graph = graph.unwrap();
+        assert_eq!(Some(vec![]), graph.parents(&commit(1)));
+        assert_eq!(Some(vec![commit(2)]), graph.parents(&commit(3)));
+        assert_eq!(
+            Some(vec![commit(1), commit(2), commit(3)]),
+            graph.parents(&commit(4))
+        );
+        assert_eq!(None, graph.parents(&commit(5)));
+
+        assert_eq!(Some(1), graph.generation(&commit(1)));
+        assert_eq!(Some(4), graph.generation(&commit(4)));
+        assert_eq!(None, graph.generation(&commit(5)));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn generations_written_by_git() {
+        let dir = std::env::temp_dir().join(format!("gitrw-generations-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let git = |args: &[&str]| {
+            let output = std::process::Command::new("git")
+                .args([
+                    "-c",
+                    "user.name=gitrw",
+                    "-c",
+                    "user.email=gitrw@example.com",
+                ])
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+        fs::create_dir_all(&dir).unwrap();
+        git(&["init", "--quiet", "--bare"]);
+        let mut parent = None;
+        for message in ["first", "second", "third"] {
+            let tree = git(&["hash-object", "-t", "tree", "-w", "--stdin"]);
+            let mut args = vec!["commit-tree", tree.trim(), "-m", message];
+            if let Some(parent) = parent.as_deref() {
+                args.extend(["-p", parent]);
+            }
+            parent = Some(git(&args).trim().to_owned());
+        }
+        git(&["update-ref", "refs/heads/main", parent.as_deref().unwrap()]);
+        git(&["commit-graph", "write", "--reachable"]);
+        let graph = CommitGraph::open(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let head: CommitHash = ObjectHash::try_from(bstr::BString::from(parent.unwrap()))
+            .unwrap()
+            .into();
+        assert_eq!(Some(3), graph.unwrap().generation(&head));
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    hash::BuildHasher,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use bstr::ByteSlice;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    error::GitRwError,
+    objs::{CommitHash, GitObject},
+    shared::ObjectHash,
+    Repository,
+};
+
+/// Start of the binary format, followed by pairs of raw 20 byte hashes, old before new.
+const BINARY_MAGIC: &[u8; 9] = b"GITRWMAP\x01";
+/// Every zstd frame starts with these bytes, which a text map never does.
+pub(crate) const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const BUFFER_SIZE: usize = 1 << 20;
+
+/// How the mapping of rewritten commits gets written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CommitMapFormat {
+    /// `object-id-map.old-new.txt` with one `<old> <new>` line per commit, like git filter-repo
+    #[default]
+    Text,
+    /// `object-id-map.old-new.bin.zst`, the raw hashes compressed with zstd: about a third of the
+    /// size and much faster to read back
+    Zstd,
+}
+
+impl CommitMapFormat {
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            CommitMapFormat::Text => "object-id-map.old-new.txt",
+            CommitMapFormat::Zstd => "object-id-map.old-new.bin.zst",
+        }
+    }
+}
+
+impl FromStr for CommitMapFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(CommitMapFormat::Text),
+            "zstd" => Ok(CommitMapFormat::Zstd),
+            _ => Err(String::from("expected one of text, zstd")),
+        }
+    }
+}
+
+enum Sink<W: Write> {
+    Text(BufWriter<W>),
+    Zstd(zstd::Encoder<'static, BufWriter<W>>),
+}
+
+/// Writes a commit mapping entry by entry. The output is buffered and handed to the underlying
+/// writer in chunks, nothing but the buffer is kept in memory.
+pub struct CommitMapWriter<W: Write> {
+    sink: Sink<W>,
+}
+
+impl<W: Write> CommitMapWriter<W> {
+    pub fn create(writer: W, format: CommitMapFormat) -> io::Result<Self> {
+        let writer = BufWriter::with_capacity(BUFFER_SIZE, writer);
+        let sink = match format {
+            CommitMapFormat::Text => Sink::Text(writer),
+            CommitMapFormat::Zstd => {
+                let mut encoder = zstd::Encoder::new(writer, 0)?;
+                encoder.write_all(BINARY_MAGIC)?;
+                Sink::Zstd(encoder)
+            }
+        };
+
+        Ok(Self { sink })
+    }
+
+    pub fn write(&mut self, old: &CommitHash, new: &CommitHash) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Text(writer) => {
+                let mut line = [b' '; 82];
+                hex::encode_to_slice(old.0.bytes, &mut line[..40]).unwrap();
+                hex::encode_to_slice(new.0.bytes, &mut line[41..81]).unwrap();
+                line[81] = b'\n';
+                writer.write_all(&line)
+            }
+            Sink::Zstd(encoder) => {
+                encoder.write_all(&old.0.bytes)?;
+                encoder.write_all(&new.0.bytes)
+            }
+        }
+    }
+
+    /// Writes the remaining buffered entries and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        let writer = match self.sink {
+            Sink::Text(writer) => writer,
+            Sink::Zstd(encoder) => encoder.finish()?,
+        };
+
+        writer.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+enum Source {
+    Text(BufReader<File>),
+    Zstd(zstd::Decoder<'static, BufReader<File>>),
+}
+
+/// Reads a commit mapping written by `CommitMapWriter` in either format, the format is detected
+/// from the start of the file. Entries are read one at a time.
+pub struct CommitMapReader {
+    path: PathBuf,
+    source: Source,
+    line: Vec<u8>,
+}
+
+impl CommitMapReader {
+    pub fn open(path: &Path) -> Result<Self, GitRwError> {
+        let io = GitRwError::io(path);
+        let mut reader = BufReader::with_capacity(BUFFER_SIZE, File::open(path).map_err(io)?);
+        let source = if reader.fill_buf().map_err(io)?.starts_with(&ZSTD_MAGIC) {
+            let mut decoder = zstd::Decoder::with_buffer(reader).map_err(io)?;
+            let mut magic = [0u8; BINARY_MAGIC.len()];
+            decoder.read_exact(&mut magic).map_err(io)?;
+            if &magic != BINARY_MAGIC {
+                return Err(GitRwError::corrupt(path, "is no gitrw commit map"));
+            }
+            Source::Zstd(decoder)
+        } else {
+            Source::Text(reader)
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            source,
+            line: Vec::with_capacity(82),
+        })
+    }
+
+    /// Reads the whole mapping and returns the entries whose old hash starts with one of the
+    /// hex `prefixes`, or whose new hash does with `reverse`.
+    pub fn find(
+        self,
+        prefixes: &[&str],
+        reverse: bool,
+    ) -> Result<Vec<(CommitHash, CommitHash)>, GitRwError> {
+        let mut found = Vec::new();
+        for entry in self {
+            let entry = entry?;
+            let hash = if reverse { &entry.1 } else { &entry.0 };
+            if prefixes.iter().any(|prefix| hash.0.has_hex_prefix(prefix)) {
+                found.push(entry);
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Reads the whole mapping. Fails if a commit is mapped to two different commits.
+    pub fn read_map(self) -> Result<FxHashMap<CommitHash, CommitHash>, GitRwError> {
+        let path = self.path.clone();
+        let mut map = FxHashMap::default();
+        for entry in self {
+            let (old, new) = entry?;
+            if let Some(previous) = map.insert(old.clone(), new.clone()) {
+                if previous != new {
+                    return Err(GitRwError::corrupt(
+                        &path,
+                        format!("{old} is mapped to both {previous} and {new}"),
+                    ));
+                }
+            }
+        }
+
+        Ok(map)
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(CommitHash, CommitHash)>, GitRwError> {
+        let io = GitRwError::io(&self.path);
+        let corrupt = |reason: &str| GitRwError::corrupt(&self.path, reason);
+        match &mut self.source {
+            Source::Text(reader) => {
+                self.line.clear();
+                if reader.read_until(b'\n', &mut self.line).map_err(io)? == 0 {
+                    return Ok(None);
+                }
+
+                let (old, new) =
+                    self.line.trim_end().split_once_str(" ").ok_or_else(|| {
+                        corrupt(&format!("malformed line: {}", self.line.as_bstr()))
+                    })?;
+                let parse = |hash: &[u8]| {
+                    ObjectHash::try_from(hash.as_bstr())
+                        .map(CommitHash)
+                        .map_err(corrupt)
+                };
+                Ok(Some((parse(old)?, parse(new)?)))
+            }
+            Source::Zstd(decoder) => {
+                let mut entry = [0u8; 40];
+                let mut read = 0;
+                while read < entry.len() {
+                    match decoder.read(&mut entry[read..]).map_err(io)? {
+                        0 if read == 0 => return Ok(None),
+                        0 => return Err(corrupt("truncated commit map")),
+                        n => read += n,
+                    }
+                }
+
+                let old: [u8; 20] = entry[..20].try_into().unwrap();
+                let new: [u8; 20] = entry[20..].try_into().unwrap();
+                Ok(Some((
+                    CommitHash(ObjectHash::from(old)),
+                    CommitHash(ObjectHash::from(new)),
+                )))
+            }
+        }
+    }
+}
+
+impl Iterator for CommitMapReader {
+    type Item = Result<(CommitHash, CommitHash), GitRwError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_entry().transpose()
+    }
+}
+
+/// Writes the mapping sorted by the old hashes.
+pub(crate) fn write<W: Write, T: BuildHasher>(
+    writer: W,
+    rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+    format: CommitMapFormat,
+) -> io::Result<W> {
+    let mut sorted: Vec<_> = rewritten_commits.iter().collect();
+    sorted.sort_unstable();
+
+    let mut writer = CommitMapWriter::create(writer, format)?;
+    for (old, new) in sorted {
+        writer.write(old, new)?;
+    }
+
+    writer.finish()
+}
+
+/// Combines the mappings of two consecutive rewrites into the one of both: every commit of the
+/// original history is mapped to what the second rewrite made of its rewritten version. Commits
+/// that only existed between the rewrites are left out. Fails if the second rewrite changed a
+/// commit the first one had already replaced, i.e. it did not run on the result of the first.
+pub fn map_compose<T: BuildHasher, U: BuildHasher>(
+    first: &HashMap<CommitHash, CommitHash, T>,
+    second: &HashMap<CommitHash, CommitHash, U>,
+) -> Result<FxHashMap<CommitHash, CommitHash>, GitRwError> {
+    let intermediate: FxHashSet<&CommitHash> = first.values().collect();
+    if let Some(old) = second
+        .keys()
+        .find(|old| first.contains_key(*old) && !intermediate.contains(*old))
+    {
+        return Err(GitRwError::invalid(format!(
+            "{old} was already rewritten to {} by the first rewrite, the second one did not run on its result",
+            first[old]
+        )));
+    }
+
+    let mut composed: FxHashMap<CommitHash, CommitHash> = first
+        .iter()
+        .map(|(old, new)| (old.clone(), second.get(new).unwrap_or(new).clone()))
+        .collect();
+    composed.extend(
+        second
+            .iter()
+            .filter(|(old, _)| !intermediate.contains(*old))
+            .map(|(old, new)| (old.clone(), new.clone())),
+    );
+    // commits the second rewrite turned back into their original
+    composed.retain(|old, new| old != new);
+
+    check_cycles(&composed)?;
+    Ok(composed)
+}
+
+/// Checks the mapping of old to new commits before any ref gets moved: following the mapping
+/// must never lead back to a commit seen before, and every new commit has to exist together
+/// with all of its parents. New commits that are missing because of `dry_run` are skipped.
+pub(crate) fn validate<T: BuildHasher>(
+    repository: &Repository,
+    rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+    dry_run: bool,
+) -> Result<(), GitRwError> {
+    check_cycles(rewritten_commits)?;
+
+    let new_commits: FxHashSet<&CommitHash> = rewritten_commits.values().collect();
+    new_commits
+        .into_par_iter()
+        .try_for_each_with(repository.clone(), |repository, hash| {
+            check_commit(repository, hash, dry_run)
+        })
+}
+
+fn check_cycles<T: BuildHasher>(
+    rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+) -> Result<(), GitRwError> {
+    let mut checked: FxHashSet<&CommitHash> = FxHashSet::default();
+    for start in rewritten_commits.keys() {
+        let mut path: Vec<&CommitHash> = Vec::new();
+        let mut current = start;
+        while !checked.contains(current) {
+            if let Some(cycle_start) = path.iter().position(|hash| *hash == current) {
+                let cycle: Vec<String> = path[cycle_start..]
+                    .iter()
+                    .chain([&current])
+                    .map(|hash| hash.to_string())
+                    .collect();
+                return Err(GitRwError::invalid(format!(
+                    "The commit mapping contains a cycle: {}",
+                    cycle.join(" -> ")
+                )));
+            }
+
+            path.push(current);
+            match rewritten_commits.get(current) {
+                Some(next) if next != current => current = next,
+                _ => break,
+            }
+        }
+
+        checked.extend(path);
+    }
+
+    Ok(())
+}
+
+fn check_commit(
+    repository: &mut Repository,
+    hash: &CommitHash,
+    dry_run: bool,
+) -> Result<(), GitRwError> {
+    let reason = match repository.read_object(hash.0.clone()) {
+        Some(GitObject::Commit(commit)) => match commit
+            .parents()
+            .into_iter()
+            .find(|parent| !repository.object_exists(&parent.0))
+        {
+            Some(parent) => format!("Parent {parent} of the rewritten commit {hash} is missing"),
+            None => return Ok(()),
+        },
+        Some(_) => format!("The rewritten commit {hash} is no commit"),
+        None if dry_run => return Ok(()),
+        None => format!("The rewritten commit {hash} is missing"),
+    };
+
+    Err(GitRwError::corrupt(&repository.path, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_hash::FxHashMap;
+
+    use crate::{objs::CommitHash, shared::ObjectHash, test_repository::TestRepository};
+
+    use super::{CommitMapFormat, CommitMapReader};
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn maps_read_back_in_both_formats() {
+        let hash = |b: u8| CommitHash(ObjectHash::from([b; 20]));
+        let map: FxHashMap<_, _> = (0..=255u8).map(|b| (hash(b), hash(!b))).collect();
+
+        for format in [CommitMapFormat::Text, CommitMapFormat::Zstd] {
+            let path = std::env::temp_dir().join(format!(
+                "gitrw-commit-map-{format:?}-{}",
+                std::process::id()
+            ));
+            let file = std::fs::File::create(&path).unwrap();
+            super::write(file, &map, format).unwrap();
+
+            let entries: Vec<_> = CommitMapReader::open(&path)
+                .unwrap()
+                .collect::<Result<_, _>>()
+                .unwrap();
+            let found = CommitMapReader::open(&path)
+                .unwrap()
+                .find(&["0A0a", "fe"], true)
+                .unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(256, entries.len());
+            assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+            assert!(entries.iter().all(|(old, new)| map[old] == *new));
+            assert_eq!(vec![(hash(1), hash(0xfe)), (hash(0xf5), hash(0x0a))], found);
+        }
+    }
+
+    #[test]
+    fn maps_are_composed() {
+        let hash = |b: u8| CommitHash(ObjectHash::from([b; 20]));
+        let map = |entries: &[(u8, u8)]| -> FxHashMap<CommitHash, CommitHash> {
+            entries.iter().map(|(a, b)| (hash(*a), hash(*b))).collect()
+        };
+
+        // 1 and 2 are rewritten twice, 3 only by the first and 4 only by the second rewrite,
+        // 6 is rewritten back into 5
+        let first = map(&[(1, 11), (2, 12), (3, 13), (5, 6)]);
+        let second = map(&[(11, 21), (12, 22), (4, 24), (6, 5)]);
+        assert_eq!(
+            map(&[(1, 21), (2, 22), (3, 13), (4, 24)]),
+            super::map_compose(&first, &second).unwrap()
+        );
+
+        let error = super::map_compose(&first, &map(&[(1, 31)])).unwrap_err();
+        assert!(
+            error.to_string().contains("did not run on its result"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn cycles_are_reported() {
+        let hash = |b: u8| CommitHash(ObjectHash::from([b; 20]));
+        let mut map = FxHashMap::default();
+        map.insert(hash(1), hash(2));
+        map.insert(hash(2), hash(3));
+        map.insert(hash(4), hash(4));
+        assert!(super::check_cycles(&map).is_ok());
+
+        map.insert(hash(3), hash(1));
+        let error = super::check_cycles(&map).unwrap_err();
+        assert!(error.to_string().contains("cycle"), "{error}");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn missing_commits_are_reported() {
+        let fixture = TestRepository::create("map-validation");
+        let mut repository = fixture.open();
+        let head = repository.peel_to_commit("HEAD").unwrap();
+
+        let mut map = FxHashMap::default();
+        map.insert(CommitHash(ObjectHash::from([1; 20])), head);
+        assert!(super::validate(&repository, &map, false).is_ok());
+
+        map.insert(
+            CommitHash(ObjectHash::from([2; 20])),
+            CommitHash(ObjectHash::from([3; 20])),
+        );
+        assert!(super::validate(&repository, &map, false).is_err());
+        assert!(super::validate(&repository, &map, true).is_ok());
+    }
+}
@@ -1,28 +1,108 @@
 use core::panic;
-use std::path::Path;
+use std::{
+    cmp::Ordering,
+    collections::BinaryHeap,
+    path::Path,
+    sync::{Arc, Mutex},
+};
 
 use bstr::ByteSlice;
-use rustc_hash::FxHashSet;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use crate::{
-    objs::{CommitBase, CommitHash, Tag, Tree},
+    commit_graph::CommitGraph,
+    error::GitRwError,
+    objs::{Blob, CommitBase, CommitHash, Tag, Tree, TreeHash},
     shared::ObjectHash,
+    ReadOnly, Repository, SkipTracker,
 };
 
 use super::{
-    compression::Decompression,
-    objs::{GitObject, TagTargetType},
-    packreader::PackReader,
-    refs::GitRef,
+    compression::Decompression, objs::GitObject, packreader::PackReader, refs::RefSnapshot,
 };
 
+/// Reason for parents that are not in the repository, e.g. in shallow clones.
+const MISSING_PARENT: &str = "missing parent commit";
+/// Reason for parents whose hash belongs to a tree, blob or tag.
+const PARENT_NOT_A_COMMIT: &str = "parent is no commit";
+
+/// Number of root trees `CommitWithTree` keeps in memory before the cache gets cleared.
+const TREE_CACHE_SIZE: usize = 1024;
+
+/// A commit together with its root tree, which is only read when it is requested.
+pub struct CommitWithTree {
+    pub commit: CommitBase,
+    trees: Arc<Mutex<TreeCache>>,
+}
+
+impl CommitWithTree {
+    pub(crate) fn create(commit: CommitBase, trees: Arc<Mutex<TreeCache>>) -> Self {
+        Self { commit, trees }
+    }
+
+    /// Reads the root tree of the commit. Commits sharing the same tree get the same instance as
+    /// long as it is cached.
+    pub fn tree(&self) -> Result<Arc<Tree>, GitRwError> {
+        self.trees.lock().unwrap().get(self.commit.tree())
+    }
+}
+
+/// Root trees shared by all items of `Repository::commits_with_trees`.
+pub(crate) struct TreeCache {
+    repository: Repository<ReadOnly>,
+    trees: FxHashMap<TreeHash, Arc<Tree>>,
+}
+
+impl TreeCache {
+    pub(crate) fn create(repository: Repository<ReadOnly>) -> Self {
+        Self {
+            repository,
+            trees: FxHashMap::default(),
+        }
+    }
+
+    fn get(&mut self, hash: TreeHash) -> Result<Arc<Tree>, GitRwError> {
+        if let Some(tree) = self.trees.get(&hash) {
+            return Ok(tree.clone());
+        }
+
+        if self.trees.len() >= TREE_CACHE_SIZE {
+            self.trees.clear();
+        }
+
+        let tree = Arc::new(self.repository.read_tree(hash.clone())?);
+        self.trees.insert(hash, tree.clone());
+        Ok(tree)
+    }
+}
+
+/// A commit on the stack of `CommitsFifoIter`. Its parents come from the commit-graph if the
+/// repository has one, the commit itself is then only read once it is returned.
+struct PendingCommit {
+    hash: CommitHash,
+    commit: Option<CommitBase>,
+    parents: Vec<CommitHash>,
+}
+
+impl From<CommitBase> for PendingCommit {
+    fn from(commit: CommitBase) -> Self {
+        PendingCommit {
+            hash: commit.hash.clone(),
+            parents: commit.parents(),
+            commit: Some(commit),
+        }
+    }
+}
+
 pub(crate) struct CommitsFifoIter<'a> {
     pack_reader: &'a PackReader,
     compression: Decompression,
     repository_path: &'a Path,
-    commits: Vec<CommitBase>,
+    commit_graph: Option<CommitGraph>,
+    commits: Vec<PendingCommit>,
     processed_commits: FxHashSet<CommitHash>,
     parents_seen: FxHashSet<CommitHash>,
+    skipped: SkipTracker,
 }
 
 impl<'a> CommitsFifoIter<'a> {
@@ -30,34 +110,60 @@ impl<'a> CommitsFifoIter<'a> {
         repository_path: &'a Path,
         pack_reader: &'a PackReader,
         mut decompression: Decompression,
+        skipped: &SkipTracker,
     ) -> Self {
-        let mut commits = Vec::new();
         let processed_commits = FxHashSet::default();
         let parents_seen = FxHashSet::default();
-
-        let refs = GitRef::read_all(repository_path).unwrap();
-        for r in refs {
-            let commit = read_commit_from_ref(&mut decompression, repository_path, pack_reader, r);
-            if let Some(x) = commit {
-                commits.push(x);
-            };
-        }
-
-        let commits = commits
+        let commits = read_ref_commits(&mut decompression, repository_path, pack_reader, skipped)
             .into_iter()
-            .map(|git_object| match git_object {
-                GitObject::Commit(commit) => commit,
-                _ => panic!("this should have been a commit, but wasn't"),
-            })
+            .map(PendingCommit::from)
             .collect();
 
         CommitsFifoIter {
             pack_reader,
             compression: decompression,
             repository_path,
+            commit_graph: CommitGraph::open(repository_path),
             commits,
             processed_commits,
             parents_seen,
+            skipped: skipped.clone(),
+        }
+    }
+
+    /// `None` if the commit is missing or no commit, which is recorded in `skipped`.
+    fn read_commit(&mut self, hash: CommitHash) -> Option<CommitBase> {
+        match read_object_from_hash(
+            &mut self.compression,
+            self.repository_path,
+            self.pack_reader,
+            hash.0.clone(),
+        ) {
+            Some(GitObject::Commit(commit)) => Some(commit),
+            Some(_) => {
+                self.skipped.record(PARENT_NOT_A_COMMIT, hash);
+                None
+            }
+            None => {
+                self.skipped.record(MISSING_PARENT, hash);
+                None
+            }
+        }
+    }
+
+    fn pending(&mut self, hash: CommitHash) -> Option<PendingCommit> {
+        let parents = self
+            .commit_graph
+            .as_ref()
+            .and_then(|commit_graph| commit_graph.parents(&hash));
+
+        match parents {
+            Some(parents) => Some(PendingCommit {
+                hash,
+                commit: None,
+                parents,
+            }),
+            None => self.read_commit(hash).map(Into::into),
         }
     }
 }
@@ -66,29 +172,27 @@ impl<'a> Iterator for CommitsFifoIter<'a> {
     type Item = CommitBase;
 
     fn next(&mut self) -> Option<Self::Item> {
-        while let Some(commit) = self.commits.pop() {
-            if self.processed_commits.contains(&commit.hash) {
-                self.parents_seen.remove(&commit.hash);
-            } else if !self.parents_seen.insert(commit.hash.clone()) || commit.parents.is_empty() {
-                self.processed_commits.insert(commit.hash.clone());
-                return Some(commit);
+        while let Some(pending) = self.commits.pop() {
+            if self.processed_commits.contains(&pending.hash) {
+                self.parents_seen.remove(&pending.hash);
+            } else if !self.parents_seen.insert(pending.hash.clone()) || pending.parents.is_empty()
+            {
+                self.processed_commits.insert(pending.hash.clone());
+                let commit = match pending.commit {
+                    Some(commit) => Some(commit),
+                    None => self.read_commit(pending.hash),
+                };
+                if commit.is_some() {
+                    return commit;
+                }
             } else {
-                let parents = commit.parents();
-                self.commits.push(commit);
+                let parents = pending.parents.clone();
+                self.commits.push(pending);
                 for parent in parents {
                     if !self.processed_commits.contains(&parent) {
-                        let parent_commit = read_object_from_hash(
-                            &mut self.compression,
-                            self.repository_path,
-                            self.pack_reader,
-                            parent.0,
-                        )
-                        .unwrap();
-
-                        match parent_commit {
-                            GitObject::Commit(pc) => self.commits.push(pc),
-                            _ => panic!("Commit expected, got something else."),
-                        };
+                        if let Some(parent) = self.pending(parent) {
+                            self.commits.push(parent);
+                        }
                     }
                 }
             }
@@ -98,12 +202,15 @@ impl<'a> Iterator for CommitsFifoIter<'a> {
     }
 }
 
+/// Only keeps the hashes of the commits to visit, a commit is read once when it is returned and
+/// commits that were returned in the meantime are not read again.
 pub(crate) struct CommitsLifoIter<'a> {
     pack_reader: &'a PackReader,
     decompression: Decompression,
     repository_path: &'a Path,
-    commits: Vec<CommitBase>,
+    commits: Vec<CommitHash>,
     processed_commits: FxHashSet<CommitHash>,
+    skipped: SkipTracker,
 }
 
 impl<'a> CommitsLifoIter<'a> {
@@ -111,24 +218,12 @@ impl<'a> CommitsLifoIter<'a> {
         repository_path: &'a Path,
         pack_reader: &'a PackReader,
         mut decompression: Decompression,
+        skipped: &SkipTracker,
     ) -> CommitsLifoIter<'a> {
-        let mut commits = Vec::new();
         let processed_commits = FxHashSet::default();
-
-        let refs = GitRef::read_all(repository_path).unwrap();
-        for r in refs {
-            let commit = read_commit_from_ref(&mut decompression, repository_path, pack_reader, r);
-            if let Some(x) = commit {
-                commits.push(x)
-            };
-        }
-
-        let commits = commits
+        let commits = read_ref_commits(&mut decompression, repository_path, pack_reader, skipped)
             .into_iter()
-            .map(|git_object| match git_object {
-                GitObject::Commit(commit) => commit,
-                _ => panic!("this should have been a commit, but wasn't"),
-            })
+            .map(|commit| commit.hash)
             .collect();
 
         CommitsLifoIter {
@@ -137,6 +232,7 @@ impl<'a> CommitsLifoIter<'a> {
             commits,
             processed_commits,
             decompression,
+            skipped: skipped.clone(),
         }
     }
 }
@@ -144,27 +240,229 @@ impl<'a> CommitsLifoIter<'a> {
 impl<'a> Iterator for CommitsLifoIter<'a> {
     type Item = CommitBase;
 
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(hash) = self.commits.pop() {
+            if !self.processed_commits.insert(hash.clone()) {
+                continue;
+            }
+
+            // parents missing in shallow clones are skipped
+            let commit = match read_object_from_hash(
+                &mut self.decompression,
+                self.repository_path,
+                self.pack_reader,
+                hash.0.clone(),
+            ) {
+                Some(GitObject::Commit(commit)) => commit,
+                Some(_) => {
+                    self.skipped.record(PARENT_NOT_A_COMMIT, hash);
+                    continue;
+                }
+                None => {
+                    self.skipped.record(MISSING_PARENT, hash);
+                    continue;
+                }
+            };
+
+            for parent in commit.parents() {
+                if !self.processed_commits.contains(&parent) {
+                    self.commits.push(parent);
+                }
+            }
+
+            return Some(commit);
+        }
+
+        None
+    }
+}
+
+/// A commit waiting in the queue of `CommitsDateIter`, ordered by its corrected date and hash.
+struct DatedCommit {
+    date: i64,
+    commit: CommitBase,
+}
+
+impl PartialEq for DatedCommit {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DatedCommit {}
+
+impl PartialOrd for DatedCommit {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DatedCommit {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.date, &self.commit.hash).cmp(&(other.date, &other.commit.hash))
+    }
+}
+
+/// Walks all commits newest first by committer date. The dates are corrected like git's
+/// generation numbers: a commit counts as at least one second newer than each of its parents,
+/// so children always come before their parents even if clocks were skewed. Computing the
+/// corrected dates takes a walk over all commits up front, the commits themselves are read
+/// again while iterating.
+pub(crate) struct CommitsDateIter<'a> {
+    pack_reader: &'a PackReader,
+    decompression: Decompression,
+    repository_path: &'a Path,
+    queue: BinaryHeap<DatedCommit>,
+    queued: FxHashSet<CommitHash>,
+    dates: FxHashMap<CommitHash, i64>,
+    skipped: SkipTracker,
+}
+
+impl<'a> CommitsDateIter<'a> {
+    pub fn create(
+        repository_path: &'a Path,
+        pack_reader: &'a PackReader,
+        mut decompression: Decompression,
+        parents_first: impl Iterator<Item = CommitBase>,
+        skipped: &SkipTracker,
+    ) -> Self {
+        let mut dates: FxHashMap<CommitHash, i64> = FxHashMap::default();
+        for commit in parents_first {
+            let date = commit
+                .parents()
+                .iter()
+                .filter_map(|parent| dates.get(parent))
+                .map(|date| date + 1)
+                .fold(commit.committer_timestamp().unwrap_or(0), i64::max);
+            dates.insert(commit.hash, date);
+        }
+
+        let commits = read_ref_commits(&mut decompression, repository_path, pack_reader, skipped);
+        let mut iter = CommitsDateIter {
+            pack_reader,
+            decompression,
+            repository_path,
+            queue: BinaryHeap::new(),
+            queued: FxHashSet::default(),
+            dates,
+            skipped: skipped.clone(),
+        };
+        for commit in commits {
+            iter.push(commit);
+        }
+
+        iter
+    }
+
+    fn push(&mut self, commit: CommitBase) {
+        if self.queued.insert(commit.hash.clone()) {
+            let date = self.dates.get(&commit.hash).copied().unwrap_or(0);
+            self.queue.push(DatedCommit { date, commit });
+        }
+    }
+}
+
+impl<'a> Iterator for CommitsDateIter<'a> {
+    type Item = CommitBase;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let DatedCommit { commit, .. } = self.queue.pop()?;
+        for parent in commit.parents() {
+            if self.queued.contains(&parent) {
+                continue;
+            }
+
+            match read_object_from_hash(
+                &mut self.decompression,
+                self.repository_path,
+                self.pack_reader,
+                parent.0.clone(),
+            ) {
+                Some(GitObject::Commit(parent)) => self.push(parent),
+                Some(_) => self.skipped.record(PARENT_NOT_A_COMMIT, parent),
+                None => self.skipped.record(MISSING_PARENT, parent),
+            }
+        }
+
+        Some(commit)
+    }
+}
+
+/// Walks all commits reachable from `include` that are not reachable from any commit in
+/// `exclude`, like `git rev-list exclude..include`.
+pub(crate) struct CommitsRangeIter<'a> {
+    pack_reader: &'a PackReader,
+    decompression: Decompression,
+    repository_path: &'a Path,
+    commits: Vec<CommitBase>,
+    processed_commits: FxHashSet<CommitHash>,
+    skipped: SkipTracker,
+}
+
+impl<'a> CommitsRangeIter<'a> {
+    pub fn create(
+        repository_path: &'a Path,
+        pack_reader: &'a PackReader,
+        mut decompression: Decompression,
+        exclude: Vec<ObjectHash>,
+        include: Vec<ObjectHash>,
+        skipped: &SkipTracker,
+    ) -> CommitsRangeIter<'a> {
+        let peel = |decompression: &mut Decompression,
+                    hashes: Vec<ObjectHash>|
+         -> Vec<CommitBase> {
+            hashes
+                .into_iter()
+                .filter_map(|hash| {
+                    let commit =
+                        peel_to_commit(decompression, repository_path, pack_reader, hash.clone());
+                    if commit.is_none() {
+                        skipped.record("revision does not point to a commit", hash);
+                    }
+                    commit
+                })
+                .collect()
+        };
+
+        let excluded = peel(&mut decompression, exclude);
+        let mut excluded_iter = CommitsRangeIter {
+            pack_reader,
+            decompression,
+            repository_path,
+            commits: excluded,
+            processed_commits: FxHashSet::default(),
+            skipped: skipped.clone(),
+        };
+        for _ in excluded_iter.by_ref() {}
+
+        let commits = peel(&mut excluded_iter.decompression, include);
+        CommitsRangeIter {
+            commits,
+            ..excluded_iter
+        }
+    }
+}
+
+impl<'a> Iterator for CommitsRangeIter<'a> {
+    type Item = CommitBase;
+
     fn next(&mut self) -> Option<Self::Item> {
         while let Some(commit) = self.commits.pop() {
             if self.processed_commits.insert(commit.hash.clone()) {
-                for parent in commit
-                    .parents
-                    .iter()
-                    .enumerate()
-                    .map(|(i, _)| commit.get_str(|c| &c.parents[i]).try_into().unwrap())
-                {
-                    if !self.processed_commits.contains(&parent) {
-                        if let Some(parent_commit) = read_object_from_hash(
-                            &mut self.decompression,
-                            self.repository_path,
-                            self.pack_reader,
-                            parent.0,
-                        ) {
-                            match parent_commit {
-                                GitObject::Commit(parent) => self.commits.push(parent),
-                                _ => panic!("Expected a commit, but got something else"),
-                            };
-                        };
+                for parent in commit.parents() {
+                    if self.processed_commits.contains(&parent) {
+                        continue;
+                    }
+
+                    match read_object_from_hash(
+                        &mut self.decompression,
+                        self.repository_path,
+                        self.pack_reader,
+                        parent.0.clone(),
+                    ) {
+                        Some(GitObject::Commit(parent)) => self.commits.push(parent),
+                        Some(_) => self.skipped.record(PARENT_NOT_A_COMMIT, parent),
+                        None => self.skipped.record(MISSING_PARENT, parent),
                     }
                 }
 
@@ -176,34 +474,45 @@ impl<'a> Iterator for CommitsLifoIter<'a> {
     }
 }
 
-fn read_commit_from_ref(
-    compression: &mut Decompression,
+/// Reads the commits all refs point to, directly or through tags. Refs pointing to anything else
+/// are recorded in `skipped`.
+fn read_ref_commits(
+    decompression: &mut Decompression,
     repository_path: &Path,
     pack_reader: &PackReader,
-    r: GitRef,
-) -> Option<GitObject> {
-    let hash = match r {
-        GitRef::Simple(simple) => simple.hash,
-        GitRef::Tag(tag) => tag.hash,
-    };
-
-    let hash: ObjectHash = hash.try_into().unwrap();
-    let mut git_object =
-        read_object_from_hash(compression, repository_path, pack_reader, hash).unwrap();
-    while let GitObject::Tag(tag) = &git_object {
-        if tag.target_type() == TagTargetType::Tree {
-            break;
-        }
-
-        git_object =
-            read_object_from_hash(compression, repository_path, pack_reader, tag.object()).unwrap();
-    }
+    skipped: &SkipTracker,
+) -> Vec<CommitBase> {
+    let mut snapshot = RefSnapshot::read(repository_path).unwrap();
+    snapshot
+        .commits(decompression, repository_path, pack_reader, skipped)
+        .into_iter()
+        .map(|hash| {
+            match read_object_from_hash(decompression, repository_path, pack_reader, hash.0) {
+                Some(GitObject::Commit(commit)) => commit,
+                _ => panic!("this should have been a commit, but wasn't"),
+            }
+        })
+        .collect()
+}
 
-    if let GitObject::Commit(commit) = git_object {
-        return Some(GitObject::Commit(commit));
+/// Follows tags starting at `hash` until a commit is found. Returns `None` if the chain ends at a
+/// tree, a blob or a missing object.
+pub(crate) fn peel_to_commit(
+    decompression: &mut Decompression,
+    repository_path: &Path,
+    pack_reader: &PackReader,
+    hash: ObjectHash,
+) -> Option<CommitBase> {
+    let mut git_object = read_object_from_hash(decompression, repository_path, pack_reader, hash)?;
+    loop {
+        git_object = match git_object {
+            GitObject::Tag(tag) => {
+                read_object_from_hash(decompression, repository_path, pack_reader, tag.object())?
+            }
+            GitObject::Commit(commit) => return Some(commit),
+            _ => return None,
+        };
     }
-
-    None
 }
 
 pub(crate) fn read_object_from_hash(
@@ -234,8 +543,7 @@ pub(crate) fn read_object_from_hash(
         }
 
         if bytes.starts_with(b"blob ") {
-            todo!("Not implemented yet")
-            // return Some(GitObject::Blob(Blob::create(hash, bytes)));
+            return Some(GitObject::Blob(Blob::create(hash, bytes, true)));
         }
 
         dbg!(hash);
@@ -1,18 +1,18 @@
 use std::{
-    error::Error,
     fs::File,
     io::{self, BufReader, BufWriter, Read, Write},
     path::Path,
+    sync::Arc,
 };
 
 use flate2::Status;
-use libdeflater::Decompressor;
+use libdeflater::{DecompressionError, Decompressor};
 use memmap2::Mmap;
 use once_cell::sync::Lazy;
 
-use crate::{packreader::PackObject, WriteBytes};
+use crate::{packreader::PackObject, shared::shared_bytes, WriteBytes};
 
-pub struct Decompression {
+pub(crate) struct Decompression {
     libdeflate_decompressor: Decompressor,
     flate2_decompressor: flate2::Decompress,
     file_buf: Lazy<[u8; 8192]>,
@@ -28,7 +28,11 @@ impl Default for Decompression {
     }
 }
 
-pub fn pack_file(path: &Path, prefix: &str, write_bytes: &WriteBytes) -> Result<(), io::Error> {
+pub(crate) fn pack_file(
+    path: &Path,
+    prefix: &str,
+    write_bytes: &WriteBytes,
+) -> Result<(), io::Error> {
     let file = File::options()
         .read(true)
         .write(true)
@@ -49,30 +53,103 @@ pub fn pack_file(path: &Path, prefix: &str, write_bytes: &WriteBytes) -> Result<
 }
 
 impl Decompression {
-    #[allow(clippy::uninit_vec)]
     pub fn unpack(
         &mut self,
         mmap: &Mmap,
         pack_object: &PackObject,
         additional_offset: usize,
-    ) -> Box<[u8]> {
+    ) -> Arc<[u8]> {
         let slice = &mmap[pack_object.offset + pack_object.header_len + additional_offset + 2..];
 
-        let mut buf: Vec<u8> = Vec::with_capacity(pack_object.data_size);
-        unsafe { buf.set_len(pack_object.data_size) };
+        shared_bytes(pack_object.data_size, |buf| {
+            self.libdeflate_decompressor
+                .deflate_decompress(slice, buf)
+                .unwrap();
+        })
+    }
 
-        self.libdeflate_decompressor
-            .deflate_decompress(slice, &mut buf)
-            .unwrap();
+    /// Like `unpack`, but checks the entry instead of trusting it and fails with the reason
+    /// if it does not inflate to the size in its header.
+    pub fn try_unpack(
+        &mut self,
+        mmap: &Mmap,
+        pack_object: &PackObject,
+        additional_offset: usize,
+    ) -> Result<Arc<[u8]>, String> {
+        let slice = mmap
+            .get(pack_object.offset + pack_object.header_len + additional_offset + 2..)
+            .ok_or("entry ends before its compressed data")?;
+        // deflate can not compress better than about 1:1032
+        if pack_object.data_size / 1032 > slice.len() {
+            return Err(format!(
+                "header claims {} bytes, more than the rest of the pack inflates to",
+                pack_object.data_size
+            ));
+        }
 
-        buf.into_boxed_slice()
+        let mut buf = vec![0u8; pack_object.data_size];
+        match self
+            .libdeflate_decompressor
+            .deflate_decompress(slice, &mut buf)
+        {
+            Ok(len) if len == buf.len() => Ok(buf.into()),
+            Ok(len) => Err(format!(
+                "inflates to {len} bytes instead of {}",
+                pack_object.data_size
+            )),
+            Err(DecompressionError::InsufficientSpace) => Err(format!(
+                "inflates to more than {} bytes",
+                pack_object.data_size
+            )),
+            Err(DecompressionError::BadData) => Err("compressed data is damaged".to_owned()),
+        }
     }
 
-    pub fn unpack_file(
+    /// Like `unpack`, but stops after the first `len` bytes of the entry are inflated. Returns
+    /// fewer bytes if the entry is shorter, `None` if it does not inflate.
+    pub fn unpack_prefix(
         &mut self,
-        base_path: &Path,
-        hash_code: &str,
-    ) -> Result<Box<[u8]>, Box<dyn Error>> {
+        mmap: &Mmap,
+        pack_object: &PackObject,
+        additional_offset: usize,
+        len: usize,
+    ) -> Option<Vec<u8>> {
+        let slice =
+            mmap.get(pack_object.offset + pack_object.header_len + additional_offset + 2..)?;
+
+        self.flate2_decompressor.reset(false);
+        let mut buf = vec![0u8; len.min(pack_object.data_size)];
+        self.flate2_decompressor
+            .decompress(slice, &mut buf, flate2::FlushDecompress::None)
+            .ok()?;
+        buf.truncate(self.flate2_decompressor.total_out() as usize);
+
+        Some(buf)
+    }
+
+    /// Length of the zlib stream starting at `offset`, which is only known after inflating all
+    /// of it.
+    pub fn compressed_len(&mut self, mmap: &Mmap, offset: usize) -> Result<usize, String> {
+        let slice = &mmap[offset..];
+        self.flate2_decompressor.reset(true);
+
+        let buffer = &mut self.file_buf[..];
+        loop {
+            let total_in = self.flate2_decompressor.total_in() as usize;
+            let status = self
+                .flate2_decompressor
+                .decompress(&slice[total_in..], buffer, flate2::FlushDecompress::None)
+                .map_err(|e| format!("Object at offset {offset} does not inflate: {e}"))?;
+            match status {
+                Status::StreamEnd => return Ok(self.flate2_decompressor.total_in() as usize),
+                // there is always room for more output, so the input ended too early
+                Status::BufError => return Err(format!("Truncated object at offset {offset}")),
+                Status::Ok => {}
+            }
+        }
+    }
+
+    pub fn unpack_file(&mut self, base_path: &Path, hash_code: &str) -> io::Result<Box<[u8]>> {
         let (x, xs) = hash_code.split_at(2);
         let file_path = base_path.join("objects").join(x).join(xs);
 
@@ -89,20 +166,33 @@ impl Decompression {
         let mut status = Status::Ok;
         while status == Status::Ok {
             let bytes_read = buf_reader.read(buffer.as_mut()).unwrap();
+            let flush = if bytes_read == 0 {
+                flate2::FlushDecompress::Finish
+            } else {
+                flate2::FlushDecompress::None
+            };
+
+            // a single read can inflate to more than the reserved space, keep going until the
+            // whole input is consumed and the decompressor has no pending output left
+            let mut input = &buffer[0..bytes_read];
+            loop {
+                output_buf.reserve(bytes_read.max(4096) * 2);
+                let total_in = self.flate2_decompressor.total_in();
+                status = self
+                    .flate2_decompressor
+                    .decompress_vec(input, &mut output_buf, flush)
+                    .unwrap();
+                input = &input[(self.flate2_decompressor.total_in() - total_in) as usize..];
+                if status != Status::Ok
+                    || (input.is_empty() && output_buf.len() < output_buf.capacity())
+                {
+                    break;
+                }
+            }
+
             if bytes_read == 0 {
                 break;
             }
-
-            output_buf.reserve(bytes_read * 2);
-
-            status = self
-                .flate2_decompressor
-                .decompress_vec(
-                    &buffer[0..bytes_read],
-                    &mut output_buf,
-                    flate2::FlushDecompress::None,
-                )
-                .unwrap();
         }
 
         Ok(output_buf.into_boxed_slice())
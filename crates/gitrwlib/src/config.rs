@@ -0,0 +1,63 @@
+use std::path::Path;
+
+/// Reads all values of `section.key` from the config file of the repository. Section and key
+/// names are case insensitive, subsections and includes are not supported.
+pub(crate) fn read_config_values(repository_path: &Path, section: &str, key: &str) -> Vec<String> {
+    let Ok(content) = std::fs::read_to_string(repository_path.join("config")) else {
+        return Vec::new();
+    };
+
+    parse_config_values(&content, section, key)
+}
+
+fn parse_config_values(content: &str, section: &str, key: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut in_section = false;
+
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let header = header.split(']').next().unwrap_or_default().trim();
+            in_section = header.eq_ignore_ascii_case(section);
+            continue;
+        }
+
+        if !in_section {
+            continue;
+        }
+
+        let (name, value) = line.split_once('=').unwrap_or((line, "true"));
+        if name.trim().eq_ignore_ascii_case(key) {
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|v| v.strip_suffix('"'))
+                .unwrap_or(value);
+            values.push(value.to_string());
+        }
+    }
+
+    values
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_config_values;
+
+    #[test]
+    fn config_values() {
+        let content = "[core]\n\tbare = true\n[gitrw]\n\tprotect = refs/heads/main\n\
+                       # comment\n\tProtect = \"refs/tags/*\"\n[remote \"origin\"]\n\tprotect = x\n";
+
+        assert_eq!(
+            vec!["refs/heads/main", "refs/tags/*"],
+            parse_config_values(content, "gitrw", "protect")
+        );
+        assert_eq!(vec!["true"], parse_config_values(content, "core", "bare"));
+        assert!(parse_config_values(content, "core", "missing").is_empty());
+    }
+}
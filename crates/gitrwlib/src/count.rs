@@ -0,0 +1,46 @@
+use std::path::Path;
+
+use crate::{loose::loose_objects, packreader::PackReader};
+
+/// Object statistics of a repository, as reported by `git count-objects -v`, with the packed
+/// objects split by their type.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ObjectCounts {
+    pub packs: usize,
+    pub pack_size: u64,
+    pub commits: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    pub tags: usize,
+    pub loose: usize,
+    pub loose_size: u64,
+}
+
+impl ObjectCounts {
+    pub fn packed(&self) -> usize {
+        self.commits + self.trees + self.blobs + self.tags
+    }
+}
+
+pub(crate) fn count_objects(repository_path: &Path, pack_reader: &PackReader) -> ObjectCounts {
+    let (packs, pack_size) = pack_reader.pack_sizes();
+    let [commits, trees, blobs, tags] = pack_reader.count_object_types();
+    let (loose, loose_size) = count_loose_objects(repository_path);
+
+    ObjectCounts {
+        packs,
+        pack_size,
+        commits,
+        trees,
+        blobs,
+        tags,
+        loose,
+        loose_size,
+    }
+}
+
+fn count_loose_objects(repository_path: &Path) -> (usize, u64) {
+    loose_objects(repository_path).fold((0, 0), |(count, size), object| {
+        (count + 1, size + object.file_size().unwrap_or_default())
+    })
+}
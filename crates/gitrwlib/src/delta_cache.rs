@@ -0,0 +1,184 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex, RwLock,
+};
+
+use rustc_hash::FxHashMap;
+
+use crate::shared::ObjectHash;
+
+/// Number of chains kept in `ReadMetrics::deepest_chains`.
+const DEEPEST_CHAINS: usize = 10;
+
+/// Configures the cache of materialized objects in the middle of long delta chains.
+#[derive(Clone, Debug)]
+pub struct DeltaCacheOptions {
+    /// Chains with at least this many deltas get their midpoint cached
+    pub min_depth: usize,
+    /// Upper bound for the cached bytes, the cache is cleared once it is exceeded
+    pub max_bytes: usize,
+}
+
+impl Default for DeltaCacheOptions {
+    fn default() -> Self {
+        DeltaCacheOptions {
+            min_depth: 16,
+            max_bytes: 256 * 1024 * 1024,
+        }
+    }
+}
+
+struct CachedObject {
+    bytes: Arc<[u8]>,
+    base_offset: usize,
+}
+
+/// Materialized objects keyed by pack and offset, shared by all clones of a `PackReader`.
+pub(crate) struct DeltaCache {
+    pub(crate) options: DeltaCacheOptions,
+    objects: RwLock<FxHashMap<(usize, usize), CachedObject>>,
+    size: AtomicUsize,
+}
+
+impl DeltaCache {
+    pub(crate) fn create(options: DeltaCacheOptions) -> Self {
+        DeltaCache {
+            options,
+            objects: RwLock::new(FxHashMap::default()),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the content of the object at `offset` and the offset of the base object of its
+    /// chain, which determines the object type.
+    pub(crate) fn get(&self, pack: usize, offset: usize) -> Option<(Arc<[u8]>, usize)> {
+        self.objects
+            .read()
+            .unwrap()
+            .get(&(pack, offset))
+            .map(|cached| (cached.bytes.clone(), cached.base_offset))
+    }
+
+    pub(crate) fn insert(&self, pack: usize, offset: usize, bytes: Arc<[u8]>, base_offset: usize) {
+        if bytes.len() > self.options.max_bytes {
+            return;
+        }
+
+        let mut objects = self.objects.write().unwrap();
+        if self.size.load(Ordering::Relaxed) + bytes.len() > self.options.max_bytes {
+            objects.clear();
+            self.size.store(0, Ordering::Relaxed);
+        }
+
+        self.size.fetch_add(bytes.len(), Ordering::Relaxed);
+        objects.insert((pack, offset), CachedObject { bytes, base_offset });
+    }
+}
+
+/// Statistics about the objects read from packs so far.
+#[derive(Clone, Debug, Default)]
+pub struct ReadMetrics {
+    pub objects_read: usize,
+    /// Objects that had to be restored from a delta chain
+    pub deltas_resolved: usize,
+    pub total_delta_depth: usize,
+    /// Delta chains that were cut short by an object of the delta cache
+    pub cache_hits: usize,
+    /// The objects with the longest delta chains read so far, deepest first
+    pub deepest_chains: Vec<(ObjectHash, usize)>,
+}
+
+impl ReadMetrics {
+    pub fn max_delta_depth(&self) -> usize {
+        self.deepest_chains.first().map_or(0, |(_, depth)| *depth)
+    }
+
+    pub fn average_delta_depth(&self) -> f64 {
+        if self.deltas_resolved == 0 {
+            0.0
+        } else {
+            self.total_delta_depth as f64 / self.deltas_resolved as f64
+        }
+    }
+}
+
+/// Counters behind `ReadMetrics`, shared by all clones of a `PackReader`.
+#[derive(Default)]
+pub(crate) struct ReadCounters {
+    objects: AtomicUsize,
+    deltas: AtomicUsize,
+    total_delta_depth: AtomicUsize,
+    cache_hits: AtomicUsize,
+    /// Depth a chain needs to make it into `deepest`, avoids taking the lock for every read
+    deepest_threshold: AtomicUsize,
+    deepest: Mutex<Vec<(ObjectHash, usize)>>,
+}
+
+impl ReadCounters {
+    pub(crate) fn record_read(&self, hash: &ObjectHash, delta_depth: usize) {
+        self.objects.fetch_add(1, Ordering::Relaxed);
+        if delta_depth == 0 {
+            return;
+        }
+
+        self.deltas.fetch_add(1, Ordering::Relaxed);
+        self.total_delta_depth
+            .fetch_add(delta_depth, Ordering::Relaxed);
+        if delta_depth < self.deepest_threshold.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut deepest = self.deepest.lock().unwrap();
+        if deepest.iter().any(|(deep_hash, _)| deep_hash == hash) {
+            return;
+        }
+
+        let position = deepest
+            .iter()
+            .position(|(_, depth)| *depth < delta_depth)
+            .unwrap_or(deepest.len());
+        deepest.insert(position, (hash.clone(), delta_depth));
+        if deepest.len() >= DEEPEST_CHAINS {
+            deepest.truncate(DEEPEST_CHAINS);
+            self.deepest_threshold
+                .store(deepest[DEEPEST_CHAINS - 1].1 + 1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> ReadMetrics {
+        ReadMetrics {
+            objects_read: self.objects.load(Ordering::Relaxed),
+            deltas_resolved: self.deltas.load(Ordering::Relaxed),
+            total_delta_depth: self.total_delta_depth.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            deepest_chains: self.deepest.lock().unwrap().clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReadCounters, DEEPEST_CHAINS};
+    use crate::shared::ObjectHash;
+
+    #[test]
+    fn deepest_chains() {
+        let counters = ReadCounters::default();
+        for depth in 0..30u8 {
+            counters.record_read(&ObjectHash::from([depth; 20]), depth as usize % 20);
+        }
+        counters.record_read(&ObjectHash::from([19; 20]), 19);
+
+        let metrics = counters.snapshot();
+        assert_eq!(31, metrics.objects_read);
+        assert_eq!(29, metrics.deltas_resolved);
+        assert_eq!(19, metrics.max_delta_depth());
+        assert_eq!(DEEPEST_CHAINS, metrics.deepest_chains.len());
+        let depths: Vec<usize> = metrics.deepest_chains.iter().map(|(_, d)| *d).collect();
+        assert_eq!(vec![19, 18, 17, 16, 15, 14, 13, 12, 11, 10], depths);
+    }
+}
@@ -0,0 +1,139 @@
+use std::ops::Deref;
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    objs::{GitObject, TreeHash},
+    Repository,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TreeChange {
+    Added(Vec<u8>),
+    Removed(Vec<u8>),
+    Modified(Vec<u8>),
+}
+
+impl TreeChange {
+    pub fn path(&self) -> &[u8] {
+        match self {
+            TreeChange::Added(path) | TreeChange::Removed(path) | TreeChange::Modified(path) => {
+                path
+            }
+        }
+    }
+}
+
+struct Entry {
+    mode: Vec<u8>,
+    hash: TreeHash,
+    is_tree: bool,
+}
+
+fn read_entries<M>(
+    repository: &mut Repository<M>,
+    tree: Option<TreeHash>,
+) -> FxHashMap<Vec<u8>, Entry> {
+    let Some(tree_hash) = tree else {
+        return FxHashMap::default();
+    };
+
+    let tree = match repository.read_object(tree_hash.into()) {
+        Some(GitObject::Tree(tree)) => tree,
+        _ => panic!("Expected a tree, found something else"),
+    };
+
+    tree.lines()
+        .map(|line| {
+            (
+                line.filename().to_vec(),
+                Entry {
+                    mode: line.mode().to_vec(),
+                    hash: line.hash.deref().clone(),
+                    is_tree: line.is_tree(),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Lists the files that differ between two trees. Subtrees with identical hashes are skipped
+/// without being read, changes are sorted by path.
+pub(crate) fn diff_trees<M>(
+    repository: &mut Repository<M>,
+    old: Option<TreeHash>,
+    new: Option<TreeHash>,
+) -> Vec<TreeChange> {
+    let mut changes = Vec::new();
+    diff_recursive(repository, old, new, b"", &mut changes);
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    changes
+}
+
+fn diff_recursive<M>(
+    repository: &mut Repository<M>,
+    old: Option<TreeHash>,
+    new: Option<TreeHash>,
+    path: &[u8],
+    changes: &mut Vec<TreeChange>,
+) {
+    if old.is_some() && old == new {
+        return;
+    }
+
+    let mut old_entries = read_entries(repository, old);
+    let new_entries = read_entries(repository, new);
+
+    for (name, new_entry) in new_entries {
+        let full_path = [path, &name].concat();
+        let old_entry = old_entries.remove(&name);
+
+        match (old_entry, new_entry.is_tree) {
+            (Some(old_entry), true) if old_entry.is_tree => {
+                let child_path = [&full_path, b"/".as_slice()].concat();
+                diff_recursive(
+                    repository,
+                    Some(old_entry.hash),
+                    Some(new_entry.hash),
+                    &child_path,
+                    changes,
+                );
+            }
+            (Some(old_entry), false) if !old_entry.is_tree => {
+                if old_entry.hash != new_entry.hash || old_entry.mode != new_entry.mode {
+                    changes.push(TreeChange::Modified(full_path));
+                }
+            }
+            (old_entry, is_tree) => {
+                if let Some(old_entry) = old_entry {
+                    removed(repository, old_entry, full_path.clone(), changes);
+                }
+
+                if is_tree {
+                    let child_path = [&full_path, b"/".as_slice()].concat();
+                    diff_recursive(repository, None, Some(new_entry.hash), &child_path, changes);
+                } else {
+                    changes.push(TreeChange::Added(full_path));
+                }
+            }
+        }
+    }
+
+    for (name, old_entry) in old_entries {
+        removed(repository, old_entry, [path, &name].concat(), changes);
+    }
+}
+
+fn removed<M>(
+    repository: &mut Repository<M>,
+    entry: Entry,
+    full_path: Vec<u8>,
+    changes: &mut Vec<TreeChange>,
+) {
+    if entry.is_tree {
+        let child_path = [&full_path, b"/".as_slice()].concat();
+        diff_recursive(repository, Some(entry.hash), None, &child_path, changes);
+    } else {
+        changes.push(TreeChange::Removed(full_path));
+    }
+}
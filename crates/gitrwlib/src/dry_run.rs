@@ -0,0 +1,120 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use bstr::{BString, ByteSlice};
+use once_cell::sync::Lazy;
+use rustc_hash::FxHashMap;
+
+use crate::{refs::RefUpdate, shared::ObjectHash, WriteObject};
+
+/// An object that would have been written without `dry_run`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WrittenObject {
+    pub hash: ObjectHash,
+    pub object_type: String,
+    /// Size of the content without the object header
+    pub size: usize,
+}
+
+impl Display for WrittenObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.hash, self.object_type, self.size)
+    }
+}
+
+/// What a dry run would have changed, collected while capturing, see
+/// `Repository::capture_dry_run`.
+#[derive(Clone, Debug, Default)]
+pub struct DryRunReport {
+    /// Number of commits that would have been rewritten or dropped, `None` if no rewrite was
+    /// applied while capturing
+    pub commits: Option<usize>,
+    /// Paths matched by the delete patterns of `ObjectStore::record_removed`, directories end
+    /// with a slash
+    pub removed_paths: BTreeSet<BString>,
+    /// Identities replaced in the author or committer of a commit, old to new
+    pub contributors: BTreeMap<BString, BString>,
+    /// What would have happened to every ref
+    pub refs: Vec<RefUpdate>,
+    /// Every object that would have been written, once per hash and sorted by it
+    pub objects: Vec<WrittenObject>,
+}
+
+#[derive(Default)]
+struct Capture {
+    objects: FxHashMap<ObjectHash, WrittenObject>,
+    report: DryRunReport,
+}
+
+/// Everything skipped because of `dry_run`, per repository path that has capturing enabled.
+/// `Repository::write` only knows the path of the repository, not the instance.
+static CAPTURES: Lazy<Mutex<FxHashMap<PathBuf, Capture>>> =
+    Lazy::new(|| Mutex::new(FxHashMap::default()));
+
+pub(crate) fn start_capture(repository_path: &Path) {
+    CAPTURES
+        .lock()
+        .unwrap()
+        .entry(repository_path.to_path_buf())
+        .or_default();
+}
+
+fn with_capture(repository_path: &Path, f: impl FnOnce(&mut Capture)) {
+    if let Some(capture) = CAPTURES.lock().unwrap().get_mut(repository_path) {
+        f(capture);
+    }
+}
+
+pub(crate) fn capture(repository_path: &Path, object: &WriteObject) {
+    with_capture(repository_path, |capture| {
+        capture
+            .objects
+            .entry(object.hash.clone())
+            .or_insert_with(|| WrittenObject {
+                hash: object.hash.clone(),
+                object_type: object.prefix.clone(),
+                size: object.bytes.get_bytes().len(),
+            });
+    });
+}
+
+pub(crate) fn capture_removed_path(repository_path: &Path, path: &[u8]) {
+    with_capture(repository_path, |capture| {
+        if !capture.report.removed_paths.contains(path.as_bstr()) {
+            capture.report.removed_paths.insert(path.into());
+        }
+    });
+}
+
+pub(crate) fn capture_contributor(repository_path: &Path, old: &[u8], new: &[u8]) {
+    with_capture(repository_path, |capture| {
+        capture
+            .report
+            .contributors
+            .entry(old.into())
+            .or_insert_with(|| new.into());
+    });
+}
+
+pub(crate) fn capture_rewrite(repository_path: &Path, commits: usize, refs: &[RefUpdate]) {
+    with_capture(repository_path, |capture| {
+        capture.report.commits = Some(commits);
+        capture.report.refs = refs.to_vec();
+    });
+}
+
+/// Stops capturing and returns the report.
+pub(crate) fn take_capture(repository_path: &Path) -> DryRunReport {
+    let Some(capture) = CAPTURES.lock().unwrap().remove(repository_path) else {
+        return DryRunReport::default();
+    };
+
+    let mut report = capture.report;
+    report.objects = capture.objects.into_values().collect();
+    report.objects.sort_unstable_by(|a, b| a.hash.cmp(&b.hash));
+    report
+}
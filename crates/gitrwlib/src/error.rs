@@ -0,0 +1,74 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Errors of all public functions of the crate: reading or writing the files of a repository,
+/// arguments that cannot be used with it, and cancelled operations.
+#[derive(Debug)]
+pub enum GitRwError {
+    /// The path is neither a repository nor a working copy
+    NotARepository(PathBuf),
+    /// Reading or writing a file or directory failed
+    Io { path: PathBuf, source: io::Error },
+    /// Writing to the writer passed in by the caller failed, e.g. for packs and bundles
+    Output(io::Error),
+    /// A file of the repository has unexpected content, or an object is missing
+    Corrupt { path: PathBuf, reason: String },
+    /// A revision, ref name, pattern or option cannot be used, or the state of the repository
+    /// rules out the operation, e.g. an interrupted ref update
+    InvalidInput(String),
+    /// The operation was cancelled before it finished, the message says what was left behind
+    Cancelled(String),
+}
+
+impl GitRwError {
+    /// For `map_err`, attaches `path` to an IO error. It can be kept and passed to several
+    /// `map_err` calls on the same file.
+    pub(crate) fn io(path: &Path) -> impl Fn(io::Error) -> GitRwError + Copy + '_ {
+        move |source| GitRwError::Io {
+            path: path.to_owned(),
+            source,
+        }
+    }
+
+    pub(crate) fn invalid(message: impl Into<String>) -> GitRwError {
+        GitRwError::InvalidInput(message.into())
+    }
+
+    pub(crate) fn corrupt(path: &Path, reason: impl Into<String>) -> GitRwError {
+        GitRwError::Corrupt {
+            path: path.to_owned(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for GitRwError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GitRwError::NotARepository(path) => write!(
+                f,
+                "{} is not a git repository, pass the path of a bare repository or a working copy",
+                path.display()
+            ),
+            GitRwError::Io { path, source } => write!(f, "{}: {source}", path.display()),
+            GitRwError::Output(source) => write!(f, "writing the output failed: {source}"),
+            GitRwError::Corrupt { path, reason } => write!(f, "{}: {reason}", path.display()),
+            GitRwError::InvalidInput(message) | GitRwError::Cancelled(message) => {
+                f.write_str(message)
+            }
+        }
+    }
+}
+
+impl Error for GitRwError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GitRwError::Io { source, .. } | GitRwError::Output(source) => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -21,14 +21,18 @@ pub struct CommitFfi {
     commit: Commit,
 }
 
+/// Returns a null pointer if the repository cannot be opened.
 #[no_mangle]
 pub unsafe extern "C" fn repo_new(slice_ptr: &mut u8, len: u64) -> *mut FfiRepository<'static> {
     let x = slice::from_raw_parts(slice_ptr, len.try_into().unwrap());
     let mut path = PathBuf::new();
     path.push(x.as_bstr().to_os_str().unwrap());
 
+    let Ok(repository) = Repository::open(path) else {
+        return std::ptr::null_mut();
+    };
     Box::into_raw(Box::new(FfiRepository {
-        repository: Repository::create(path),
+        repository,
         commits_topo: None,
         commits_lifo: None,
     }))
@@ -0,0 +1,86 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::error::GitRwError;
+
+/// Where the parts of a repository are stored. Both are the same directory, except for linked
+/// worktrees: their own directory only contains `HEAD` and a `commondir` file pointing to the
+/// directory with the objects, refs and config shared by all worktrees.
+#[derive(Clone, Debug)]
+pub(crate) struct GitDirs {
+    pub(crate) git_dir: PathBuf,
+    pub(crate) common_dir: PathBuf,
+    /// The checked out files, `None` for bare repositories
+    pub(crate) work_tree: Option<PathBuf>,
+}
+
+/// Finds the repository of a working copy with a `.git` directory, or follows the `.git` file
+/// of a worktree or submodule checkout. `path` can be the `.git` file itself. Other paths are
+/// taken as the repository directory.
+pub(crate) fn resolve(path: &Path) -> Result<GitDirs, GitRwError> {
+    let dot_git = path.join(".git");
+    let (git_dir, work_tree) = if path.is_file() {
+        let work_tree = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        (read_git_file(path)?, Some(work_tree))
+    } else if dot_git.is_file() {
+        (read_git_file(&dot_git)?, Some(path.to_path_buf()))
+    } else if dot_git.is_dir() {
+        (dot_git, Some(path.to_path_buf()))
+    } else {
+        (path.to_path_buf(), None)
+    };
+
+    let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common_dir) => git_dir.join(common_dir.trim_end()),
+        Err(_) => git_dir.clone(),
+    };
+
+    Ok(GitDirs {
+        git_dir,
+        common_dir,
+        work_tree,
+    })
+}
+
+/// Reads `gitdir: <path>`, a relative path is relative to the directory of the file.
+fn read_git_file(git_file: &Path) -> Result<PathBuf, GitRwError> {
+    let content = fs::read_to_string(git_file).map_err(GitRwError::io(git_file))?;
+    let target = content
+        .trim_end()
+        .strip_prefix("gitdir: ")
+        .ok_or_else(|| GitRwError::corrupt(git_file, "does not contain a gitdir: line"))?;
+
+    Ok(git_file.parent().unwrap_or(Path::new("")).join(target))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    #[test]
+    fn worktree_git_file() {
+        let dir = std::env::temp_dir().join(format!("gitrw-git-dir-{}", std::process::id()));
+        let worktree_dir = dir.join("repo.git/worktrees/wt");
+        fs::create_dir_all(&worktree_dir).unwrap();
+        fs::create_dir_all(dir.join("wt")).unwrap();
+        fs::write(dir.join("wt/.git"), "gitdir: ../repo.git/worktrees/wt\n").unwrap();
+        fs::write(worktree_dir.join("commondir"), "../..\n").unwrap();
+
+        let dirs = super::resolve(&dir.join("wt")).unwrap();
+        assert_eq!(dir.join("wt/../repo.git/worktrees/wt"), dirs.git_dir);
+        assert_eq!(
+            fs::canonicalize(dir.join("repo.git")).unwrap(),
+            fs::canonicalize(dirs.common_dir).unwrap()
+        );
+
+        assert_eq!(Some(dir.join("wt")), dirs.work_tree);
+
+        let dirs = super::resolve(&dir.join("repo.git")).unwrap();
+        assert_eq!(dirs.git_dir, dirs.common_dir);
+        assert_eq!(None, dirs.work_tree);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
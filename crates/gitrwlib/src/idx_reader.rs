@@ -1,32 +1,32 @@
 use std::{
-    error::Error,
     fs::File,
-    io::{BufReader, Read, Seek, SeekFrom},
+    io::{self, BufReader, Read, Seek, SeekFrom},
     path::Path,
 };
 
-use crate::shared::ObjectHash;
+use crate::{error::GitRwError, shared::ObjectHash};
 
 const HEADER_LEN: usize = 8;
 const HASH_LEN: usize = 20;
 const FANOUT_LEN: usize = 4;
 const HASHES_TABLE_START: usize = HEADER_LEN + 256 * FANOUT_LEN;
 
-pub struct PackOffset {
+pub(crate) struct PackOffset {
     pub hash: ObjectHash,
     pub offset: usize,
 }
 
 #[allow(clippy::uninit_vec)]
-pub fn get_pack_offsets(idx_path: &Path) -> Result<Vec<PackOffset>, Box<dyn Error>> {
-    let file = File::open(idx_path)?;
+pub(crate) fn get_pack_offsets(idx_path: &Path) -> Result<Vec<PackOffset>, GitRwError> {
+    let io = GitRwError::io(idx_path);
+    let file = File::open(idx_path).map_err(io)?;
     let mut reader = BufReader::new(file);
 
     let mut buffer = Vec::with_capacity(HASHES_TABLE_START);
     unsafe { buffer.set_len(HASHES_TABLE_START) };
 
-    reader.read_exact(&mut buffer)?;
-    verify_header(&buffer)?;
+    reader.read_exact(&mut buffer).map_err(io)?;
+    verify_header(idx_path, &buffer)?;
 
     let object_count = get_file_count_from_fanout(&buffer[HEADER_LEN + 255 * FANOUT_LEN..]);
     let mut result = Vec::with_capacity(object_count);
@@ -34,22 +34,16 @@ pub fn get_pack_offsets(idx_path: &Path) -> Result<Vec<PackOffset>, Box<dyn Erro
         return Ok(result);
     }
 
-    let mut hashes = Vec::with_capacity(object_count);
-
-    for _ in 0..object_count {
-        let mut hash = [0u8; 20];
-        reader.read_exact(&mut hash)?;
-        hashes.push(hash);
-    }
+    let hashes = read_hashes(&mut reader, object_count).map_err(io)?;
 
     let offset: u64 =
         HASHES_TABLE_START as u64 + HASH_LEN as u64 * object_count as u64 + 4 * object_count as u64;
-    reader.seek(SeekFrom::Start(offset))?;
+    reader.seek(SeekFrom::Start(offset)).map_err(io)?;
 
     let mut pack_offset = [0u8; 4];
     let mut large_offsets = Vec::new();
     for hash in hashes {
-        reader.read_exact(&mut pack_offset)?;
+        reader.read_exact(&mut pack_offset).map_err(io)?;
         let mut offset: usize = pack_offset[3] as usize;
         offset += (pack_offset[2] as usize) << 8;
         offset += (pack_offset[1] as usize) << 16;
@@ -69,11 +63,11 @@ pub fn get_pack_offsets(idx_path: &Path) -> Result<Vec<PackOffset>, Box<dyn Erro
         + HASH_LEN as u64 * object_count as u64
         + 4 * object_count as u64
         + 4 * object_count as u64;
-    reader.seek(SeekFrom::Start(offset))?;
+    reader.seek(SeekFrom::Start(offset)).map_err(io)?;
 
     let mut pack_offset = [0u8; 8];
     for large_offset in large_offsets {
-        reader.read_exact(&mut pack_offset)?;
+        reader.read_exact(&mut pack_offset).map_err(io)?;
         result.push(PackOffset {
             hash: large_offset.into(),
             offset: usize::from_be_bytes(pack_offset),
@@ -83,6 +77,41 @@ pub fn get_pack_offsets(idx_path: &Path) -> Result<Vec<PackOffset>, Box<dyn Erro
     Ok(result)
 }
 
+/// Reads the CRC32 checksums the idx stores for the raw bytes of every pack entry.
+pub(crate) fn get_pack_crcs(idx_path: &Path) -> Result<Vec<(ObjectHash, u32)>, GitRwError> {
+    let io = GitRwError::io(idx_path);
+    let file = File::open(idx_path).map_err(io)?;
+    let mut reader = BufReader::new(file);
+
+    let mut buffer = vec![0u8; HASHES_TABLE_START];
+    reader.read_exact(&mut buffer).map_err(io)?;
+    verify_header(idx_path, &buffer)?;
+
+    let object_count = get_file_count_from_fanout(&buffer[HEADER_LEN + 255 * FANOUT_LEN..]);
+    let hashes = read_hashes(&mut reader, object_count).map_err(io)?;
+
+    // the crc table directly follows the hashes
+    let mut crc = [0u8; 4];
+    let mut result = Vec::with_capacity(object_count);
+    for hash in hashes {
+        reader.read_exact(&mut crc).map_err(io)?;
+        result.push((ObjectHash::from(hash), u32::from_be_bytes(crc)));
+    }
+
+    Ok(result)
+}
+
+fn read_hashes(reader: &mut BufReader<File>, object_count: usize) -> io::Result<Vec<[u8; 20]>> {
+    let mut hashes = Vec::with_capacity(object_count);
+    for _ in 0..object_count {
+        let mut hash = [0u8; 20];
+        reader.read_exact(&mut hash)?;
+        hashes.push(hash);
+    }
+
+    Ok(hashes)
+}
+
 #[inline]
 fn msb_set(pack_offset: &[u8]) -> bool {
     (pack_offset[0] & 0b10000000) != 0
@@ -98,22 +127,7 @@ fn get_file_count_from_fanout(bytes: &[u8]) -> usize {
     result
 }
 
-#[derive(Debug)]
-pub enum IdxError {
-    InvalidHeader,
-}
-
-impl std::error::Error for IdxError {}
-
-impl std::fmt::Display for IdxError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            &IdxError::InvalidHeader => f.write_str("IDX file has invalid header."),
-        }
-    }
-}
-
-fn verify_header(buffer: &[u8]) -> Result<(), IdxError> {
+fn verify_header(idx_path: &Path, buffer: &[u8]) -> Result<(), GitRwError> {
     if buffer[0] == 255
         && buffer[1] == b't'
         && buffer[2] == b'O'
@@ -126,7 +140,7 @@ fn verify_header(buffer: &[u8]) -> Result<(), IdxError> {
         return Ok(());
     }
 
-    Err(IdxError::InvalidHeader)
+    Err(GitRwError::corrupt(idx_path, "IDX file has invalid header"))
 }
 
 #[cfg(test)]
@@ -138,7 +152,7 @@ mod test {
     pub fn header_test() {
         let buf = [0u8; 1024];
 
-        let r = verify_header(&buf);
+        let r = verify_header(std::path::Path::new("test.idx"), &buf);
         assert!(r.is_err());
     }
 }
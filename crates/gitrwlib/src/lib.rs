@@ -1,52 +1,152 @@
-use core::panic;
 use std::{
-    collections::HashMap,
-    error::Error,
-    hash::{BuildHasher, Hasher},
-    io::{self, BufWriter, Write},
+    collections::{HashMap, HashSet},
+    hash::{BuildHasher, BuildHasherDefault, Hasher},
+    io::Write,
+    marker::PhantomData,
+    ops::Deref,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
-use commits::{CommitsFifoIter, CommitsLifoIter};
+use bstr::ByteSlice;
+use commit_cache::CommitCache;
+use commits::{CommitsDateIter, CommitsFifoIter, CommitsLifoIter, CommitsRangeIter, TreeCache};
 use compression::Decompression;
 
-use objs::{CommitBase, CommitEditable, CommitHash, GitObject, Tag, Tree};
+use objs::{
+    Blob, CommitBase, CommitEditable, CommitHash, GitObject, Tag, Tree, TreeHash, TreeLine,
+};
 use packreader::PackReader;
-use rayon::prelude::{ParallelBridge, ParallelIterator};
-use refs::GitRef;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rewrite_state::RewriteState;
 use rs_sha1::{HasherContext, Sha1Hasher};
-use shared::ObjectHash;
+use rustc_hash::FxHasher;
 
+mod bundle;
+mod cancel;
+mod commit_cache;
+mod commit_filter;
+mod commit_graph;
+mod commit_map;
 mod commits;
 mod compression;
+mod config;
+mod count;
+mod delta_cache;
+mod diff;
+mod dry_run;
+mod error;
+mod git_dir;
 // pub mod ffi;
 mod idx_reader;
+mod loose;
+mod message_hashes;
+mod notes;
+mod object_location;
+mod object_store;
 mod pack_diff;
+mod pack_stats;
+mod pack_writer;
 mod packreader;
+mod range_stats;
+mod ref_journal;
 mod refs;
+mod reftable;
+mod rewrite;
+mod rewrite_state;
+mod server_info;
 mod shared;
+mod skipped;
+mod target;
+#[cfg(test)]
+mod test_repository;
+mod tree_report;
+mod verify;
+mod workspace;
 
 pub mod objs;
+pub mod pack;
+pub mod path_matcher;
+pub mod prelude;
+
+pub use cancel::CancellationToken;
+pub use commit_filter::CommitFilter;
+pub use commit_graph::CommitGraph;
+pub use commit_map::{map_compose, CommitMapFormat, CommitMapReader, CommitMapWriter};
+pub use commits::CommitWithTree;
+pub use count::ObjectCounts;
+pub use delta_cache::{DeltaCacheOptions, ReadMetrics};
+pub use diff::TreeChange;
+pub use dry_run::{DryRunReport, WrittenObject};
+pub use error::GitRwError;
+pub use loose::LooseObject;
+pub use message_hashes::MessageHashes;
+pub use object_location::ObjectLocation;
+pub use object_store::ObjectStore;
+pub use pack_stats::{PackEntry, PackStats};
+pub use path_matcher::{PathEntry, PathMatcher};
+pub use range_stats::RangeStats;
+pub use ref_journal::JournalEntry;
+pub use refs::{
+    DanglingRefs, GitRef, RefInfo, RefOptions, RefRename, RefStatus, RefUpdate, SimpleRef, TagRef,
+};
+pub use rewrite::{CommitAction, RewriteOptions};
+pub use shared::ObjectHash;
+pub use skipped::{SkipReport, SkipTracker, SkippedObjects};
+pub use tree_report::{RemovedFiles, TreeRewriteReport};
+pub use verify::{CorruptObject, CorruptObjects, VerifyOptions, VerifyReport, DEFAULT_MAX_ERRORS};
+pub use workspace::{ExpiredFiles, DEFAULT_EXPIRY};
 
-pub struct Repository {
+/// Mode of repositories opened with `Repository::open_read_only` or
+/// `Repository::open_pack_file`. They can be read like any other repository, but have none of
+/// the methods that write objects, rewrite commits or update refs, not even for dry runs:
+///
+/// ```compile_fail
+/// let mut repository = gitrwlib::Repository::open_read_only("repo.git".into())?;
+/// repository.update_server_info()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct ReadOnly;
+
+/// Mode of repositories opened with `Repository::open`, the default. Only these can be
+/// rewritten.
+#[derive(Clone, Copy, Debug)]
+pub struct ReadWrite;
+
+/// A git repository, `M` is either `ReadWrite` or `ReadOnly`.
+pub struct Repository<M = ReadWrite> {
+    /// Directory with the objects and refs, the common directory for linked worktrees
     path: PathBuf,
+    /// Directory with `HEAD`
+    git_dir: PathBuf,
+    work_tree: Option<PathBuf>,
+    /// Only a marker, `fn() -> M` keeps the repository `Send` and `Sync` for every mode
+    mode: PhantomData<fn() -> M>,
+    /// Shared by all clones, checked by long running operations like rewrites
+    cancel: CancellationToken,
+    /// Shared by all clones, see `set_commit_cache`
+    commit_cache: Option<Arc<CommitCache>>,
+    /// Shared by all clones, see `set_skip_tracker`
+    skipped: SkipTracker,
+    /// See `set_message_hashes`
+    message_hashes: Option<MessageHashes>,
+    /// See `set_state_file`
+    rewrite_state: Option<Arc<RewriteState>>,
     pack_reader: PackReader,
     decompression: Decompression,
 }
 
-impl Clone for Repository {
+impl<M> Clone for Repository<M> {
     fn clone(&self) -> Self {
-        Self {
-            path: self.path.clone(),
-            pack_reader: self.pack_reader.clone(),
-            decompression: Decompression::default(),
-        }
+        self.with_mode()
     }
 }
 
-#[derive(Debug)]
+/// Object content, cloning only shares the bytes.
+#[derive(Clone, Debug)]
 pub struct WriteBytes {
-    bytes: Box<[u8]>,
+    bytes: Arc<[u8]>,
     start: usize,
 }
 
@@ -56,6 +156,7 @@ impl WriteBytes {
     }
 }
 
+#[derive(Clone)]
 pub struct WriteObject {
     pub hash: ObjectHash,
     prefix: String,
@@ -66,7 +167,7 @@ impl From<CommitEditable> for WriteObject {
     fn from(value: CommitEditable) -> Self {
         let wb = value.to_bytes();
         Self {
-            hash: calculate_hash(&wb.bytes, b"commit"),
+            hash: calculate_hash(&wb.bytes[wb.start..], b"commit"),
             prefix: String::from("commit"),
             bytes: wb,
         }
@@ -83,6 +184,16 @@ impl From<Tag> for WriteObject {
     }
 }
 
+impl From<Blob> for WriteObject {
+    fn from(value: Blob) -> Self {
+        Self {
+            hash: value.hash().clone(),
+            prefix: String::from("blob"),
+            bytes: value.bytes(),
+        }
+    }
+}
+
 impl From<Tree> for WriteObject {
     fn from(value: Tree) -> Self {
         Self {
@@ -93,7 +204,7 @@ impl From<Tree> for WriteObject {
     }
 }
 
-pub fn calculate_hash(data: &[u8], prefix: &[u8]) -> ObjectHash {
+pub(crate) fn calculate_hash(data: &[u8], prefix: &[u8]) -> ObjectHash {
     let mut hasher = Sha1Hasher::default();
     hasher.write(prefix);
     hasher.write(b" ");
@@ -105,108 +216,1594 @@ pub fn calculate_hash(data: &[u8], prefix: &[u8]) -> ObjectHash {
     ObjectHash::from(bytes)
 }
 
-impl Repository {
-    pub fn create(path: PathBuf) -> Self {
-        let pack_reader = PackReader::create(&path).unwrap();
-        let decompression = Decompression::default();
+fn object_exists(repository_path: &Path, pack_reader: &PackReader, hash: &ObjectHash) -> bool {
+    if pack_reader.contains(hash) {
+        return true;
+    }
 
-        Self {
+    let hash = hash.to_string();
+    repository_path
+        .join("objects")
+        .join(&hash[0..2])
+        .join(&hash[2..])
+        .is_file()
+}
+
+impl Repository<ReadWrite> {
+    /// Opens the repository at `path`. Besides bare repositories this can be a working copy, or
+    /// a worktree or submodule checkout with a `.git` file pointing to the repository.
+    pub fn open(path: PathBuf) -> Result<Self, GitRwError> {
+        Self::open_as(path)
+    }
+}
+
+impl Repository<ReadOnly> {
+    /// Like `open`, but the repository has none of the methods that write objects, refs or
+    /// files, see `ReadOnly`.
+    pub fn open_read_only(path: PathBuf) -> Result<Self, GitRwError> {
+        Self::open_as(path)
+    }
+
+    /// Opens a single pack or bundle file read-only, e.g. to inspect an upload before accepting
+    /// it. All objects get indexed in memory first. There are no refs and no loose objects, the
+    /// commits are listed by `commits_in_packs`.
+    pub fn open_pack_file(path: PathBuf) -> Result<Self, GitRwError> {
+        let pack_reader = PackReader::from_pack_file(&path)?;
+
+        Ok(Self {
+            git_dir: path.clone(),
             path,
+            work_tree: None,
+            mode: PhantomData,
+            cancel: CancellationToken::default(),
+            commit_cache: None,
+            skipped: SkipTracker::default(),
+            message_hashes: None,
+            rewrite_state: None,
             pack_reader,
-            decompression,
+            decompression: Decompression::default(),
+        })
+    }
+}
+
+impl<M> Repository<M> {
+    /// A clone of the repository with another mode, the read-only helpers of the crate use
+    /// `Repository<ReadOnly>` for repositories of any mode.
+    fn with_mode<N>(&self) -> Repository<N> {
+        Repository {
+            path: self.path.clone(),
+            git_dir: self.git_dir.clone(),
+            work_tree: self.work_tree.clone(),
+            mode: PhantomData,
+            cancel: self.cancel.clone(),
+            commit_cache: self.commit_cache.clone(),
+            skipped: self.skipped.clone(),
+            message_hashes: self.message_hashes,
+            rewrite_state: self.rewrite_state.clone(),
+            pack_reader: self.pack_reader.clone(),
+            decompression: Decompression::default(),
+        }
+    }
+
+    fn open_as(path: PathBuf) -> Result<Self, GitRwError> {
+        let dirs = git_dir::resolve(&path)?;
+        if !dirs.common_dir.join("objects").is_dir() {
+            return Err(GitRwError::NotARepository(path));
         }
+
+        let pack_reader = PackReader::create(&dirs.common_dir)?;
+        let decompression = Decompression::default();
+
+        Ok(Self {
+            path: dirs.common_dir,
+            git_dir: dirs.git_dir,
+            work_tree: dirs.work_tree,
+            mode: PhantomData,
+            cancel: CancellationToken::default(),
+            commit_cache: None,
+            skipped: SkipTracker::default(),
+            message_hashes: None,
+            rewrite_state: None,
+            pack_reader,
+            decompression,
+        })
+    }
+
+    /// Directory with the objects and refs of the repository.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The checked out files, `None` for bare repositories and if the `.git` directory was
+    /// opened directly.
+    pub fn work_tree(&self) -> Option<&Path> {
+        self.work_tree.as_deref()
+    }
+
+    /// Whether the repository has no checked out files, going by `core.bare` if the `.git`
+    /// directory was opened directly.
+    pub fn is_bare(&self) -> bool {
+        self.work_tree.is_none()
+            && self
+                .config_values("core", "bare")
+                .last()
+                .map(String::as_str)
+                != Some("false")
     }
 
     pub fn read_object(&mut self, hash: ObjectHash) -> Option<GitObject> {
         commits::read_object_from_hash(&mut self.decompression, &self.path, &self.pack_reader, hash)
     }
 
-    pub fn write(mut repo_path: PathBuf, object: WriteObject, dry_run: bool) {
-        if dry_run {
-            return;
+    /// Reads the tree with the given hash, fails if the object is missing or is no tree.
+    pub fn read_tree(&mut self, hash: TreeHash) -> Result<Tree, GitRwError> {
+        match self.read_object(hash.clone().into()) {
+            Some(GitObject::Tree(tree)) => Ok(tree),
+            Some(_) => Err(GitRwError::corrupt(
+                &self.path,
+                format!("Object {hash} is not a tree"),
+            )),
+            None => Err(GitRwError::corrupt(
+                &self.path,
+                format!("Tree {hash} is missing"),
+            )),
         }
+    }
+
+    /// Reads the object at `offset` of a pack without looking up its hash, which gets calculated
+    /// from the content instead. The ids of the packs and the offsets of some of their entries
+    /// are part of `pack_stats`. Returns `None` for unknown packs and offsets outside of the pack.
+    pub fn read_at_offset(&mut self, pack_id: usize, offset: usize) -> Option<GitObject> {
+        self.pack_reader
+            .read_git_object_at_offset(&mut self.decompression, pack_id, offset)
+    }
 
-        let hash = object.hash.to_string();
-        let data = object.bytes;
-        let prefix = object.prefix;
+    /// Checks whether an object is present in one of the packs or as loose object. Only the idx
+    /// tables and the file system are consulted, nothing gets decompressed.
+    pub fn object_exists(&self, hash: &ObjectHash) -> bool {
+        object_exists(&self.path, &self.pack_reader, hash)
+    }
 
-        repo_path.push("objects");
-        repo_path.push(&hash[0..2]);
+    /// Every copy of an object: in each pack containing it, then the loose file. Reads use the
+    /// first one, an object kept by a gc may e.g. still be in a `.keep` pack. Empty if the
+    /// object is missing.
+    pub fn object_location(&self, hash: &ObjectHash) -> Vec<ObjectLocation> {
+        let mut locations: Vec<ObjectLocation> = self
+            .pack_reader
+            .locations(hash)
+            .into_iter()
+            .map(|(pack_file, offset)| ObjectLocation::Packed { pack_file, offset })
+            .collect();
 
-        std::fs::create_dir_all(&repo_path).unwrap();
+        let hex = hash.to_string();
+        let loose = self.path.join("objects").join(&hex[0..2]).join(&hex[2..]);
+        if loose.is_file() {
+            locations.push(ObjectLocation::Loose(loose));
+        }
 
-        repo_path.push(&hash[2..]);
-        if !Path::new(&repo_path).exists() {
-            match compression::pack_file(&repo_path, prefix.as_str(), &data) {
-                Ok(_) => {}
-                Err(e) => match e.kind() {
-                    io::ErrorKind::AlreadyExists => {}
-                    _ => panic!("Error writing object: {}", e),
-                },
+        locations
+    }
+
+    /// Type and size of an object without reading its content: only the pack entry header, the
+    /// start of the delta instructions or the header of the loose object get inflated.
+    pub fn object_header(
+        &mut self,
+        hash: &ObjectHash,
+    ) -> Result<(&'static str, usize), GitRwError> {
+        if let Some((object_type, size)) = self
+            .pack_reader
+            .object_header(&mut self.decompression, hash)
+        {
+            return Ok((packreader::object_type_name(object_type), size));
+        }
+
+        let hex = hash.to_string();
+        let loose = LooseObject {
+            hash: hash.clone(),
+            path: self.path.join("objects").join(&hex[0..2]).join(&hex[2..]),
+        };
+        if !loose.path.is_file() {
+            return Err(GitRwError::corrupt(
+                &self.path,
+                format!("Object {hash} is missing"),
+            ));
+        }
+
+        let (object_type, size) = loose.header()?;
+        let object_type = ["commit", "tree", "blob", "tag"]
+            .into_iter()
+            .find(|name| *name == object_type)
+            .ok_or_else(|| {
+                GitRwError::corrupt(&loose.path, format!("unknown object type {object_type}"))
+            })?;
+        Ok((object_type, size))
+    }
+
+    /// Counts packs, packed objects by type and loose objects.
+    pub fn count_objects(&self) -> ObjectCounts {
+        count::count_objects(&self.path, &self.pack_reader)
+    }
+
+    /// Creates the bare repository `target` with the objects and refs of this one, to be rewritten
+    /// instead of it. Its `HEAD` points to the default branch: the branch `HEAD` points to here,
+    /// the branch of a detached `HEAD`, or `main` or `master`. Fails if `target` exists.
+    pub fn copy_to(&self, target: &Path) -> Result<(), GitRwError> {
+        target::copy_repository(&self.path, &self.git_dir, target)
+    }
+
+    /// Whether a commit-graph is present and readable, history walks use it to find parents
+    /// without inflating commits.
+    pub fn has_commit_graph(&self) -> bool {
+        self.commit_graph().is_some()
+    }
+
+    /// The commit-graph with parents and generation numbers, `None` if the repository has none
+    /// or it cannot be read.
+    pub fn commit_graph(&self) -> Option<CommitGraph> {
+        CommitGraph::open(&self.path)
+    }
+
+    /// Reads every entry of every pack and checks that its content matches the hash in the idx
+    /// file. Damaged entries and deltas with a damaged or missing base don't stop the scan, the
+    /// report lists them up to `VerifyOptions::max_errors`. Fails if the idx files can not be
+    /// read or the cancellation token of the repository gets cancelled.
+    pub fn verify_packs(&self, options: &VerifyOptions) -> Result<VerifyReport, GitRwError> {
+        verify::verify_packs(&self.pack_reader, options, &self.cancel)
+    }
+
+    /// Enables caching of materialized objects in the middle of long delta chains, which makes
+    /// reading the objects at the end of such chains a lot cheaper. `None` disables the cache.
+    /// Clones created afterwards share the cache.
+    pub fn set_delta_cache(&mut self, options: Option<DeltaCacheOptions>) {
+        self.pack_reader.set_delta_cache(options);
+    }
+
+    /// Lets `token` stop rewrites, `pack_stats` and `verify_packs` of this repository and of the
+    /// clones created afterwards. The commits are no longer walked once it is cancelled,
+    /// `apply_rewrite` then leaves the refs untouched and only writes the commits rewritten so
+    /// far to a `.partial` mapping file in the workspace.
+    pub fn set_cancellation(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Lets `tracker` collect the refs and parents that commit walks of this repository and of
+    /// the clones created afterwards skip, e.g. refs pointing to trees or parents missing in
+    /// shallow clones.
+    pub fn set_skip_tracker(&mut self, tracker: SkipTracker) {
+        self.skipped = tracker;
+    }
+
+    /// Everything commit walks skipped so far.
+    pub fn skip_report(&self) -> SkipReport {
+        self.skipped.report()
+    }
+
+    /// Statistics about all objects read from packs so far, by this repository and its clones.
+    pub fn read_metrics(&self) -> ReadMetrics {
+        self.pack_reader.read_metrics()
+    }
+
+    /// All loose objects of the repository, objects that are only stored in packs are not part
+    /// of it.
+    pub fn loose_objects(&self) -> impl Iterator<Item = LooseObject> {
+        loose::loose_objects(&self.path)
+    }
+
+    /// Object counts, delta chain depths and compression of every pack. Lists up to `top`
+    /// entries per pack with the highest ratio of stored to inflated size and with the longest
+    /// delta chains. Entries with damaged headers are listed in `PackStats::corrupt` instead.
+    /// Fails if the cancellation token of the repository gets cancelled.
+    pub fn pack_stats(&self, top: usize) -> Result<Vec<PackStats>, GitRwError> {
+        pack_stats::pack_stats(&self.pack_reader, top, &self.cancel)
+    }
+
+    /// Writes a pack of the objects selected by `revs` to `writer`, e.g. to pipe the result of a
+    /// rewrite into `git index-pack --stdin`. `rev` includes everything reachable from it,
+    /// `^rev` excludes the commits reachable from it and `a..b` is short for `^a b`. Without any
+    /// included revisions the targets of all refs are included. The trees and blobs of included
+    /// commits are always packed, so the pack never depends on other objects. Entries are not
+    /// deltified. Returns the number of objects written.
+    pub fn write_pack(&self, revs: &[&str], writer: impl Write) -> Result<usize, GitRwError> {
+        pack_writer::write_pack(self, revs, writer)
+    }
+
+    /// Writes a git bundle, which `git clone` and `git fetch` accept like a remote, of the refs
+    /// and objects selected by `revs` to `writer`. `revs` works like for `write_pack`, but the
+    /// included revisions have to be refs. Excluded commits become prerequisites of the bundle
+    /// that the receiving repository must already have. Without `revs` the bundle contains every
+    /// ref and `HEAD`, like `git bundle create --all`. Returns the number of refs and objects
+    /// written.
+    pub fn write_bundle(
+        &self,
+        revs: &[&str],
+        writer: impl Write,
+    ) -> Result<(usize, usize), GitRwError> {
+        bundle::write_bundle(self, revs, writer)
+    }
+
+    /// Returns all hashes of `hashes` that are not present in the repository.
+    pub fn filter_missing(&self, hashes: impl IntoIterator<Item = ObjectHash>) -> Vec<ObjectHash> {
+        let (path, pack_reader) = (&self.path, &self.pack_reader);
+        let hashes: Vec<ObjectHash> = hashes.into_iter().collect();
+        hashes
+            .into_par_iter()
+            .filter(|hash| !object_exists(path, pack_reader, hash))
+            .collect()
+    }
+
+    /// Resolves a full object hash, a ref name (`HEAD`, `main`, `v1.0`, `refs/heads/main`) or
+    /// an unambiguous abbreviated hash to the object it points at. Ref names win over
+    /// abbreviations. Tags are not peeled.
+    pub fn resolve(&self, rev: &str) -> Option<ObjectHash> {
+        if rev.len() == 40 && rev.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return rev.as_bytes().as_bstr().try_into().ok();
+        }
+
+        match refs::find_ref_target(&self.path, &self.git_dir, rev) {
+            Ok(Some(target)) => target.try_into().ok(),
+            _ => self.resolve_prefix(rev).ok(),
+        }
+    }
+
+    /// Finds the object whose hash starts with `prefix`, at least 4 hex digits. Fails if no
+    /// object or more than one matches.
+    pub fn resolve_prefix(&self, prefix: &str) -> Result<ObjectHash, GitRwError> {
+        if prefix.len() < 4 || prefix.len() > 40 || !prefix.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(GitRwError::invalid(format!(
+                "{prefix} is no hash abbreviation of at least 4 hex digits"
+            )));
+        }
+
+        let mut found = self.pack_reader.find_prefix(prefix);
+        found.extend(
+            loose::loose_objects(&self.path)
+                .map(|object| object.hash)
+                .filter(|hash| hash.has_hex_prefix(prefix)),
+        );
+        found.sort();
+        found.dedup();
+
+        match found.len() {
+            0 => Err(GitRwError::invalid(format!(
+                "No object starts with {prefix}"
+            ))),
+            1 => Ok(found.pop().unwrap()),
+            n => Err(GitRwError::invalid(format!(
+                "Abbreviation {prefix} is ambiguous, {n} objects match"
+            ))),
+        }
+    }
+
+    /// Resolves `rev` like `resolve` and follows tags until a commit is reached. Returns `None`
+    /// for unknown revisions and for tags pointing to trees or blobs.
+    pub fn peel_to_commit(&mut self, rev: &str) -> Option<CommitHash> {
+        let hash = self.resolve(rev)?;
+        commits::peel_to_commit(&mut self.decompression, &self.path, &self.pack_reader, hash)
+            .map(|commit| commit.hash)
+    }
+
+    /// Finds the object at `path` inside the tree of the given commit, tag or tree. An empty
+    /// path returns the root tree itself.
+    pub fn lookup_path(&mut self, hash: ObjectHash, path: &[u8]) -> Option<GitObject> {
+        let mut object = self.read_object(hash)?;
+        loop {
+            object = match object {
+                GitObject::Tag(tag) => self.read_object(tag.object())?,
+                GitObject::Commit(commit) => self.read_object(commit.tree().into())?,
+                _ => break,
+            };
+        }
+
+        for component in path.split_str("/").filter(|c| !c.is_empty()) {
+            let tree = match object {
+                GitObject::Tree(tree) => tree,
+                _ => return None,
+            };
+
+            let hash = tree
+                .lines()
+                .find(|line| line.filename() == component)?
+                .hash
+                .0
+                .clone();
+            object = self.read_object(hash)?;
+        }
+
+        Some(object)
+    }
+
+    /// Visits every entry below `tree_hash` depth first. Directories are reported before their
+    /// content, paths are relative to the root tree and use '/' as separator.
+    pub fn walk_tree(
+        &mut self,
+        tree_hash: TreeHash,
+        f: &mut impl FnMut(&mut Repository<M>, &[u8], &TreeLine),
+    ) -> Result<(), GitRwError> {
+        self.walk_tree_recursive(tree_hash, b"", f)
+    }
+
+    fn walk_tree_recursive(
+        &mut self,
+        tree_hash: TreeHash,
+        path: &[u8],
+        f: &mut impl FnMut(&mut Repository<M>, &[u8], &TreeLine),
+    ) -> Result<(), GitRwError> {
+        let tree = self.read_tree(tree_hash)?;
+
+        for line in tree.lines() {
+            let full_path = if path.is_empty() {
+                line.filename().to_vec()
+            } else {
+                [path, b"/", line.filename()].concat()
+            };
+
+            f(self, &full_path, &line);
+
+            if line.is_tree() {
+                self.walk_tree_recursive(line.hash.deref().clone(), &full_path, f)?;
             }
         }
+        Ok(())
     }
 
-    pub fn write_commits(
-        repository_path: PathBuf,
-        commits: impl Iterator<Item = WriteObject> + Send,
-        dry_run: bool,
-    ) {
-        commits.par_bridge().for_each(|commit| {
-            Self::write(repository_path.clone(), commit, dry_run);
-        });
+    /// Records what dry runs on this repository would change from now on, until it is
+    /// retrieved with `dry_run_report` or `dry_run_objects`.
+    pub fn capture_dry_run(&self) {
+        dry_run::start_capture(&self.path);
     }
 
-    pub fn write_trees(
-        repository_path: PathBuf,
-        trees: impl Iterator<Item = objs::Tree> + Send,
-        dry_run: bool,
-    ) {
-        trees.par_bridge().for_each(|tree| {
-            Self::write(repository_path.clone(), tree.into(), dry_run);
-        });
+    /// Stops capturing and returns what the dry runs would have changed.
+    pub fn dry_run_report(&self) -> DryRunReport {
+        dry_run::take_capture(&self.path)
+    }
+
+    /// Stops capturing and returns every object a dry run would have written, once per hash.
+    pub fn dry_run_objects(&self) -> Vec<WrittenObject> {
+        self.dry_run_report().objects
     }
 
     pub fn commits_topo(&self) -> impl Iterator<Item = CommitBase> + '_ {
-        CommitsFifoIter::create(&self.path, &self.pack_reader, Decompression::default())
+        CommitsFifoIter::create(
+            &self.path,
+            &self.pack_reader,
+            Decompression::default(),
+            &self.skipped,
+        )
+    }
+
+    /// All commits newest first by committer date, the natural order for listings. Children
+    /// always come before their parents, commits with skewed clocks are treated as slightly
+    /// newer than their newest parent. Reads every commit once before the first one is returned.
+    pub fn commits_by_date(&self) -> impl Iterator<Item = CommitBase> + '_ {
+        CommitsDateIter::create(
+            &self.path,
+            &self.pack_reader,
+            Decompression::default(),
+            self.commits_topo(),
+            &self.skipped,
+        )
+    }
+
+    /// Like `commits_topo`, every commit comes with its root tree that is read on demand.
+    pub fn commits_with_trees(&self) -> impl Iterator<Item = CommitWithTree> + '_ {
+        let trees = Arc::new(Mutex::new(TreeCache::create(self.with_mode())));
+        self.commits_topo()
+            .map(move |commit| CommitWithTree::create(commit, trees.clone()))
+    }
+
+    /// Every commit stored in the packs, reachable or not, in no particular order.
+    pub fn commits_in_packs(&self) -> impl Iterator<Item = CommitBase> + '_ {
+        let mut decompression = Decompression::default();
+        self.pack_reader.commit_offsets().into_iter().filter_map(
+            move |(pack_id, offset)| match self.pack_reader.read_git_object_at_offset(
+                &mut decompression,
+                pack_id,
+                offset,
+            ) {
+                Some(GitObject::Commit(commit)) => Some(commit),
+                _ => None,
+            },
+        )
     }
 
     pub fn commits_lifo(&self) -> impl Iterator<Item = CommitBase> + '_ {
-        CommitsLifoIter::create(&self.path, &self.pack_reader, Decompression::default())
+        CommitsLifoIter::create(
+            &self.path,
+            &self.pack_reader,
+            Decompression::default(),
+            &self.skipped,
+        )
+    }
+
+    /// Commits reachable from `include` but not from `exclude`. Tags are peeled to their commits.
+    pub fn commits_range(
+        &self,
+        exclude: Vec<ObjectHash>,
+        include: Vec<ObjectHash>,
+    ) -> impl Iterator<Item = CommitBase> + '_ {
+        CommitsRangeIter::create(
+            &self.path,
+            &self.pack_reader,
+            Decompression::default(),
+            exclude,
+            include,
+            &self.skipped,
+        )
+    }
+
+    /// Number of commits in `range`. A single revision counts every commit reachable from it,
+    /// `a..b` the commits reachable from `b` but not from `a`. An empty side stands for `HEAD`.
+    pub fn count_commits(&self, range: &str) -> Result<usize, GitRwError> {
+        let (exclude, include) = self.resolve_range(range)?;
+        Ok(self.commits_range(exclude, include).count())
+    }
+
+    /// The commits, trees and blobs `range` introduced, e.g. `v1..v2` for the objects a release
+    /// added. Ranges are written like for `count_commits`.
+    pub fn range_stats(&mut self, range: &str) -> Result<RangeStats, GitRwError> {
+        let (exclude, include) = self.resolve_range(range)?;
+        range_stats::range_stats(self, exclude, include)
+    }
+
+    fn resolve_range(&self, range: &str) -> Result<(Vec<ObjectHash>, Vec<ObjectHash>), GitRwError> {
+        Ok(match range.split_once("..") {
+            Some((a, b)) => (vec![self.resolve_rev(a)?], vec![self.resolve_rev(b)?]),
+            None => (Vec::new(), vec![self.resolve_rev(range)?]),
+        })
+    }
+
+    /// Number of commits reachable from `a` but not from `b`, and the other way round.
+    pub fn ahead_behind(&self, a: &str, b: &str) -> Result<(usize, usize), GitRwError> {
+        let a = self.resolve_rev(a)?;
+        let b = self.resolve_rev(b)?;
+        let ahead = self.commits_range(vec![b.clone()], vec![a.clone()]).count();
+        let behind = self.commits_range(vec![a], vec![b]).count();
+        Ok((ahead, behind))
+    }
+
+    fn resolve_rev(&self, rev: &str) -> Result<ObjectHash, GitRwError> {
+        let rev = if rev.is_empty() { "HEAD" } else { rev };
+        self.resolve(rev)
+            .ok_or_else(|| GitRwError::invalid(format!("Unknown revision: {rev}")))
     }
 
-    pub fn refs(&self) -> Result<Vec<GitRef>, Box<dyn Error>> {
+    /// Commits reachable from refs matching one of `patterns` but not from any other ref, e.g. the
+    /// commits of a fork that are not part of upstream. `*` matches any sequence of characters.
+    pub fn commits_unique_to_refs(
+        &self,
+        patterns: &[String],
+    ) -> Result<HashSet<CommitHash, BuildHasherDefault<FxHasher>>, GitRwError> {
+        let (include, exclude) = refs::partition_ref_targets(&self.path, patterns)?;
+        if include.is_empty() {
+            return Err(GitRwError::invalid(format!(
+                "No ref matches {}",
+                patterns.join(", ")
+            )));
+        }
+
+        Ok(self
+            .commits_range(exclude, include)
+            .map(|commit| commit.hash)
+            .collect())
+    }
+
+    /// Compares two trees recursively. `None` stands for a missing tree, so every file of the
+    /// other side is reported as added or removed.
+    pub fn diff_trees(&mut self, old: Option<TreeHash>, new: Option<TreeHash>) -> Vec<TreeChange> {
+        diff::diff_trees(self, old, new)
+    }
+
+    pub fn refs(&self) -> Result<Vec<GitRef>, GitRwError> {
         GitRef::read_all(&self.path)
     }
 
+    /// Every ref sorted by name with the type of its target and the commit tags peel to.
+    pub fn ref_infos(&mut self) -> Result<Vec<RefInfo>, GitRwError> {
+        refs::ref_infos(self)
+    }
+
+    /// The ref updates of an interrupted `update_refs`, `None` if the last update finished.
+    pub fn ref_journal(&self) -> Result<Option<Vec<JournalEntry>>, GitRwError> {
+        ref_journal::read(&self.path)
+    }
+
+    /// How long workspace files are kept: `gitrw.expireDays` of the repository config, or
+    /// `DEFAULT_EXPIRY`.
+    pub fn workspace_expiry(&self) -> std::time::Duration {
+        self.config_values("gitrw", "expireDays")
+            .last()
+            .and_then(|days| days.trim().parse::<u64>().ok())
+            .map_or(DEFAULT_EXPIRY, |days| {
+                std::time::Duration::from_secs(days * 24 * 60 * 60)
+            })
+    }
+
+    /// Describes everything that makes git show another history than the stored commits: grafts,
+    /// replace refs and shallow clones. Rewrites only see the stored commits.
+    pub fn altered_history(&self) -> Result<Vec<String>, GitRwError> {
+        refs::altered_history(&self.path)
+    }
+
+    /// Reads all values of `section.key` from the repository config, e.g. `gitrw.protect`.
+    pub fn config_values(&self, section: &str, key: &str) -> Vec<String> {
+        config::read_config_values(&self.path, section, key)
+    }
+}
+
+impl Repository<ReadWrite> {
+    /// Remembers the new hashes of up to `max_entries` rewritten commits, so rewrites that edit a
+    /// commit the same way as an earlier rewrite do not serialize and hash it again. `None`
+    /// disables the cache. Clones created afterwards share the cache.
+    pub fn set_commit_cache(&mut self, max_entries: Option<usize>) {
+        self.commit_cache =
+            max_entries.map(|max_entries| Arc::new(CommitCache::create(max_entries)));
+    }
+
+    /// Makes rewrites update the hashes of rewritten commits mentioned in the messages of later
+    /// commits, see `MessageHashes`. `rewrite_history` sets it from `RewriteOptions`.
+    pub fn set_message_hashes(&mut self, message_hashes: Option<MessageHashes>) {
+        self.message_hashes = message_hashes;
+    }
+
+    /// Lets rewrites continue where an interrupted one with the same changes stopped: the text
+    /// commit mapping at `path` is loaded, the commits in it are not rewritten again, and every
+    /// commit rewritten from now on is appended. Dropped commits that leave their children
+    /// without a parent are listed with the all-zero hash. A missing file is created. `rewrite_history`
+    /// sets it from `RewriteOptions`.
+    pub fn set_state_file(&mut self, path: Option<&Path>) -> Result<(), GitRwError> {
+        self.rewrite_state = match path {
+            Some(path) => Some(Arc::new(RewriteState::load(path)?)),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Checks that a rewrite with `options` can start and applies the options that are settings
+    /// of the repository, see `set_message_hashes` and `set_state_file`. Done by
+    /// `rewrite_history`, callers of `rewrite_commits` do it themselves.
+    pub fn prepare_rewrite(&mut self, options: &RewriteOptions) -> Result<(), GitRwError> {
+        self.check_refs(&options.ref_options)?;
+        self.set_message_hashes(options.message_hashes);
+        self.set_state_file(options.state_file.as_deref())
+    }
+
+    /// How often rewrites found a commit in the commit cache.
+    pub fn commit_cache_hits(&self) -> usize {
+        self.commit_cache.as_ref().map_or(0, |cache| cache.hits())
+    }
+
+    /// Deletes the loose objects of rewritten commits and their trees that are not reachable
+    /// from any ref anymore, a lighter cleanup than a full gc after rewriting a small range.
+    /// Returns the deleted objects, nothing is deleted with `dry_run`.
+    pub fn prune_superseded(
+        &self,
+        superseded: impl IntoIterator<Item = CommitHash>,
+        dry_run: bool,
+    ) -> Result<Vec<LooseObject>, GitRwError> {
+        loose::prune_superseded(self, superseded, dry_run)
+    }
+
+    /// Writes new objects into this repository, or only records them with `dry_run`.
+    pub fn object_store(&self, dry_run: bool) -> ObjectStore {
+        ObjectStore::new(self.path.clone(), dry_run)
+    }
+
+    pub(crate) fn write(
+        repo_path: PathBuf,
+        object: WriteObject,
+        dry_run: bool,
+    ) -> Result<(), GitRwError> {
+        ObjectStore::new(repo_path, dry_run).write(object)
+    }
+
+    /// Finishes an interrupted ref update by applying all of its new targets.
+    pub fn complete_ref_journal(&self) -> Result<Vec<JournalEntry>, GitRwError> {
+        let entries = ref_journal::read(&self.path)?
+            .ok_or_else(|| GitRwError::invalid("No interrupted ref update found"))?;
+        ref_journal::complete(&self.path, &entries)?;
+        Ok(entries)
+    }
+
+    /// Undoes an interrupted ref update by restoring the old targets of all refs.
+    pub fn rollback_ref_journal(&self) -> Result<Vec<JournalEntry>, GitRwError> {
+        let entries = ref_journal::read(&self.path)?
+            .ok_or_else(|| GitRwError::invalid("No interrupted ref update found"))?;
+        ref_journal::rollback(&self.path, &entries)?;
+        Ok(entries)
+    }
+
+    /// Fails if the refs could not be updated after a rewrite, so that it can be refused before
+    /// any work is done: a previous ref update was interrupted, refs point to missing objects
+    /// and `options.dangling` is `DanglingRefs::Fail`, a renamed or the output ref already
+    /// exists, or the history is altered and `options.allow_altered_history` is not set.
+    pub fn check_refs(&self, options: &RefOptions) -> Result<(), GitRwError> {
+        refs::check_journal(&self.path)?;
+        refs::check_renames(&self.path, options)?;
+        if let Some(output_ref) = &options.output_ref {
+            refs::check_output_ref(&self.path, output_ref)?;
+        }
+        refs::check_dangling(self, options)?;
+        refs::check_altered_history(&self.path, options)
+    }
+
+    /// Removes the files of the workspace, `gitrw/` in the repository directory, that were last
+    /// modified more than `max_age` ago. The journal of an interrupted ref update is kept. With
+    /// `dry_run` the files are only listed.
+    pub fn expire_workspace(
+        &self,
+        max_age: std::time::Duration,
+        dry_run: bool,
+    ) -> Result<ExpiredFiles, GitRwError> {
+        workspace::expire(&self.path, max_age, dry_run)
+    }
+
+    /// Points all refs to the rewritten commits and reports what happened to every ref. Fails
+    /// without changing anything if a protected ref would be rewritten and
+    /// `options.allow_protected` is not set.
     pub fn update_refs<T: BuildHasher>(
         &mut self,
         rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+        options: &RefOptions,
         dry_run: bool,
-    ) {
-        if !dry_run {
-            refs::GitRef::update(self, rewritten_commits, dry_run);
-        }
+    ) -> Result<Vec<RefUpdate>, GitRwError> {
+        refs::GitRef::update(self, rewritten_commits, options, dry_run)
+    }
+
+    /// Regenerates `info/refs` and `objects/info/packs` like `git update-server-info`, which
+    /// servers using the dumb HTTP protocol hand out instead of asking the repository.
+    pub fn update_server_info(&mut self) -> Result<(), GitRwError> {
+        server_info::update(self)
+    }
+
+    /// Records the original hash of every rewritten commit as a git note under `refs/notes/gitrw`,
+    /// readable with `git notes --ref=gitrw show <commit>`.
+    pub fn write_notes<T: BuildHasher>(
+        &mut self,
+        rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+        dry_run: bool,
+    ) -> Result<(), GitRwError> {
+        notes::write_notes(self, rewritten_commits, dry_run)
     }
 
+    /// Writes the old to new commit mapping to the current directory, sorted by the old hash so
+    /// that repeated runs produce identical files. The rewritten hashes themselves are
+    /// reproducible: the same input repository and options always yield the same new objects.
+    /// `CommitMapReader` reads the file back.
     pub fn write_rewritten_commits_file(
         rewritten_commits: HashMap<
             CommitHash,
             CommitHash,
             std::hash::BuildHasherDefault<rustc_hash::FxHasher>,
         >,
+        format: CommitMapFormat,
         dry_run: bool,
-    ) {
+    ) -> Result<(), GitRwError> {
         if dry_run {
-            return;
+            return Ok(());
+        }
+
+        let path = Path::new(format.file_name());
+        let file = std::fs::File::create(path).map_err(GitRwError::io(path))?;
+        commit_map::write(file, &rewritten_commits, format).map_err(GitRwError::io(path))?;
+
+        println!("{} written", format.file_name());
+        Ok(())
+    }
+
+    /// Writes the mapping of a cancelled rewrite with a `.partial` suffix to the workspace,
+    /// returns the error reporting the cancellation.
+    fn write_partial_commits_file(
+        &self,
+        rewritten_commits: HashMap<
+            CommitHash,
+            CommitHash,
+            std::hash::BuildHasherDefault<rustc_hash::FxHasher>,
+        >,
+        format: CommitMapFormat,
+        dry_run: bool,
+    ) -> GitRwError {
+        if dry_run || rewritten_commits.is_empty() {
+            return GitRwError::Cancelled(String::from(
+                "Rewrite cancelled, refs were left untouched",
+            ));
+        }
+
+        let file_name = format!("{}.partial", format.file_name());
+        let written = workspace::file(&self.path, &file_name).and_then(|path| {
+            std::fs::File::create(&path)
+                .and_then(|file| commit_map::write(file, &rewritten_commits, format))
+                .map_err(GitRwError::io(&path))?;
+            Ok(path)
+        });
+        GitRwError::Cancelled(match written {
+            Ok(path) => format!(
+                "Rewrite cancelled, refs were left untouched. The {} commits rewritten so far are listed in {}",
+                rewritten_commits.len(),
+                path.display()
+            ),
+            Err(e) => format!("Rewrite cancelled, refs were left untouched. Writing {file_name} failed: {e}"),
+        })
+    }
+
+    /// Rewrites the whole history and updates the refs: see `rewrite_commits` for the callbacks
+    /// and `apply_rewrite` for what happens afterwards.
+    pub fn rewrite_history<C, T>(
+        &mut self,
+        options: &RewriteOptions,
+        commit_cb: C,
+        tree_cb: T,
+    ) -> Result<Vec<RefUpdate>, GitRwError>
+    where
+        C: FnMut(&mut CommitEditable) -> CommitAction + Send,
+        T: Fn(&mut Repository, TreeHash) -> Result<Option<TreeHash>, GitRwError> + Sync,
+    {
+        self.rewrite_history_checked(options, commit_cb, tree_cb, |_, _| Ok(()))
+    }
+
+    /// Like `rewrite_history`, but `check` gets the old to new mapping of the rewritten commits
+    /// before any ref is touched, e.g. to compare the rewritten trees with their originals. The
+    /// refs stay as they are if it fails. It is not called for cancelled rewrites.
+    pub fn rewrite_history_checked<C, T, V>(
+        &mut self,
+        options: &RewriteOptions,
+        commit_cb: C,
+        tree_cb: T,
+        check: V,
+    ) -> Result<Vec<RefUpdate>, GitRwError>
+    where
+        C: FnMut(&mut CommitEditable) -> CommitAction + Send,
+        T: Fn(&mut Repository, TreeHash) -> Result<Option<TreeHash>, GitRwError> + Sync,
+        V: FnOnce(
+            &mut Repository,
+            &HashMap<CommitHash, CommitHash, BuildHasherDefault<FxHasher>>,
+        ) -> Result<(), GitRwError>,
+    {
+        self.prepare_rewrite(options)?;
+        let rewritten_commits = self.rewrite_commits(options, commit_cb, tree_cb)?;
+        if !self.is_cancelled() {
+            check(self, &rewritten_commits)?;
+        }
+        self.apply_rewrite(rewritten_commits, options)
+    }
+
+    /// Like `rewrite_history` without a tree callback, the commits are rewritten by
+    /// `rewrite_commits_parallel`.
+    pub fn rewrite_history_parallel<C>(
+        &mut self,
+        options: &RewriteOptions,
+        commit_cb: C,
+    ) -> Result<Vec<RefUpdate>, GitRwError>
+    where
+        C: Fn(&mut CommitEditable) -> CommitAction + Sync,
+    {
+        self.prepare_rewrite(options)?;
+        let rewritten_commits = self.rewrite_commits_parallel(options, commit_cb)?;
+        self.apply_rewrite(rewritten_commits, options)
+    }
+
+    /// Walks all commits parents first and writes the changed ones, without touching any refs.
+    /// `tree_cb` gets the root tree of every commit and returns its replacement, or `None` if it
+    /// stays the same. It runs on several threads at once and has to write new trees itself.
+    /// The rewrite stops at the first error of `tree_cb` or of writing a commit or the state
+    /// file, the commits written until then are kept.
+    /// `commit_cb` runs in order for every commit, whose parents and tree already point to the
+    /// rewritten objects. Signatures and committer dates are handled as `options` say, and
+    /// `commit_cb` only runs for the commits that `RewriteOptions::select_commit` selects.
+    /// Returns the old to new mapping of all rewritten commits.
+    pub fn rewrite_commits<C, T>(
+        &self,
+        options: &RewriteOptions,
+        commit_cb: C,
+        tree_cb: T,
+    ) -> Result<
+        HashMap<CommitHash, CommitHash, std::hash::BuildHasherDefault<rustc_hash::FxHasher>>,
+        GitRwError,
+    >
+    where
+        C: FnMut(&mut CommitEditable) -> CommitAction + Send,
+        T: Fn(&mut Repository, TreeHash) -> Result<Option<TreeHash>, GitRwError> + Sync,
+    {
+        rewrite::rewrite_commits(self, options, commit_cb, tree_cb)
+    }
+
+    /// Same as `rewrite_commits` without a tree callback, but `commit_cb` runs on many commits
+    /// at once and therefore only gets to see one commit at a time: commits are processed
+    /// level by level, each commit after all of its parents.
+    pub fn rewrite_commits_parallel<C>(
+        &self,
+        options: &RewriteOptions,
+        commit_cb: C,
+    ) -> Result<
+        HashMap<CommitHash, CommitHash, std::hash::BuildHasherDefault<rustc_hash::FxHasher>>,
+        GitRwError,
+    >
+    where
+        C: Fn(&mut CommitEditable) -> CommitAction + Sync,
+    {
+        rewrite::rewrite_commits_parallel(self, options, commit_cb)
+    }
+
+    /// Checks that following `rewritten_commits` never runs in circles and that every new commit
+    /// exists with all of its parents, so refs can safely be moved to them. Commits that were
+    /// not written because of `dry_run` are skipped.
+    pub fn validate_commit_map<T: BuildHasher>(
+        &self,
+        rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+        dry_run: bool,
+    ) -> Result<(), GitRwError> {
+        commit_map::validate(self, rewritten_commits, dry_run)
+    }
+
+    /// Finishes a rewrite: verifies the unchanged commits if requested, validates the commit
+    /// mapping, updates the refs, regenerates the files for dumb HTTP servers if requested or
+    /// already present, expires stale workspace files, writes the notes if requested and the
+    /// commit mapping file.
+    /// Does nothing else if no commit was rewritten and no refs are to be deleted.
+    pub fn apply_rewrite(
+        &mut self,
+        rewritten_commits: HashMap<
+            CommitHash,
+            CommitHash,
+            std::hash::BuildHasherDefault<rustc_hash::FxHasher>,
+        >,
+        options: &RewriteOptions,
+    ) -> Result<Vec<RefUpdate>, GitRwError> {
+        if self.is_cancelled() {
+            return Err(self.write_partial_commits_file(
+                rewritten_commits,
+                options.map_format,
+                options.dry_run,
+            ));
         }
 
-        let file = std::fs::File::create("object-id-map.old-new.txt").unwrap();
-        let mut writer = BufWriter::new(file);
-        for (old, new) in rewritten_commits.iter() {
-            writer.write_fmt(format_args!("{old} {new}\n")).unwrap();
+        if options.verify_unchanged {
+            rewrite::verify_unchanged(self, &rewritten_commits)?;
         }
 
-        println!("object-id-map.old-new.txt written");
+        if rewritten_commits.is_empty() && options.ref_options.delete.is_empty() {
+            if options.dry_run {
+                dry_run::capture_rewrite(&self.path, 0, &[]);
+            }
+            return Ok(Vec::new());
+        }
+
+        self.validate_commit_map(&rewritten_commits, options.dry_run)?;
+
+        let ref_updates =
+            self.update_refs(&rewritten_commits, &options.ref_options, options.dry_run)?;
+        if options.dry_run {
+            dry_run::capture_rewrite(&self.path, rewritten_commits.len(), &ref_updates);
+        }
+        if !options.dry_run && (options.update_server_info || server_info::exists(&self.path)) {
+            self.update_server_info()?;
+        }
+        if !options.dry_run {
+            // state left by earlier runs, `expire_workspace` reports why a cleanup failed
+            let _ = workspace::expire(&self.path, self.workspace_expiry(), false);
+        }
+        if !rewritten_commits.is_empty() {
+            if options.write_notes {
+                self.write_notes(&rewritten_commits, options.dry_run)?;
+            }
+            Self::write_rewritten_commits_file(
+                rewritten_commits,
+                options.map_format,
+                options.dry_run,
+            )?;
+        }
+
+        Ok(ref_updates)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hash::Hasher;
+
+    use bstr::{BString, ByteSlice};
+    use rs_sha1::{HasherContext, Sha1Hasher};
+    use rustc_hash::FxHashMap;
+
+    use crate::{
+        dry_run,
+        objs::{CommitEditable, CommitHash, GitObject},
+        shared::ObjectHash,
+        test_repository::TestRepository,
+        LooseObject, Repository, WriteObject,
+    };
+
+    #[test]
+    fn rewritten_commits_are_sorted() {
+        let hash = |b: u8| CommitHash(ObjectHash::from([b; 20]));
+        let mut map = FxHashMap::default();
+        for b in [9u8, 3, 250, 0, 17] {
+            map.insert(hash(b), hash(b.wrapping_add(1)));
+        }
+
+        let out = crate::commit_map::write(Vec::new(), &map, crate::CommitMapFormat::Text).unwrap();
+
+        let lines: Vec<_> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        let mut sorted = lines.clone();
+        sorted.sort();
+
+        assert_eq!(5, lines.len());
+        assert_eq!(sorted, lines);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn object_headers_match_content() {
+        let fixture = TestRepository::create("object-headers-match-content");
+        let mut repository = fixture.open();
+        let mut reader = repository.clone();
+        for commit in repository.commits_topo().take(200) {
+            assert_eq!(
+                ("commit", commit.content().len()),
+                reader.object_header(&commit.hash.0).unwrap()
+            );
+
+            let tree_hash = commit.tree();
+            let tree = reader.read_tree(tree_hash.clone()).unwrap();
+            assert_eq!(
+                ("tree", tree.bytes().get_bytes().len()),
+                reader.object_header(&tree_hash.0).unwrap()
+            );
+        }
+
+        assert!(repository
+            .object_header(&ObjectHash::from([0u8; 20]))
+            .is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn commit_counts() {
+        let fixture = TestRepository::create("commit-counts");
+        let repository = fixture.open();
+
+        assert_eq!(5, repository.count_commits("HEAD").unwrap());
+        assert_eq!(3, repository.count_commits("feature").unwrap());
+        assert_eq!(0, repository.count_commits("..HEAD").unwrap());
+        assert_eq!(3, repository.count_commits("light..HEAD").unwrap());
+        assert_eq!((2, 0), repository.ahead_behind("HEAD", "feature").unwrap());
+        let third = fixture.git(&["rev-parse", "HEAD^1"]);
+        assert_eq!((1, 1), repository.ahead_behind("feature", &third).unwrap());
+        assert!(repository.count_commits("no-such-ref").is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn missing_objects() {
+        let fixture = TestRepository::create("missing-objects");
+        let repository = fixture.open();
+        let head = repository.resolve("HEAD").unwrap();
+        let missing = ObjectHash::from([0u8; 20]);
+
+        assert!(repository.object_exists(&head));
+        assert!(!repository.object_exists(&missing));
+        assert_eq!(
+            vec![missing.clone()],
+            repository.filter_missing([head, missing])
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn loose_object_headers() {
+        let fixture = TestRepository::create("loose-object-headers");
+        let mut repository = fixture.open();
+        let loose: Vec<LooseObject> = repository.loose_objects().collect();
+        assert_eq!(repository.count_objects().loose, loose.len());
+
+        for object in loose.into_iter().take(100) {
+            let (object_type, size) = object.header().unwrap();
+            let content_len = match repository.read_object(object.hash).unwrap() {
+                GitObject::Commit(commit) => commit.content().len(),
+                GitObject::Tree(tree) => tree.bytes().get_bytes().len(),
+                GitObject::Blob(blob) => blob.content().len(),
+                GitObject::Tag(_) => size,
+            };
+            assert!(["commit", "tree", "blob", "tag"].contains(&object_type.as_str()));
+            assert_eq!(content_len, size, "{object_type}");
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn peel_head_to_commit() {
+        let fixture = TestRepository::create("peel-head-to-commit");
+        let mut repository = fixture.open();
+        let head = repository.resolve("HEAD").unwrap();
+
+        assert_eq!(Some(CommitHash(head)), repository.peel_to_commit("HEAD"));
+        assert_eq!(None, repository.peel_to_commit("does-not-exist"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn abbreviated_hashes() {
+        let fixture = TestRepository::create("abbreviated-hashes");
+        let repository = fixture.open();
+        let head = repository.resolve("HEAD").unwrap();
+        let hex = head.to_string();
+
+        assert_eq!(Some(head.clone()), repository.resolve(&hex[..12]));
+        assert_eq!(head, repository.resolve_prefix(&hex[..12]).unwrap());
+        assert!(repository.resolve_prefix(&hex[..3]).is_err());
+        assert!(repository.resolve_prefix("no-hex").is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn dry_run_objects_are_captured() {
+        let fixture = TestRepository::create("dry-run-objects-are-captured");
+        let mut repository = fixture.open();
+        let head = repository.resolve("HEAD").unwrap();
+        let mut commit = match repository.read_object(head) {
+            Some(GitObject::Commit(commit)) => CommitEditable::create(commit),
+            _ => panic!("HEAD is not a commit"),
+        };
+        commit.set_committer_time(b"0 +0000".to_vec());
+        let object: WriteObject = commit.into();
+        let hash = object.hash.clone();
+
+        repository.capture_dry_run();
+        Repository::write(repository.path.clone(), object, true).unwrap();
+        dry_run::capture_removed_path(&repository.path, b"secret.txt");
+        dry_run::capture_contributor(&repository.path, b"A <a@x.org>", b"B <b@x.org>");
+        dry_run::capture_contributor(&repository.path, b"A <a@x.org>", b"C <c@x.org>");
+        let report = repository.dry_run_report();
+        let written = report.objects;
+
+        assert_eq!(1, written.len());
+        assert_eq!(hash, written[0].hash);
+        assert_eq!("commit", written[0].object_type);
+        assert!(!repository.object_exists(&hash));
+        assert_eq!(None, report.commits);
+        assert!(report.removed_paths.contains(b"secret.txt".as_bstr()));
+        assert_eq!(
+            Some(&BString::from("B <b@x.org>")),
+            report.contributors.get(b"A <a@x.org>".as_bstr())
+        );
+        assert!(repository.dry_run_objects().is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn commits_with_trees() {
+        let fixture = TestRepository::create("commits-with-trees");
+        let repository = fixture.open();
+
+        for item in repository.commits_with_trees().take(10) {
+            let tree = item.tree().unwrap();
+            assert_eq!(&item.commit.tree(), tree.hash());
+            assert!(std::sync::Arc::ptr_eq(&tree, &item.tree().unwrap()));
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn commits_by_date() {
+        let fixture = TestRepository::create("commits-by-date");
+        let repository = fixture.open();
+
+        let mut returned = std::collections::HashSet::new();
+        for commit in repository.commits_by_date() {
+            assert!(commit
+                .parents()
+                .iter()
+                .all(|parent| !returned.contains(parent)));
+            assert!(returned.insert(commit.hash));
+        }
+        assert_eq!(repository.commits_topo().count(), returned.len());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn cancelled_rewrite_leaves_refs_untouched() {
+        let fixture = TestRepository::create("cancelled-rewrite-leaves-refs-untouched");
+        let mut repository = fixture.open();
+        let token = crate::CancellationToken::new();
+        repository.set_cancellation(token.clone());
+        token.cancel();
+
+        let options = crate::RewriteOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let rewritten_commits = repository.rewrite_commits(
+            &options,
+            |commit| {
+                commit.set_committer(b"Someone <someone@example.org>".to_vec());
+                crate::CommitAction::Keep
+            },
+            |_, _| Ok(None),
+        );
+        let rewritten_commits = rewritten_commits.unwrap();
+        assert!(rewritten_commits.is_empty());
+
+        let error = repository
+            .apply_rewrite(rewritten_commits, &options)
+            .unwrap_err();
+        assert!(error.to_string().contains("cancelled"), "{error}");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn tree_callback_error_stops_rewrite() {
+        let fixture = TestRepository::create("tree-callback-error-stops-rewrite");
+        let repository = fixture.open();
+        let options = crate::RewriteOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let result = repository.rewrite_commits(
+            &options,
+            |_| crate::CommitAction::Keep,
+            |repository, tree_hash| {
+                Err(crate::GitRwError::corrupt(
+                    &repository.path,
+                    format!("{tree_hash} is unreadable"),
+                ))
+            },
+        );
+
+        let error = result.unwrap_err();
+        assert!(matches!(error, crate::GitRwError::Corrupt { .. }));
+        assert!(error.to_string().contains("is unreadable"), "{error}");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn resumed_rewrite_keeps_dropped_root_commits() {
+        let fixture = TestRepository::create("resume-drop");
+        let path = fixture.path.clone();
+        let state_file = path.with_extension("state");
+        let _ = std::fs::remove_file(&state_file);
+
+        let mut repository = Repository::open(path.clone()).unwrap();
+        let root = repository
+            .commits_topo()
+            .find(|commit| commit.parents().is_empty())
+            .unwrap()
+            .hash;
+        let drop_root = |commit: &mut CommitEditable| {
+            if commit.base().parents().is_empty() {
+                crate::CommitAction::Drop
+            } else {
+                crate::CommitAction::Keep
+            }
+        };
+        let dry_run = crate::RewriteOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let expected = repository
+            .rewrite_commits_parallel(&dry_run, drop_root)
+            .unwrap();
+
+        // interrupted after the root commit and its children
+        let token = crate::CancellationToken::new();
+        repository.set_cancellation(token.clone());
+        repository.set_state_file(Some(&state_file)).unwrap();
+        let options = crate::RewriteOptions::default();
+        let interrupted = repository
+            .rewrite_commits_parallel(&options, |commit| {
+                if commit.base().parents().contains(&root) {
+                    token.cancel();
+                }
+                drop_root(commit)
+            })
+            .unwrap();
+        let state = std::fs::read_to_string(&state_file).unwrap();
+
+        let mut repository = Repository::open(path.clone()).unwrap();
+        repository.set_state_file(Some(&state_file)).unwrap();
+        let mut resumed_commits = Vec::new();
+        let resumed = repository
+            .rewrite_commits(
+                &options,
+                |commit| {
+                    resumed_commits.push(commit.base_hash().clone());
+                    drop_root(commit)
+                },
+                |_, _| Ok(None),
+            )
+            .unwrap();
+        std::fs::remove_file(&state_file).unwrap();
+
+        assert!(state.contains(&format!("{root} {}\n", "0".repeat(40))));
+        assert!(!interrupted.is_empty() && interrupted.len() < expected.len());
+        assert!(!resumed_commits.contains(&root));
+        assert_eq!(expected.len() - interrupted.len(), resumed_commits.len());
+        assert_eq!(expected, resumed);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn commit_cache_skips_repeated_rewrites() {
+        let fixture = TestRepository::create("commit-cache-skips-repeated-rewrites");
+        let mut repository = fixture.open();
+        repository.set_commit_cache(Some(1 << 20));
+
+        let options = crate::RewriteOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let rewrite = |repository: &Repository| {
+            repository
+                .rewrite_commits_parallel(&options, |commit| {
+                    commit.set_committer(b"Someone <someone@example.org>".to_vec());
+                    crate::CommitAction::Keep
+                })
+                .unwrap()
+        };
+        let first = rewrite(&repository);
+        assert_eq!(0, repository.commit_cache_hits());
+
+        let second = rewrite(&repository.clone());
+        assert_eq!(first, second);
+        assert_eq!(
+            repository.commits_topo().count(),
+            repository.commit_cache_hits()
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn verify_unchanged_commits() {
+        let fixture = TestRepository::create("verify-unchanged-commits");
+        let mut repository = fixture.open();
+        let mut rewritten_commits = FxHashMap::default();
+        assert_eq!(
+            repository.commits_topo().count(),
+            crate::rewrite::verify_unchanged(&repository, &rewritten_commits).unwrap()
+        );
+
+        // a rewritten commit whose children are kept
+        let head = repository.peel_to_commit("HEAD").unwrap();
+        let GitObject::Commit(commit) = repository.read_object(head.0).unwrap() else {
+            panic!("HEAD is no commit");
+        };
+        let parent = commit.parents().into_iter().next().unwrap();
+        rewritten_commits.insert(parent.clone(), parent);
+        let error = crate::rewrite::verify_unchanged(&repository, &rewritten_commits).unwrap_err();
+        assert!(error.to_string().contains("was rewritten"), "{error}");
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn write_pack_of_head() {
+        let fixture = TestRepository::create("write-pack-of-head");
+        let repository = fixture.open();
+        let mut pack = Vec::new();
+        let count = repository.write_pack(&["HEAD"], &mut pack).unwrap();
+
+        assert!(count > 0);
+        assert_eq!(b"PACK", &pack[..4]);
+        assert_eq!(2, u32::from_be_bytes(pack[4..8].try_into().unwrap()));
+        assert_eq!(
+            count as u32,
+            u32::from_be_bytes(pack[8..12].try_into().unwrap())
+        );
+
+        let (content, checksum) = pack.split_at(pack.len() - 20);
+        let mut hasher = Sha1Hasher::default();
+        hasher.write(content);
+        let expected: [u8; 20] = HasherContext::finish(&mut hasher).into();
+        assert_eq!(&expected[..], checksum);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn write_bundle_of_head() {
+        let fixture = TestRepository::create("write-bundle-of-head");
+        let repository = fixture.open();
+        let head = repository.resolve("HEAD").unwrap();
+        let mut bundle = Vec::new();
+        let (refs, objects) = repository.write_bundle(&["HEAD"], &mut bundle).unwrap();
+
+        assert_eq!(1, refs);
+        assert!(objects > 0);
+        let header = format!("# v2 git bundle\n{head} HEAD\n\nPACK");
+        assert!(bundle.starts_with(header.as_bytes()));
+        assert!(repository
+            .write_bundle(&[&head.to_string()], Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn write_bundle_of_all_refs() {
+        let fixture = TestRepository::create("bundle-all-refs");
+        let git_dir = fixture.path.clone();
+        let repository = fixture.open();
+        let head = repository.resolve("HEAD").unwrap();
+        let path =
+            std::env::temp_dir().join(format!("gitrw-all-refs-{}.bundle", std::process::id()));
+        let (refs, _) = repository
+            .write_bundle(&[], std::fs::File::create(&path).unwrap())
+            .unwrap();
+        let bundle = std::fs::read(&path).unwrap();
+        let verified = std::process::Command::new("git")
+            .arg("--git-dir")
+            .arg(&git_dir)
+            .args(["bundle", "verify"])
+            .arg(&path)
+            .output()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let header_end = bundle.find(b"\n\nPACK").unwrap();
+        let header: Vec<&[u8]> = bundle[..header_end].lines().collect();
+        assert_eq!(b"# v2 git bundle", header[0]);
+        assert_eq!(refs, header.len() - 1);
+        assert_eq!(format!("{head} HEAD").as_bytes(), *header.last().unwrap());
+        assert!(verified.status.success(), "{verified:?}");
+        assert!(repository.write_bundle(&["^HEAD"], Vec::new()).is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn open_bundle_file() {
+        let fixture = TestRepository::create("open-bundle-file");
+        let repository = fixture.open();
+        let path =
+            std::env::temp_dir().join(format!("gitrw-open-bundle-{}.bundle", std::process::id()));
+        let (_, objects) = repository
+            .write_bundle(&["HEAD"], std::fs::File::create(&path).unwrap())
+            .unwrap();
+
+        let bundle: Repository<super::ReadOnly> = Repository::open_pack_file(path.clone()).unwrap();
+        let counts = bundle.count_objects();
+        let commits = bundle.commits_in_packs().count();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(objects, counts.packed());
+        assert_eq!(repository.count_commits("HEAD").unwrap(), commits);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn loose_objects_only() {
+        let path = std::env::temp_dir().join(format!("gitrw-loose-only-{}", std::process::id()));
+        std::fs::create_dir_all(path.join("objects")).unwrap();
+        std::fs::create_dir_all(path.join("refs/heads")).unwrap();
+
+        let blob = crate::objs::Blob::from_content(&b"loose\n"[..]);
+        let hash = blob.hash().clone();
+        Repository::write(path.clone(), blob.into(), false).unwrap();
+
+        let opened = Repository::open(path.clone()).map(|mut repository| {
+            (
+                repository.count_objects(),
+                repository.object_location(&hash),
+                repository.read_object(hash.clone()),
+            )
+        });
+        std::fs::remove_dir_all(&path).unwrap();
+
+        let (counts, locations, object) = opened.unwrap();
+        assert_eq!((0, 1), (counts.packs, counts.loose));
+        let hex = hash.to_string();
+        assert_eq!(
+            vec![crate::ObjectLocation::Loose(
+                path.join("objects").join(&hex[0..2]).join(&hex[2..])
+            )],
+            locations
+        );
+        match object {
+            Some(GitObject::Blob(blob)) => assert_eq!(b"loose\n", blob.content()),
+            _ => panic!("blob {hash} not found"),
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn missing_parents_are_skipped() {
+        let fixture = TestRepository::empty("no-parent");
+        let path = fixture.path.clone();
+        let tree = fixture.git(&["hash-object", "-t", "tree", "-w", "--stdin"]);
+        let root = fixture.git(&["commit-tree", &tree, "-m", "root"]);
+        let head = fixture.git(&["commit-tree", &tree, "-p", &root, "-m", "head"]);
+        fixture.git(&["update-ref", "refs/heads/main", &head]);
+        fixture.git(&["commit-graph", "write", "--reachable"]);
+        // like the boundary of a shallow clone
+        std::fs::remove_file(path.join("objects").join(&root[..2]).join(&root[2..])).unwrap();
+
+        let walk = || {
+            let repository = Repository::open(path.clone()).unwrap();
+            let commits = repository.commits_topo().count();
+            (commits, repository.skip_report())
+        };
+        // the graph still lists the parent, it is only found missing when it is read
+        let with_graph = walk();
+        std::fs::remove_file(path.join("objects/info/commit-graph")).unwrap();
+        let without_graph = walk();
+
+        for (commits, report) in [with_graph, without_graph] {
+            assert_eq!(1, commits);
+            assert_eq!(
+                vec![root.clone()],
+                report.skipped["missing parent commit"].examples
+            );
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn copy_points_head_to_default_branch() {
+        let fixture = TestRepository::create("copy");
+        // HEAD of the source points to a branch that does not exist
+        fixture.git(&["symbolic-ref", "HEAD", "refs/heads/trunk"]);
+
+        let target = fixture.path.with_extension("target");
+        let repository = Repository::open_read_only(fixture.path.clone()).unwrap();
+        let copied = repository.copy_to(&target);
+        let copied_again = repository.copy_to(&target);
+        let head = std::fs::read_to_string(target.join("HEAD")).unwrap();
+        let count =
+            Repository::open(target.clone()).and_then(|target| target.count_commits("HEAD"));
+        std::fs::remove_dir_all(&target).unwrap();
+
+        assert!(copied.is_ok());
+        assert!(copied_again.is_err());
+        assert_eq!("ref: refs/heads/main\n", head);
+        assert_eq!(5, count.unwrap());
+        assert_eq!("refs/heads/trunk", fixture.git(&["symbolic-ref", "HEAD"]));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn open_reports_missing_repository() {
+        let path = std::env::temp_dir().join(format!("gitrw-missing-{}", std::process::id()));
+        let error = Repository::open(path.clone()).err().unwrap();
+
+        match error {
+            crate::GitRwError::NotARepository(reported) => assert_eq!(path, reported),
+            _ => panic!("unexpected error {error}"),
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn expired_timeout_stops_pack_reading() {
+        let fixture = TestRepository::create("expired-timeout-stops-pack-reading");
+        let mut repository = fixture.open();
+        repository.set_cancellation(crate::CancellationToken::with_timeout(
+            std::time::Duration::ZERO,
+        ));
+
+        assert!(repository.is_cancelled());
+        assert!(repository.pack_stats(1).is_err());
+        assert!(repository
+            .verify_packs(&crate::VerifyOptions::default())
+            .is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn read_only_repository_reads() {
+        let fixture = TestRepository::create("read-only");
+        let path = fixture.path.clone();
+        let repository = Repository::open_read_only(path.clone()).unwrap();
+        let clone = repository.clone();
+
+        assert_eq!(
+            clone.count_commits("HEAD").unwrap(),
+            Repository::open(path)
+                .unwrap()
+                .count_commits("HEAD")
+                .unwrap()
+        );
     }
 }
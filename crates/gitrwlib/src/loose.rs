@@ -0,0 +1,172 @@
+use std::{
+    fs::{self, File},
+    io::Read,
+    ops::Deref,
+    path::{Path, PathBuf},
+};
+
+use bstr::ByteSlice;
+use flate2::read::ZlibDecoder;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    error::GitRwError,
+    objs::{CommitHash, GitObject, TreeHash},
+    shared::ObjectHash,
+    Repository,
+};
+
+/// An object stored in its own file below `objects/`. Only the directories are read while
+/// iterating, the type is read on demand.
+#[derive(Clone, Debug)]
+pub struct LooseObject {
+    pub hash: ObjectHash,
+    pub path: PathBuf,
+}
+
+impl LooseObject {
+    /// Size of the compressed file.
+    pub fn file_size(&self) -> Result<u64, GitRwError> {
+        fs::metadata(&self.path)
+            .map(|metadata| metadata.len())
+            .map_err(GitRwError::io(&self.path))
+    }
+
+    /// Type and content size of the object, only the header at the start of the file gets
+    /// inflated.
+    pub fn header(&self) -> Result<(String, usize), GitRwError> {
+        let io = GitRwError::io(&self.path);
+        let invalid = || GitRwError::corrupt(&self.path, "invalid object header");
+        let mut decoder = ZlibDecoder::new(File::open(&self.path).map_err(io)?);
+        let mut header = Vec::with_capacity(32);
+        let mut byte = [0u8; 1];
+        // "<type> <size>\0", the size has at most 20 digits
+        while header.len() < 32 {
+            decoder.read_exact(&mut byte).map_err(io)?;
+            if byte[0] == 0 {
+                let (object_type, size) = header.split_once_str(b" ").ok_or_else(invalid)?;
+                let size = size
+                    .to_str()
+                    .ok()
+                    .and_then(|size| size.parse().ok())
+                    .ok_or_else(invalid)?;
+                let object_type = object_type.to_str().map_err(|_| invalid())?;
+                return Ok((object_type.to_owned(), size));
+            }
+            header.push(byte[0]);
+        }
+
+        Err(invalid())
+    }
+}
+
+/// Iterates over the fan-out directories `objects/xx/`, files that are not named like an object
+/// hash are skipped.
+pub(crate) fn loose_objects(repository_path: &Path) -> impl Iterator<Item = LooseObject> {
+    let dirs = fs::read_dir(repository_path.join("objects"))
+        .into_iter()
+        .flatten();
+    dirs.flatten()
+        .filter_map(|dir| {
+            let prefix = dir.file_name().into_string().ok()?;
+            (prefix.len() == 2 && prefix.bytes().all(|c| c.is_ascii_hexdigit()))
+                .then(|| (prefix, dir.path()))
+        })
+        .flat_map(|(prefix, dir)| {
+            fs::read_dir(dir)
+                .into_iter()
+                .flatten()
+                .flatten()
+                .filter_map(move |file| {
+                    let name = file.file_name().into_string().ok()?;
+                    if name.len() != 38 {
+                        return None;
+                    }
+
+                    let hash = format!("{prefix}{name}");
+                    let hash = ObjectHash::try_from(hash.as_bytes().as_bstr()).ok()?;
+                    Some(LooseObject {
+                        hash,
+                        path: file.path(),
+                    })
+                })
+        })
+}
+
+/// Deletes the loose objects of the `superseded` commits and of all trees below them, unless they
+/// are still reachable from one of the refs. Reflogs are not taken into account. Returns the
+/// deleted objects, nothing is deleted with `dry_run`.
+pub(crate) fn prune_superseded(
+    repository: &Repository,
+    superseded: impl IntoIterator<Item = CommitHash>,
+    dry_run: bool,
+) -> Result<Vec<LooseObject>, GitRwError> {
+    let mut loose: FxHashMap<ObjectHash, LooseObject> = loose_objects(&repository.path)
+        .map(|object| (object.hash.clone(), object))
+        .collect();
+    if loose.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut reader = repository.clone();
+    let mut candidates: FxHashMap<ObjectHash, LooseObject> = FxHashMap::default();
+    let mut visited = FxHashSet::default();
+    for commit_hash in superseded {
+        let commit = match reader.read_object(commit_hash.0.clone()) {
+            Some(GitObject::Commit(commit)) => commit,
+            _ => continue,
+        };
+
+        if let Some(object) = loose.remove(&commit_hash.0) {
+            candidates.insert(object.hash.clone(), object);
+        }
+        visit_trees(&mut reader, commit.tree(), &mut visited, &mut |tree_hash| {
+            if let Some(object) = loose.remove(&tree_hash.0) {
+                candidates.insert(object.hash.clone(), object);
+            }
+        });
+    }
+
+    let mut visited = FxHashSet::default();
+    for commit in repository.commits_topo() {
+        if candidates.is_empty() {
+            break;
+        }
+
+        candidates.remove(&commit.hash.0);
+        visit_trees(&mut reader, commit.tree(), &mut visited, &mut |tree_hash| {
+            candidates.remove(&tree_hash.0);
+        });
+    }
+
+    let mut pruned: Vec<LooseObject> = candidates.into_values().collect();
+    pruned.sort_unstable_by(|a, b| a.hash.cmp(&b.hash));
+    if !dry_run {
+        for object in pruned.iter() {
+            fs::remove_file(&object.path).map_err(GitRwError::io(&object.path))?;
+        }
+    }
+
+    Ok(pruned)
+}
+
+fn visit_trees(
+    repository: &mut Repository,
+    tree_hash: TreeHash,
+    visited: &mut FxHashSet<TreeHash>,
+    f: &mut impl FnMut(&TreeHash),
+) {
+    if !visited.insert(tree_hash.clone()) {
+        return;
+    }
+
+    f(&tree_hash);
+    let tree = match repository.read_object(tree_hash.into()) {
+        Some(GitObject::Tree(tree)) => tree,
+        _ => return,
+    };
+
+    for line in tree.lines().filter(|line| line.is_tree()) {
+        visit_trees(repository, line.hash.deref().clone(), visited, f);
+    }
+}
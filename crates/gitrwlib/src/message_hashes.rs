@@ -0,0 +1,187 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use bstr::ByteSlice;
+
+use crate::{
+    objs::{CommitEditable, CommitHash},
+    shared::ObjectHash,
+};
+
+/// Shortest abbreviation of a commit hash that is recognized in commit messages.
+const MIN_ABBREVIATION: usize = 7;
+const HASH_LENGTH: usize = 40;
+
+/// What happens to hashes of rewritten commits mentioned in commit messages, e.g. in
+/// `This reverts commit abcdef12`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageHashes {
+    /// Replace the hash with the rewritten one, abbreviated to the same length
+    Replace,
+    /// Replace the hash and add `[formerly <old>]` after it
+    Annotate,
+}
+
+impl FromStr for MessageHashes {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(MessageHashes::Replace),
+            "annotate" => Ok(MessageHashes::Annotate),
+            _ => Err(String::from("expected one of replace, annotate")),
+        }
+    }
+}
+
+/// The commits rewritten so far, sorted by their old hash to resolve abbreviations.
+pub(crate) struct HashIndex {
+    mode: MessageHashes,
+    rewritten: BTreeMap<ObjectHash, CommitHash>,
+}
+
+impl HashIndex {
+    pub(crate) fn new(mode: MessageHashes) -> Self {
+        HashIndex {
+            mode,
+            rewritten: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, old_hash: &CommitHash, new_hash: &CommitHash) {
+        self.rewritten
+            .insert(old_hash.clone().into(), new_hash.clone());
+    }
+
+    /// Replaces the hashes of rewritten commits in the message. Abbreviations are only replaced
+    /// if they match exactly one rewritten commit.
+    pub(crate) fn update(&self, commit: &mut CommitEditable) {
+        if self.rewritten.is_empty() {
+            return;
+        }
+
+        let message = commit.message();
+        let mut updated: Vec<u8> = Vec::with_capacity(message.len());
+        let mut position = 0;
+        for (start, end) in hex_words(message) {
+            let word = &message[start..end];
+            if let Some(new_hash) = self.lookup(word) {
+                updated.extend_from_slice(&message[position..start]);
+                updated.extend_from_slice(&new_hash.to_string().as_bytes()[..word.len()]);
+                if self.mode == MessageHashes::Annotate {
+                    updated.extend_from_slice(b" [formerly ");
+                    updated.extend_from_slice(word);
+                    updated.push(b']');
+                }
+                position = end;
+            }
+        }
+
+        if position > 0 {
+            updated.extend_from_slice(&message[position..]);
+            commit.set_message(updated);
+        }
+    }
+
+    fn lookup(&self, word: &[u8]) -> Option<&CommitHash> {
+        // padding the abbreviation gives the smallest and largest hash it can stand for
+        let bound = |padding: u8| -> Option<ObjectHash> {
+            let mut hex = word.to_vec();
+            hex.resize(HASH_LENGTH, padding);
+            hex.as_bstr().try_into().ok()
+        };
+        let mut matches = self.rewritten.range(bound(b'0')?..=bound(b'f')?);
+        match (matches.next(), matches.next()) {
+            (Some((_, new_hash)), None) => Some(new_hash),
+            _ => None,
+        }
+    }
+}
+
+/// Start and end of the lowercase hex words that can be commit hashes: between 7 and 40
+/// characters, not only digits and not part of a longer word.
+fn hex_words(message: &[u8]) -> impl Iterator<Item = (usize, usize)> + '_ {
+    let is_word = |c: &u8| c.is_ascii_alphanumeric() || *c == b'_';
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        while position < message.len() {
+            let start = position;
+            let length = message[start..].iter().take_while(|c| is_word(c)).count();
+            position = start + length.max(1);
+
+            let word = &message[start..position];
+            if (MIN_ABBREVIATION..=HASH_LENGTH).contains(&length)
+                && word.iter().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+                && !word.iter().all(u8::is_ascii_digit)
+            {
+                return Some((start, position));
+            }
+        }
+        None
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        objs::{CommitBase, CommitEditable, CommitHash},
+        shared::ObjectHash,
+    };
+
+    use super::{hex_words, HashIndex, MessageHashes};
+
+    fn hash(hex: &str) -> CommitHash {
+        let hash: ObjectHash = bstr::BStr::new(hex).try_into().unwrap();
+        hash.into()
+    }
+
+    fn updated_message(index: &HashIndex, message: &str) -> String {
+        let bytes = format!(
+            "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nauthor A <a@x.org> 1600000000 +0200\ncommitter C <c@x.org> 1600000000 +0200\n\n{message}"
+        );
+        let mut commit = CommitEditable::create(CommitBase::create(
+            hash("0000000000000000000000000000000000000000"),
+            bytes.into_bytes(),
+            false,
+        ));
+        index.update(&mut commit);
+        String::from_utf8(commit.message().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn words() {
+        let message = b"Revert abcdef1 and deadbeef0, not 1234567, xabcdef1 or abcdef";
+        let words: Vec<&[u8]> = hex_words(message)
+            .map(|(start, end)| &message[start..end])
+            .collect();
+        assert_eq!(vec![&b"abcdef1"[..], b"deadbeef0"], words);
+    }
+
+    #[test]
+    fn hashes_are_replaced() {
+        let old = "abcdef1234567890abcdef1234567890abcdef12";
+        let new = "1111111111111111111111111111111111111111";
+        let mut index = HashIndex::new(MessageHashes::Replace);
+        index.insert(&hash(old), &hash(new));
+        index.insert(
+            &hash("abcdef1999999999999999999999999999999999"),
+            &hash("2222222222222222222222222222222222222222"),
+        );
+
+        assert_eq!(
+            format!("This reverts commit {new}.\n"),
+            updated_message(&index, &format!("This reverts commit {old}.\n"))
+        );
+        assert_eq!(
+            "Fixes 11111111 (abcdef1 is ambiguous)\n",
+            updated_message(&index, "Fixes abcdef12 (abcdef1 is ambiguous)\n")
+        );
+        assert_eq!("No hashes\n", updated_message(&index, "No hashes\n"));
+
+        let mut index = HashIndex::new(MessageHashes::Annotate);
+        index.insert(&hash(old), &hash(new));
+        assert_eq!(
+            "See 11111111 [formerly abcdef12]",
+            updated_message(&index, "See abcdef12")
+        );
+    }
+}
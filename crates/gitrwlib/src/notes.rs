@@ -0,0 +1,97 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    hash::BuildHasher,
+    ops::Deref,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bstr::ByteSlice;
+
+use crate::{
+    objs::{Blob, CommitBase, CommitEditable, CommitHash, GitObject, Tree, TreeHash, TreeLine},
+    refs::GitRef,
+    reftable, GitRwError, Repository, WriteObject,
+};
+
+pub(crate) const NOTES_REF: &str = "refs/notes/gitrw";
+
+/// Adds a note with the original commit hash to every rewritten commit. Notes of a previous run
+/// are kept, the new notes commit uses the current notes commit as parent.
+pub(crate) fn write_notes<T: BuildHasher>(
+    repository: &mut Repository,
+    rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+    dry_run: bool,
+) -> Result<(), GitRwError> {
+    if dry_run || rewritten_commits.is_empty() {
+        return Ok(());
+    }
+
+    let parent = repository.resolve(NOTES_REF);
+
+    // filename -> (mode, object), sorted like git expects tree entries to be
+    let mut entries: BTreeMap<Vec<u8>, (Vec<u8>, TreeHash)> = BTreeMap::new();
+    if let Some(parent) = &parent {
+        if let Some(GitObject::Tree(tree)) = repository.lookup_path(parent.clone(), b"") {
+            for line in tree.lines() {
+                entries.insert(
+                    line.filename().to_vec(),
+                    (line.mode().to_vec(), line.hash.deref().clone()),
+                );
+            }
+        }
+    }
+
+    for (old, new) in rewritten_commits.iter() {
+        let blob = Blob::from_content(format!("{old}\n").into_bytes());
+        let blob_hash: TreeHash = blob.hash().clone().into();
+        Repository::write(repository.path.clone(), blob.into(), dry_run)?;
+
+        entries.insert(
+            new.to_string().into_bytes(),
+            (b"100644".to_vec(), blob_hash),
+        );
+    }
+
+    let texts: Vec<Vec<u8>> = entries
+        .iter()
+        .map(|(name, (mode, _))| [mode.as_slice(), b" ", name].concat())
+        .collect();
+    let tree: Tree = entries
+        .values()
+        .zip(texts.iter())
+        .map(|((_, hash), text)| TreeLine {
+            hash: Cow::Borrowed(hash),
+            text: text.as_bstr(),
+        })
+        .collect();
+    let tree_hash = tree.hash().clone();
+    Repository::write(repository.path.clone(), tree.into(), dry_run)?;
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let mut bytes = format!("tree {tree_hash}\n");
+    if let Some(parent) = parent {
+        bytes.push_str(&format!("parent {parent}\n"));
+    }
+    bytes.push_str(&format!(
+        "author gitrw <gitrw> {now} +0000\ncommitter gitrw <gitrw> {now} +0000\n\nNotes added by gitrw\n"
+    ));
+
+    let bytes = bytes.into_bytes();
+    let hash = crate::calculate_hash(&bytes, b"commit");
+    let commit = CommitEditable::create(CommitBase::create(hash.into(), bytes, false));
+    let write_object: WriteObject = commit.into();
+    let write_object_hash = write_object.hash.clone();
+    let commit_hash = write_object_hash.to_string();
+    Repository::write(repository.path.clone(), write_object, dry_run)?;
+
+    if reftable::is_reftable(&repository.path) {
+        let update = (NOTES_REF.into(), Some(write_object_hash));
+        reftable::update_refs(&repository.path, [update])
+    } else {
+        GitRef::write_ref(repository.path.to_str().unwrap(), NOTES_REF, &commit_hash)
+    }
+}
@@ -0,0 +1,21 @@
+use std::{fmt::Display, path::PathBuf};
+
+/// Where a copy of an object is stored, see `Repository::object_location`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ObjectLocation {
+    /// The file below `objects`
+    Loose(PathBuf),
+    /// An entry of a pack, the offset is where the entry starts in the pack file
+    Packed { pack_file: String, offset: usize },
+}
+
+impl Display for ObjectLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObjectLocation::Loose(path) => write!(f, "loose {}", path.display()),
+            ObjectLocation::Packed { pack_file, offset } => {
+                write!(f, "packed {pack_file} at offset {offset}")
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{compression, dry_run, GitRwError, WriteObject};
+
+/// Writes loose objects into a repository, created by `Repository::object_store`. A dry run
+/// store never touches the file system, the objects are only recorded for
+/// `Repository::capture_dry_run`, so callbacks writing new objects do not have to check for dry
+/// runs themselves.
+#[derive(Clone, Debug)]
+pub struct ObjectStore {
+    repository_path: PathBuf,
+    dry_run: bool,
+}
+
+impl ObjectStore {
+    pub(crate) fn new(repository_path: PathBuf, dry_run: bool) -> Self {
+        ObjectStore {
+            repository_path,
+            dry_run,
+        }
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Tells the report of a captured dry run that a delete pattern matched `path`, does
+    /// nothing otherwise. Directories end with a slash.
+    pub fn record_removed(&self, path: &[u8]) {
+        if self.dry_run {
+            dry_run::capture_removed_path(&self.repository_path, path);
+        }
+    }
+
+    /// Writes the object unless it already exists as a loose object.
+    pub fn write(&self, object: impl Into<WriteObject>) -> Result<(), GitRwError> {
+        let object: WriteObject = object.into();
+        if self.dry_run {
+            dry_run::capture(&self.repository_path, &object);
+            return Ok(());
+        }
+
+        let hash = object.hash.to_string();
+        let mut path = self.repository_path.join("objects");
+        path.push(&hash[0..2]);
+
+        std::fs::create_dir_all(&path).map_err(GitRwError::io(&path))?;
+
+        path.push(&hash[2..]);
+        if !Path::new(&path).exists() {
+            match compression::pack_file(&path, object.prefix.as_str(), &object.bytes) {
+                Ok(_) => {}
+                Err(e) => match e.kind() {
+                    io::ErrorKind::AlreadyExists => {}
+                    _ => return Err(GitRwError::io(&path)(e)),
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::objs::Blob;
+
+    use super::ObjectStore;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn dry_run_writes_nothing() {
+        let path = std::env::temp_dir().join(format!("gitrw-object-store-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+
+        let blob = || Blob::from_content(&b"content\n"[..]);
+        let hex = blob().hash().to_string();
+        let object_path = path.join("objects").join(&hex[0..2]).join(&hex[2..]);
+
+        ObjectStore::new(path.clone(), true).write(blob()).unwrap();
+        let dry_run_entries = std::fs::read_dir(&path).unwrap().count();
+        ObjectStore::new(path.clone(), false).write(blob()).unwrap();
+        let written = object_path.is_file();
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(0, dry_run_entries);
+        assert!(written);
+    }
+}
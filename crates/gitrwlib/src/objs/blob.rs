@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use memchr::memchr;
+
+use crate::WriteBytes;
+
+use super::{Blob, ObjectHash};
+
+impl Blob {
+    pub fn create(hash: ObjectHash, bytes: impl Into<Arc<[u8]>>, skip_first_null: bool) -> Blob {
+        let bytes = bytes.into();
+        let bytes_start = if skip_first_null {
+            memchr(b'\0', &bytes).unwrap() + 1
+        } else {
+            0
+        };
+
+        Blob {
+            hash,
+            bytes,
+            bytes_start,
+        }
+    }
+
+    pub fn from_content(content: impl Into<Arc<[u8]>>) -> Blob {
+        let content = content.into();
+        Blob {
+            hash: crate::calculate_hash(&content, b"blob"),
+            bytes: content,
+            bytes_start: 0,
+        }
+    }
+
+    pub fn hash(&self) -> &ObjectHash {
+        &self.hash
+    }
+
+    pub fn content(&self) -> &[u8] {
+        &self.bytes[self.bytes_start..]
+    }
+
+    pub fn bytes(self) -> WriteBytes {
+        WriteBytes {
+            bytes: self.bytes,
+            start: self.bytes_start,
+        }
+    }
+}
@@ -1,11 +1,11 @@
-use std::fmt::Display;
+use std::{borrow::Cow, fmt::Display, sync::Arc};
 
 use bstr::{BStr, BString, ByteSlice, ByteVec};
 
 use crate::shared::SliceIndexes;
 
 use super::{CommitBase, CommitEditable, CommitHash, ObjectHash, TreeHash, WriteBytes};
-use memchr::memchr;
+use memchr::{memchr, memrchr};
 
 impl Display for CommitHash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -34,24 +34,109 @@ impl Display for CommitBase {
     }
 }
 
+/// Index of the space between the identity and the time of an author or committer line. Like git
+/// the time starts after the last '>', so angle brackets in the name cannot shift it. Lines
+/// without an email or with anything else than `<seconds> <offset>` after it are ambiguous: the
+/// whole line becomes the identity and the time stays empty, which writes the line back as is.
 fn time_index(line: &[u8]) -> usize {
-    let mut spaces = 0;
-    for (i, b) in line.iter().rev().enumerate() {
-        let index_from_back = line.len() - i - 1;
-        if *b == b' ' {
-            spaces += 1;
+    match memrchr(b'>', line) {
+        Some(mail_end) if is_time(&line[mail_end + 1..]) => mail_end + 1,
+        _ => line.len(),
+    }
+}
+
+/// Matches ` 1600000000 +0200`, including the leading space.
+fn is_time(time: &[u8]) -> bool {
+    let is_number = |s: &[u8]| !s.is_empty() && s.iter().all(u8::is_ascii_digit);
+    match time
+        .strip_prefix(b" ")
+        .and_then(|time| time.split_once_str(" "))
+    {
+        Some((seconds, offset)) => {
+            is_number(seconds)
+                && matches!(offset.first(), Some(b'+' | b'-'))
+                && is_number(&offset[1..])
+        }
+        None => false,
+    }
+}
+
+/// Length of an author or committer line without the key, see `push_identity`.
+fn identity_len(identity: &[u8], time: &[u8]) -> usize {
+    identity.len() + usize::from(!time.is_empty()) + time.len()
+}
+
+/// Lines without a time were ambiguous and are written back without the separating space.
+fn push_identity(result: &mut Vec<u8>, key: &[u8], identity: &[u8], time: &[u8]) {
+    result.push_str(key);
+    result.push_str(identity);
+    if !time.is_empty() {
+        result.push_str(b" ");
+        result.push_str(time);
+    }
+    result.push_str(b"\n");
+}
+
+/// The identity and the time of an author or committer line, see `time_index`.
+fn split_identity(bytes: &[u8], line: &[u8]) -> (SliceIndexes, SliceIndexes) {
+    let time_index = time_index(line);
+    let time_start = (time_index + 1).min(line.len());
+    (
+        SliceIndexes::from_slice(bytes, &line[..time_index], 0),
+        SliceIndexes::from_slice(bytes, &line[time_start..], 0),
+    )
+}
+
+/// Headers that sign the commit, see `CommitEditable::set_strip_signature`.
+const SIGNATURE_HEADERS: [&[u8]; 2] = [b"gpgsig ", b"gpgsig-sha256 "];
+
+/// The signature headers among the headers starting at `start`, which end at the first empty
+/// line. Lines starting with a space continue the previous header.
+fn signature_headers(bytes: &[u8], start: usize) -> Vec<SliceIndexes> {
+    let mut signatures = Vec::new();
+    let mut signature_start = None;
+    let mut position = start;
+    while position < bytes.len() {
+        let end = memchr(b'\n', &bytes[position..]).map_or(bytes.len(), |i| position + i + 1);
+        let line = &bytes[position..end];
+        if line == b"\n" {
+            break;
         }
 
-        if spaces == 2 {
-            return index_from_back;
+        if !line.starts_with(b" ") {
+            if let Some(signature_start) = signature_start.take() {
+                signatures.push(SliceIndexes::new(
+                    signature_start,
+                    position - signature_start,
+                ));
+            }
+            if SIGNATURE_HEADERS
+                .iter()
+                .any(|header| line.starts_with(header))
+            {
+                signature_start = Some(position);
+            }
         }
+        position = end;
     }
 
-    line.len()
+    if let Some(signature_start) = signature_start {
+        signatures.push(SliceIndexes::new(
+            signature_start,
+            position - signature_start,
+        ));
+    }
+    signatures
+}
+
+fn timestamp(time: &BStr) -> Option<i64> {
+    let seconds = time.split_str(" ").next()?;
+    seconds.to_str().ok()?.parse().ok()
 }
 
 impl CommitBase {
-    pub fn create(hash: CommitHash, bytes: Box<[u8]>, skip_first_null: bool) -> Self {
+    pub fn create(hash: CommitHash, bytes: impl Into<Arc<[u8]>>, skip_first_null: bool) -> Self {
+        let bytes: Arc<[u8]> = bytes.into();
         let mut bytes_start = 0;
         let mut line_reader = if skip_first_null {
             bytes_start = memchr(b'\0', &bytes).unwrap();
@@ -72,16 +157,10 @@ impl CommitBase {
         }
 
         let author_line = &line[7..];
-        let author_time_index = time_index(author_line);
-        let author = SliceIndexes::from_slice(&bytes, &author_line[0..author_time_index], 0);
-        let author_time = SliceIndexes::from_slice(&bytes, author_line, author_time_index + 1);
+        let (author, author_time) = split_identity(&bytes, author_line);
 
         let committer_line = line_reader.next().map(|line| &line[10..]).unwrap();
-        let committer_time_index = time_index(committer_line);
-        let committer =
-            SliceIndexes::from_slice(&bytes, &committer_line[0..committer_time_index], 0);
-        let committer_time =
-            SliceIndexes::from_slice(&bytes, committer_line, committer_time_index + 1);
+        let (committer, committer_time) = split_identity(&bytes, committer_line);
 
         let committer_line_start: usize =
             unsafe { committer_line.as_ptr().offset_from(bytes.as_ptr()) }
@@ -89,6 +168,7 @@ impl CommitBase {
                 .unwrap();
         let remainder_start: usize = committer_line_start + committer_line.len() + 1;
         let remainder = SliceIndexes::new(remainder_start, bytes.len() - remainder_start);
+        let signatures = signature_headers(&bytes, remainder_start);
 
         Self {
             hash,
@@ -103,6 +183,7 @@ impl CommitBase {
             committer,
             committer_time,
             remainder,
+            signatures,
         }
     }
 
@@ -126,9 +207,53 @@ impl CommitBase {
         self.get_str(|c| &c.committer)
     }
 
+    pub fn author_time(&self) -> &bstr::BStr {
+        self.get_str(|c| &c.author_time)
+    }
+
+    pub fn committer_time(&self) -> &bstr::BStr {
+        self.get_str(|c| &c.committer_time)
+    }
+
+    /// Seconds since the epoch from the author line, `None` if they cannot be parsed.
+    pub fn author_timestamp(&self) -> Option<i64> {
+        timestamp(self.author_time())
+    }
+
+    /// Seconds since the epoch from the committer line, `None` if they cannot be parsed.
+    pub fn committer_timestamp(&self) -> Option<i64> {
+        timestamp(self.committer_time())
+    }
+
+    /// The commit message, everything after the headers.
+    pub fn message(&self) -> &[u8] {
+        let start = self.message_start().unwrap_or(self.remainder.range().end);
+        &self.bytes.bytes[start..self.remainder.range().end]
+    }
+
+    /// Where the message starts after the blank line ending the headers, `None` if there is none.
+    fn message_start(&self) -> Option<usize> {
+        let remainder = self.remainder.range();
+        let headers = &self.bytes.bytes[remainder.clone()];
+        if headers.starts_with(b"\n") {
+            Some(remainder.start + 1)
+        } else {
+            headers.find(b"\n\n").map(|end| remainder.start + end + 2)
+        }
+    }
+
     pub fn tree(&self) -> TreeHash {
         self.get_str(|c| &c.tree_line).try_into().unwrap()
     }
+
+    pub fn content(&self) -> &[u8] {
+        self.bytes.get_bytes()
+    }
+
+    /// Whether the commit has a `gpgsig` or `gpgsig-sha256` header.
+    pub fn is_signed(&self) -> bool {
+        !self.signatures.is_empty()
+    }
 }
 
 impl CommitEditable {
@@ -138,15 +263,25 @@ impl CommitEditable {
             base,
             tree: None,
             author: None,
+            author_time: None,
             committer: None,
+            committer_time: None,
             parents,
+            parents_replaced: false,
+            strip_signature: false,
+            refreshed_committer_date: None,
+            message: None,
         }
     }
 
     pub fn has_changes(&self) -> bool {
         self.tree.is_some()
+            || self.parents_replaced
             || self.author.is_some()
+            || self.author_time.is_some()
             || self.committer.is_some()
+            || self.committer_time.is_some()
+            || self.message.is_some()
             || self.parents.iter().any(|p| p.is_some())
     }
 
@@ -168,10 +303,43 @@ impl CommitEditable {
             .collect()
     }
 
+    /// The header fields and the message after the edits, which together with the base hash
+    /// determine the rewritten commit.
+    pub(crate) fn edit_key(&self) -> Box<[u8]> {
+        let mut key = Vec::with_capacity(256);
+        key.extend_from_slice(self.tree().to_string().as_bytes());
+        for parent in self.parents() {
+            key.push(b'\n');
+            key.extend_from_slice(parent.to_string().as_bytes());
+        }
+        for field in [
+            self.author_bytes(),
+            self.author_time().as_bytes(),
+            self.committer_bytes(),
+            &self.written_committer_time(),
+        ] {
+            key.push(b'\n');
+            key.extend_from_slice(field);
+        }
+        if self.strips_signature() {
+            key.extend_from_slice(b"\nunsigned");
+        }
+        if let Some(message) = &self.message {
+            key.extend_from_slice(b"\nmessage\n");
+            key.extend_from_slice(message);
+        }
+        key.into()
+    }
+
     pub fn base_hash(&self) -> &CommitHash {
         &self.base.hash
     }
 
+    /// The commit as it was read, without any of the changes.
+    pub fn base(&self) -> &CommitBase {
+        &self.base
+    }
+
     pub fn tree(&self) -> TreeHash {
         if let Some(t) = &self.tree {
             t.clone()
@@ -184,10 +352,21 @@ impl CommitEditable {
         self.tree = Some(value);
     }
 
+    /// Drops a tree set by `set_tree` or the tree callback, the commit keeps its original tree.
+    pub fn reset_tree(&mut self) {
+        self.tree = None;
+    }
+
     pub fn set_parent(&mut self, index: usize, value: CommitHash) {
         self.parents[index] = Some(value);
     }
 
+    /// Replaces the whole parent list, which also allows removing parents.
+    pub fn set_parents(&mut self, parents: Vec<CommitHash>) {
+        self.parents = parents.into_iter().map(Some).collect();
+        self.parents_replaced = true;
+    }
+
     // pub fn author(&self) -> &bstr::BStr {
     //     self.author.get(&self.bytes).as_bstr()
     // }
@@ -212,6 +391,15 @@ impl CommitEditable {
         self.author = Some(author);
     }
 
+    /// Timestamp and timezone offset of the author, e.g. `1600000000 +0200`.
+    pub fn author_time(&self) -> &BStr {
+        self.get_str(|c| &c.author_time, |c| &c.author_time)
+    }
+
+    pub fn set_author_time(&mut self, author_time: Vec<u8>) {
+        self.author_time = Some(author_time);
+    }
+
     // pub fn committer(&self) -> &bstr::BStr {
     //     self.committer.get(&self.bytes).as_bstr()
     // }
@@ -229,6 +417,89 @@ impl CommitEditable {
         self.committer = Some(committer);
     }
 
+    /// Timestamp and timezone offset of the committer, e.g. `1600000000 +0200`.
+    pub fn committer_time(&self) -> &BStr {
+        self.get_str(|c| &c.committer_time, |c| &c.committer_time)
+    }
+
+    pub fn set_committer_time(&mut self, committer_time: Vec<u8>) {
+        self.committer_time = Some(committer_time);
+    }
+
+    /// Removes the `gpgsig` and `gpgsig-sha256` headers if the commit is written with changes,
+    /// they would not match the changed commit anymore. This is no change on its own: commits
+    /// written as they are keep their valid signatures.
+    pub fn set_strip_signature(&mut self, strip: bool) {
+        self.strip_signature = strip;
+    }
+
+    /// The message after the edits.
+    pub fn message(&self) -> &[u8] {
+        match &self.message {
+            Some(message) => message,
+            None => self.base.message(),
+        }
+    }
+
+    pub fn set_message(&mut self, message: Vec<u8>) {
+        self.message = Some(message);
+    }
+
+    /// Sets the committer date to `seconds` since the epoch if the commit is written with
+    /// changes, keeping the timezone offset, unless the committer time was set explicitly. Like
+    /// `set_strip_signature` this is no change on its own.
+    pub fn set_refresh_committer_date(&mut self, seconds: Option<i64>) {
+        self.refreshed_committer_date = seconds;
+    }
+
+    /// The committer time as it is written if the commit has changes.
+    fn written_committer_time(&self) -> Cow<'_, [u8]> {
+        match (&self.committer_time, self.refreshed_committer_date) {
+            (None, Some(seconds)) => {
+                let time = self.base.committer_time();
+                let offset = time.split_str(" ").nth(1).unwrap_or(b"+0000");
+                let mut refreshed = seconds.to_string().into_bytes();
+                refreshed.push(b' ');
+                refreshed.extend_from_slice(offset);
+                Cow::Owned(refreshed)
+            }
+            _ => Cow::Borrowed(self.committer_time().as_bytes()),
+        }
+    }
+
+    fn strips_signature(&self) -> bool {
+        self.strip_signature && self.base.is_signed()
+    }
+
+    /// The headers after the committer line and the message, without the signatures if they
+    /// are stripped.
+    fn remainder_parts(&self) -> Vec<&[u8]> {
+        let bytes = &self.base.bytes.bytes;
+        let remainder = self.base.remainder.range();
+        let message_start = self.base.message_start();
+        let end = match (&self.message, message_start) {
+            (Some(_), Some(message_start)) => message_start,
+            _ => remainder.end,
+        };
+
+        let mut parts = Vec::with_capacity(self.base.signatures.len() + 3);
+        let mut position = remainder.start;
+        if self.strips_signature() {
+            for signature in self.base.signatures.iter() {
+                parts.push(&bytes[position..signature.range().start]);
+                position = signature.range().end;
+            }
+        }
+        parts.push(&bytes[position..end]);
+        if let Some(message) = &self.message {
+            if message_start.is_none() {
+                parts.push(b"\n");
+            }
+            parts.push(message);
+        }
+        parts
+    }
+
     // pub fn tree_str(&self) -> &BStr {
     //     if let Some(t) = self.tree {
     //         format!("{}", t).as_bytes().as_bstr()
@@ -267,10 +538,11 @@ impl CommitEditable {
         let parents: Vec<_> = self.parents().iter().map(|p| format!("{}", p)).collect();
 
         let author = self.get_str(|c| &c.author, |c| &c.author);
-        let author_time = self.base.get_str(|c| &c.author_time);
+        let author_time = self.get_str(|c| &c.author_time, |c| &c.author_time);
         let committer = self.get_str(|c| &c.committer, |c| &c.committer);
-        let committer_time = self.base.get_str(|c| &c.committer_time);
-        let remainder = self.base.get_str(|c| &c.remainder);
+        let committer_time = self.written_committer_time();
+        let committer_time = committer_time.as_ref();
+        let remainder = self.remainder_parts();
 
         let mut result: Vec<u8> = Vec::with_capacity(
             b"tree \n".len()
@@ -279,13 +551,11 @@ impl CommitEditable {
                     .iter()
                     .map(|parent| b"parent \n".len() + parent.len())
                     .sum::<usize>()
-                + b"author  \n".len()
-                + author.len()
-                + committer_time.len()
-                + b"committer  \n".len()
-                + committer.len()
-                + author_time.len()
-                + remainder.len(),
+                + b"author \n".len()
+                + identity_len(author, author_time)
+                + b"committer \n".len()
+                + identity_len(committer, committer_time)
+                + remainder.iter().map(|part| part.len()).sum::<usize>(),
         );
 
         result.push_str(b"tree ");
@@ -298,25 +568,127 @@ impl CommitEditable {
             result.push_str(b"\n");
         }
 
-        result.push_str(b"author ");
-        result.push_str(author);
-        result.push_str(b" ");
-        result.push_str(author_time);
-        result.push_str(b"\n");
+        push_identity(&mut result, b"author ", author, author_time);
+        push_identity(&mut result, b"committer ", committer, committer_time);
 
-        result.push_str(b"committer ");
-        result.push_str(committer);
-        result.push_str(b" ");
-        result.push_str(committer_time);
-        result.push_str(b"\n");
-
-        result.push_str(remainder);
+        for part in remainder {
+            result.push_str(part);
+        }
 
         debug_assert_eq!(result.capacity(), result.len());
 
         WriteBytes {
-            bytes: result.into_boxed_slice(),
+            bytes: result.into(),
             start: 0,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::ObjectHash;
+
+    use super::{CommitBase, CommitEditable};
+
+    fn commit(author: &str) -> CommitBase {
+        let bytes = format!(
+            "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nauthor {author}\ncommitter C <c@x.org> 1600000000 +0200\n\nmessage\n"
+        );
+        CommitBase::create(
+            ObjectHash::from([0u8; 20]).into(),
+            bytes.into_bytes(),
+            false,
+        )
+    }
+
+    #[test]
+    fn angle_brackets_in_names() {
+        let commit = commit("A <b> c <a@x.org> 1600000000 +0200");
+        assert_eq!("A <b> c <a@x.org>", commit.author());
+        assert_eq!("1600000000 +0200", commit.author_time());
+    }
+
+    #[test]
+    fn ambiguous_identities_are_preserved() {
+        for author in [
+            "A a@x.org 1600000000 +0200",
+            "A <a@x.org>",
+            "A <a@x.org> 1600000000 +0200 trailing",
+            "A <a@x.org>  1600000000 +0200",
+        ] {
+            let base = commit(author);
+            assert_eq!(author, base.author());
+            assert_eq!("", base.author_time());
+
+            let mut editable = CommitEditable::create(base);
+            editable.set_committer(b"D <d@x.org>".to_vec());
+            let bytes = editable.to_bytes();
+            let expected = format!("\nauthor {author}\ncommitter D <d@x.org> 1600000000 +0200\n");
+            assert!(
+                bytes
+                    .get_bytes()
+                    .windows(expected.len())
+                    .any(|w| w == expected.as_bytes()),
+                "{author}"
+            );
+        }
+    }
+
+    #[test]
+    fn signatures_are_stripped_from_changed_commits() {
+        let content = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nauthor A <a@x.org> 1600000000 +0200\ncommitter C <c@x.org> 1600000000 +0200\nencoding UTF-8\ngpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n -----END PGP SIGNATURE-----\ngpgsig-sha256 -----BEGIN PGP SIGNATURE-----\n def\n -----END PGP SIGNATURE-----\n\nmessage\n gpgsig in the message\n";
+        let create = || {
+            CommitBase::create(
+                ObjectHash::from([0u8; 20]).into(),
+                content.as_bytes(),
+                false,
+            )
+        };
+        assert!(create().is_signed());
+        assert!(!commit("A <a@x.org> 1600000000 +0200").is_signed());
+
+        let mut unchanged = CommitEditable::create(create());
+        unchanged.set_strip_signature(true);
+        assert_eq!(content.as_bytes(), unchanged.to_bytes().get_bytes());
+
+        let mut kept = CommitEditable::create(create());
+        kept.set_committer(b"D <d@x.org>".to_vec());
+        let kept = kept.to_bytes();
+        assert!(kept
+            .get_bytes()
+            .ends_with(content.split_once("encoding").unwrap().1.as_bytes()));
+
+        let mut stripped = CommitEditable::create(create());
+        stripped.set_committer(b"D <d@x.org>".to_vec());
+        stripped.set_strip_signature(true);
+        assert_eq!(
+            "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nauthor A <a@x.org> 1600000000 +0200\ncommitter D <d@x.org> 1600000000 +0200\nencoding UTF-8\n\nmessage\n gpgsig in the message\n".as_bytes(),
+            stripped.to_bytes().get_bytes()
+        );
+    }
+
+    #[test]
+    fn committer_date_is_refreshed_for_changed_commits() {
+        let mut unchanged = CommitEditable::create(commit("A <a@x.org> 1600000000 +0200"));
+        unchanged.set_refresh_committer_date(Some(1700000000));
+        assert_eq!(
+            commit("A <a@x.org> 1600000000 +0200").content(),
+            unchanged.to_bytes().get_bytes()
+        );
+
+        let mut refreshed = CommitEditable::create(commit("A <a@x.org> 1600000000 +0200"));
+        refreshed.set_author(b"B <b@x.org>".to_vec());
+        refreshed.set_refresh_committer_date(Some(1700000000));
+        assert!(refreshed
+            .to_bytes()
+            .get_bytes()
+            .ends_with(b"author B <b@x.org> 1600000000 +0200\ncommitter C <c@x.org> 1700000000 +0200\n\nmessage\n"));
+
+        let mut explicit = CommitEditable::create(commit("A <a@x.org> 1600000000 +0200"));
+        explicit.set_committer_time(b"1650000000 +0000".to_vec());
+        explicit.set_refresh_committer_date(Some(1700000000));
+        assert!(std::str::from_utf8(explicit.to_bytes().get_bytes())
+            .unwrap()
+            .contains("committer C <c@x.org> 1650000000 +0000\n"));
+    }
+}
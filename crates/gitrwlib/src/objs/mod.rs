@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use crate::{
     shared::{ObjectHash, RefSlice, SliceIndexes},
     WriteBytes,
@@ -5,11 +7,14 @@ use crate::{
 
 use self::tree::TreeLineIndex;
 
+pub use self::tree::TreeLine;
+
+mod blob;
 mod commit;
 mod tag;
 mod tree;
 
-#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Hash)]
 pub struct TreeHash(pub(crate) ObjectHash);
 
 impl From<TreeHash> for ObjectHash {
@@ -18,35 +23,50 @@ impl From<TreeHash> for ObjectHash {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Hash)]
 pub struct CommitHash(pub(crate) ObjectHash);
 
+impl From<CommitHash> for ObjectHash {
+    fn from(val: CommitHash) -> Self {
+        val.0
+    }
+}
+
 #[derive(Debug)]
 pub struct CommitEditable {
     base: CommitBase,
     tree: Option<TreeHash>,
-    pub parents: Vec<Option<CommitHash>>,
+    pub(crate) parents: Vec<Option<CommitHash>>,
+    parents_replaced: bool,
     author: Option<Vec<u8>>,
+    author_time: Option<Vec<u8>>,
     committer: Option<Vec<u8>>,
+    committer_time: Option<Vec<u8>>,
+    strip_signature: bool,
+    refreshed_committer_date: Option<i64>,
+    message: Option<Vec<u8>>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CommitBase {
     pub hash: CommitHash,
     bytes: WriteBytes,
-    pub tree_line: SliceIndexes,
-    pub parents: Vec<SliceIndexes>,
-    pub author: SliceIndexes,
-    pub author_time: SliceIndexes,
-    pub committer: SliceIndexes,
-    pub committer_time: SliceIndexes,
-    pub remainder: SliceIndexes,
+    pub(crate) tree_line: SliceIndexes,
+    pub(crate) parents: Vec<SliceIndexes>,
+    pub(crate) author: SliceIndexes,
+    pub(crate) author_time: SliceIndexes,
+    pub(crate) committer: SliceIndexes,
+    pub(crate) committer_time: SliceIndexes,
+    pub(crate) remainder: SliceIndexes,
+    /// The `gpgsig` and `gpgsig-sha256` headers inside `remainder`, including their
+    /// continuation lines
+    pub(crate) signatures: Vec<SliceIndexes>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Tag {
     hash: Option<ObjectHash>,
-    bytes: Box<[u8]>,
+    bytes: Arc<[u8]>,
     bytes_start: usize,
     object: RefSlice<u8>,
     obj_type: RefSlice<u8>,
@@ -54,11 +74,18 @@ pub struct Tag {
     remainder: RefSlice<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
+pub struct Blob {
+    hash: ObjectHash,
+    bytes: Arc<[u8]>,
+    bytes_start: usize,
+}
+
+#[derive(Clone, Debug)]
 pub enum GitObject {
     Commit(CommitBase),
     Tree(Tree),
-    // Blob(Blob),
+    Blob(Blob),
     Tag(Tag),
 }
 
@@ -67,13 +94,16 @@ pub enum TagTargetType {
     Tag,
     Commit,
     Tree,
+    Blob,
 }
 
-#[derive(Debug)]
+/// Parsed objects share their content, clones are cheap enough to hand to other threads and
+/// caches.
+#[derive(Clone, Debug)]
 pub struct Tree {
     object_hash: TreeHash,
     lines: Vec<TreeLineIndex>,
-    bytes: Box<[u8]>,
+    bytes: Arc<[u8]>,
     bytes_start: usize,
 }
 
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use bstr::{BStr, ByteSlice, ByteVec, Lines};
 
 use crate::{objs::TagTargetType, shared::RefSlice, WriteBytes};
@@ -5,7 +7,12 @@ use crate::{objs::TagTargetType, shared::RefSlice, WriteBytes};
 use super::{ObjectHash, Tag};
 
 impl Tag {
-    pub fn create(hash: Option<ObjectHash>, bytes: Box<[u8]>, skip_first_null: bool) -> Tag {
+    pub fn create(
+        hash: Option<ObjectHash>,
+        bytes: impl Into<Arc<[u8]>>,
+        skip_first_null: bool,
+    ) -> Tag {
+        let bytes: Arc<[u8]> = bytes.into();
         let mut line_reader: Lines<'_>;
 
         let mut null_idx = 0;
@@ -75,6 +82,8 @@ impl Tag {
             return TagTargetType::Commit;
         } else if target == b"tree" {
             return TagTargetType::Tree;
+        } else if target == b"blob" {
+            return TagTargetType::Blob;
         }
 
         panic!(
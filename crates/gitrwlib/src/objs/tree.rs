@@ -1,6 +1,7 @@
-use std::{borrow::Cow, fmt::Display};
+use std::{borrow::Cow, fmt::Display, sync::Arc};
 
 use bstr::{BStr, ByteSlice, ByteVec};
+use memchr::memchr;
 
 use crate::{
     shared::{self, RefSlice},
@@ -9,18 +10,27 @@ use crate::{
 
 use super::{ObjectHash, Tree, TreeHash};
 
+/// Typical length of a tree entry: mode, a short filename and the 20 byte hash. Only used to
+/// size the line index up front, large trees would otherwise reallocate it over and over.
+const AVERAGE_ENTRY_LENGTH: usize = 40;
+
 impl Tree {
-    pub fn create(object_hash: TreeHash, bytes: Box<[u8]>, skip_first_null: bool) -> Tree {
+    pub fn create(
+        object_hash: TreeHash,
+        bytes: impl Into<Arc<[u8]>>,
+        skip_first_null: bool,
+    ) -> Tree {
+        let bytes = bytes.into();
         let start_index = if skip_first_null {
-            bytes.iter().position(|x| *x == b'\0').unwrap() + 1
+            memchr(b'\0', &bytes).unwrap() + 1
         } else {
             0
         };
 
         let mut position = start_index;
 
-        let mut null_terminator_index_opt = bytes[position..].iter().position(|x| *x == b'\0');
-        let mut lines = Vec::new();
+        let mut null_terminator_index_opt = memchr(b'\0', &bytes[position..]);
+        let mut lines = Vec::with_capacity((bytes.len() - start_index) / AVERAGE_ENTRY_LENGTH);
 
         while let Some(null_terminator_index) = null_terminator_index_opt {
             let text = RefSlice::new(position, null_terminator_index);
@@ -37,7 +47,7 @@ impl Tree {
                 text,
             });
 
-            null_terminator_index_opt = bytes[position..].iter().position(|x| *x == b'\0');
+            null_terminator_index_opt = memchr(b'\0', &bytes[position..]);
         }
 
         Tree {
@@ -48,13 +58,17 @@ impl Tree {
         }
     }
 
-    pub fn lines(&self) -> impl Iterator<Item = TreeLine> {
+    pub fn lines(&self) -> impl Iterator<Item = TreeLine<'_>> {
         self.lines.iter().map(|tree_line_index| TreeLine {
             hash: Cow::Borrowed(&tree_line_index.hash),
             text: tree_line_index.text.get(&self.bytes).as_bstr(), // text: self._bytes.get(tree_line_index.text),
         })
     }
 
+    pub fn content(&self) -> &[u8] {
+        &self.bytes[self.bytes_start..]
+    }
+
     pub fn bytes(self) -> WriteBytes {
         WriteBytes {
             bytes: self.bytes,
@@ -65,18 +79,17 @@ impl Tree {
 
 impl<'a> FromIterator<TreeLine<'a>> for Tree {
     fn from_iter<T: IntoIterator<Item = TreeLine<'a>>>(iter: T) -> Self {
-        let mut buf: Vec<u8> = Vec::new();
+        let iter = iter.into_iter();
+        let mut buf: Vec<u8> = Vec::with_capacity(iter.size_hint().0 * AVERAGE_ENTRY_LENGTH);
         for line in iter {
             buf.push_str(line.text);
             buf.push(b'\0');
-            for c in line.hash.0.bytes {
-                buf.push(c);
-            }
+            buf.extend_from_slice(&line.hash.0.bytes);
         }
 
         let object_hash = crate::calculate_hash(&buf, b"tree");
 
-        Self::create(TreeHash(object_hash), buf.into_boxed_slice(), false)
+        Self::create(TreeHash(object_hash), buf, false)
     }
 }
 
@@ -90,15 +103,20 @@ impl<'a> TreeLine<'a> {
         self.text[0] != b'1'
     }
 
+    pub fn mode(&self) -> &[u8] {
+        let seperator_index = memchr(b' ', self.text).unwrap();
+        &self.text[..seperator_index]
+    }
+
     pub fn filename(&self) -> &[u8] {
-        let seperator_index = self.text.iter().position(|c| *c == b' ').unwrap();
+        let seperator_index = memchr(b' ', self.text).unwrap();
         &self.text[seperator_index + 1..]
     }
 }
 
 impl<'a> Display for TreeLine<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let null_index = self.text.iter().position(|c| *c == b' ').unwrap();
+        let null_index = memchr(b' ', self.text).unwrap();
         f.write_fmt(format_args!(
             "{}{} {} {}\t{}",
             if self.is_tree() {
@@ -118,7 +136,7 @@ impl<'a> Display for TreeLine<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct TreeLineIndex {
     hash: TreeHash,
     text: shared::RefSlice<u8>,
@@ -130,6 +148,22 @@ impl Display for TreeHash {
     }
 }
 
+/// Hash of the tree without any entries, 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+const EMPTY_TREE: [u8; 20] = [
+    0x4b, 0x82, 0x5d, 0xc6, 0x42, 0xcb, 0x6e, 0xb9, 0xa0, 0x60, 0xe5, 0x4b, 0xf8, 0xd6, 0x92, 0x88,
+    0xfb, 0xee, 0x49, 0x04,
+];
+
+impl TreeHash {
+    pub fn empty() -> TreeHash {
+        TreeHash(ObjectHash::from(EMPTY_TREE))
+    }
+
+    pub fn is_empty_tree(&self) -> bool {
+        self.0.bytes == EMPTY_TREE
+    }
+}
+
 impl TryFrom<&[u8]> for TreeHash {
     type Error = &'static str;
 
@@ -161,3 +195,85 @@ impl Display for Tree {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::borrow::Cow;
+
+    use bstr::ByteSlice;
+
+    use crate::shared::ObjectHash;
+
+    use super::{Tree, TreeHash, TreeLine, AVERAGE_ENTRY_LENGTH};
+
+    /// A tree of `texts`, all entries point to the same object.
+    fn synthetic_tree(texts: &[String]) -> Tree {
+        let hash = TreeHash(ObjectHash::from([7; 20]));
+        texts
+            .iter()
+            .map(|text| TreeLine {
+                hash: Cow::Borrowed(&hash),
+                text: text.as_bytes().as_bstr(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn empty_tree_hash() {
+        let tree: Tree = std::iter::empty().collect();
+
+        assert_eq!(&TreeHash::empty(), tree.hash());
+        assert!(tree.hash().is_empty_tree());
+        assert_eq!(
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904",
+            TreeHash::empty().to_string()
+        );
+    }
+
+    #[test]
+    fn large_tree_is_parsed() {
+        let names: Vec<String> = (0..100_000)
+            .map(|i| format!("100644 file_{i}.rs"))
+            .collect();
+        let hash = TreeHash(ObjectHash::from([7; 20]));
+        let tree = synthetic_tree(&names);
+
+        let parsed = Tree::create(tree.hash().clone(), tree.content(), false);
+        assert_eq!(names.len(), parsed.lines().count());
+        assert!(parsed
+            .lines()
+            .zip(&names)
+            .all(|(line, name)| line.text == name.as_str() && *line.hash == hash));
+        assert_eq!(b"file_99999.rs", parsed.lines().last().unwrap().filename());
+    }
+
+    #[test]
+    fn tree_with_more_entries_than_estimated() {
+        // 29 bytes per entry, the line index has to grow beyond its initial capacity
+        let names: Vec<String> = (0..1000).map(|i| format!("100644 {:x}", i % 16)).collect();
+        let tree = synthetic_tree(&names);
+        let estimate = tree.content().len() / AVERAGE_ENTRY_LENGTH;
+
+        let parsed = Tree::create(tree.hash().clone(), tree.content(), false);
+        assert!(names.len() > estimate);
+        assert_eq!(names.len(), parsed.lines().count());
+        assert!(parsed
+            .lines()
+            .zip(&names)
+            .all(|(line, name)| line.text == name.as_str()));
+    }
+
+    #[test]
+    fn clones_share_content() {
+        let hash = TreeHash(ObjectHash::from([7; 20]));
+        let line = TreeLine {
+            hash: Cow::Borrowed(&hash),
+            text: b"100644 README.md".as_bstr(),
+        };
+        let tree: Tree = std::iter::once(line).collect();
+        let clone = tree.clone();
+
+        assert!(std::ptr::eq(tree.content(), clone.content()));
+        assert_eq!(tree.bytes().get_bytes().as_ptr(), clone.content().as_ptr());
+    }
+}
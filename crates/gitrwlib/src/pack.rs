@@ -0,0 +1,177 @@
+//! Reading single pack files on their own, without a repository or any of the rewrite
+//! machinery around them.
+//!
+//! ```no_run
+//! use gitrwlib::pack::PackFile;
+//!
+//! let mut pack = PackFile::open("objects/pack/pack-1234.pack")?;
+//! for hash in pack.hashes() {
+//!     let entry = pack.entry(&hash).unwrap();
+//!     println!("{hash} {} {}", entry.object_type, entry.content.len());
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    calculate_hash,
+    compression::Decompression,
+    error::GitRwError,
+    objs::GitObject,
+    packreader::{object_type_name, PackReader},
+    shared::ObjectHash,
+};
+
+/// An object read from a pack, deltas are already applied.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub hash: ObjectHash,
+    /// Position of the entry in the pack file
+    pub offset: usize,
+    /// `commit`, `tree`, `blob` or `tag`, for deltas the type of their base
+    pub object_type: &'static str,
+    pub content: Arc<[u8]>,
+}
+
+/// A pack file opened read-only. Clones share the mapped pack and its index, each clone
+/// inflates objects on its own, so they can be handed to other threads.
+pub struct PackFile {
+    path: PathBuf,
+    reader: PackReader,
+    decompression: Decompression,
+}
+
+impl Clone for PackFile {
+    fn clone(&self) -> Self {
+        PackFile {
+            path: self.path.clone(),
+            reader: self.reader.clone(),
+            decompression: Decompression::default(),
+        }
+    }
+}
+
+impl PackFile {
+    /// Opens a `.pack` or bundle file. The objects are looked up in the `.idx` file next to
+    /// the pack. Without one, every entry is inflated once to index the pack in memory, objects
+    /// whose delta base is missing from the pack are left out then.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, GitRwError> {
+        let path = path.as_ref();
+        let idx_path = path.with_extension("idx");
+        let reader = if idx_path.is_file() && path.extension().is_some_and(|e| e == "pack") {
+            PackReader::from_indexed_pack(path, &idx_path)?
+        } else {
+            PackReader::from_pack_file(path)?
+        };
+
+        Ok(PackFile {
+            path: path.to_path_buf(),
+            reader,
+            decompression: Decompression::default(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of objects in the pack.
+    pub fn len(&self) -> usize {
+        self.reader.object_count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, hash: &ObjectHash) -> bool {
+        self.reader.contains(hash)
+    }
+
+    /// The hashes of all objects, in the order their entries are stored in the pack.
+    pub fn hashes(&self) -> Vec<ObjectHash> {
+        let mut hashes = Vec::with_capacity(self.len());
+        self.reader.for_each_pack(|_, _, _, offsets| {
+            hashes.extend(offsets.into_iter().map(|(hash, _)| hash));
+        });
+        hashes
+    }
+
+    /// Reads the object with the given hash, `None` if it is not part of the pack.
+    pub fn entry(&mut self, hash: &ObjectHash) -> Option<Entry> {
+        let offset = self.reader.offset(hash)?;
+        let (content, base) = self
+            .reader
+            .read_git_object_bytes(&mut self.decompression, hash)?;
+
+        Some(Entry {
+            hash: hash.clone(),
+            offset,
+            object_type: object_type_name(base.object_type),
+            content,
+        })
+    }
+
+    /// Reads the entry starting at `offset`, its hash is calculated from the content. Returns
+    /// `None` for offsets outside of the pack, other offsets have to point to the start of an
+    /// entry.
+    pub fn entry_at(&mut self, offset: usize) -> Option<Entry> {
+        let (content, base) = self
+            .reader
+            .read_at_offset(&mut self.decompression, 0, offset)?;
+        let object_type = object_type_name(base.object_type);
+
+        Some(Entry {
+            hash: calculate_hash(&content, object_type.as_bytes()),
+            offset,
+            object_type,
+            content,
+        })
+    }
+
+    /// Reads and parses the object with the given hash.
+    pub fn object(&mut self, hash: &ObjectHash) -> Option<GitObject> {
+        self.reader
+            .read_git_object(&mut self.decompression, hash.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{objs::GitObject, test_repository::TestRepository};
+
+    use super::PackFile;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn pack_without_idx() {
+        let fixture = TestRepository::create("pack-file");
+        let mut repository = fixture.open();
+        let path =
+            std::env::temp_dir().join(format!("gitrw-pack-file-{}.pack", std::process::id()));
+        let count = repository
+            .write_pack(&["HEAD"], std::fs::File::create(&path).unwrap())
+            .unwrap();
+
+        let mut pack = PackFile::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let head = repository.resolve("HEAD").unwrap();
+        let Some(GitObject::Commit(commit)) = repository.read_object(head.clone()) else {
+            panic!("HEAD is no commit");
+        };
+        let entry = pack.entry(&head).unwrap();
+
+        assert_eq!(count, pack.len());
+        assert_eq!(count, pack.hashes().len());
+        assert_eq!("commit", entry.object_type);
+        assert_eq!(commit.content(), &entry.content[..]);
+        assert_eq!(head, pack.entry_at(entry.offset).unwrap().hash);
+        assert!(matches!(pack.object(&head), Some(GitObject::Commit(_))));
+        assert!(pack.entry(&crate::ObjectHash::from([0; 20])).is_none());
+    }
+}
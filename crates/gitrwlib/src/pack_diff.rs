@@ -1,8 +1,10 @@
+use std::sync::Arc;
+
 use memmap2::Mmap;
 
-use crate::{compression::Decompression, packreader::PackObject};
+use crate::{compression::Decompression, packreader::PackObject, shared::shared_bytes};
 
-pub struct CopyInstruction {
+pub(crate) struct CopyInstruction {
     offset: usize,
     len: usize,
 }
@@ -68,7 +70,7 @@ impl std::fmt::Debug for CopyInstruction {
 }
 
 #[derive(Clone)]
-pub struct AddInstruction {
+pub(crate) struct AddInstruction {
     bytes: Box<[u8]>,
 }
 
@@ -96,7 +98,7 @@ impl AddInstruction {
 }
 
 #[derive(Debug)]
-pub enum DiffInstruction {
+pub(crate) enum DiffInstruction {
     Copy(CopyInstruction),
     Add(AddInstruction),
 }
@@ -111,7 +113,7 @@ impl DiffInstruction {
 }
 
 #[derive(Debug)]
-pub struct PackDiff {
+pub(crate) struct PackDiff {
     pub target_len: usize,
     pub negative_offset: usize,
     pub instructions: Vec<DiffInstruction>,
@@ -124,20 +126,8 @@ impl PackDiff {
         pack_object: &PackObject,
     ) -> PackDiff {
         let (base_offset, bytes_read) = read_base_offset(mmap, pack_object);
-
         let diff_instruction_bytes = compression.unpack(mmap, pack_object, bytes_read);
-
-        let (_, bytes_read) = read_varint(&diff_instruction_bytes, 0);
-        let (target_len, bytes_read) = read_varint(&diff_instruction_bytes, bytes_read);
-
-        let instructions =
-            build_delta_instructions(diff_instruction_bytes, pack_object, bytes_read);
-
-        PackDiff {
-            instructions,
-            target_len,
-            negative_offset: base_offset,
-        }
+        PackDiff::from_delta(&diff_instruction_bytes, base_offset)
     }
 
     pub fn create_for_ref(
@@ -145,22 +135,40 @@ impl PackDiff {
         mmap: &Mmap,
         pack_object: &PackObject,
     ) -> PackDiff {
-        // TODO consolidate with Self::create
         let diff_instruction_bytes = compression.unpack(mmap, pack_object, 20);
+        PackDiff::from_delta(&diff_instruction_bytes, 0)
+    }
 
-        let (_, bytes_read) = read_varint(&diff_instruction_bytes, 0);
-        let (target_len, bytes_read) = read_varint(&diff_instruction_bytes, bytes_read);
-
-        let instructions =
-            build_delta_instructions(diff_instruction_bytes, pack_object, bytes_read);
+    /// Parses inflated delta data without checking it, see `check_delta`.
+    pub fn from_delta(delta: &[u8], negative_offset: usize) -> PackDiff {
+        let (_, bytes_read) = read_varint(delta, 0);
+        let (target_len, bytes_read) = read_varint(delta, bytes_read);
 
         PackDiff {
-            instructions,
+            instructions: build_delta_instructions(delta, bytes_read),
             target_len,
-            negative_offset: 0,
+            negative_offset,
         }
     }
 
+    /// Size of the object the delta at `pack_object` restores, only the start of the delta
+    /// instructions gets inflated.
+    pub fn target_len(
+        compression: &mut Decompression,
+        mmap: &Mmap,
+        pack_object: &PackObject,
+    ) -> Option<usize> {
+        let additional_offset = match pack_object.object_type {
+            6 => try_read_base_offset(mmap, pack_object)?.1,
+            _ => 20,
+        };
+
+        // the base and target sizes are varints of at most 10 bytes each
+        let header = compression.unpack_prefix(mmap, pack_object, additional_offset, 20)?;
+        let (_, bytes_read) = checked_varint(&header, 0)?;
+        Some(checked_varint(&header, bytes_read)?.0)
+    }
+
     pub fn combine(self, other: &PackDiff) -> PackDiff {
         let mut instructions = Vec::new();
 
@@ -187,28 +195,25 @@ impl PackDiff {
         }
     }
 
-    #[allow(clippy::uninit_vec)]
-    pub fn apply(&self, bytes: &[u8]) -> Box<[u8]> {
-        let mut target = Vec::with_capacity(self.target_len);
-        unsafe { target.set_len(self.target_len) };
-        let mut target_offset = 0;
+    pub fn apply(&self, bytes: &[u8]) -> Arc<[u8]> {
+        shared_bytes(self.target_len, |target| {
+            let mut target_offset = 0;
 
-        for instruction in self.instructions.iter() {
-            match instruction {
-                DiffInstruction::Add(add) => {
-                    let len = add.bytes.len();
-                    target[target_offset..target_offset + len].copy_from_slice(&add.bytes);
-                    target_offset += len;
-                }
-                DiffInstruction::Copy(copy) => {
-                    target[target_offset..target_offset + copy.len]
-                        .copy_from_slice(&bytes[copy.offset..copy.offset + copy.len]);
-                    target_offset += copy.len;
+            for instruction in self.instructions.iter() {
+                match instruction {
+                    DiffInstruction::Add(add) => {
+                        let len = add.bytes.len();
+                        target[target_offset..target_offset + len].copy_from_slice(&add.bytes);
+                        target_offset += len;
+                    }
+                    DiffInstruction::Copy(copy) => {
+                        target[target_offset..target_offset + copy.len]
+                            .copy_from_slice(&bytes[copy.offset..copy.offset + copy.len]);
+                        target_offset += copy.len;
+                    }
                 }
             }
-        }
-
-        target.into_boxed_slice()
+        })
     }
 }
 
@@ -261,37 +266,96 @@ fn get_instructions_from_copy(
     result
 }
 
-fn read_varint(delta_data: &[u8], mut offset: usize) -> (usize, usize) {
-    let mut byte = delta_data[offset];
+fn read_varint(delta_data: &[u8], offset: usize) -> (usize, usize) {
+    checked_varint(delta_data, offset).unwrap()
+}
+
+fn checked_varint(delta_data: &[u8], mut offset: usize) -> Option<(usize, usize)> {
+    let mut byte = *delta_data.get(offset)?;
     offset += 1;
     let mut len = (byte & 0b01111111) as usize;
     let mut fsb_set = (byte & 0b10000000) != 0;
     let mut shift = 7;
     while fsb_set {
-        byte = delta_data[offset];
+        byte = *delta_data.get(offset)?;
         offset += 1;
         fsb_set = (byte & 0b10000000) != 0;
-        len |= ((byte & 0b01111111) as usize) << shift;
+        len |= ((byte & 0b01111111) as usize).checked_shl(shift)?;
         shift += 7;
     }
 
-    (len, offset)
+    Some((len, offset))
 }
 
-fn build_delta_instructions(
-    diff_data: Box<[u8]>,
-    pack_object: &PackObject,
-    mut bytes_read: usize,
-) -> Vec<DiffInstruction> {
+/// Checks inflated delta data against the length of its base before `PackDiff::from_delta`
+/// trusts it. Returns the length of the object the delta restores.
+pub(crate) fn check_delta(delta: &[u8], base_len: usize) -> Result<usize, String> {
+    let truncated = || "delta header is truncated".to_owned();
+    let (expected_base_len, offset) = checked_varint(delta, 0).ok_or_else(truncated)?;
+    let (target_len, mut offset) = checked_varint(delta, offset).ok_or_else(truncated)?;
+    if expected_base_len != base_len {
+        return Err(format!(
+            "delta expects a base of {expected_base_len} bytes, the base has {base_len}"
+        ));
+    }
+
+    let mut restored = 0;
+    while offset < delta.len() {
+        let instruction = delta[offset];
+        offset += 1;
+
+        restored += if (instruction & 0b10000000) != 0 {
+            // offset and length bytes follow for every bit that is set, lowest first
+            let mut fields = [0usize; 7];
+            for (bit, field) in fields.iter_mut().enumerate() {
+                if (instruction & (1 << bit)) != 0 {
+                    *field = *delta.get(offset).ok_or("copy instruction is truncated")? as usize;
+                    offset += 1;
+                }
+            }
+
+            let copy_offset = fields[0] | fields[1] << 8 | fields[2] << 16 | fields[3] << 24;
+            let len = match fields[4] | fields[5] << 8 | fields[6] << 16 {
+                0 => 0x10000,
+                len => len,
+            };
+            if copy_offset + len > base_len {
+                return Err(format!(
+                    "delta copies {len} bytes from offset {copy_offset} of a {base_len} byte base"
+                ));
+            }
+            len
+        } else if instruction == 0 {
+            return Err("delta contains the reserved instruction 0".to_owned());
+        } else {
+            let len = instruction as usize;
+            if offset + len > delta.len() {
+                return Err("add instruction is truncated".to_owned());
+            }
+            offset += len;
+            len
+        };
+    }
+
+    if restored != target_len {
+        return Err(format!(
+            "delta restores {restored} bytes instead of {target_len}"
+        ));
+    }
+
+    Ok(target_len)
+}
+
+fn build_delta_instructions(diff_data: &[u8], mut bytes_read: usize) -> Vec<DiffInstruction> {
     let mut result: Vec<DiffInstruction> = Vec::new();
-    while bytes_read < pack_object.data_size {
+    while bytes_read < diff_data.len() {
         let instruction = diff_data[bytes_read];
 
         if (instruction & 0b10000000) != 0 {
-            let copy_instruction = CopyInstruction::create(&diff_data, &mut bytes_read);
+            let copy_instruction = CopyInstruction::create(diff_data, &mut bytes_read);
             result.push(DiffInstruction::Copy(copy_instruction));
         } else {
-            let add_instruction = AddInstruction::create(&diff_data, &mut bytes_read);
+            let add_instruction = AddInstruction::create(diff_data, &mut bytes_read);
             result.push(DiffInstruction::Add(add_instruction));
         }
     }
@@ -299,31 +363,35 @@ fn build_delta_instructions(
     result
 }
 
-fn read_base_offset(mmap: &Mmap, pack_object: &PackObject) -> (usize, usize) {
-    let mut byte = mmap
-        .get(pack_object.offset + pack_object.header_len)
-        .unwrap();
+pub(crate) fn read_base_offset(mmap: &Mmap, pack_object: &PackObject) -> (usize, usize) {
+    try_read_base_offset(mmap, pack_object).unwrap()
+}
+
+/// Like `read_base_offset`, `None` if the offset is truncated or too large.
+pub(crate) fn try_read_base_offset(
+    mmap: &Mmap,
+    pack_object: &PackObject,
+) -> Option<(usize, usize)> {
+    let mut byte = *mmap.get(pack_object.offset + pack_object.header_len)?;
     let mut bytes_read = 1;
     let mut offset = (byte & 127) as usize;
 
     while (byte & 128) != 0 {
-        offset += 1;
-        byte = mmap
-            .get(pack_object.offset + pack_object.header_len + bytes_read)
-            .unwrap();
+        byte = *mmap.get(pack_object.offset + pack_object.header_len + bytes_read)?;
         bytes_read += 1;
-        offset <<= 7;
-        offset += (byte & 127) as usize;
+        offset = (offset + 1)
+            .checked_mul(128)?
+            .checked_add((byte & 127) as usize)?;
     }
 
-    (offset, bytes_read)
+    Some((offset, bytes_read))
 }
 
 #[cfg(test)]
 mod test {
     use std::vec;
 
-    use super::{AddInstruction, CopyInstruction, DiffInstruction, PackDiff};
+    use super::{check_delta, AddInstruction, CopyInstruction, DiffInstruction, PackDiff};
 
     #[test]
     pub fn patch_diff() {
@@ -371,4 +439,21 @@ mod test {
         assert_eq!(*bytes, target_text);
         // println!("Text: {}", bytes.to_str().unwrap());
     }
+
+    #[test]
+    fn broken_deltas_are_rejected() {
+        // base of 11 bytes, target of 15: copy 11 bytes from offset 0, add "abcd"
+        let delta = [11, 15, 0b10010000, 11, 4, b'a', b'b', b'c', b'd'];
+        assert_eq!(Ok(15), check_delta(&delta, 11));
+
+        let restored = PackDiff::from_delta(&delta, 0).apply(b"hello world");
+        assert_eq!(b"hello worldabcd", &restored[..]);
+
+        assert!(check_delta(&delta, 10).is_err());
+        assert!(check_delta(&delta[..1], 11).is_err());
+        assert!(check_delta(&delta[..7], 11).is_err());
+        assert!(check_delta(&[11, 15, 0b10010001, 1, 11], 11).is_err());
+        assert!(check_delta(&[11, 15, 0], 11).is_err());
+        assert!(check_delta(&[11, 16, 0b10010000, 11, 4, b'a', b'b', b'c', b'd'], 11).is_err());
+    }
 }
@@ -0,0 +1,208 @@
+use std::cmp::Reverse;
+
+use crate::{
+    cancel::CancellationToken,
+    compression::Decompression,
+    error::GitRwError,
+    packreader::{object_type_name, PackObject, PackReader},
+    shared::ObjectHash,
+    verify::{CorruptObject, CorruptObjects, DEFAULT_MAX_ERRORS},
+};
+
+/// Entries smaller than this are not ranked in `PackStats::worst_compressed`, zlib can not do
+/// much for them anyway.
+const MIN_RANKED_SIZE: usize = 1024;
+
+/// A single entry of a pack file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackEntry {
+    pub hash: ObjectHash,
+    /// Position of the entry in the pack, see `Repository::read_at_offset`
+    pub offset: usize,
+    /// Type of the object, for deltas the type of their base
+    pub object_type: &'static str,
+    pub delta_depth: usize,
+    /// Bytes the entry takes up in the pack, including its header
+    pub stored_size: usize,
+    /// Size of the inflated entry, for deltas the size of the delta instructions
+    pub inflated_size: usize,
+    /// Size of the object itself, for deltas the size after applying them
+    pub object_size: usize,
+}
+
+impl PackEntry {
+    pub fn compression_ratio(&self) -> f64 {
+        self.stored_size as f64 / self.inflated_size.max(1) as f64
+    }
+}
+
+/// Statistics of a single pack file, only the entry headers are read to compute them.
+#[derive(Debug, Default)]
+pub struct PackStats {
+    /// Id of the pack for `Repository::read_at_offset`
+    pub pack_id: usize,
+    pub pack_file: String,
+    pub pack_size: u64,
+    pub commits: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    pub tags: usize,
+    /// Number of entries stored as a delta
+    pub deltas: usize,
+    pub max_delta_depth: usize,
+    pub total_delta_depth: usize,
+    pub stored_size: u64,
+    pub inflated_size: u64,
+    /// The entries with the highest ratio of stored to inflated size, worst first
+    pub worst_compressed: Vec<PackEntry>,
+    /// The entries with the longest delta chains, deepest first
+    pub deepest_chains: Vec<PackEntry>,
+    /// Entries whose header or delta chain can not be read, they are left out of all other
+    /// numbers. Only the headers are checked, `Repository::verify_packs` checks the content.
+    pub corrupt: CorruptObjects,
+}
+
+impl PackStats {
+    pub fn objects(&self) -> usize {
+        self.commits + self.trees + self.blobs + self.tags
+    }
+
+    /// Average delta chain depth of the entries stored as a delta.
+    pub fn average_delta_depth(&self) -> f64 {
+        if self.deltas == 0 {
+            0.0
+        } else {
+            self.total_delta_depth as f64 / self.deltas as f64
+        }
+    }
+
+    pub fn compression_ratio(&self) -> f64 {
+        self.stored_size as f64 / self.inflated_size.max(1) as f64
+    }
+}
+
+pub(crate) fn pack_stats(
+    pack_reader: &PackReader,
+    top: usize,
+    cancel: &CancellationToken,
+) -> Result<Vec<PackStats>, GitRwError> {
+    let mut all_stats = Vec::new();
+    pack_reader.for_each_pack(|pack_id, pack_file, mmap, offsets| {
+        if cancel.is_cancelled() {
+            return;
+        }
+
+        let mut stats = PackStats {
+            pack_id,
+            pack_file: pack_file.to_owned(),
+            pack_size: mmap.len() as u64,
+            ..Default::default()
+        };
+
+        let corrupt = |i: usize, reason: String| CorruptObject {
+            pack_file: pack_file.to_owned(),
+            hash: offsets[i].0.to_string(),
+            offset: offsets[i].1,
+            reason,
+        };
+
+        // the pack ends with the checksum of its content
+        let end = mmap.len().saturating_sub(20);
+        let mut entries = Vec::with_capacity(offsets.len());
+        for (i, (_, offset)) in offsets.iter().enumerate() {
+            if cancel.is_cancelled() {
+                return;
+            }
+
+            let next_offset = offsets.get(i + 1).map_or(end, |(_, next)| *next);
+            let header = PackObject::try_create(mmap, *offset)
+                .filter(|_| *offset < next_offset && next_offset <= end)
+                .ok_or_else(|| "entry header is truncated or outside of the pack".to_owned())
+                .and_then(|pack_object| {
+                    let chain = pack_reader.try_delta_chain(mmap, *offset)?;
+                    Ok((pack_object, chain))
+                });
+            let (pack_object, (object_type, delta_depth)) = match header {
+                Ok(header) => header,
+                Err(reason) => {
+                    stats.corrupt.push(corrupt(i, reason), DEFAULT_MAX_ERRORS);
+                    continue;
+                }
+            };
+
+            match object_type {
+                1 => stats.commits += 1,
+                2 => stats.trees += 1,
+                3 => stats.blobs += 1,
+                _ => stats.tags += 1,
+            }
+
+            if delta_depth > 0 {
+                stats.deltas += 1;
+                stats.total_delta_depth += delta_depth;
+                stats.max_delta_depth = stats.max_delta_depth.max(delta_depth);
+            }
+
+            let stored_size = next_offset - offset;
+            stats.stored_size += stored_size as u64;
+            stats.inflated_size += pack_object.data_size as u64;
+            entries.push((
+                i,
+                object_type,
+                delta_depth,
+                stored_size,
+                pack_object.data_size,
+            ));
+        }
+
+        // only the ranked entries are worth inflating the start of their delta instructions
+        let mut decompression = Decompression::default();
+        let mut unreadable = Vec::new();
+        let mut entry = |e: usize| {
+            let (i, object_type, delta_depth, stored_size, inflated_size) = entries[e];
+            let object_size =
+                match pack_reader.object_header_at(&mut decompression, mmap, offsets[i].1) {
+                    Some((_, object_size)) => object_size,
+                    None => {
+                        unreadable.push(i);
+                        0
+                    }
+                };
+            PackEntry {
+                hash: offsets[i].0.clone(),
+                offset: offsets[i].1,
+                object_type: object_type_name(object_type),
+                delta_depth,
+                stored_size,
+                inflated_size,
+                object_size,
+            }
+        };
+
+        let mut ranked: Vec<usize> = (0..entries.len())
+            .filter(|e| entries[*e].4 >= MIN_RANKED_SIZE)
+            .collect();
+        let ratio = |e: &usize| entries[*e].3 as f64 / entries[*e].4 as f64;
+        ranked.sort_unstable_by(|a, b| ratio(b).total_cmp(&ratio(a)));
+        stats.worst_compressed = ranked.into_iter().take(top).map(&mut entry).collect();
+
+        let mut deepest: Vec<usize> = (0..entries.len()).filter(|e| entries[*e].2 > 0).collect();
+        deepest.sort_unstable_by_key(|e| Reverse(entries[*e].2));
+        stats.deepest_chains = deepest.into_iter().take(top).map(&mut entry).collect();
+
+        unreadable.sort_unstable();
+        unreadable.dedup();
+        for i in unreadable {
+            let reason = "delta instructions do not inflate".to_owned();
+            stats.corrupt.push(corrupt(i, reason), DEFAULT_MAX_ERRORS);
+        }
+
+        all_stats.push(stats);
+    });
+
+    if cancel.is_cancelled() {
+        return Err(GitRwError::Cancelled(String::from("Analysis cancelled")));
+    }
+
+    Ok(all_stats)
+}
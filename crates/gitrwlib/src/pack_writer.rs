@@ -0,0 +1,224 @@
+use std::{
+    hash::Hasher,
+    io::{self, Write},
+    ops::Deref,
+};
+
+use flate2::{write::ZlibEncoder, Compression};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use rs_sha1::{HasherContext, Sha1Hasher};
+use rustc_hash::FxHashSet;
+
+use crate::{
+    error::GitRwError,
+    objs::{GitObject, TreeHash},
+    refs,
+    shared::ObjectHash,
+    Repository,
+};
+
+/// Objects are read and compressed in parallel, but written in order in batches of this size.
+const BATCH_SIZE: usize = 1024;
+
+/// Mode of submodule entries, their commits belong to another repository.
+const GITLINK_MODE: &[u8] = b"160000";
+
+/// Passes everything through to `inner` and hashes it for the trailer of the pack.
+struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha1Hasher,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.write(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes an undeltified version 2 pack of the objects selected by `revs` to `writer`, see
+/// `Repository::write_pack`. Returns the number of objects written.
+pub(crate) fn write_pack<M, W: Write>(
+    repository: &Repository<M>,
+    revs: &[&str],
+    writer: W,
+) -> Result<usize, GitRwError> {
+    let objects = collect_objects(repository, revs)?;
+
+    let mut writer = HashingWriter {
+        inner: writer,
+        hasher: Sha1Hasher::default(),
+    };
+    let count = u32::try_from(objects.len())
+        .map_err(|_| GitRwError::invalid("A pack can contain at most 2^32 - 1 objects"))?;
+    writer.write_all(b"PACK").map_err(GitRwError::Output)?;
+    writer
+        .write_all(&2u32.to_be_bytes())
+        .map_err(GitRwError::Output)?;
+    writer
+        .write_all(&count.to_be_bytes())
+        .map_err(GitRwError::Output)?;
+
+    for batch in objects.chunks(BATCH_SIZE) {
+        if repository.is_cancelled() {
+            return Err(GitRwError::Cancelled(String::from(
+                "Writing the pack cancelled",
+            )));
+        }
+
+        let entries: Vec<Result<Vec<u8>, GitRwError>> = batch
+            .par_iter()
+            .map_with(repository.clone(), encode_entry)
+            .collect();
+        for entry in entries {
+            writer.write_all(&entry?).map_err(GitRwError::Output)?;
+        }
+    }
+
+    let checksum: [u8; 20] = HasherContext::finish(&mut writer.hasher).into();
+    writer
+        .inner
+        .write_all(&checksum)
+        .and_then(|_| writer.inner.flush())
+        .map_err(GitRwError::Output)?;
+
+    Ok(objects.len())
+}
+
+/// Every object reachable from the included revisions: annotated tags, commits that are not
+/// reachable from an excluded revision, and all trees and blobs of these commits.
+fn collect_objects<M>(
+    repository: &Repository<M>,
+    revs: &[&str],
+) -> Result<Vec<ObjectHash>, GitRwError> {
+    let mut exclude = Vec::new();
+    let mut include = Vec::new();
+    for rev in revs {
+        if let Some(rev) = rev.strip_prefix('^') {
+            exclude.push(repository.resolve_rev(rev)?);
+        } else if let Some((a, b)) = rev.split_once("..") {
+            exclude.push(repository.resolve_rev(a)?);
+            include.push(repository.resolve_rev(b)?);
+        } else {
+            include.push(repository.resolve_rev(rev)?);
+        }
+    }
+
+    if include.is_empty() {
+        include = refs::partition_ref_targets(&repository.path, &[])?.1;
+    }
+
+    let mut reader = repository.clone();
+    let mut objects = Vec::new();
+    let mut seen = FxHashSet::default();
+
+    // tags are peeled by the commit walk, but the tag objects themselves belong to the pack
+    for hash in include.iter() {
+        let mut hash = hash.clone();
+        loop {
+            match reader.read_object(hash.clone()) {
+                Some(GitObject::Tag(tag)) => {
+                    if seen.insert(hash.clone()) {
+                        objects.push(hash);
+                    }
+                    hash = tag.object();
+                }
+                Some(GitObject::Tree(tree)) => {
+                    add_tree(&mut reader, tree.hash().clone(), &mut seen, &mut objects)?;
+                    break;
+                }
+                Some(GitObject::Blob(_)) => {
+                    if seen.insert(hash.clone()) {
+                        objects.push(hash);
+                    }
+                    break;
+                }
+                Some(GitObject::Commit(_)) => break,
+                None => {
+                    return Err(GitRwError::corrupt(
+                        &repository.path,
+                        format!("Object {hash} is missing"),
+                    ))
+                }
+            }
+        }
+    }
+
+    for commit in repository.commits_range(exclude, include) {
+        if repository.is_cancelled() {
+            return Err(GitRwError::Cancelled(String::from(
+                "Writing the pack cancelled",
+            )));
+        }
+
+        seen.insert(commit.hash.0.clone());
+        objects.push(commit.hash.0.clone());
+        add_tree(&mut reader, commit.tree(), &mut seen, &mut objects)?;
+    }
+
+    Ok(objects)
+}
+
+fn add_tree<M>(
+    reader: &mut Repository<M>,
+    tree_hash: TreeHash,
+    seen: &mut FxHashSet<ObjectHash>,
+    objects: &mut Vec<ObjectHash>,
+) -> Result<(), GitRwError> {
+    if !seen.insert(tree_hash.0.clone()) {
+        return Ok(());
+    }
+
+    objects.push(tree_hash.0.clone());
+    let tree = reader.read_tree(tree_hash)?;
+    for line in tree.lines() {
+        if line.is_tree() {
+            add_tree(reader, line.hash.deref().clone(), seen, objects)?;
+        } else if line.mode() != GITLINK_MODE && seen.insert(line.hash.0.clone()) {
+            objects.push(line.hash.0.clone());
+        }
+    }
+
+    Ok(())
+}
+
+/// The header with type and size followed by the compressed content.
+fn encode_entry<M>(reader: &mut Repository<M>, hash: &ObjectHash) -> Result<Vec<u8>, GitRwError> {
+    let object = reader
+        .read_object(hash.clone())
+        .ok_or_else(|| GitRwError::corrupt(&reader.path, format!("Object {hash} is missing")))?;
+
+    let tag_bytes;
+    let (object_type, content): (u8, &[u8]) = match &object {
+        GitObject::Commit(commit) => (1, commit.content()),
+        GitObject::Tree(tree) => (2, tree.content()),
+        GitObject::Blob(blob) => (3, blob.content()),
+        GitObject::Tag(tag) => {
+            tag_bytes = tag.to_bytes();
+            (4, &tag_bytes[..])
+        }
+    };
+
+    // type and the lowest 4 bits of the size, then 7 bits of the size per byte
+    let mut entry = Vec::with_capacity(content.len() / 2 + 16);
+    let mut size = content.len();
+    let mut byte = (object_type << 4) | (size & 0x0f) as u8;
+    size >>= 4;
+    while size != 0 {
+        entry.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    entry.push(byte);
+
+    let mut encoder = ZlibEncoder::new(entry, Compression::default());
+    encoder
+        .write_all(content)
+        .and_then(|_| encoder.finish())
+        .map_err(GitRwError::Output)
+}
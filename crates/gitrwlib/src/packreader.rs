@@ -1,5 +1,4 @@
 use core::panic;
-use std::error::Error;
 
 use std::fs::{self, File};
 use std::hash::BuildHasherDefault;
@@ -7,13 +6,17 @@ use std::path::Path;
 use std::sync::{Arc, RwLock};
 
 use memmap2::Mmap;
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use rustc_hash::FxHashMap;
 
+use crate::calculate_hash;
 use crate::compression::Decompression;
-use crate::idx_reader::get_pack_offsets;
-use crate::objs::{CommitBase, Tag};
+use crate::delta_cache::{DeltaCache, DeltaCacheOptions, ReadCounters, ReadMetrics};
+use crate::error::GitRwError;
+use crate::idx_reader::{get_pack_crcs, get_pack_offsets};
+use crate::objs::{Blob, CommitBase, Tag};
 use crate::objs::{GitObject, Tree};
-use crate::pack_diff::PackDiff;
+use crate::pack_diff::{check_delta, read_base_offset, try_read_base_offset, PackDiff};
 use crate::shared::ObjectHash;
 
 #[derive(Debug)]
@@ -29,8 +32,10 @@ struct PackWithObjects {
 }
 
 #[derive(Clone)]
-pub struct PackReader {
+pub(crate) struct PackReader {
     packs: Vec<PackWithObjects>,
+    delta_cache: Option<Arc<DeltaCache>>,
+    counters: Arc<ReadCounters>,
 }
 
 impl Clone for PackWithObjects {
@@ -47,33 +52,388 @@ impl Clone for PackWithObjects {
 }
 
 impl PackReader {
-    pub fn create(repository_path: &Path) -> Result<PackReader, Box<dyn Error>> {
+    pub fn create(repository_path: &Path) -> Result<PackReader, GitRwError> {
         let mut packs_with_objects = Vec::new();
 
-        for pack in get_packs(repository_path).into_iter() {
-            let pack_file = File::open(pack.pack_file.clone())?;
-            let pack_map = unsafe { Mmap::map(&pack_file)? };
+        for pack in get_packs(repository_path)?.into_iter() {
+            packs_with_objects.push(open_indexed(pack)?);
+        }
 
-            let pack_offsets = get_pack_offsets(Path::new(&pack.idx_file)).unwrap();
-            let offsets = Arc::new(RwLock::new(FxHashMap::with_capacity_and_hasher(
-                pack_offsets.len(),
-                BuildHasherDefault::default(),
-            )));
+        Ok(PackReader {
+            packs: packs_with_objects,
+            delta_cache: None,
+            counters: Arc::default(),
+        })
+    }
 
-            for offset in pack_offsets.into_iter() {
-                offsets.write().unwrap().insert(offset.hash, offset.offset);
-            }
+    /// Reads a single pack file with the objects listed in its idx file.
+    pub(crate) fn from_indexed_pack(
+        pack_path: &Path,
+        idx_path: &Path,
+    ) -> Result<PackReader, GitRwError> {
+        let pack = Pack {
+            idx_file: idx_path.to_string_lossy().into_owned(),
+            pack_file: pack_path.to_string_lossy().into_owned(),
+        };
+
+        Ok(PackReader {
+            packs: vec![open_indexed(pack)?],
+            delta_cache: None,
+            counters: Arc::default(),
+        })
+    }
+
+    /// Reads a single pack or bundle file without an idx file, the objects are indexed in
+    /// memory by inflating all of them. Objects whose delta base is not part of the pack, like
+    /// in thin packs, are left out.
+    pub(crate) fn from_pack_file(path: &Path) -> Result<PackReader, GitRwError> {
+        let file = File::open(path).map_err(GitRwError::io(path))?;
+        let mmap = unsafe { Mmap::map(&file).map_err(GitRwError::io(path))? };
+        let start = pack_start(&mmap)
+            .ok_or_else(|| GitRwError::corrupt(path, "is neither a pack nor a bundle"))?;
+
+        let entries =
+            scan_entries(&mmap, start).map_err(|reason| GitRwError::corrupt(path, reason))?;
+        let mut pack_reader = PackReader {
+            packs: vec![PackWithObjects {
+                pack: mmap,
+                objects: Arc::default(),
+                pack_file: path.to_string_lossy().into_owned(),
+            }],
+            delta_cache: None,
+            counters: Arc::default(),
+        };
+
+        // all bases of OFS_DELTA chains are in the pack, REF_DELTA bases might follow later or
+        // depend on other REF_DELTAs and are resolved afterwards
+        let mmap = &pack_reader.packs[0].pack;
+        let (mut pending, resolvable): (Vec<usize>, Vec<usize>) =
+            entries.into_iter().partition(|offset| {
+                let chain = ofs_delta_chain(mmap, *offset);
+                let base = chain.last().map_or(*offset, |last| {
+                    let pack_object = PackObject::create(mmap, *last);
+                    pack_object.offset - read_base_offset(mmap, &pack_object).0
+                });
+                PackObject::create(mmap, base).object_type == 7
+            });
 
-            packs_with_objects.push(PackWithObjects {
-                pack: pack_map,
-                objects: offsets,
-                pack_file: pack.pack_file,
+        let hashes: Vec<(ObjectHash, usize)> = resolvable
+            .par_iter()
+            .map_init(Decompression::default, |decompression, offset| {
+                let (bytes, pack_object, _) = pack_reader.read_at(decompression, 0, *offset);
+                let name = object_type_name(pack_object.object_type);
+                (calculate_hash(&bytes, name.as_bytes()), *offset)
+            })
+            .collect();
+        pack_reader.packs[0].objects = Arc::new(RwLock::new(hashes.into_iter().collect()));
+
+        let mut decompression = Decompression::default();
+        loop {
+            let before = pending.len();
+            pending.retain(|offset| {
+                let pack_object = PackObject::create(&pack_reader.packs[0].pack, *offset);
+                let slice_start = pack_object.offset + pack_object.header_len;
+                let base: ObjectHash = pack_reader.packs[0].pack[slice_start..slice_start + 20]
+                    .try_into()
+                    .unwrap();
+                if pack_object.object_type != 7 || get_offset(&pack_reader, &base).is_none() {
+                    return true;
+                }
+
+                let (bytes, base_object, _) = pack_reader.read_at(&mut decompression, 0, *offset);
+                let name = object_type_name(base_object.object_type);
+                let hash = calculate_hash(&bytes, name.as_bytes());
+                pack_reader.packs[0]
+                    .objects
+                    .write()
+                    .unwrap()
+                    .insert(hash, *offset);
+                false
             });
+
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
         }
 
-        Ok(PackReader {
-            packs: packs_with_objects,
-        })
+        Ok(pack_reader)
+    }
+
+    /// Enables the cache for objects in the middle of long delta chains, or disables it with
+    /// `None`. The cache is shared with all clones created afterwards.
+    pub(crate) fn set_delta_cache(&mut self, options: Option<DeltaCacheOptions>) {
+        self.delta_cache = options.map(|options| Arc::new(DeltaCache::create(options)));
+    }
+
+    pub(crate) fn read_metrics(&self) -> ReadMetrics {
+        self.counters.snapshot()
+    }
+
+    /// Looks the object up in the idx tables of all packs.
+    pub fn contains(&self, object_hash: &ObjectHash) -> bool {
+        get_offset(self, object_hash).is_some()
+    }
+
+    /// Number of objects in all packs, objects stored in several packs count more than once.
+    pub(crate) fn object_count(&self) -> usize {
+        self.packs
+            .iter()
+            .map(|pack| pack.objects.read().unwrap().len())
+            .sum()
+    }
+
+    /// Offset of the entry of the object in the first pack containing it.
+    pub(crate) fn offset(&self, object_hash: &ObjectHash) -> Option<usize> {
+        get_offset(self, object_hash).map(|(_, _, offset)| offset)
+    }
+
+    /// Every packed object whose hash starts with the hex `prefix`, without duplicates.
+    pub(crate) fn find_prefix(&self, prefix: &str) -> Vec<ObjectHash> {
+        let mut found: Vec<ObjectHash> = self
+            .packs
+            .iter()
+            .flat_map(|pack| {
+                let objects = pack.objects.read().unwrap();
+                objects
+                    .keys()
+                    .filter(|hash| hash.has_hex_prefix(prefix))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    /// The pack file and offset of every copy of the object, in the order the packs are
+    /// searched when reading it.
+    pub(crate) fn locations(&self, object_hash: &ObjectHash) -> Vec<(String, usize)> {
+        self.packs
+            .iter()
+            .filter_map(|pack| {
+                let offset = *pack.objects.read().unwrap().get(object_hash)?;
+                Some((pack.pack_file.clone(), offset))
+            })
+            .collect()
+    }
+
+    /// Number of packs and their total size in bytes, including the idx files.
+    pub(crate) fn pack_sizes(&self) -> (usize, u64) {
+        let size = self
+            .packs
+            .iter()
+            .map(|pack| {
+                let idx_file = Path::new(&pack.pack_file).with_extension("idx");
+                let idx_size = fs::metadata(idx_file).map_or(0, |m| m.len());
+                pack.pack.len() as u64 + idx_size
+            })
+            .sum();
+        (self.packs.len(), size)
+    }
+
+    /// Counts the packed objects by their type: commits, trees, blobs and tags. Deltified
+    /// objects are counted as the type of their base, only the pack headers are read.
+    pub(crate) fn count_object_types(&self) -> [usize; 4] {
+        let mut counts = [0usize; 4];
+        for pack in self.packs.iter() {
+            for offset in pack.objects.read().unwrap().values() {
+                let (object_type, _) = self.delta_chain(&pack.pack, *offset);
+                counts[object_type as usize - 1] += 1;
+            }
+        }
+
+        counts
+    }
+
+    /// Pack id and offset of every commit, deltified ones included.
+    pub(crate) fn commit_offsets(&self) -> Vec<(usize, usize)> {
+        let mut commits = Vec::new();
+        self.for_each_pack(|pack_id, _, mmap, offsets| {
+            commits.extend(
+                offsets
+                    .into_iter()
+                    .filter(|(_, offset)| self.delta_chain(mmap, *offset).0 == 1)
+                    .map(|(_, offset)| (pack_id, offset)),
+            );
+        });
+
+        commits
+    }
+
+    /// Calls `f` for every pack with its id for `read_at_offset`, its file name, its content and
+    /// the offsets of all objects in it, sorted by offset.
+    pub(crate) fn for_each_pack(
+        &self,
+        mut f: impl FnMut(usize, &str, &Mmap, Vec<(ObjectHash, usize)>),
+    ) {
+        for (pack_id, pack) in self.packs.iter().enumerate() {
+            let mut offsets: Vec<(ObjectHash, usize)> = pack
+                .objects
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(hash, offset)| (hash.clone(), *offset))
+                .collect();
+            offsets.sort_unstable_by_key(|(_, offset)| *offset);
+            f(pack_id, &pack.pack_file, &pack.pack, offsets);
+        }
+    }
+
+    /// The CRC32 checksums of the raw entries of a pack, as stored in its idx file.
+    pub(crate) fn pack_crcs(
+        &self,
+        pack_id: usize,
+    ) -> Result<FxHashMap<ObjectHash, u32>, GitRwError> {
+        let idx_file = Path::new(&self.packs[pack_id].pack_file).with_extension("idx");
+        Ok(get_pack_crcs(&idx_file)?.into_iter().collect())
+    }
+
+    /// Follows the delta chain of the object at `offset` without decompressing anything.
+    /// Returns the type of the base object and the number of deltas on the way to it.
+    pub(crate) fn delta_chain(&self, mmap: &Mmap, offset: usize) -> (u8, usize) {
+        self.try_delta_chain(mmap, offset)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Like `delta_chain`, but fails for damaged headers, missing bases and chains that loop.
+    pub(crate) fn try_delta_chain(
+        &self,
+        mmap: &Mmap,
+        offset: usize,
+    ) -> Result<(u8, usize), String> {
+        let (mut mmap, mut base_offset) = (mmap, offset);
+        for depth in 0..=MAX_DELTA_DEPTH {
+            let pack_object = PackObject::try_create(mmap, base_offset)
+                .ok_or_else(|| on_chain(offset, base_offset)("header is truncated".to_owned()))?;
+            match pack_object.object_type {
+                1..=4 => return Ok((pack_object.object_type, depth)),
+                _ => {
+                    (mmap, base_offset, _) = self
+                        .checked_delta_base(mmap, &pack_object)
+                        .map_err(on_chain(offset, base_offset))?
+                }
+            }
+        }
+
+        Err(format!(
+            "delta chain is longer than {MAX_DELTA_DEPTH}, it probably loops"
+        ))
+    }
+
+    /// Where the base of the delta `pack_object` is stored and where its delta data starts,
+    /// relative to the end of its header.
+    fn checked_delta_base<'a>(
+        &'a self,
+        mmap: &'a Mmap,
+        pack_object: &PackObject,
+    ) -> Result<(&'a Mmap, usize, usize), String> {
+        let offset = pack_object.offset;
+        match pack_object.object_type {
+            6 => {
+                let (negative_offset, bytes_read) = try_read_base_offset(mmap, pack_object)
+                    .filter(|(negative_offset, _)| (1..=offset).contains(negative_offset))
+                    .ok_or_else(|| "base offset points outside of the pack".to_owned())?;
+                Ok((mmap, offset - negative_offset, bytes_read))
+            }
+            7 => {
+                let slice_start = offset + pack_object.header_len;
+                let base_object_hash: ObjectHash = mmap
+                    .get(slice_start..slice_start + 20)
+                    .ok_or_else(|| "base hash is truncated".to_owned())?
+                    .try_into()
+                    .unwrap();
+                let (_, base_mmap, base_offset) = get_offset(self, &base_object_hash)
+                    .ok_or_else(|| format!("delta base {base_object_hash} is missing"))?;
+                Ok((base_mmap, base_offset, 20))
+            }
+            object_type => Err(format!("unknown object type {object_type}")),
+        }
+    }
+
+    /// Type and size of a packed object. Deltas are not applied, their type comes from the base
+    /// at the end of the chain and their size from the start of the delta instructions.
+    pub(crate) fn object_header(
+        &self,
+        decompression: &mut Decompression,
+        object_hash: &ObjectHash,
+    ) -> Option<(u8, usize)> {
+        let (_, mmap, offset) = get_offset(self, object_hash)?;
+        self.object_header_at(decompression, mmap, offset)
+    }
+
+    /// Like `object_header`, `None` if the entry at `offset` is damaged.
+    pub(crate) fn object_header_at(
+        &self,
+        decompression: &mut Decompression,
+        mmap: &Mmap,
+        offset: usize,
+    ) -> Option<(u8, usize)> {
+        let pack_object = PackObject::try_create(mmap, offset)?;
+        let (object_type, _) = self.try_delta_chain(mmap, offset).ok()?;
+        let size = match pack_object.object_type {
+            6 | 7 => PackDiff::target_len(decompression, mmap, &pack_object)?,
+            _ => pack_object.data_size,
+        };
+
+        Some((object_type, size))
+    }
+
+    /// Like `read_at_offset`, but every header, compressed stream and delta of the chain gets
+    /// checked instead of trusted. Slower, meant for damaged packs: fails with a description of
+    /// the first problem instead of panicking. Returns the content and the type of the object.
+    pub(crate) fn read_checked(
+        &self,
+        decompression: &mut Decompression,
+        mmap: &Mmap,
+        offset: usize,
+    ) -> Result<(Arc<[u8]>, u8), String> {
+        let at = |base_offset| on_chain(offset, base_offset);
+        let mut deltas = Vec::new();
+        let (mut mmap, mut base_offset) = (mmap, offset);
+        let (base, object_type) = loop {
+            if deltas.len() > MAX_DELTA_DEPTH {
+                return Err(format!(
+                    "delta chain is longer than {MAX_DELTA_DEPTH}, it probably loops"
+                ));
+            }
+
+            let pack_object = PackObject::try_create(mmap, base_offset)
+                .ok_or_else(|| at(base_offset)("header is truncated".to_owned()))?;
+            if let 1..=4 = pack_object.object_type {
+                let bytes = decompression
+                    .try_unpack(mmap, &pack_object, 0)
+                    .map_err(at(base_offset))?;
+                break (bytes, pack_object.object_type);
+            }
+
+            let (next_mmap, next_offset, data_offset) = self
+                .checked_delta_base(mmap, &pack_object)
+                .map_err(at(base_offset))?;
+            let delta = decompression
+                .try_unpack(mmap, &pack_object, data_offset)
+                .map_err(at(base_offset))?;
+            deltas.push((base_offset, delta));
+            (mmap, base_offset) = (next_mmap, next_offset);
+        };
+
+        // the lengths of all deltas have to fit together before they can be combined
+        let mut len = base.len();
+        for (delta_offset, delta) in deltas.iter().rev() {
+            len = check_delta(delta, len).map_err(at(*delta_offset))?;
+        }
+
+        let mut deltas = deltas
+            .iter()
+            .map(|(_, delta)| PackDiff::from_delta(delta, 0));
+        let Some(mut pack_diff) = deltas.next() else {
+            return Ok((base, object_type));
+        };
+        for target_diff in deltas {
+            pack_diff = pack_diff.combine(&target_diff);
+        }
+
+        Ok((pack_diff.apply(&base), object_type))
     }
 
     pub fn read_git_object(
@@ -81,88 +441,275 @@ impl PackReader {
         decompression: &mut Decompression,
         object_hash: ObjectHash,
     ) -> Option<GitObject> {
-        if let Some(r) = self.read_git_object_bytes(decompression, &object_hash) {
-            let git_object = match r.1.object_type {
-                1u8 => GitObject::Commit(CommitBase::create(object_hash.into(), r.0, false)),
-                2u8 => GitObject::Tree(Tree::create(object_hash.into(), r.0, false)),
-                // 3u8 => GitObject::Blob(Blob::create(object_hash, bytes)),
-                4u8 => GitObject::Tag(Tag::create(object_hash.into(), r.0, false)),
-                _ => panic!("unknown git object type"),
-            };
-
-            Some(git_object)
-        } else {
-            None
-        }
+        let (bytes, pack_object) = self.read_git_object_bytes(decompression, &object_hash)?;
+        Some(to_git_object(object_hash, pack_object.object_type, bytes))
+    }
+
+    /// Like `read_at_offset`, the hash of the object is calculated from its content.
+    pub fn read_git_object_at_offset(
+        &self,
+        decompression: &mut Decompression,
+        pack_id: usize,
+        offset: usize,
+    ) -> Option<GitObject> {
+        let (bytes, pack_object) = self.read_at_offset(decompression, pack_id, offset)?;
+        let object_hash =
+            calculate_hash(&bytes, object_type_name(pack_object.object_type).as_bytes());
+        Some(to_git_object(object_hash, pack_object.object_type, bytes))
     }
 
     pub fn read_git_object_bytes(
         &self,
         decompression: &mut Decompression,
         object_hash: &ObjectHash,
-    ) -> Option<(Box<[u8]>, PackObject)> {
-        if let Some((mmap, offset)) = get_offset(self, object_hash) {
-            let bytes: Box<[u8]>;
-
-            let mut pack_object = PackObject::create(mmap, offset);
-            if pack_object.object_type == 6 {
-                // diff
-                (bytes, pack_object) = restore_diff_object_bytes(decompression, mmap, pack_object);
-            } else if pack_object.object_type == 7 {
-                // OBJ_REF_DELTA: 20 bytes for the base object hash, then the instructions
-                let slice_start = pack_object.offset + pack_object.header_len;
-                let base_object_hash: ObjectHash =
-                    mmap[slice_start..slice_start + 20].try_into().unwrap();
+    ) -> Option<(Arc<[u8]>, PackObject)> {
+        let (bytes, pack_object, delta_depth) =
+            self.read_object_bytes_with_depth(decompression, object_hash)?;
+        self.counters.record_read(object_hash, delta_depth);
+        Some((bytes, pack_object))
+    }
 
-                let base = self
-                    .read_git_object_bytes(decompression, &base_object_hash)
-                    .unwrap();
+    /// Reads the object starting at `offset` in the pack with index `pack_id`, as passed to
+    /// `for_each_pack`, without looking up any hash. Returns `None` for unknown packs and offsets
+    /// outside of the pack. The offset has to point to the start of an entry.
+    pub fn read_at_offset(
+        &self,
+        decompression: &mut Decompression,
+        pack_id: usize,
+        offset: usize,
+    ) -> Option<(Arc<[u8]>, PackObject)> {
+        let pack = self.packs.get(pack_id)?;
+        // the pack ends with the checksum of its content
+        if offset < 12 || offset >= pack.pack.len().saturating_sub(20) {
+            return None;
+        }
+
+        let (bytes, pack_object, _) = self.read_at(decompression, pack_id, offset);
+        Some((bytes, pack_object))
+    }
+
+    fn read_object_bytes_with_depth(
+        &self,
+        decompression: &mut Decompression,
+        object_hash: &ObjectHash,
+    ) -> Option<(Arc<[u8]>, PackObject, usize)> {
+        let (pack_id, _, offset) = get_offset(self, object_hash)?;
+        Some(self.read_at(decompression, pack_id, offset))
+    }
+
+    fn read_at(
+        &self,
+        decompression: &mut Decompression,
+        pack_id: usize,
+        offset: usize,
+    ) -> (Arc<[u8]>, PackObject, usize) {
+        let mmap = &self.packs[pack_id].pack;
+        let pack_object = PackObject::create(mmap, offset);
+        if pack_object.object_type == 6 {
+            // diff
+            self.restore_diff_object_bytes(decompression, pack_id, mmap, pack_object)
+        } else if pack_object.object_type == 7 {
+            // OBJ_REF_DELTA: 20 bytes for the base object hash, then the instructions
+            let slice_start = pack_object.offset + pack_object.header_len;
+            let base_object_hash: ObjectHash =
+                mmap[slice_start..slice_start + 20].try_into().unwrap();
+
+            let (base_bytes, base_object, base_depth) = self
+                .read_object_bytes_with_depth(decompression, &base_object_hash)
+                .unwrap();
+
+            let pack_diff = PackDiff::create_for_ref(decompression, mmap, &pack_object);
+            (pack_diff.apply(&base_bytes), base_object, base_depth + 1)
+        } else {
+            // plain object, should be easy to extract
+            let bytes = decompression.unpack(mmap, &pack_object, 0);
+            (bytes, pack_object, 0)
+        }
+    }
 
-                let pack_diff = PackDiff::create_for_ref(decompression, mmap, &pack_object);
-                bytes = pack_diff.apply(&base.0);
-                pack_object = base.1;
-            } else {
-                // plain object, should be easy to extract
-                bytes = decompression.unpack(mmap, &pack_object, 0);
+    /// Applies the OFS_DELTA chain starting at `pack_object`. Returns the content, the base
+    /// object of the chain and the length of the chain. With the delta cache enabled, long
+    /// chains only get applied down to a cached object in their middle.
+    fn restore_diff_object_bytes(
+        &self,
+        compression: &mut Decompression,
+        pack_index: usize,
+        mmap: &Mmap,
+        mut pack_object: PackObject,
+    ) -> (Arc<[u8]>, PackObject, usize) {
+        let chain = ofs_delta_chain(mmap, pack_object.offset);
+        let mut cached = None;
+        if let Some(delta_cache) = &self.delta_cache {
+            cached = chain[1..]
+                .iter()
+                .find_map(|offset| delta_cache.get(pack_index, *offset).map(|c| (*offset, c)));
+
+            if cached.is_some() {
+                self.counters.record_cache_hit();
+            } else if chain.len() >= delta_cache.options.min_depth {
+                let middle = chain[chain.len() / 2];
+                let (bytes, base_object, _) = self.restore_diff_object_bytes(
+                    compression,
+                    pack_index,
+                    mmap,
+                    PackObject::create(mmap, middle),
+                );
+                delta_cache.insert(pack_index, middle, bytes.clone(), base_object.offset);
+                cached = Some((middle, (bytes, base_object.offset)));
             }
+        }
 
-            return Some((bytes, pack_object));
+        let mut pack_diff = PackDiff::create(compression, mmap, &pack_object);
+        pack_object = PackObject::create(mmap, pack_object.offset - pack_diff.negative_offset);
+
+        while pack_object.object_type == 6 {
+            if let Some((offset, (bytes, base_offset))) = &cached {
+                if pack_object.offset == *offset {
+                    let base_object = PackObject::create(mmap, *base_offset);
+                    return (pack_diff.apply(bytes), base_object, chain.len());
+                }
+            }
+
+            // OFS_DELTA
+            let target_diff = PackDiff::create(compression, mmap, &pack_object);
+            pack_diff = pack_diff.combine(&target_diff);
+            pack_object = PackObject::create(mmap, pack_object.offset - pack_diff.negative_offset);
         }
 
-        None
+        let content = compression.unpack(mmap, &pack_object, 0);
+        (pack_diff.apply(&content), pack_object, chain.len())
+    }
+}
+
+/// Names the delta base at `base_offset` in problems found while reading the entry at `offset`.
+fn on_chain(offset: usize, base_offset: usize) -> impl Fn(String) -> String {
+    move |e| match base_offset == offset {
+        true => e,
+        false => format!("delta base at offset {base_offset}: {e}"),
     }
 }
 
-fn restore_diff_object_bytes(
-    compression: &mut Decompression,
-    mmap: &Mmap,
-    mut pack_object: PackObject,
-) -> (Box<[u8]>, PackObject) {
-    let mut pack_diff = PackDiff::create(compression, mmap, &pack_object);
-    pack_object = PackObject::create(mmap, pack_object.offset - pack_diff.negative_offset);
+fn open_indexed(pack: Pack) -> Result<PackWithObjects, GitRwError> {
+    let pack_path = Path::new(&pack.pack_file);
+    let pack_file = File::open(pack_path).map_err(GitRwError::io(pack_path))?;
+    let pack_map = unsafe { Mmap::map(&pack_file).map_err(GitRwError::io(pack_path))? };
+
+    let idx_path = Path::new(&pack.idx_file);
+    let pack_offsets =
+        get_pack_offsets(idx_path).map_err(|e| GitRwError::corrupt(idx_path, e.to_string()))?;
+    let offsets = Arc::new(RwLock::new(FxHashMap::with_capacity_and_hasher(
+        pack_offsets.len(),
+        BuildHasherDefault::default(),
+    )));
+
+    for offset in pack_offsets.into_iter() {
+        offsets.write().unwrap().insert(offset.hash, offset.offset);
+    }
+
+    Ok(PackWithObjects {
+        pack: pack_map,
+        objects: offsets,
+        pack_file: pack.pack_file,
+    })
+}
+
+/// Offset of the pack inside of a pack or bundle file. Bundles start with a header listing
+/// their refs that ends with an empty line.
+fn pack_start(mmap: &Mmap) -> Option<usize> {
+    let start = if mmap.starts_with(b"PACK") {
+        0
+    } else if mmap.starts_with(b"# v2 git bundle\n") || mmap.starts_with(b"# v3 git bundle\n") {
+        memchr::memmem::find(mmap, b"\n\n")? + 2
+    } else {
+        return None;
+    };
+
+    // version 2 and 3 only differ in how the entry sizes may be encoded
+    let header = mmap.get(start..start + 12)?;
+    (header.starts_with(b"PACK") && matches!(header[7], 2 | 3)).then_some(start)
+}
+
+/// Offsets of all entries of the pack starting at `start`, found by inflating every entry to
+/// learn where the next one begins.
+fn scan_entries(mmap: &Mmap, start: usize) -> Result<Vec<usize>, String> {
+    let count = mmap[start + 8..start + 12]
+        .try_into()
+        .map(u32::from_be_bytes)
+        .map_err(|_| "Pack header is truncated")? as usize;
+    let mut decompression = Decompression::default();
+    let mut offsets = Vec::with_capacity(count);
+    let mut offset = start + 12;
+    for _ in 0..count {
+        if offset + 20 >= mmap.len() {
+            return Err(format!(
+                "Pack ends before entry {} of {count}",
+                offsets.len()
+            ));
+        }
+
+        let pack_object = PackObject::create(mmap, offset);
+        let data_start = match pack_object.object_type {
+            1..=4 => offset + pack_object.header_len,
+            6 => offset + pack_object.header_len + read_base_offset(mmap, &pack_object).1,
+            7 => offset + pack_object.header_len + 20,
+            object_type => {
+                return Err(format!(
+                    "Unknown object type {object_type} at offset {offset}"
+                ))
+            }
+        };
 
+        offsets.push(offset);
+        offset = data_start + decompression.compressed_len(mmap, data_start)?;
+    }
+
+    Ok(offsets)
+}
+
+/// Offsets of all OFS_DELTA entries of the chain starting at `offset`, read from the headers only.
+fn ofs_delta_chain(mmap: &Mmap, offset: usize) -> Vec<usize> {
+    let mut chain = Vec::new();
+    let mut pack_object = PackObject::create(mmap, offset);
     while pack_object.object_type == 6 {
-        // OFS_DELTA
-        let target_diff = PackDiff::create(compression, mmap, &pack_object);
-        pack_diff = pack_diff.combine(&target_diff);
-        pack_object = PackObject::create(mmap, pack_object.offset - pack_diff.negative_offset);
+        chain.push(pack_object.offset);
+        let (negative_offset, _) = read_base_offset(mmap, &pack_object);
+        pack_object = PackObject::create(mmap, pack_object.offset - negative_offset);
+    }
+
+    chain
+}
+
+fn to_git_object(object_hash: ObjectHash, object_type: u8, bytes: Arc<[u8]>) -> GitObject {
+    match object_type {
+        1u8 => GitObject::Commit(CommitBase::create(object_hash.into(), bytes, false)),
+        2u8 => GitObject::Tree(Tree::create(object_hash.into(), bytes, false)),
+        3u8 => GitObject::Blob(Blob::create(object_hash, bytes, false)),
+        4u8 => GitObject::Tag(Tag::create(object_hash.into(), bytes, false)),
+        _ => panic!("unknown git object type"),
     }
+}
 
-    let content = compression.unpack(mmap, &pack_object, 0);
-    (pack_diff.apply(&content), pack_object)
+pub(crate) fn object_type_name(object_type: u8) -> &'static str {
+    match object_type {
+        1 => "commit",
+        2 => "tree",
+        3 => "blob",
+        4 => "tag",
+        _ => panic!("unknown git object type"),
+    }
 }
 
 fn get_offset<'a>(
     pack_reader: &'a PackReader,
     object_hash: &ObjectHash,
-) -> Option<(&'a Mmap, usize)> {
-    for pack in pack_reader.packs.iter() {
+) -> Option<(usize, &'a Mmap, usize)> {
+    for (pack_index, pack) in pack_reader.packs.iter().enumerate() {
         if let Some(result) = pack
             .objects
             .read()
             .unwrap()
             .get(object_hash)
-            .map(|x| (&pack.pack, *x))
+            .map(|x| (pack_index, &pack.pack, *x))
         {
             return Some(result);
         }
@@ -173,8 +720,12 @@ fn get_offset<'a>(
 
 const TYPE_MASK: u8 = 0b01110000;
 
+/// Longer delta chains are taken for loops when reading damaged packs, git itself stops at
+/// 4095 when packing.
+const MAX_DELTA_DEPTH: usize = 10_000;
+
 #[derive(Debug)]
-pub struct PackObject {
+pub(crate) struct PackObject {
     pub object_type: u8,
     pub offset: usize,
     pub header_len: usize,
@@ -183,42 +734,51 @@ pub struct PackObject {
 
 impl PackObject {
     pub fn create(mmap: &Mmap, offset: usize) -> PackObject {
-        let mut read_byte = mmap.get(offset).unwrap();
+        PackObject::try_create(mmap, offset).unwrap()
+    }
+
+    /// Like `create`, `None` if the header is truncated or its size too large.
+    pub fn try_create(mmap: &Mmap, offset: usize) -> Option<PackObject> {
+        let mut read_byte = mmap.get(offset)?;
         let mut bytes_read = 1;
         let mut fsb_set = (read_byte & 0b10000000) != 0;
         let object_type = (read_byte & TYPE_MASK) >> 4;
         let mut data_size: usize = (read_byte & 0b00001111) as usize;
         let mut shift = 4;
         while fsb_set {
-            read_byte = mmap.get(offset + bytes_read).unwrap();
+            read_byte = mmap.get(offset + bytes_read)?;
             bytes_read += 1;
             fsb_set = (read_byte & 0b10000000) != 0;
-            data_size |= ((read_byte & 0x7F) as usize) << shift;
+            data_size |= ((read_byte & 0x7F) as usize).checked_shl(shift)?;
             shift += 7;
         }
 
-        PackObject {
+        Some(PackObject {
             object_type,
             offset,
             header_len: bytes_read,
             data_size,
-        }
+        })
     }
 }
 
-fn get_packs(repository_path: &Path) -> Vec<Pack> {
+fn get_packs(repository_path: &Path) -> Result<Vec<Pack>, GitRwError> {
     let mut packs = Vec::new();
 
     let pack_dir = repository_path.join("objects/pack");
 
-    for file in fs::read_dir(pack_dir)
-        .unwrap()
-        .map(|x| x.unwrap())
-        .filter(|x| !x.file_type().unwrap().is_dir())
-    {
-        let path_buf = file.path();
-        let path = path_buf.to_str().unwrap();
-        if path.ends_with(".idx") {
+    // repositories that were only ever written to by imports or `git commit` without a gc
+    let files = match fs::read_dir(&pack_dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(packs),
+        files => files.map_err(GitRwError::io(&pack_dir))?,
+    };
+
+    for file in files {
+        let path_buf = file.map_err(GitRwError::io(&pack_dir))?.path();
+        let Some(path) = path_buf.to_str() else {
+            continue;
+        };
+        if path.ends_with(".idx") && path_buf.is_file() {
             let mut pack_path = String::from(path.split_at(path.len() - 4).0);
             pack_path.push_str(".pack");
 
@@ -229,5 +789,5 @@ fn get_packs(repository_path: &Path) -> Vec<Pack> {
         }
     }
 
-    packs
+    Ok(packs)
 }
@@ -0,0 +1,345 @@
+use std::cell::{Cell, RefCell};
+
+use regex::bytes::{Regex, RegexSet, RegexSetBuilder};
+
+use crate::GitRwError;
+
+/// A tree entry offered to a `PathMatcher`. Its path is the directory followed by the name, with
+/// a trailing '/' for directories, e.g. `/src/main.rs` or `/src/bin/`.
+pub struct PathEntry<'a> {
+    directory: &'a [u8],
+    name: &'a [u8],
+    is_tree: bool,
+    size: RefCell<Option<&'a mut dyn FnMut() -> usize>>,
+    cached_size: Cell<Option<usize>>,
+}
+
+impl<'a> PathEntry<'a> {
+    /// `directory` starts and ends with '/', e.g. `/` or `/src/`.
+    pub fn file(directory: &'a [u8], name: &'a [u8]) -> Self {
+        Self::create(directory, name, false)
+    }
+
+    pub fn directory(directory: &'a [u8], name: &'a [u8]) -> Self {
+        Self::create(directory, name, true)
+    }
+
+    fn create(directory: &'a [u8], name: &'a [u8], is_tree: bool) -> Self {
+        PathEntry {
+            directory,
+            name,
+            is_tree,
+            size: RefCell::new(None),
+            cached_size: Cell::new(None),
+        }
+    }
+
+    /// Looks up the size of a file with `size` the first time a matcher asks for it.
+    pub fn with_size(self, size: &'a mut dyn FnMut() -> usize) -> Self {
+        *self.size.borrow_mut() = Some(size);
+        self
+    }
+
+    pub fn is_tree(&self) -> bool {
+        self.is_tree
+    }
+
+    pub fn name(&self) -> &[u8] {
+        self.name
+    }
+
+    /// Size of the file, `None` for directories and entries without size lookup.
+    pub fn size(&self) -> Option<usize> {
+        if let Some(size) = self.cached_size.get() {
+            return Some(size);
+        }
+
+        let size = self.size.borrow_mut().as_mut().map(|size| size())?;
+        self.cached_size.set(Some(size));
+        Some(size)
+    }
+
+    pub fn path(&self) -> Vec<u8> {
+        self.parts().concat()
+    }
+
+    fn parts(&self) -> [&'a [u8]; 3] {
+        let slash: &'static [u8] = if self.is_tree { b"/" } else { b"" };
+        [self.directory, self.name, slash]
+    }
+
+    fn path_len(&self) -> usize {
+        self.parts().iter().map(|part| part.len()).sum()
+    }
+
+    /// Compares without putting the path together.
+    pub fn starts_with(&self, prefix: &[u8]) -> bool {
+        prefix.len() <= self.path_len()
+            && self
+                .parts()
+                .iter()
+                .flat_map(|part| part.iter())
+                .zip(prefix)
+                .all(|(a, b)| a == b)
+    }
+
+    pub fn ends_with(&self, suffix: &[u8]) -> bool {
+        suffix.len() <= self.path_len()
+            && self
+                .parts()
+                .iter()
+                .rev()
+                .flat_map(|part| part.iter().rev())
+                .zip(suffix.iter().rev())
+                .all(|(a, b)| a == b)
+    }
+
+    pub fn path_eq(&self, path: &[u8]) -> bool {
+        path.len() == self.path_len() && self.starts_with(path)
+    }
+}
+
+/// Decides which tree entries a filter applies to, e.g. which files `remove` deletes. Matchers
+/// can be combined with `AnyOf`, `AllOf` and `Not`.
+pub trait PathMatcher: Send + Sync {
+    fn matches(&self, entry: &PathEntry) -> bool;
+}
+
+/// The whole path, e.g. `/src/main.rs` or `/target/`.
+pub struct Exact(pub Vec<u8>);
+
+impl PathMatcher for Exact {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        entry.path_eq(&self.0)
+    }
+}
+
+pub struct Prefix(pub Vec<u8>);
+
+impl PathMatcher for Prefix {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        entry.starts_with(&self.0)
+    }
+}
+
+pub struct Suffix(pub Vec<u8>);
+
+impl PathMatcher for Suffix {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        entry.ends_with(&self.0)
+    }
+}
+
+/// Matches the name of files, e.g. `Extension(b"pem".to_vec())` matches `/keys/server.pem`.
+pub struct Extension(pub Vec<u8>);
+
+impl PathMatcher for Extension {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        let name = entry.name();
+        !entry.is_tree()
+            && name.len() > self.0.len()
+            && name.ends_with(&self.0)
+            && name[name.len() - self.0.len() - 1] == b'.'
+    }
+}
+
+/// Files bigger than the given number of bytes.
+pub struct BiggerThan(pub usize);
+
+impl PathMatcher for BiggerThan {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        !entry.is_tree() && entry.size().is_some_and(|size| size > self.0)
+    }
+}
+
+/// Any of the regexes matches the whole path, or only the name.
+pub struct PathRegex {
+    regexes: RegexSet,
+    name_only: bool,
+}
+
+impl PathRegex {
+    pub fn path(patterns: &[String]) -> Result<Self, GitRwError> {
+        Ok(PathRegex {
+            regexes: build_regex_set(patterns)?,
+            name_only: false,
+        })
+    }
+
+    pub fn name(patterns: &[String]) -> Result<Self, GitRwError> {
+        Ok(PathRegex {
+            regexes: build_regex_set(patterns)?,
+            name_only: true,
+        })
+    }
+}
+
+fn build_regex_set(patterns: &[String]) -> Result<RegexSet, GitRwError> {
+    // pattern lists generated by secret scanners can contain many thousands of entries, which
+    // exceed the default size limits of the regex crate when compiled into a single set
+    RegexSetBuilder::new(patterns)
+        .size_limit(1 << 30)
+        .dfa_size_limit(1 << 30)
+        .build()
+        .map_err(|e| GitRwError::invalid(e.to_string()))
+}
+
+impl PathMatcher for PathRegex {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        if self.name_only {
+            self.regexes.is_match(entry.name())
+        } else {
+            self.regexes.is_match(&entry.path())
+        }
+    }
+}
+
+/// Shell style pattern for the whole path: `*` matches anything but '/', `**` matches anything
+/// and `?` a single character other than '/'.
+pub struct Glob(Regex);
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self, GitRwError> {
+        let mut regex = String::from("^");
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '*' if chars.peek() == Some(&'*') => {
+                    chars.next();
+                    regex.push_str(".*");
+                }
+                '*' => regex.push_str("[^/]*"),
+                '?' => regex.push_str("[^/]"),
+                c => regex.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        regex.push('$');
+
+        Regex::new(&regex)
+            .map(Glob)
+            .map_err(|e| GitRwError::invalid(e.to_string()))
+    }
+}
+
+impl PathMatcher for Glob {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        self.0.is_match(&entry.path())
+    }
+}
+
+/// Entries below a directory the inner matcher matches, e.g. `InDirectory(Exact("/src/"))`
+/// matches `/src/bin/tool.rs` but not `/src/` itself.
+pub struct InDirectory(pub Box<dyn PathMatcher>);
+
+impl PathMatcher for InDirectory {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        let directory = entry.directory;
+        // every '/' but the first one ends a directory, `/a/b/` contains `/a/` and `/a/b/`
+        (1..directory.len())
+            .filter(|end| directory[*end] == b'/')
+            .any(|end| {
+                let start = directory[..end].iter().rposition(|c| *c == b'/').unwrap() + 1;
+                let parent = PathEntry::directory(&directory[..start], &directory[start..end]);
+                self.0.matches(&parent)
+            })
+    }
+}
+
+pub struct AnyOf(pub Vec<Box<dyn PathMatcher>>);
+
+impl PathMatcher for AnyOf {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        self.0.iter().any(|matcher| matcher.matches(entry))
+    }
+}
+
+pub struct AllOf(pub Vec<Box<dyn PathMatcher>>);
+
+impl PathMatcher for AllOf {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        self.0.iter().all(|matcher| matcher.matches(entry))
+    }
+}
+
+pub struct Not(pub Box<dyn PathMatcher>);
+
+impl PathMatcher for Not {
+    fn matches(&self, entry: &PathEntry) -> bool {
+        !self.0.matches(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AllOf, AnyOf, BiggerThan, Exact, Extension, Glob, InDirectory, Not, PathEntry, PathMatcher,
+        PathRegex, Prefix, Suffix,
+    };
+
+    #[test]
+    fn entry_paths() {
+        let file = PathEntry::file(b"/src/", b"main.rs");
+        assert!(Exact(b"/src/main.rs".to_vec()).matches(&file));
+        assert!(Prefix(b"/src/ma".to_vec()).matches(&file));
+        assert!(Suffix(b"c/main.rs".to_vec()).matches(&file));
+        assert!(!Suffix(b"/main.rs/".to_vec()).matches(&file));
+        assert!(Extension(b"rs".to_vec()).matches(&file));
+        assert!(!Extension(b"s".to_vec()).matches(&file));
+
+        let directory = PathEntry::directory(b"/src/", b"bin");
+        assert!(Exact(b"/src/bin/".to_vec()).matches(&directory));
+        assert!(!Exact(b"/src/bin".to_vec()).matches(&directory));
+        assert!(Suffix(b"/bin/".to_vec()).matches(&directory));
+
+        let below_src = InDirectory(Box::new(Exact(b"/src/".to_vec())));
+        assert!(below_src.matches(&file));
+        assert!(below_src.matches(&PathEntry::file(b"/src/bin/", b"tool.rs")));
+        assert!(!below_src.matches(&PathEntry::directory(b"/", b"src")));
+        assert!(!below_src.matches(&PathEntry::file(b"/lib/src2/", b"a.rs")));
+        let below_bin = InDirectory(Box::new(Suffix(b"/bin/".to_vec())));
+        assert!(below_bin.matches(&PathEntry::file(b"/src/bin/", b"tool.rs")));
+        assert!(!below_bin.matches(&file));
+    }
+
+    #[test]
+    fn globs_and_regexes() {
+        let glob = Glob::new("/src/*.rs").unwrap();
+        assert!(glob.matches(&PathEntry::file(b"/src/", b"main.rs")));
+        assert!(!glob.matches(&PathEntry::file(b"/src/bin/", b"tool.rs")));
+        let glob = Glob::new("**/*.r?").unwrap();
+        assert!(glob.matches(&PathEntry::file(b"/src/bin/", b"tool.rs")));
+
+        let path = PathRegex::path(&["^/src/.*secret".into()]).unwrap();
+        let name = PathRegex::name(&["^secret".into()]).unwrap();
+        let entry = PathEntry::file(b"/src/secret/", b"a.txt");
+        assert!(path.matches(&entry));
+        assert!(!name.matches(&entry));
+    }
+
+    #[test]
+    fn combined_matchers() {
+        let big_json = AllOf(vec![
+            Box::new(Extension(b"json".to_vec())),
+            Box::new(BiggerThan(100)),
+        ]);
+        let mut lookups = 0;
+        let mut size = || {
+            lookups += 1;
+            500
+        };
+        {
+            let entry = PathEntry::file(b"/", b"data.json").with_size(&mut size);
+            assert!(big_json.matches(&entry));
+            assert!(big_json.matches(&entry));
+        }
+        assert_eq!(1, lookups);
+
+        assert!(!big_json.matches(&PathEntry::file(b"/", b"data.json")));
+
+        let either = AnyOf(vec![
+            Box::new(Not(Box::new(big_json))),
+            Box::new(BiggerThan(0)),
+        ]);
+        assert!(either.matches(&PathEntry::file(b"/", b"data.json")));
+    }
+}
@@ -0,0 +1,13 @@
+//! The types needed by most users of the library: `use gitrwlib::prelude::*;`
+
+pub use crate::objs::{
+    Blob, CommitBase, CommitEditable, CommitHash, GitObject, Tag, Tree, TreeHash, TreeLine,
+};
+pub use crate::{
+    CommitAction, CommitFilter, CommitGraph, CommitMapFormat, CommitMapReader, CommitWithTree,
+    CorruptObject, CorruptObjects, DanglingRefs, GitRef, GitRwError, JournalEntry, LooseObject,
+    MessageHashes, ObjectHash, ObjectLocation, ObjectStore, PathEntry, PathMatcher, RangeStats,
+    ReadOnly, ReadWrite, RefInfo, RefOptions, RefRename, RefStatus, RefUpdate, RemovedFiles,
+    Repository, RewriteOptions, SkipReport, SkipTracker, TreeChange, TreeRewriteReport,
+    VerifyOptions, WriteObject, WrittenObject,
+};
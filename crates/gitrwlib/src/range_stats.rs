@@ -0,0 +1,135 @@
+use std::ops::Deref;
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    error::GitRwError,
+    objs::{CommitBase, CommitHash, GitObject, TreeHash},
+    shared::ObjectHash,
+    Repository,
+};
+
+const GITLINK_MODE: &[u8] = b"160000";
+
+/// The objects a range of commits introduced. Trees and blobs are found by comparing the tree
+/// of every commit with the trees of its parents: an object counts if no parent has it at the
+/// same path, and only the first time it appears in the range.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeStats {
+    pub commits: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    /// Uncompressed size of the new blobs in bytes
+    pub blob_size: u64,
+}
+
+pub(crate) fn range_stats<M>(
+    repository: &mut Repository<M>,
+    exclude: Vec<ObjectHash>,
+    include: Vec<ObjectHash>,
+) -> Result<RangeStats, GitRwError> {
+    let commits: Vec<CommitBase> = repository.commits_range(exclude, include).collect();
+    let mut stats = RangeStats {
+        commits: commits.len(),
+        ..Default::default()
+    };
+
+    let mut seen = FxHashSet::default();
+    for commit in commits {
+        let parent_trees: Vec<TreeHash> = commit
+            .parents()
+            .into_iter()
+            .filter_map(|parent| commit_tree(repository, parent))
+            .collect();
+        count_new(
+            repository,
+            &parent_trees,
+            commit.tree(),
+            &mut seen,
+            &mut stats,
+        )?;
+    }
+
+    Ok(stats)
+}
+
+/// `None` for parents missing from a shallow clone, all objects of the commit are new then.
+fn commit_tree<M>(repository: &mut Repository<M>, commit: CommitHash) -> Option<TreeHash> {
+    match repository.read_object(commit.into()) {
+        Some(GitObject::Commit(commit)) => Some(commit.tree()),
+        _ => None,
+    }
+}
+
+fn count_new<M>(
+    repository: &mut Repository<M>,
+    old_trees: &[TreeHash],
+    tree: TreeHash,
+    seen: &mut FxHashSet<ObjectHash>,
+    stats: &mut RangeStats,
+) -> Result<(), GitRwError> {
+    if old_trees.contains(&tree) || !seen.insert(tree.clone().into()) {
+        return Ok(());
+    }
+    stats.trees += 1;
+
+    // the entries of all parents by name, with their hashes and whether they are trees
+    let mut old_entries: FxHashMap<Vec<u8>, Vec<(TreeHash, bool)>> = FxHashMap::default();
+    for old_tree in old_trees {
+        for line in repository.read_tree(old_tree.clone())?.lines() {
+            old_entries
+                .entry(line.filename().to_vec())
+                .or_default()
+                .push((line.hash.deref().clone(), line.is_tree()));
+        }
+    }
+
+    for line in repository.read_tree(tree)?.lines() {
+        if line.mode() == GITLINK_MODE {
+            continue;
+        }
+
+        let hash = line.hash.deref();
+        let old = old_entries
+            .get(line.filename())
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if old.iter().any(|(old_hash, _)| old_hash == hash) {
+            continue;
+        }
+
+        if line.is_tree() {
+            let old_subtrees: Vec<TreeHash> = old
+                .iter()
+                .filter(|(_, is_tree)| *is_tree)
+                .map(|(old_hash, _)| old_hash.clone())
+                .collect();
+            count_new(repository, &old_subtrees, hash.clone(), seen, stats)?;
+        } else if seen.insert(hash.clone().into()) {
+            let (_, size) = repository.object_header(&hash.clone().into())?;
+            stats.blobs += 1;
+            stats.blob_size += size as u64;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{test_repository::TestRepository, RangeStats, Repository};
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn range_stats_of_head() {
+        let fixture = TestRepository::create("range-stats");
+        let mut repository = Repository::open_read_only(fixture.path.clone()).unwrap();
+
+        let stats = repository.range_stats("HEAD").unwrap();
+        assert_eq!(5, stats.commits);
+        assert!(stats.trees > 0 && stats.blobs > 0 && stats.blob_size > 0);
+
+        let empty = repository.range_stats("HEAD..HEAD").unwrap();
+        assert_eq!(RangeStats::default(), empty);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::GitRwError,
+    refs::{GitRef, RefStatus, RefUpdate},
+    workspace,
+};
+
+/// File in the gitrw workspace that lists the intended ref updates while they are applied.
+pub(crate) const JOURNAL_FILE: &str = "ref-journal";
+
+/// Where versions before the workspace kept the journal, in the repository directory.
+const LEGACY_JOURNAL_FILE: &str = "gitrw-ref-journal";
+
+/// The journal left by an older version if there is one, the one in the workspace otherwise.
+fn journal_path(repository_path: &Path) -> PathBuf {
+    let legacy = repository_path.join(LEGACY_JOURNAL_FILE);
+    if legacy.exists() {
+        legacy
+    } else {
+        workspace::path(repository_path).join(JOURNAL_FILE)
+    }
+}
+
+/// A ref update recorded in the journal before it is applied.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub name: String,
+    /// `None` if the ref gets created
+    pub old_target: Option<String>,
+    /// `None` if the ref gets deleted
+    pub new_target: Option<String>,
+}
+
+impl Display for JournalEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let old_target = self.old_target.as_deref().unwrap_or("created");
+        match &self.new_target {
+            Some(new_target) if self.old_target.as_ref() == Some(new_target) => {
+                write!(f, "{}: {}", self.name, old_target)
+            }
+            Some(new_target) => write!(f, "{}: {} -> {}", self.name, old_target, new_target),
+            None => write!(f, "{}: {} -> deleted", self.name, old_target),
+        }
+    }
+}
+
+impl From<&RefUpdate> for JournalEntry {
+    fn from(update: &RefUpdate) -> Self {
+        let new_target = match &update.status {
+            RefStatus::Updated(new_target) | RefStatus::Created(new_target) => {
+                Some(new_target.clone())
+            }
+            RefStatus::Deleted => None,
+            _ => Some(update.old_target.clone()),
+        };
+        let old_target = match update.status {
+            RefStatus::Created(_) => None,
+            _ => Some(update.old_target.clone()),
+        };
+
+        JournalEntry {
+            name: update.name.clone(),
+            old_target,
+            new_target,
+        }
+    }
+}
+
+/// Reads the journal of an interrupted ref update, `None` if there is none.
+pub(crate) fn read(repository_path: &Path) -> Result<Option<Vec<JournalEntry>>, GitRwError> {
+    let path = journal_path(repository_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let mut entries = Vec::new();
+    let content = fs::read_to_string(&path).map_err(GitRwError::io(&path))?;
+    for line in content.lines() {
+        let mut parts = line.splitn(3, ' ');
+        let (Some(old_target), Some(new_target), Some(name)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(GitRwError::corrupt(
+                &path,
+                format!("Malformed line: {line}"),
+            ));
+        };
+
+        entries.push(JournalEntry {
+            name: name.to_owned(),
+            old_target: (old_target != "-").then(|| old_target.to_owned()),
+            new_target: (new_target != "-").then(|| new_target.to_owned()),
+        });
+    }
+
+    Ok(Some(entries))
+}
+
+/// Records `entries`, writes every ref as a loose ref, removes `packed-refs` and finally the
+/// journal. A crash in between leaves the journal behind for `complete` or `rollback`.
+pub(crate) fn apply(repository_path: &Path, entries: &[JournalEntry]) -> Result<(), GitRwError> {
+    let mut journal = String::new();
+    for entry in entries {
+        let old_target = entry.old_target.as_deref().unwrap_or("-");
+        let new_target = entry.new_target.as_deref().unwrap_or("-");
+        journal.push_str(&format!("{old_target} {new_target} {}\n", entry.name));
+    }
+
+    // written completely or not at all
+    let tmp_path = workspace::file(repository_path, &format!("{JOURNAL_FILE}.tmp"))?;
+    fs::write(&tmp_path, journal).map_err(GitRwError::io(&tmp_path))?;
+    let journal_path = workspace::path(repository_path).join(JOURNAL_FILE);
+    fs::rename(&tmp_path, &journal_path).map_err(GitRwError::io(&journal_path))?;
+
+    complete(repository_path, entries)
+}
+
+/// Applies the new targets of all `entries` and removes the journal.
+pub(crate) fn complete(repository_path: &Path, entries: &[JournalEntry]) -> Result<(), GitRwError> {
+    for entry in entries {
+        set_ref(repository_path, &entry.name, entry.new_target.as_deref())?;
+    }
+
+    finish(repository_path)
+}
+
+/// Restores the old targets of all `entries`, deletes created refs and removes the journal.
+pub(crate) fn rollback(repository_path: &Path, entries: &[JournalEntry]) -> Result<(), GitRwError> {
+    for entry in entries {
+        set_ref(repository_path, &entry.name, entry.old_target.as_deref())?;
+    }
+
+    finish(repository_path)
+}
+
+fn set_ref(repository_path: &Path, name: &str, target: Option<&str>) -> Result<(), GitRwError> {
+    match target {
+        Some(target) => GitRef::write_ref(repository_path.to_str().unwrap(), name, target)?,
+        None => {
+            let path = repository_path.join(name);
+            if path.exists() {
+                fs::remove_file(&path).map_err(GitRwError::io(&path))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// All refs are loose refs now, `packed-refs` would only bring back deleted refs.
+fn finish(repository_path: &Path) -> Result<(), GitRwError> {
+    let packed_refs = repository_path.join("packed-refs");
+    if packed_refs.exists() {
+        fs::remove_file(&packed_refs).map_err(GitRwError::io(&packed_refs))?;
+    }
+
+    let journal = journal_path(repository_path);
+    fs::remove_file(&journal).map_err(GitRwError::io(&journal))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{JournalEntry, JOURNAL_FILE, LEGACY_JOURNAL_FILE};
+
+    #[test]
+    fn rollback_restores_old_targets() {
+        let dir = std::env::temp_dir().join(format!("gitrw-ref-journal-{}", std::process::id()));
+        fs::create_dir_all(dir.join("refs/heads")).unwrap();
+        fs::write(dir.join("refs/heads/gone"), "c").unwrap();
+
+        let entries = vec![
+            JournalEntry {
+                name: String::from("refs/heads/main"),
+                old_target: Some(String::from("a")),
+                new_target: Some(String::from("b")),
+            },
+            JournalEntry {
+                name: String::from("refs/heads/gone"),
+                old_target: Some(String::from("c")),
+                new_target: None,
+            },
+            JournalEntry {
+                name: String::from("refs/heads/archive/main"),
+                old_target: None,
+                new_target: Some(String::from("d")),
+            },
+        ];
+
+        super::apply(&dir, &entries).unwrap();
+        assert_eq!(
+            "b",
+            fs::read_to_string(dir.join("refs/heads/main")).unwrap()
+        );
+        assert!(!dir.join("refs/heads/gone").exists());
+        assert_eq!(
+            "d",
+            fs::read_to_string(dir.join("refs/heads/archive/main")).unwrap()
+        );
+        assert!(!dir.join("gitrw").join(JOURNAL_FILE).exists());
+
+        // simulate a crash of an older version after the journal was written
+        fs::write(
+            dir.join(LEGACY_JOURNAL_FILE),
+            "a b refs/heads/main\nc - refs/heads/gone\n- d refs/heads/archive/main\n",
+        )
+        .unwrap();
+        let journal = super::read(&dir).unwrap().unwrap();
+        assert_eq!(entries, journal);
+
+        super::rollback(&dir, &journal).unwrap();
+        assert_eq!(
+            "a",
+            fs::read_to_string(dir.join("refs/heads/main")).unwrap()
+        );
+        assert_eq!(
+            "c",
+            fs::read_to_string(dir.join("refs/heads/gone")).unwrap()
+        );
+        assert!(!dir.join("refs/heads/archive/main").exists());
+        assert!(!dir.join(LEGACY_JOURNAL_FILE).exists());
+        assert_eq!(None, super::read(&dir).unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,10 +1,11 @@
 use std::{
     collections::HashMap,
-    error::Error,
-    fs::File,
+    fmt::Display,
+    fs::{self, File},
     hash::BuildHasher,
     io::BufReader,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use bstr::{
@@ -12,12 +13,350 @@ use bstr::{
     BStr, BString, ByteSlice,
 };
 
+use rustc_hash::{FxHashMap, FxHashSet};
+
 use crate::{
-    objs::{CommitHash, Tag, TagTargetType},
+    commits::peel_to_commit,
+    compression::Decompression,
+    error::GitRwError,
+    objs::{CommitHash, GitObject, Tag, TagTargetType},
+    packreader::PackReader,
+    ref_journal::{self, JournalEntry},
+    reftable::{self, RefValue},
     shared::ObjectHash,
-    Repository,
+    Repository, SkipTracker,
 };
 
+/// Controls how `Repository::update_refs` treats the refs of the repository.
+#[derive(Clone, Debug, Default)]
+pub struct RefOptions {
+    /// Refs that must not be changed unless `allow_protected` is set, `*` matches any sequence of
+    /// characters, e.g. `refs/tags/*`. Values of `gitrw.protect` in the repository config are
+    /// protected as well.
+    pub protected: Vec<String>,
+    pub allow_protected: bool,
+    /// Also update remote-tracking refs (`refs/remotes/*`), which are left alone by default
+    /// because the next fetch overwrites them anyway.
+    pub include_remotes: bool,
+    /// Refs to delete instead of updating, using the same patterns as `protected`.
+    pub delete: Vec<String>,
+    /// What to do with refs whose target object is missing, e.g. after an interrupted fetch.
+    pub dangling: DanglingRefs,
+    /// Rewrite even if grafts, replace refs or a shallow clone change what git shows as history,
+    /// see `Repository::altered_history`.
+    pub allow_altered_history: bool,
+    /// Publish the rewritten targets of matching refs under new names and leave the original
+    /// refs unchanged. The first matching rename applies.
+    pub rename: Vec<RefRename>,
+    /// Leave all refs unchanged and create this ref, pointing to the rewritten commit of `HEAD`,
+    /// instead. The ref must not exist yet.
+    pub output_ref: Option<String>,
+}
+
+/// Replaces the prefix `from` of ref names with `to`, e.g. `refs/heads/:refs/heads/archive/`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefRename {
+    pub from: String,
+    pub to: String,
+}
+
+impl RefRename {
+    fn apply(&self, ref_name: &[u8]) -> Option<String> {
+        let rest = ref_name.strip_prefix(self.from.as_bytes())?;
+        Some(format!("{}{}", self.to, rest.as_bstr()))
+    }
+}
+
+impl FromStr for RefRename {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((from, to)) = s.split_once(':') else {
+            return Err(String::from("expected <OLD_PREFIX>:<NEW_PREFIX>"));
+        };
+
+        if !from.starts_with("refs/") || !to.starts_with("refs/") {
+            return Err(String::from("both prefixes have to start with refs/"));
+        }
+
+        if from == to {
+            return Err(String::from("the prefixes have to differ"));
+        }
+
+        Ok(RefRename {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        })
+    }
+}
+
+/// Handling of refs that point to objects missing from the repository.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DanglingRefs {
+    /// Leave the ref as it is and report it as not mapped
+    #[default]
+    Keep,
+    /// Delete the ref while updating the refs
+    Delete,
+    /// Refuse to rewrite anything
+    Fail,
+}
+
+impl FromStr for DanglingRefs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(DanglingRefs::Keep),
+            "delete" => Ok(DanglingRefs::Delete),
+            "fail" => Ok(DanglingRefs::Fail),
+            _ => Err(String::from("expected one of keep, delete, fail")),
+        }
+    }
+}
+
+impl RefOptions {
+    pub fn is_protected(&self, ref_name: &[u8]) -> bool {
+        self.protected
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), ref_name))
+    }
+
+    pub fn is_deleted(&self, ref_name: &[u8]) -> bool {
+        self.delete
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), ref_name))
+    }
+
+    /// The name the rewritten target of the ref is published under, `None` if the ref itself
+    /// gets updated.
+    pub fn renamed(&self, ref_name: &[u8]) -> Option<String> {
+        self.rename.iter().find_map(|rename| rename.apply(ref_name))
+    }
+}
+
+/// Matches a ref name against a pattern in which `*` stands for any sequence of characters.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.iter().position(|c| *c == b'*') {
+        None => pattern == name,
+        Some(star) => {
+            let (prefix, rest) = (&pattern[..star], &pattern[star + 1..]);
+            if !name.starts_with(prefix) {
+                return false;
+            }
+
+            let name = &name[prefix.len()..];
+            (0..=name.len()).any(|i| glob_match(rest, &name[i..]))
+        }
+    }
+}
+
+/// Splits the targets of all refs into those of refs matching one of `patterns` and all others.
+pub(crate) fn partition_ref_targets(
+    base_path: &Path,
+    patterns: &[String],
+) -> Result<(Vec<ObjectHash>, Vec<ObjectHash>), GitRwError> {
+    let mut matching = Vec::new();
+    let mut others = Vec::new();
+    for git_ref in GitRef::read_all(base_path)? {
+        let Ok(target) = ObjectHash::try_from(git_ref.get_target()) else {
+            continue;
+        };
+
+        if patterns
+            .iter()
+            .any(|pattern| glob_match(pattern.as_bytes(), git_ref.get_name()))
+        {
+            matching.push(target);
+        } else {
+            others.push(target);
+        }
+    }
+
+    Ok((matching, others))
+}
+
+/// Name and target of every ref.
+pub(crate) fn ref_targets(base_path: &Path) -> Result<Vec<(BString, BString)>, GitRwError> {
+    Ok(GitRef::read_all(base_path)?
+        .iter()
+        .map(|r| (r.get_name().to_owned(), r.get_target().to_owned()))
+        .collect())
+}
+
+/// What happened to a ref during `Repository::update_refs`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefStatus {
+    /// The ref now points to the given rewritten target
+    Updated(String),
+    /// The ref was created by a `RefRename` and points to the given target, the old target is
+    /// the one of the original ref
+    Created(String),
+    Unchanged,
+    Deleted,
+    /// Remote-tracking ref that was left alone
+    Skipped,
+    /// The target could not be mapped and was left as it is, with the reason
+    Unmapped(&'static str),
+}
+
+#[derive(Clone, Debug)]
+pub struct RefUpdate {
+    pub name: String,
+    pub old_target: String,
+    pub status: RefStatus,
+}
+
+impl RefUpdate {
+    fn create(git_ref: &GitRef, status: RefStatus) -> Self {
+        RefUpdate {
+            name: git_ref.get_name().to_string(),
+            old_target: git_ref.get_target().to_string(),
+            status,
+        }
+    }
+}
+
+impl Display for RefUpdate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.status {
+            RefStatus::Updated(new_target) => {
+                write!(f, "{}: {} -> {}", self.name, self.old_target, new_target)
+            }
+            RefStatus::Created(new_target) => {
+                write!(
+                    f,
+                    "{}: created, {} -> {}",
+                    self.name, self.old_target, new_target
+                )
+            }
+            RefStatus::Unchanged => write!(f, "{}: unchanged", self.name),
+            RefStatus::Deleted => write!(f, "{}: deleted", self.name),
+            RefStatus::Skipped => write!(f, "{}: skipped", self.name),
+            RefStatus::Unmapped(reason) => {
+                write!(f, "{}: WARNING not mapped, {}", self.name, reason)
+            }
+        }
+    }
+}
+
+/// A ref together with the type of its target and, for annotated tags, the commit it peels to.
+#[derive(Clone, Debug)]
+pub struct RefInfo {
+    pub name: String,
+    pub target: String,
+    /// `commit`, `tree`, `blob` or `tag`, `None` if the target object is missing
+    pub target_type: Option<&'static str>,
+    /// The commit a tag points to, directly or through other tags
+    pub peeled: Option<CommitHash>,
+}
+
+/// All refs sorted by name, with their targets read to find out the types.
+pub(crate) fn ref_infos<M>(repository: &mut Repository<M>) -> Result<Vec<RefInfo>, GitRwError> {
+    let mut snapshot = RefSnapshot::read(&repository.path)?;
+    let mut infos = Vec::with_capacity(snapshot.refs.len());
+    for r in std::mem::take(&mut snapshot.refs) {
+        let hash = ObjectHash::try_from(r.get_target()).ok();
+        let target_type = match hash.clone().and_then(|hash| repository.read_object(hash)) {
+            Some(GitObject::Commit(_)) => Some("commit"),
+            Some(GitObject::Tree(_)) => Some("tree"),
+            Some(GitObject::Blob(_)) => Some("blob"),
+            Some(GitObject::Tag(_)) => Some("tag"),
+            None => None,
+        };
+
+        let peeled = match (target_type, hash) {
+            (Some("tag"), Some(hash)) => snapshot.peel(
+                &mut repository.decompression,
+                &repository.path,
+                &repository.pack_reader,
+                hash,
+            ),
+            _ => None,
+        };
+
+        infos.push(RefInfo {
+            name: r.get_name().to_string(),
+            target: r.get_target().to_string(),
+            target_type,
+            peeled,
+        });
+    }
+
+    infos.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+    Ok(infos)
+}
+
+/// The refs of a repository, read once, together with a cache of the commits their targets peel
+/// to. Many refs commonly point to the same tag chain, which then only has to be read once.
+pub(crate) struct RefSnapshot {
+    pub(crate) refs: Vec<GitRef>,
+    peeled: FxHashMap<ObjectHash, Option<CommitHash>>,
+}
+
+impl RefSnapshot {
+    pub(crate) fn read(base_path: &Path) -> Result<Self, GitRwError> {
+        let refs = GitRef::read_all(base_path)?;
+        Ok(RefSnapshot {
+            refs,
+            peeled: FxHashMap::default(),
+        })
+    }
+
+    /// Same as `Repository::peel_to_commit`, with the result cached per hash.
+    pub(crate) fn peel(
+        &mut self,
+        decompression: &mut Decompression,
+        repository_path: &Path,
+        pack_reader: &PackReader,
+        hash: ObjectHash,
+    ) -> Option<CommitHash> {
+        if let Some(peeled) = self.peeled.get(&hash) {
+            return peeled.clone();
+        }
+
+        let peeled = peel_to_commit(decompression, repository_path, pack_reader, hash.clone())
+            .map(|commit| commit.hash);
+        self.peeled.insert(hash, peeled.clone());
+        peeled
+    }
+
+    /// The distinct commits all refs peel to, in the order of the refs. Refs that do not peel to
+    /// a commit or whose target is no object hash are recorded in `skipped`.
+    pub(crate) fn commits(
+        &mut self,
+        decompression: &mut Decompression,
+        repository_path: &Path,
+        pack_reader: &PackReader,
+        skipped: &SkipTracker,
+    ) -> Vec<CommitHash> {
+        let targets: Vec<(BString, ObjectHash)> = self
+            .refs
+            .iter()
+            .filter_map(|r| match ObjectHash::try_from(r.get_target()) {
+                Ok(target) => Some((r.get_name().to_owned(), target)),
+                Err(_) => {
+                    skipped.record("ref target is no object hash", r.get_name().to_owned());
+                    None
+                }
+            })
+            .collect();
+
+        let mut seen = FxHashSet::default();
+        targets
+            .into_iter()
+            .filter_map(|(name, hash)| {
+                let commit = self.peel(decompression, repository_path, pack_reader, hash);
+                if commit.is_none() {
+                    skipped.record("ref does not point to a commit", name);
+                }
+                commit
+            })
+            .filter(|commit| seen.insert(commit.clone()))
+            .collect()
+    }
+}
+
 trait RefName {
     fn get_name(&self) -> &BStr;
     fn get_target(&self) -> &BStr;
@@ -59,21 +398,25 @@ pub struct TagRef {
 }
 
 impl GitRef {
-    pub fn read_all(base_path: &std::path::Path) -> Result<Vec<GitRef>, Box<dyn Error>> {
+    pub fn read_all(base_path: &std::path::Path) -> Result<Vec<GitRef>, GitRwError> {
+        if reftable::is_reftable(base_path) {
+            return read_reftable_refs(base_path);
+        }
+
         let packed_refs_path = base_path.join("packed-refs");
 
-        let file = File::open(packed_refs_path);
+        let file = File::open(&packed_refs_path);
         let packed_refs = match file {
             Ok(file) => {
                 let reader = BufReader::new(file);
 
-                let packed_refs = get_packed_refs(&mut reader.byte_lines())?;
+                let packed_refs = get_packed_refs(&packed_refs_path, &mut reader.byte_lines())?;
                 Some(packed_refs)
             }
             Err(_) => None,
         };
 
-        let mut refs = get_loose_refs(base_path, "refs");
+        let mut refs = get_loose_refs(base_path, "refs")?;
 
         if let Some(mut p) = packed_refs {
             refs.append(&mut p);
@@ -86,123 +429,648 @@ impl GitRef {
     pub fn update<T: BuildHasher>(
         repository: &mut Repository,
         rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+        options: &RefOptions,
         dry_run: bool,
-    ) {
-        for r in repository.refs().unwrap() {
-            Self::rewrite_ref(
-                repository,
-                r.get_name(),
-                r.get_target(),
-                rewritten_commits,
-                dry_run,
-            );
+    ) -> Result<Vec<RefUpdate>, GitRwError> {
+        check_journal(&repository.path)?;
+        check_renames(&repository.path, options)?;
+
+        let mut snapshot = RefSnapshot::read(&repository.path)?;
+        if let Some(output_ref) = &options.output_ref {
+            check_output_ref(&repository.path, output_ref)?;
+            let created = Self::create_output_ref(repository, output_ref, rewritten_commits)?;
+            if !dry_run {
+                Self::write_output_ref(repository, &created)?;
+            }
+            // the other refs are only reported, their files are not touched
+            let mut updates: Vec<RefUpdate> = snapshot
+                .refs
+                .iter()
+                .map(|r| RefUpdate::create(r, RefStatus::Unchanged))
+                .collect();
+            updates.push(created);
+            return Ok(updates);
         }
 
-        let mut path = repository.path.clone();
-        path.push("packed-refs");
-        if path.exists() {
-            std::fs::remove_file(path).unwrap();
+        let (refs, skipped_refs): (Vec<GitRef>, Vec<GitRef>) = std::mem::take(&mut snapshot.refs)
+            .into_iter()
+            .partition(|r| options.include_remotes || !r.get_name().starts_with(b"refs/remotes/"));
+
+        let mut options = options.clone();
+        options
+            .protected
+            .extend(repository.config_values("gitrw", "protect"));
+
+        if !options.allow_protected {
+            let protected: Vec<String> = refs
+                .iter()
+                .filter(|r| options.is_protected(r.get_name()))
+                .filter(|r| {
+                    options.is_deleted(r.get_name())
+                        || options.renamed(r.get_name()).is_none()
+                            && Self::is_rewritten(
+                                repository,
+                                &mut snapshot,
+                                r.get_target(),
+                                rewritten_commits,
+                            )
+                })
+                .map(|r| r.get_name().to_string())
+                .collect();
+
+            if !protected.is_empty() {
+                return Err(GitRwError::invalid(format!(
+                    "Protected refs would be rewritten: {}. Use --allow-protected to update them anyway",
+                    protected.join(", ")
+                )));
+            }
         }
+
+        let dangling: FxHashSet<BString> = dangling_refs(repository, &refs)
+            .filter(|r| !options.is_deleted(r.get_name()))
+            .map(|r| r.get_name().to_owned())
+            .collect();
+        if options.dangling == DanglingRefs::Fail && !dangling.is_empty() {
+            return Err(dangling_error(&repository.path, &dangling));
+        }
+
+        let mut updates = Vec::with_capacity(refs.len() + skipped_refs.len());
+        let mut mapped_targets: FxHashMap<BString, RefStatus> = FxHashMap::default();
+        for r in refs {
+            let delete = options.is_deleted(r.get_name())
+                || (options.dangling == DanglingRefs::Delete && dangling.contains(r.get_name()));
+            let status = if delete {
+                RefStatus::Deleted
+            } else {
+                Self::map_ref(
+                    repository,
+                    &mut mapped_targets,
+                    r.get_target(),
+                    rewritten_commits,
+                    dry_run,
+                )?
+            };
+
+            let renamed = options.renamed(r.get_name()).filter(|_| !delete);
+            let Some(renamed) = renamed else {
+                updates.push(RefUpdate::create(&r, status));
+                continue;
+            };
+
+            // unmapped refs are reported for the original, the new ref is only created for
+            // targets that are known to be right
+            let new_target = match &status {
+                RefStatus::Updated(new_target) => Some(new_target.clone()),
+                RefStatus::Unchanged => Some(r.get_target().to_string()),
+                _ => None,
+            };
+            let status = if new_target.is_some() {
+                RefStatus::Unchanged
+            } else {
+                status
+            };
+            updates.push(RefUpdate::create(&r, status));
+
+            if let Some(new_target) = new_target {
+                updates.push(RefUpdate {
+                    name: renamed,
+                    old_target: r.get_target().to_string(),
+                    status: RefStatus::Created(new_target),
+                });
+            }
+        }
+
+        // packed-refs gets removed, skipped refs are kept as loose refs
+        for r in skipped_refs {
+            updates.push(RefUpdate::create(&r, RefStatus::Skipped));
+        }
+
+        if let Some(head_update) = Self::update_head(repository, &updates, rewritten_commits)? {
+            updates.push(head_update);
+        }
+
+        if !dry_run {
+            Self::write_updates(repository, &updates)?;
+        }
+
+        Ok(updates)
+    }
+
+    fn write_updates(repository: &Repository, updates: &[RefUpdate]) -> Result<(), GitRwError> {
+        // symbolic refs are never changed, only reported
+        let entries: Vec<JournalEntry> = updates
+            .iter()
+            .filter(|update| !update.old_target.starts_with("ref: "))
+            .map(JournalEntry::from)
+            .collect();
+        if reftable::is_reftable(&repository.path) {
+            // a new table replaces all refs at once, there is nothing to recover
+            write_reftable_updates(&repository.path, &entries)
+        } else {
+            ref_journal::apply(&repository.path, &entries)
+        }
+    }
+
+    /// Adds the new output ref as a loose ref or to the reftable. Unlike `write_updates` the
+    /// packed refs stay as they are, the ref did not exist before so there is nothing to recover.
+    fn write_output_ref(repository: &Repository, created: &RefUpdate) -> Result<(), GitRwError> {
+        let entry = JournalEntry::from(created);
+        if reftable::is_reftable(&repository.path) {
+            return write_reftable_updates(&repository.path, &[entry]);
+        }
+
+        let target = entry.new_target.ok_or_else(|| {
+            GitRwError::invalid(format!("The output ref {} has no target", entry.name))
+        })?;
+        let repository_path = repository.path.to_str().unwrap();
+        Self::write_ref(repository_path, &entry.name, &target)
     }
 
-    fn write_ref(repository_path: &str, ref_name: &str, ref_target: &str) {
+    /// The ref `output_ref` pointing to the rewritten commit `HEAD` peels to, or to the commit
+    /// itself if it was not rewritten.
+    fn create_output_ref<T: BuildHasher>(
+        repository: &mut Repository,
+        output_ref: &str,
+        rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+    ) -> Result<RefUpdate, GitRwError> {
+        let head = find_ref_target(&repository.path, &repository.git_dir, "HEAD")?
+            .and_then(|target| ObjectHash::try_from(target.as_bstr()).ok())
+            .and_then(|hash| {
+                peel_to_commit(
+                    &mut repository.decompression,
+                    &repository.path,
+                    &repository.pack_reader,
+                    hash,
+                )
+            })
+            .ok_or_else(|| {
+                GitRwError::invalid(
+                    "HEAD does not point to a commit, there is nothing to write to the output ref",
+                )
+            })?;
+
+        let new_target = rewritten_commits.get(&head.hash).unwrap_or(&head.hash);
+        Ok(RefUpdate {
+            name: output_ref.to_owned(),
+            old_target: head.hash.to_string(),
+            status: RefStatus::Created(new_target.to_string()),
+        })
+    }
+
+    /// A detached HEAD is moved to its rewritten commit like any other ref. A symbolic HEAD is
+    /// only reported if the branch it points to does not exist after the update.
+    fn update_head<T: BuildHasher>(
+        repository: &Repository,
+        updates: &[RefUpdate],
+        rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+    ) -> Result<Option<RefUpdate>, GitRwError> {
+        let Some(head) = read_head(&repository.path, &repository.git_dir)? else {
+            return Ok(None);
+        };
+
+        let status = match head.strip_prefix("ref: ") {
+            Some(target) => {
+                let exists = updates
+                    .iter()
+                    .any(|update| update.name == target && update.status != RefStatus::Deleted);
+                if exists {
+                    return Ok(None);
+                }
+                RefStatus::Unmapped("points to a branch that does not exist")
+            }
+            None => {
+                let Ok(hash) = ObjectHash::try_from(head.as_bytes().as_bstr()) else {
+                    return Ok(None);
+                };
+                match rewritten_commits.get(&CommitHash(hash)) {
+                    Some(new_target) => RefStatus::Updated(new_target.to_string()),
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        Ok(Some(RefUpdate {
+            name: String::from("HEAD"),
+            old_target: head.to_owned(),
+            status,
+        }))
+    }
+
+    /// Checks whether the commit a ref points at, directly or through tags, was rewritten.
+    fn is_rewritten<T: BuildHasher>(
+        repository: &mut Repository,
+        snapshot: &mut RefSnapshot,
+        ref_target: &BStr,
+        rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+    ) -> bool {
+        let Ok(hash) = ObjectHash::try_from(ref_target) else {
+            return false;
+        };
+
+        snapshot
+            .peel(
+                &mut repository.decompression,
+                &repository.path,
+                &repository.pack_reader,
+                hash,
+            )
+            .is_some_and(|commit| rewritten_commits.contains_key(&commit))
+    }
+
+    pub(crate) fn write_ref(
+        repository_path: &str,
+        ref_name: &str,
+        ref_target: &str,
+    ) -> Result<(), GitRwError> {
         let path: PathBuf = [repository_path, ref_name].iter().collect();
 
         let file_name = path.file_name().unwrap();
         let ref_path = path.to_str().unwrap();
-        let dir_path = &ref_path[0..ref_path.len() - file_name.len()];
-        std::fs::create_dir_all(dir_path).unwrap();
-        std::fs::write(path, ref_target).unwrap();
+        let dir_path = Path::new(&ref_path[0..ref_path.len() - file_name.len()]);
+        std::fs::create_dir_all(dir_path).map_err(GitRwError::io(dir_path))?;
+        std::fs::write(&path, ref_target).map_err(GitRwError::io(&path))
     }
 
-    fn rewrite_ref<T: BuildHasher>(
+    fn map_ref<T: BuildHasher>(
         repository: &mut Repository,
-        ref_name: &BStr,
+        mapped_targets: &mut FxHashMap<BString, RefStatus>,
         ref_target: &BStr,
         rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
         dry_run: bool,
-    ) -> ObjectHash {
-        let tag_target_obj = repository
-            .read_object(ref_target.try_into().unwrap())
-            .unwrap();
-        match tag_target_obj {
-            crate::objs::GitObject::Commit(_) => {
-                let tag_target: CommitHash = ref_target.try_into().unwrap();
-                let rewritten_target = rewritten_commits.get(&tag_target).unwrap_or(&tag_target);
-                Self::write_ref(
-                    repository.path.clone().to_str().unwrap(),
-                    ref_name.to_str().unwrap(),
-                    rewritten_target.to_string().as_str(),
-                );
-
-                rewritten_target.clone().0
+    ) -> Result<RefStatus, GitRwError> {
+        match mapped_targets.get(ref_target) {
+            Some(status) => Ok(status.clone()),
+            None => {
+                let status = Self::map_target(repository, ref_target, rewritten_commits, dry_run)?;
+                mapped_targets.insert(ref_target.to_owned(), status.clone());
+                Ok(status)
             }
-            crate::objs::GitObject::Tree(tree) => {
-                Self::write_ref(
-                    repository.path.to_str().unwrap(),
-                    ref_name.to_str().unwrap(),
-                    ref_target.to_str().unwrap(),
-                );
-
-                tree.hash().0.clone()
-            }
-            crate::objs::GitObject::Tag(mut target_tag) => match target_tag.target_type() {
+        }
+    }
+
+    fn map_target<T: BuildHasher>(
+        repository: &mut Repository,
+        ref_target: &BStr,
+        rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+        dry_run: bool,
+    ) -> Result<RefStatus, GitRwError> {
+        let Ok(hash) = ObjectHash::try_from(ref_target) else {
+            return Ok(RefStatus::Unmapped("target is not an object hash"));
+        };
+
+        let status = match repository.read_object(hash.clone()) {
+            None => RefStatus::Unmapped("target object is missing"),
+            Some(GitObject::Commit(_)) => match rewritten_commits.get(&CommitHash(hash)) {
+                Some(rewritten_target) => RefStatus::Updated(rewritten_target.to_string()),
+                None => RefStatus::Unchanged,
+            },
+            Some(GitObject::Tree(_)) => RefStatus::Unmapped("points to a tree"),
+            Some(GitObject::Blob(_)) => RefStatus::Unmapped("points to a blob"),
+            Some(GitObject::Tag(mut target_tag)) => match target_tag.target_type() {
                 TagTargetType::Commit => {
                     let target_hash = CommitHash(target_tag.object());
-                    let target_tag_object =
-                        rewritten_commits.get(&target_hash).unwrap_or(&target_hash);
+                    let Some(rewritten_target) = rewritten_commits.get(&target_hash) else {
+                        return Ok(RefStatus::Unchanged);
+                    };
 
-                    target_tag.set_object(target_tag_object.clone().0);
+                    target_tag.set_object(rewritten_target.clone().0);
                     let tag = Tag::create(None, target_tag.to_bytes(), false);
                     let tag_hash = tag.hash().clone();
-                    Repository::write(repository.path.clone(), tag.into(), dry_run);
-                    let target_hash = tag_hash;
-
-                    Self::write_ref(
-                        repository.path.to_str().unwrap(),
-                        ref_name.to_str().unwrap(),
-                        target_hash.to_string().as_str(),
-                    );
+                    Repository::write(repository.path.clone(), tag.into(), dry_run)?;
 
-                    target_hash.clone()
-                }
-                TagTargetType::Tree => {
-                    let target_tag_hash = target_tag.hash().clone();
-                    Repository::write(repository.path.clone(), target_tag.into(), dry_run);
-                    target_tag_hash
-                }
-                TagTargetType::Tag => {
-                    panic!("Did not expect a tag to point to another tag");
+                    RefStatus::Updated(tag_hash.to_string())
                 }
+                TagTargetType::Tree => RefStatus::Unmapped("tag points to a tree"),
+                TagTargetType::Blob => RefStatus::Unmapped("tag points to a blob"),
+                TagTargetType::Tag => RefStatus::Unmapped("tag points to another tag"),
+            },
+        };
+        Ok(status)
+    }
+}
+
+/// Fails if the journal of an interrupted ref update is left.
+pub(crate) fn check_journal(repository_path: &Path) -> Result<(), GitRwError> {
+    if ref_journal::read(repository_path)?.is_some() {
+        return Err(GitRwError::invalid(
+            "A previous ref update was interrupted, complete or roll it back first (gitrw recover-refs)",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Fails if a ref created by `options.rename` already exists or several refs would be renamed to
+/// the same name.
+pub(crate) fn check_renames(base_path: &Path, options: &RefOptions) -> Result<(), GitRwError> {
+    if options.rename.is_empty() {
+        return Ok(());
+    }
+
+    let refs = GitRef::read_all(base_path)?;
+    let names: FxHashSet<&BStr> = refs.iter().map(|r| r.get_name()).collect();
+    let mut renamed_names = FxHashSet::default();
+    let mut existing = Vec::new();
+    for r in refs.iter() {
+        if !options.include_remotes && r.get_name().starts_with(b"refs/remotes/") {
+            continue;
+        }
+
+        let Some(renamed) = options.renamed(r.get_name()) else {
+            continue;
+        };
+        if names.contains(renamed.as_bytes().as_bstr()) {
+            existing.push(renamed);
+        } else if !renamed_names.insert(renamed.clone()) {
+            return Err(GitRwError::invalid(format!(
+                "Several refs would be renamed to {renamed}"
+            )));
+        }
+    }
+
+    if existing.is_empty() {
+        Ok(())
+    } else {
+        existing.sort_unstable();
+        Err(GitRwError::invalid(format!(
+            "Renamed refs already exist: {}. Delete them first or choose another prefix",
+            existing.join(", ")
+        )))
+    }
+}
+
+/// Fails if the output ref is no name below `refs/` or already exists.
+pub(crate) fn check_output_ref(base_path: &Path, output_ref: &str) -> Result<(), GitRwError> {
+    if !output_ref.starts_with("refs/") {
+        return Err(GitRwError::invalid(format!(
+            "The output ref {output_ref} has to start with refs/"
+        )));
+    }
+
+    if GitRef::read_all(base_path)?
+        .iter()
+        .any(|r| r.get_name() == output_ref.as_bytes())
+    {
+        return Err(GitRwError::invalid(format!(
+            "The output ref {output_ref} already exists. Delete it first or choose another name"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Refs whose target is not an object of the repository.
+fn dangling_refs<'a>(
+    repository: &'a Repository,
+    refs: &'a [GitRef],
+) -> impl Iterator<Item = &'a GitRef> + 'a {
+    refs.iter().filter(|r| {
+        ObjectHash::try_from(r.get_target()).is_ok_and(|hash| !repository.object_exists(&hash))
+    })
+}
+
+fn dangling_error(base_path: &Path, names: &FxHashSet<BString>) -> GitRwError {
+    let mut names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+    names.sort_unstable();
+    GitRwError::corrupt(
+        base_path,
+        format!(
+            "Refs point to missing objects: {}. Fetch the objects again or delete the refs",
+            names.join(", ")
+        ),
+    )
+}
+
+/// Everything that makes git show a different history than the one stored in the commits, which
+/// gitrw reads and rewrites as they are.
+pub(crate) fn altered_history(base_path: &Path) -> Result<Vec<String>, GitRwError> {
+    let mut altered = Vec::new();
+    if fs::metadata(base_path.join("info/grafts")).is_ok_and(|m| m.len() > 0) {
+        altered.push(String::from("info/grafts changes the parents of commits"));
+    }
+
+    let replace_refs = GitRef::read_all(base_path)?
+        .iter()
+        .filter(|r| r.get_name().starts_with(b"refs/replace/"))
+        .count();
+    if replace_refs > 0 {
+        altered.push(format!(
+            "refs/replace substitutes objects ({replace_refs} replace refs)"
+        ));
+    }
+
+    if base_path.join("shallow").is_file() {
+        altered.push(String::from(
+            "the repository is a shallow clone, the history is cut off at the commits listed in shallow",
+        ));
+    }
+
+    Ok(altered)
+}
+
+/// Fails if the history git shows differs from the stored one, unless
+/// `options.allow_altered_history` is set.
+pub(crate) fn check_altered_history(
+    base_path: &Path,
+    options: &RefOptions,
+) -> Result<(), GitRwError> {
+    if options.allow_altered_history {
+        return Ok(());
+    }
+
+    let altered = altered_history(base_path)?;
+    if altered.is_empty() {
+        return Ok(());
+    }
+
+    Err(GitRwError::invalid(format!(
+        "The history git shows differs from the stored commits: {}. gitrw rewrites the stored commits and ignores these changes, pass --allow-altered-history to rewrite anyway",
+        altered.join(", ")
+    )))
+}
+
+/// Fails if `options.dangling` is `DanglingRefs::Fail` and a ref that would be updated points to
+/// a missing object. Meant to run before a long rewrite, `update_refs` checks it again.
+pub(crate) fn check_dangling(
+    repository: &Repository,
+    options: &RefOptions,
+) -> Result<(), GitRwError> {
+    if options.dangling != DanglingRefs::Fail {
+        return Ok(());
+    }
+
+    let refs: Vec<GitRef> = GitRef::read_all(&repository.path)?
+        .into_iter()
+        .filter(|r| options.include_remotes || !r.get_name().starts_with(b"refs/remotes/"))
+        .filter(|r| !options.is_deleted(r.get_name()))
+        .collect();
+    let dangling: FxHashSet<BString> = dangling_refs(repository, &refs)
+        .map(|r| r.get_name().to_owned())
+        .collect();
+    if dangling.is_empty() {
+        Ok(())
+    } else {
+        Err(dangling_error(&repository.path, &dangling))
+    }
+}
+
+/// The content of `HEAD`, `ref: <name>` or an object hash. Repositories using reftables store it
+/// in the tables of `git_dir`, their `HEAD` file only keeps older git versions from treating the
+/// directory as a repository.
+fn read_head(base_path: &Path, git_dir: &Path) -> Result<Option<String>, GitRwError> {
+    if !reftable::is_reftable(base_path) {
+        let head = std::fs::read_to_string(git_dir.join("HEAD")).ok();
+        return Ok(head.map(|head| head.trim_end().to_owned()));
+    }
+
+    Ok(
+        match reftable::read_refs(git_dir)?.remove(b"HEAD".as_bstr()) {
+            Some(RefValue::Symbolic(target)) => Some(format!("ref: {target}")),
+            Some(RefValue::Object(hash) | RefValue::Peeled(hash, _)) => Some(hash.to_string()),
+            Some(RefValue::Deletion) | None => None,
+        },
+    )
+}
+
+/// The branch `HEAD` points to if it exists. A detached or dangling `HEAD` falls back to the first
+/// branch pointing to the same commit, then to `main` and `master`.
+pub(crate) fn default_branch(
+    base_path: &Path,
+    git_dir: &Path,
+) -> Result<Option<String>, GitRwError> {
+    let refs = GitRef::read_all(base_path)?;
+    let head = read_head(base_path, git_dir)?.unwrap_or_default();
+    let branches = || {
+        refs.iter()
+            .filter(|r| r.get_name().starts_with(b"refs/heads/"))
+    };
+
+    if let Some(branch) = head.strip_prefix("ref: ") {
+        if branches().any(|r| r.get_name() == branch.as_bytes()) {
+            return Ok(Some(branch.to_owned()));
+        }
+    } else if let Some(r) = branches().find(|r| r.get_target() == head.as_bytes()) {
+        return Ok(Some(r.get_name().to_string()));
+    }
+
+    Ok(["refs/heads/main", "refs/heads/master"]
+        .into_iter()
+        .find(|name| branches().any(|r| r.get_name() == name.as_bytes()))
+        .map(str::to_owned))
+}
+
+/// Looks up the target of a ref by name. `HEAD` is read from `git_dir` and followed if it is a
+/// symbolic ref, other names are tried as given and with the `refs/`, `refs/tags/`,
+/// `refs/heads/` and `refs/remotes/` prefixes, in the same order git uses.
+pub(crate) fn find_ref_target(
+    base_path: &Path,
+    git_dir: &Path,
+    name: &str,
+) -> Result<Option<BString>, GitRwError> {
+    Ok(find_ref(base_path, git_dir, name)?.map(|(_, target)| target))
+}
+
+/// Same as `find_ref_target`, but also returns the full name of the ref. `HEAD` keeps its name
+/// even if it is a symbolic ref.
+pub(crate) fn find_ref(
+    base_path: &Path,
+    git_dir: &Path,
+    name: &str,
+) -> Result<Option<(BString, BString)>, GitRwError> {
+    if name == "HEAD" {
+        let target = match read_head(base_path, git_dir)? {
+            Some(head) => match head.strip_prefix("ref: ") {
+                Some(target) => find_ref_target(base_path, git_dir, target)?,
+                None => Some(BString::from(head)),
             },
+            None => None,
+        };
+        return Ok(target.map(|target| (BString::from("HEAD"), target)));
+    }
+
+    let refs = GitRef::read_all(base_path)?;
+    for candidate in [
+        name.to_owned(),
+        format!("refs/{name}"),
+        format!("refs/tags/{name}"),
+        format!("refs/heads/{name}"),
+        format!("refs/remotes/{name}"),
+    ] {
+        if let Some(r) = refs.iter().find(|r| r.get_name() == candidate.as_bytes()) {
+            return Ok(Some((r.get_name().to_owned(), r.get_target().to_owned())));
         }
     }
+
+    Ok(None)
+}
+
+/// The refs below `refs/` of a repository using reftables. Symbolic refs are skipped like in
+/// `get_loose_refs`.
+fn read_reftable_refs(base_path: &Path) -> Result<Vec<GitRef>, GitRwError> {
+    Ok(reftable::read_refs(base_path)?
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(b"refs/"))
+        .filter_map(|(name, value)| match value {
+            RefValue::Object(hash) => Some(GitRef::Simple(SimpleRef {
+                name,
+                hash: hash.to_string().into(),
+            })),
+            RefValue::Peeled(hash, peeled) => Some(GitRef::Tag(TagRef {
+                name,
+                hash: hash.to_string().into(),
+                obj_hash: peeled.to_string().into(),
+            })),
+            RefValue::Deletion | RefValue::Symbolic(_) => None,
+        })
+        .collect())
 }
 
-fn get_loose_refs(base_path: &Path, current_path: &str) -> Vec<GitRef> {
+/// Writes the entries whose target changes to a new reftable.
+fn write_reftable_updates(base_path: &Path, entries: &[JournalEntry]) -> Result<(), GitRwError> {
+    let mut updates = Vec::new();
+    for entry in entries {
+        if entry.new_target == entry.old_target {
+            continue;
+        }
+
+        let hash = match &entry.new_target {
+            Some(target) => Some(
+                ObjectHash::try_from(target.as_bytes().as_bstr())
+                    .map_err(|e| GitRwError::corrupt(base_path, format!("{}: {e}", entry.name)))?,
+            ),
+            None => None,
+        };
+        updates.push((BString::from(entry.name.as_str()), hash));
+    }
+
+    reftable::update_refs(base_path, updates)
+}
+
+fn get_loose_refs(base_path: &Path, current_path: &str) -> Result<Vec<GitRef>, GitRwError> {
     let mut result: Vec<GitRef> = Vec::new();
 
     let full_path = base_path.join(current_path);
-    for dir_entry in std::fs::read_dir(full_path).unwrap().map(|x| x.unwrap()) {
-        let file_type = dir_entry.file_type().unwrap();
+    for dir_entry in fs::read_dir(&full_path).map_err(GitRwError::io(&full_path))? {
+        let dir_entry = dir_entry.map_err(GitRwError::io(&full_path))?;
+        let entry_path = dir_entry.path();
+        let file_type = dir_entry.file_type().map_err(GitRwError::io(&entry_path))?;
+        let Some(file_name) = dir_entry.file_name().to_str().map(str::to_owned) else {
+            return Err(GitRwError::corrupt(
+                &entry_path,
+                "ref name is not valid UTF-8",
+            ));
+        };
+
+        let name = format!("{current_path}/{file_name}");
         if file_type.is_dir() {
-            let mut next_path = String::new();
-            next_path.push_str(current_path);
-            next_path.push('/');
-            next_path.push_str(dir_entry.path().file_name().unwrap().to_str().unwrap());
-            result.append(&mut get_loose_refs(base_path, &next_path));
+            result.append(&mut get_loose_refs(base_path, &name)?);
         } else {
             let hash = BString::from(
-                std::fs::read_to_string(&dir_entry.path())
-                    .unwrap()
+                fs::read_to_string(&entry_path)
+                    .map_err(GitRwError::io(&entry_path))?
                     .trim_end(),
             );
 
-            let mut name = String::new();
-            name.push_str(current_path);
-            name.push('/');
-            name.push_str(dir_entry.file_name().to_str().unwrap());
-
             if !hash.starts_with(b"ref: ") {
                 result.push(GitRef::Simple(SimpleRef {
                     name: BString::from(name),
@@ -212,13 +1080,34 @@ fn get_loose_refs(base_path: &Path, current_path: &str) -> Vec<GitRef> {
         }
     }
 
-    result
+    Ok(result)
 }
 
-fn get_packed_refs(lines: &mut ByteLines<BufReader<File>>) -> Result<Vec<GitRef>, Box<dyn Error>> {
+/// Splits a `<hash> <name>` line of `packed-refs`.
+fn split_packed_ref(path: &Path, line: &[u8]) -> Result<(BString, BString), GitRwError> {
+    if line.len() <= 41 || line[40] != b' ' {
+        return Err(GitRwError::corrupt(
+            path,
+            format!("malformed line '{}'", line.as_bstr()),
+        ));
+    }
+
+    Ok((
+        line[..40].as_bstr().to_owned(),
+        line[41..].as_bstr().to_owned(),
+    ))
+}
+
+fn get_packed_refs(
+    path: &Path,
+    lines: &mut ByteLines<BufReader<File>>,
+) -> Result<Vec<GitRef>, GitRwError> {
     let mut result: Vec<GitRef> = Vec::new();
 
-    let mut previous_line = Some(lines.next().unwrap().unwrap());
+    let Some(first_line) = lines.next() else {
+        return Ok(result);
+    };
+    let mut previous_line = Some(first_line.map_err(GitRwError::io(path))?);
     let mut line_started = previous_line
         .as_ref()
         .map(|x| !x.starts_with(b"#"))
@@ -227,10 +1116,10 @@ fn get_packed_refs(lines: &mut ByteLines<BufReader<File>>) -> Result<Vec<GitRef>
     for current_line in lines.by_ref().flatten() {
         if current_line.starts_with(b"^") {
             if let Some(x) = previous_line.take() {
-                let split = x.split_at(41);
+                let (hash, name) = split_packed_ref(path, &x)?;
                 result.push(GitRef::Tag(TagRef {
-                    hash: split.0[0..split.0.len() - 1].as_bstr().to_owned(),
-                    name: split.1.as_bstr().to_owned(),
+                    hash,
+                    name,
                     obj_hash: current_line.split_at(1).1.as_bstr().to_owned(),
                 }));
             };
@@ -239,11 +1128,8 @@ fn get_packed_refs(lines: &mut ByteLines<BufReader<File>>) -> Result<Vec<GitRef>
         } else {
             if line_started {
                 if let Some(x) = previous_line.take() {
-                    let split = x.split_at(41);
-                    result.push(GitRef::Simple(SimpleRef {
-                        hash: split.0[0..split.0.len() - 1].as_bstr().to_owned(),
-                        name: split.1.as_bstr().to_owned(),
-                    }));
+                    let (hash, name) = split_packed_ref(path, &x)?;
+                    result.push(GitRef::Simple(SimpleRef { hash, name }));
                 };
             }
 
@@ -253,11 +1139,10 @@ fn get_packed_refs(lines: &mut ByteLines<BufReader<File>>) -> Result<Vec<GitRef>
     }
 
     if line_started {
-        let previous_line = previous_line.unwrap();
-        let split = previous_line.split_at(41);
-        let hash = split.0[..split.0.len() - 1].as_bstr().to_owned();
-        let name = split.1.as_bstr().to_owned();
-        result.push(GitRef::Simple(SimpleRef { hash, name }));
+        if let Some(previous_line) = previous_line {
+            let (hash, name) = split_packed_ref(path, &previous_line)?;
+            result.push(GitRef::Simple(SimpleRef { hash, name }));
+        }
     }
 
     Ok(result)
@@ -266,11 +1151,153 @@ fn get_packed_refs(lines: &mut ByteLines<BufReader<File>>) -> Result<Vec<GitRef>
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::test_repository::TestRepository;
 
     #[test]
     #[cfg_attr(miri, ignore)]
     fn read_packed_refs() {
-        let test = GitRef::read_all(std::path::Path::new(".git")).expect("Cannot read file");
-        dbg!(test);
+        let fixture = TestRepository::create("packed-refs");
+        let refs = GitRef::read_all(&fixture.path).expect("Cannot read file");
+        let names: Vec<&BStr> = refs.iter().map(|r| r.get_name()).collect();
+
+        assert!(fixture.path.join("packed-refs").is_file());
+        assert_eq!(
+            vec![
+                "refs/heads/feature",
+                "refs/heads/main",
+                "refs/tags/light",
+                "refs/tags/v1"
+            ],
+            names
+        );
+    }
+
+    #[test]
+    fn ref_patterns() {
+        assert!(glob_match(b"refs/heads/main", b"refs/heads/main"));
+        assert!(!glob_match(b"refs/heads/main", b"refs/heads/main2"));
+        assert!(glob_match(b"refs/pull/*", b"refs/pull/12/head"));
+        assert!(glob_match(
+            b"refs/tags/release-*-old",
+            b"refs/tags/release-1.0-old"
+        ));
+        assert!(!glob_match(
+            b"refs/tags/release-*-old",
+            b"refs/tags/release-1.0"
+        ));
+        assert!(glob_match(b"*", b"refs/heads/main"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn renamed_refs_must_not_exist() {
+        let rename: RefRename = "refs/heads/:refs/heads/archive/".parse().unwrap();
+        assert_eq!(
+            Some(String::from("refs/heads/archive/main")),
+            rename.apply(b"refs/heads/main")
+        );
+        assert_eq!(None, rename.apply(b"refs/tags/v1"));
+        assert!("refs/heads/".parse::<RefRename>().is_err());
+        assert!("heads/:refs/archive/".parse::<RefRename>().is_err());
+
+        let dir = std::env::temp_dir().join(format!("gitrw-ref-rename-{}", std::process::id()));
+        fs::create_dir_all(dir.join("refs/heads")).unwrap();
+        fs::write(dir.join("refs/heads/main"), "a\n").unwrap();
+        let options = RefOptions {
+            rename: vec![rename],
+            ..Default::default()
+        };
+        let free = check_renames(&dir, &options);
+        fs::create_dir_all(dir.join("refs/heads/archive")).unwrap();
+        fs::write(dir.join("refs/heads/archive/main"), "b\n").unwrap();
+        let taken = check_renames(&dir, &options);
+        let output_free = check_output_ref(&dir, "refs/heads/cleaned");
+        let output_taken = check_output_ref(&dir, "refs/heads/main");
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(output_free.is_ok());
+        assert!(output_taken.is_err());
+        assert!(check_output_ref(&dir, "cleaned").is_err());
+
+        assert!(free.is_ok());
+        assert!(taken
+            .unwrap_err()
+            .to_string()
+            .contains("refs/heads/archive/main"));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn refs_without_object_hash_are_skipped() {
+        let dir = std::env::temp_dir().join(format!("gitrw-ref-garbage-{}", std::process::id()));
+        fs::create_dir_all(dir.join("refs/heads")).unwrap();
+        fs::create_dir_all(dir.join("objects/pack")).unwrap();
+        fs::write(dir.join("refs/heads/broken"), "garbage\n").unwrap();
+        let pack_reader = PackReader::create(&dir).unwrap();
+        let skipped = SkipTracker::new();
+        let commits = RefSnapshot::read(&dir).unwrap().commits(
+            &mut Decompression::default(),
+            &dir,
+            &pack_reader,
+            &skipped,
+        );
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(commits.is_empty());
+        let report = skipped.report();
+        assert_eq!(
+            vec![String::from("refs/heads/broken")],
+            report.skipped["ref target is no object hash"].examples
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn output_ref_keeps_packed_refs() {
+        let fixture = TestRepository::create("output-ref");
+        let dir = fixture.path.clone();
+        let head = fixture.git(&["rev-parse", "HEAD"]);
+        let packed_refs = fs::read(dir.join("packed-refs")).unwrap();
+
+        let options = RefOptions {
+            output_ref: Some(String::from("refs/heads/cleaned")),
+            ..Default::default()
+        };
+        let updates = fixture
+            .open()
+            .update_refs(&FxHashMap::default(), &options, false);
+        let cleaned = fs::read_to_string(dir.join("refs/heads/cleaned")).unwrap();
+
+        assert_eq!(5, updates.unwrap().len());
+        assert_eq!(packed_refs, fs::read(dir.join("packed-refs")).unwrap());
+        assert_eq!(head, cleaned.trim());
+        assert_eq!(
+            "refs/heads/cleaned\nrefs/heads/feature\nrefs/heads/main\nrefs/tags/light\nrefs/tags/v1",
+            fixture.git(&["for-each-ref", "--format=%(refname)"])
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn altered_history_requires_permission() {
+        let dir = std::env::temp_dir().join(format!("gitrw-altered-{}", std::process::id()));
+        fs::create_dir_all(dir.join("refs/replace")).unwrap();
+        fs::create_dir_all(dir.join("info")).unwrap();
+        assert!(altered_history(&dir).unwrap().is_empty());
+
+        fs::write(dir.join("info/grafts"), "a b\n").unwrap();
+        fs::write(dir.join("shallow"), "a\n").unwrap();
+        let altered = altered_history(&dir).unwrap();
+        let checked = check_altered_history(&dir, &RefOptions::default());
+        let allowed = RefOptions {
+            allow_altered_history: true,
+            ..Default::default()
+        };
+        let allowed = check_altered_history(&dir, &allowed);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(2, altered.len());
+        assert!(checked.is_err());
+        assert!(allowed.is_ok());
     }
 }
@@ -0,0 +1,569 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use bstr::BString;
+
+use crate::{error::GitRwError, shared::ObjectHash};
+
+const HASH_LEN: usize = 20;
+/// `sha1` in the header of version 2 tables
+const SHA1_ID: u32 = 0x7368_6131;
+const REF_BLOCK: u8 = b'r';
+/// Block size of the tables gitrw writes, the same as git uses by default
+const BLOCK_SIZE: usize = 4096;
+/// Every this many records a record stores its full name, which readers can seek to
+const RESTART_INTERVAL: usize = 16;
+
+/// What a ref record stores for its name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RefValue {
+    /// The ref was deleted, which hides it in all older tables
+    Deletion,
+    Object(ObjectHash),
+    /// An annotated tag and the object it peels to
+    Peeled(ObjectHash, ObjectHash),
+    Symbolic(BString),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RefRecord {
+    pub(crate) name: BString,
+    pub(crate) update_index: u64,
+    pub(crate) value: RefValue,
+}
+
+/// Repositories with `extensions.refstorage = reftable` keep their refs in the tables listed in
+/// `reftable/tables.list` instead of `refs/` and `packed-refs`.
+pub(crate) fn is_reftable(base_path: &Path) -> bool {
+    base_path.join("reftable").join("tables.list").is_file()
+}
+
+/// All refs of the repository by name. The tables are applied oldest first, so newer records
+/// replace older ones and deleted refs are left out.
+pub(crate) fn read_refs(base_path: &Path) -> Result<BTreeMap<BString, RefValue>, GitRwError> {
+    let reftable_dir = base_path.join("reftable");
+    let list_path = reftable_dir.join("tables.list");
+    let tables = fs::read_to_string(&list_path).map_err(GitRwError::io(&list_path))?;
+
+    let mut refs = BTreeMap::new();
+    for table in tables.lines().filter(|line| !line.is_empty()) {
+        let table_path = reftable_dir.join(table);
+        let bytes = fs::read(&table_path).map_err(GitRwError::io(&table_path))?;
+        let records =
+            read_table(&bytes).map_err(|reason| GitRwError::corrupt(&table_path, reason))?;
+
+        for record in records {
+            match record.value {
+                RefValue::Deletion => refs.remove(&record.name),
+                value => refs.insert(record.name, value),
+            };
+        }
+    }
+
+    Ok(refs)
+}
+
+/// Sets or deletes refs in one step: all changes go into a single new table, which only becomes
+/// visible when `tables.list` is replaced while it is locked.
+pub(crate) fn update_refs(
+    base_path: &Path,
+    updates: impl IntoIterator<Item = (BString, Option<ObjectHash>)>,
+) -> Result<(), GitRwError> {
+    let updates: BTreeMap<BString, Option<ObjectHash>> = updates.into_iter().collect();
+    if updates.is_empty() {
+        return Ok(());
+    }
+
+    let reftable_dir = base_path.join("reftable");
+    let list_path = reftable_dir.join("tables.list");
+    let lock_path = reftable_dir.join("tables.list.lock");
+    let mut lock = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock_path)
+        .map_err(GitRwError::io(&lock_path))?;
+
+    let written = (|| -> Result<(), GitRwError> {
+        let mut tables: Vec<String> = fs::read_to_string(&list_path)
+            .map_err(GitRwError::io(&list_path))?
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        let update_index = match tables.last() {
+            Some(table) => {
+                let path = reftable_dir.join(table);
+                let header = fs::read(&path).map_err(GitRwError::io(&path))?;
+                read_be(&header, 16, 8).ok_or_else(|| GitRwError::corrupt(&path, "truncated"))? + 1
+            }
+            None => 1,
+        };
+
+        let records: Vec<RefRecord> = updates
+            .into_iter()
+            .map(|(name, hash)| RefRecord {
+                name,
+                update_index,
+                value: hash.map_or(RefValue::Deletion, RefValue::Object),
+            })
+            .collect();
+        let table = write_table(&records, update_index);
+        let name = format!(
+            "0x{update_index:012x}-0x{update_index:012x}-{:08x}.ref",
+            crc32fast::hash(&table)
+        );
+        let temp_path = reftable_dir.join(format!("{name}.tmp"));
+        fs::write(&temp_path, &table).map_err(GitRwError::io(&temp_path))?;
+        fs::rename(&temp_path, reftable_dir.join(&name)).map_err(GitRwError::io(&temp_path))?;
+
+        tables.push(name);
+        lock.write_all(format!("{}\n", tables.join("\n")).as_bytes())
+            .and_then(|_| lock.sync_all())
+            .and_then(|_| fs::rename(&lock_path, &list_path))
+            .map_err(GitRwError::io(&lock_path))
+    })();
+
+    if written.is_err() {
+        let _ = fs::remove_file(&lock_path);
+    }
+    written
+}
+
+/// A version 1 table of `records`, which have to be sorted by name, with aligned ref blocks and
+/// without indexes. All records get `update_index`.
+fn write_table(records: &[RefRecord], update_index: u64) -> Vec<u8> {
+    let mut header = b"REFT\x01".to_vec();
+    header.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+    header.extend_from_slice(&update_index.to_be_bytes());
+    header.extend_from_slice(&update_index.to_be_bytes());
+
+    let mut table = header.clone();
+    let mut block = RefBlock::default();
+    for record in records {
+        let mut encoded = block.encode(record, update_index);
+        if !block.fits(table.len(), encoded.len()) {
+            block.flush(&mut table);
+            block = RefBlock::default();
+            encoded = block.encode(record, update_index);
+        }
+        block.push(&encoded, &record.name, table.len());
+    }
+    if !block.restarts.is_empty() {
+        block.flush(&mut table);
+    }
+
+    let mut footer = header;
+    // no ref index, obj, obj index, log and log index sections
+    footer.extend_from_slice(&[0; 40]);
+    footer.extend_from_slice(&crc32fast::hash(&footer).to_be_bytes());
+    table.extend_from_slice(&footer);
+    table
+}
+
+/// A ref block while it is filled, see `write_table`.
+#[derive(Default)]
+struct RefBlock {
+    records: Vec<u8>,
+    /// Offsets of the restart records from the start of the block
+    restarts: Vec<usize>,
+    count: usize,
+    last_name: Vec<u8>,
+}
+
+impl RefBlock {
+    /// Every `RESTART_INTERVAL`th record stores its whole name, the others only the part that
+    /// differs from the name before.
+    fn encode(&self, record: &RefRecord, min_update_index: u64) -> Vec<u8> {
+        let prefix_len = if self.count.is_multiple_of(RESTART_INTERVAL) {
+            0
+        } else {
+            common_prefix(&self.last_name, &record.name)
+        };
+        let suffix = &record.name[prefix_len..];
+
+        let (value_type, value) = match &record.value {
+            RefValue::Deletion => (0, Vec::new()),
+            RefValue::Object(hash) => (1, hash.bytes.to_vec()),
+            RefValue::Peeled(hash, peeled) => (2, [hash.bytes, peeled.bytes].concat()),
+            RefValue::Symbolic(target) => {
+                let mut value = Vec::new();
+                write_varint(&mut value, target.len() as u64);
+                value.extend_from_slice(target);
+                (3, value)
+            }
+        };
+
+        let mut encoded = Vec::new();
+        write_varint(&mut encoded, prefix_len as u64);
+        write_varint(&mut encoded, (suffix.len() as u64) << 3 | value_type);
+        encoded.extend_from_slice(suffix);
+        write_varint(&mut encoded, record.update_index - min_update_index);
+        encoded.extend_from_slice(&value);
+        encoded
+    }
+
+    /// Offset of the first record from the start of the block. The first block starts with the
+    /// file header.
+    fn records_offset(table_len: usize) -> usize {
+        match table_len {
+            24 => 28,
+            _ => 4,
+        }
+    }
+
+    fn fits(&self, table_len: usize, record_len: usize) -> bool {
+        let restarts =
+            self.restarts.len() + usize::from(self.count.is_multiple_of(RESTART_INTERVAL));
+        self.restarts.is_empty()
+            || Self::records_offset(table_len) + self.records.len() + record_len + restarts * 3 + 2
+                <= BLOCK_SIZE
+    }
+
+    fn push(&mut self, encoded: &[u8], name: &[u8], table_len: usize) {
+        if self.count.is_multiple_of(RESTART_INTERVAL) {
+            self.restarts
+                .push(Self::records_offset(table_len) + self.records.len());
+        }
+        self.records.extend_from_slice(encoded);
+        self.count += 1;
+        self.last_name = name.to_owned();
+    }
+
+    /// Appends the block to the table, padded to `BLOCK_SIZE`.
+    fn flush(&self, table: &mut Vec<u8>) {
+        let block_start = if table.len() == 24 { 0 } else { table.len() };
+        let mut block = vec![REF_BLOCK, 0, 0, 0];
+        block.extend_from_slice(&self.records);
+        for restart in self.restarts.iter() {
+            block.extend_from_slice(&(*restart as u32).to_be_bytes()[1..]);
+        }
+        block.extend_from_slice(&(self.restarts.len() as u16).to_be_bytes());
+
+        let block_len = table.len() - block_start + block.len();
+        block[1..4].copy_from_slice(&(block_len as u32).to_be_bytes()[1..]);
+        table.extend_from_slice(&block);
+        if block_len < BLOCK_SIZE {
+            table.resize(block_start + BLOCK_SIZE, 0);
+        }
+    }
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+/// Counterpart of `read_varint`.
+fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    let mut encoded = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        encoded.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    bytes.extend(encoded.iter().rev());
+}
+
+/// Size of the header of a table, the footer repeats it.
+fn header_len(version: u8) -> usize {
+    match version {
+        1 => 24,
+        _ => 28,
+    }
+}
+
+/// Reads all ref records of a single table in the order they are stored, sorted by name.
+pub(crate) fn read_table(bytes: &[u8]) -> Result<Vec<RefRecord>, &'static str> {
+    if bytes.get(0..4) != Some(b"REFT") {
+        return Err("not a reftable");
+    }
+
+    let version = bytes[4];
+    if version != 1 && version != 2 {
+        return Err("unsupported reftable version");
+    }
+    let header_len = header_len(version);
+    let footer_len = header_len + 44;
+    if bytes.len() < header_len + footer_len {
+        return Err("reftable is truncated");
+    }
+    if version == 2 && read_be(bytes, 24, 4) != Some(SHA1_ID as u64) {
+        return Err("only reftables with SHA-1 hashes are supported");
+    }
+
+    let footer_start = bytes.len() - footer_len;
+    let footer = &bytes[footer_start..];
+    if footer[..header_len] != bytes[..header_len] {
+        return Err("reftable footer does not match the header");
+    }
+    let crc = read_be(footer, footer_len - 4, 4).unwrap() as u32;
+    if crc32fast::hash(&footer[..footer_len - 4]) != crc {
+        return Err("reftable footer checksum mismatch");
+    }
+
+    let min_update_index = read_be(bytes, 8, 8).unwrap();
+    let mut records = Vec::new();
+    // the first block starts with the file header, its length includes the header
+    let mut block_start = 0;
+    let mut type_offset = header_len;
+    while type_offset < footer_start && bytes[type_offset] == REF_BLOCK {
+        let block_len = read_be(bytes, type_offset + 1, 3).ok_or("reftable is truncated")?;
+        let block_end = block_start + block_len as usize;
+        if block_end > footer_start || block_end < type_offset + 6 {
+            return Err("invalid reftable block length");
+        }
+
+        let restart_count = read_be(bytes, block_end - 2, 2).unwrap() as usize;
+        let records_end = (block_end - 2)
+            .checked_sub(restart_count * 3)
+            .filter(|end| *end >= type_offset + 4)
+            .ok_or("invalid reftable restart count")?;
+        read_ref_records(
+            &bytes[type_offset + 4..records_end],
+            min_update_index,
+            &mut records,
+        )?;
+
+        // aligned tables pad their blocks with zeros
+        block_start = block_end;
+        while block_start < footer_start && bytes[block_start] == 0 {
+            block_start += 1;
+        }
+        type_offset = block_start;
+    }
+
+    Ok(records)
+}
+
+fn read_ref_records(
+    mut bytes: &[u8],
+    min_update_index: u64,
+    records: &mut Vec<RefRecord>,
+) -> Result<(), &'static str> {
+    let mut name: Vec<u8> = Vec::new();
+    while !bytes.is_empty() {
+        let prefix_len = read_varint(&mut bytes)? as usize;
+        let suffix_and_type = read_varint(&mut bytes)?;
+        let suffix_len = (suffix_and_type >> 3) as usize;
+        if prefix_len > name.len() {
+            return Err("invalid reftable ref name prefix");
+        }
+        name.truncate(prefix_len);
+        name.extend_from_slice(take(&mut bytes, suffix_len)?);
+
+        let update_index = min_update_index + read_varint(&mut bytes)?;
+        let value = match suffix_and_type & 0x7 {
+            0 => RefValue::Deletion,
+            1 => RefValue::Object(read_hash(&mut bytes)?),
+            2 => RefValue::Peeled(read_hash(&mut bytes)?, read_hash(&mut bytes)?),
+            3 => {
+                let target_len = read_varint(&mut bytes)? as usize;
+                RefValue::Symbolic(take(&mut bytes, target_len)?.into())
+            }
+            _ => return Err("unknown reftable ref value type"),
+        };
+
+        records.push(RefRecord {
+            name: name.clone().into(),
+            update_index,
+            value,
+        });
+    }
+
+    Ok(())
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8], &'static str> {
+    if bytes.len() < len {
+        return Err("reftable record is truncated");
+    }
+
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn read_hash(bytes: &mut &[u8]) -> Result<ObjectHash, &'static str> {
+    ObjectHash::try_from(take(bytes, HASH_LEN)?).map_err(|_| "invalid reftable object id")
+}
+
+/// Big endian number of `len` bytes.
+fn read_be(bytes: &[u8], offset: usize, len: usize) -> Option<u64> {
+    let bytes = bytes.get(offset..offset + len)?;
+    Some(
+        bytes
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as u64),
+    )
+}
+
+/// The varint of reftables: every continuation adds one before shifting, like the offsets of
+/// `OFS_DELTA` pack entries.
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, &'static str> {
+    let mut value = 0u64;
+    loop {
+        let byte = *take(bytes, 1)?.first().unwrap();
+        if value >> 57 != 0 {
+            return Err("reftable varint overflows");
+        }
+        value = value << 7 | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        value += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{read_refs, read_table, update_refs, write_table, RefRecord, RefValue, BLOCK_SIZE};
+    use crate::ObjectHash;
+
+    /// Version 1 table with a single unaligned ref block of the given records, which are
+    /// `(prefix length, suffix, value type, value)`.
+    fn table(update_index: u64, records: &[(u8, &str, u8, Vec<u8>)]) -> Vec<u8> {
+        let mut header = b"REFT\x01\x00\x10\x00".to_vec();
+        header.extend_from_slice(&update_index.to_be_bytes());
+        header.extend_from_slice(&update_index.to_be_bytes());
+
+        let mut block = Vec::new();
+        for (prefix_len, suffix, value_type, value) in records {
+            block.push(*prefix_len);
+            block.push((suffix.len() as u8) << 3 | value_type);
+            block.extend_from_slice(suffix.as_bytes());
+            block.push(0);
+            block.extend_from_slice(value);
+        }
+        // a single restart point at the first record, right after the block header
+        block.extend_from_slice(&[0, 0, 28, 0, 1]);
+
+        let mut table = header.clone();
+        table.push(b'r');
+        let block_len = (table.len() + 3 + block.len()) as u32;
+        table.extend_from_slice(&block_len.to_be_bytes()[1..]);
+        table.extend_from_slice(&block);
+
+        let mut footer = header;
+        footer.extend_from_slice(&[0; 40]);
+        footer.extend_from_slice(&crc32fast::hash(&footer).to_be_bytes());
+        table.extend_from_slice(&footer);
+        table
+    }
+
+    fn hash(byte: u8) -> ObjectHash {
+        ObjectHash::from([byte; 20])
+    }
+
+    #[test]
+    fn refs_from_tables() {
+        let peeled = [[0x22; 20], [0x11; 20]].concat();
+        let first = table(
+            1,
+            &[
+                (0, "HEAD", 3, b"\x0frefs/heads/main".to_vec()),
+                (0, "refs/heads/main", 1, vec![0x11; 20]),
+                (11, "old", 1, vec![0x33; 20]),
+                (5, "tags/v1", 2, peeled),
+            ],
+        );
+        let second = table(2, &[(0, "refs/heads/old", 0, Vec::new())]);
+
+        let records = read_table(&first).unwrap();
+        assert_eq!(4, records.len());
+        assert_eq!("refs/heads/old", records[2].name);
+        assert_eq!(1, records[2].update_index);
+
+        let dir = std::env::temp_dir().join(format!("gitrw-reftable-{}", std::process::id()));
+        fs::create_dir_all(dir.join("reftable")).unwrap();
+        fs::write(dir.join("reftable/first.ref"), &first).unwrap();
+        fs::write(dir.join("reftable/second.ref"), &second).unwrap();
+        fs::write(dir.join("reftable/tables.list"), "first.ref\nsecond.ref\n").unwrap();
+        let refs = read_refs(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let refs: Vec<(String, RefValue)> = refs
+            .unwrap()
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    String::from("HEAD"),
+                    RefValue::Symbolic("refs/heads/main".into())
+                ),
+                (
+                    String::from("refs/heads/main"),
+                    RefValue::Object(hash(0x11))
+                ),
+                (
+                    String::from("refs/tags/v1"),
+                    RefValue::Peeled(hash(0x22), hash(0x11))
+                ),
+            ],
+            refs
+        );
+
+        let mut corrupt = first.clone();
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 1;
+        assert!(read_table(&corrupt).is_err());
+    }
+
+    #[test]
+    fn written_tables_round_trip() {
+        let records: Vec<RefRecord> = (0..500)
+            .map(|i| RefRecord {
+                name: format!("refs/heads/branch-{i:04}").into(),
+                update_index: 7,
+                value: match i % 3 {
+                    0 => RefValue::Object(hash(i as u8)),
+                    1 => RefValue::Peeled(hash(i as u8), hash(1)),
+                    _ => RefValue::Symbolic("refs/heads/main".into()),
+                },
+            })
+            .collect();
+        let table = write_table(&records, 7);
+        assert_eq!(0, (table.len() - 68) % BLOCK_SIZE);
+        assert!(table.len() > 4 * BLOCK_SIZE);
+        assert_eq!(records, read_table(&table).unwrap());
+
+        let dir = std::env::temp_dir().join(format!("gitrw-reftable-write-{}", std::process::id()));
+        fs::create_dir_all(dir.join("reftable")).unwrap();
+        fs::write(dir.join("reftable/base.ref"), &table).unwrap();
+        fs::write(dir.join("reftable/tables.list"), "base.ref\n").unwrap();
+        let updated = update_refs(
+            &dir,
+            [
+                ("refs/heads/branch-0000".into(), Some(hash(0xff))),
+                ("refs/heads/branch-0001".into(), None),
+            ],
+        );
+        let refs = read_refs(&dir);
+        let tables = fs::read_to_string(dir.join("reftable/tables.list"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        updated.unwrap();
+        let refs = refs.unwrap();
+        assert_eq!(499, refs.len());
+        assert_eq!(
+            Some(&RefValue::Object(hash(0xff))),
+            refs.get(b"refs/heads/branch-0000".as_slice())
+        );
+        assert!(!refs.contains_key(b"refs/heads/branch-0001".as_slice()));
+        let tables = tables.unwrap();
+        assert_eq!(2, tables.lines().count());
+        assert!(tables
+            .lines()
+            .last()
+            .unwrap()
+            .starts_with("0x000000000008-"));
+    }
+}
@@ -0,0 +1,617 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    fs::File,
+    hash::BuildHasher,
+    path::{Path, PathBuf},
+    sync::{mpsc::channel, OnceLock},
+};
+
+use rayon::prelude::{IntoParallelIterator, ParallelBridge, ParallelIterator};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    commit_cache::CommitCache,
+    commit_filter::CommitFilter,
+    commit_map::{CommitMapFormat, CommitMapWriter},
+    dry_run,
+    message_hashes::{HashIndex, MessageHashes},
+    objs::{CommitBase, CommitEditable, CommitHash, TreeHash},
+    refs::RefOptions,
+    rewrite_state::DROPPED,
+    GitRwError, Repository, WriteObject,
+};
+
+/// What `Repository::rewrite_history` does with a commit after the commit callback ran.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommitAction {
+    /// Write the commit if anything about it changed
+    Keep,
+    /// Remove the commit from the history. Its children continue on its first parent, or become
+    /// root commits if it did not have any.
+    Drop,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RewriteOptions {
+    pub dry_run: bool,
+    /// Record the original hash of every rewritten commit as a git note in `refs/notes/gitrw`
+    pub write_notes: bool,
+    pub ref_options: RefOptions,
+    /// Format of the file with the mapping of all rewritten commits
+    pub map_format: CommitMapFormat,
+    /// Before updating the refs, re-hash every commit that is kept as it is and check that none
+    /// of its parents was rewritten, see `verify_unchanged`
+    pub verify_unchanged: bool,
+    /// Regenerate `info/refs` and `objects/info/packs` for dumb HTTP servers after updating the
+    /// refs, which always happens if the repository already has one of them
+    pub update_server_info: bool,
+    /// Remove the signatures of the commits that are changed, see
+    /// `CommitEditable::set_strip_signature`
+    pub strip_signatures: bool,
+    /// Committer date in seconds since the epoch of the commits that are changed, usually the
+    /// time the rewrite started. `None` keeps their committer dates, see
+    /// `CommitEditable::set_refresh_committer_date`
+    pub refresh_committer_date: Option<i64>,
+    /// Only the commits matching the filter are modified, the others keep their tree and
+    /// metadata and only get their rewritten parents, see `RewriteOptions::select_commit`
+    pub commit_filter: Option<CommitFilter>,
+    /// Update the hashes of rewritten commits mentioned in commit messages, see
+    /// `Repository::set_message_hashes`
+    pub message_hashes: Option<MessageHashes>,
+    /// Continue an interrupted rewrite from the commit mapping it left in this file, see
+    /// `Repository::set_state_file`
+    pub state_file: Option<PathBuf>,
+}
+
+impl RewriteOptions {
+    /// Whether the commit callback may modify the commit. Commits that do not match the
+    /// `commit_filter` get their original tree back, callers keep them without any other change.
+    pub fn select_commit(&self, commit: &mut CommitEditable) -> bool {
+        match &self.commit_filter {
+            Some(filter) if !filter.matches(commit.base()) => {
+                commit.reset_tree();
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+struct OrderedCommit {
+    commit: CommitBase,
+    new_tree: Option<TreeHash>,
+    index: usize,
+}
+
+impl Eq for OrderedCommit {}
+
+impl PartialEq for OrderedCommit {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl PartialOrd for OrderedCommit {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedCommit {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.index.cmp(&other.index)
+    }
+}
+
+/// Walks all commits in topological order. `tree_cb` runs in parallel for many commits at once,
+/// `commit_cb` runs strictly in order with the parents already pointing to their rewritten
+/// versions, and only for the commits `options` selects. Returns the mapping of all rewritten
+/// commits, old to new, or the first error of `tree_cb` or of writing a commit.
+pub(crate) fn rewrite_commits<C, T>(
+    repository: &Repository,
+    options: &RewriteOptions,
+    mut commit_cb: C,
+    tree_cb: T,
+) -> Result<FxHashMap<CommitHash, CommitHash>, GitRwError>
+where
+    C: FnMut(&mut CommitEditable) -> CommitAction + Send,
+    T: Fn(&mut Repository, TreeHash) -> Result<Option<TreeHash>, GitRwError> + Sync,
+{
+    let writer = CommitWriter::create(repository, options);
+    let (mut rewritten_commits, mut dropped_commits, mut progress) =
+        Progress::create(repository, options.dry_run)?;
+    let resumed = repository.rewrite_state.as_deref();
+    // stops both the walk and the commit writing, the commits written before stay mapped
+    let failure: OnceLock<GitRwError> = OnceLock::new();
+
+    // the repository itself is not Sync, every thread works on its own clone
+    let walk_repository = repository.clone();
+    let tree_cb = &tree_cb;
+    let failure_ref = &failure;
+    let rewritten_commits_ref = &mut rewritten_commits;
+    rayon::scope(move |scope| {
+        let (tx, rx) = channel::<OrderedCommit>();
+        let rewritten_commits = rewritten_commits_ref;
+        let failure = failure_ref;
+        scope.spawn(move |_| {
+            let mut process = |ordered_commit: OrderedCommit| {
+                if failure.get().is_some()
+                    || resumed.is_some_and(|state| state.contains(&ordered_commit.commit.hash))
+                {
+                    return;
+                }
+                if let Err(e) = update_commit(
+                    writer,
+                    CommitEditable::create(ordered_commit.commit),
+                    ordered_commit.new_tree,
+                    rewritten_commits,
+                    &mut dropped_commits,
+                    &mut progress,
+                    &mut commit_cb,
+                ) {
+                    let _ = failure.set(e);
+                }
+            };
+
+            let mut heap: BinaryHeap<Reverse<OrderedCommit>> = BinaryHeap::new();
+            let mut commit_index = 0usize;
+            for ordered_commit in rx.into_iter() {
+                if ordered_commit.index == commit_index {
+                    commit_index += 1;
+                    process(ordered_commit);
+
+                    while let Some(commit) = heap.pop() {
+                        if commit.0.index == commit_index {
+                            commit_index += 1;
+                            process(commit.0);
+                        } else {
+                            heap.push(commit);
+                            break;
+                        }
+                    }
+                } else {
+                    heap.push(Reverse(ordered_commit));
+                }
+            }
+        });
+
+        let cancel = walk_repository.cancel.clone();
+        walk_repository
+            .commits_topo()
+            .take_while(|_| !cancel.is_cancelled() && failure.get().is_none())
+            .enumerate()
+            .par_bridge()
+            .for_each_with(
+                (walk_repository.clone(), tx),
+                |(repository, tx), (index, commit)| {
+                    let new_tree = if resumed.is_some_and(|state| state.contains(&commit.hash)) {
+                        None
+                    } else {
+                        match tree_cb(repository, commit.tree()) {
+                            Ok(new_tree) => new_tree,
+                            // the commit writing waits for this index and never gets past it
+                            Err(e) => {
+                                let _ = failure.set(e);
+                                return;
+                            }
+                        }
+                    };
+                    tx.send(OrderedCommit {
+                        commit,
+                        new_tree,
+                        index,
+                    })
+                    .unwrap();
+                },
+            );
+    });
+
+    match failure.into_inner() {
+        Some(e) => Err(e),
+        None => Ok(rewritten_commits),
+    }
+}
+
+/// Number of commits that are read ahead and split into levels by `rewrite_commits_parallel`.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Like `rewrite_commits` without tree callback, but `commit_cb` runs in parallel: commits are
+/// read in chunks and every chunk is split into levels of commits that do not depend on each
+/// other. All commits of a level are rewritten, hashed and written at once, the commit mapping
+/// is only updated between the levels.
+pub(crate) fn rewrite_commits_parallel<C>(
+    repository: &Repository,
+    options: &RewriteOptions,
+    commit_cb: C,
+) -> Result<FxHashMap<CommitHash, CommitHash>, GitRwError>
+where
+    C: Fn(&mut CommitEditable) -> CommitAction + Sync,
+{
+    let writer = CommitWriter::create(repository, options);
+    let (mut rewritten_commits, mut dropped_commits, mut progress) =
+        Progress::create(repository, options.dry_run)?;
+    let resumed = repository.rewrite_state.as_deref();
+
+    let mut commits = repository
+        .commits_topo()
+        .filter(|commit| !resumed.is_some_and(|state| state.contains(&commit.hash)));
+    loop {
+        let mut levels: Vec<Vec<CommitBase>> = Vec::new();
+        let mut commit_levels: FxHashMap<CommitHash, usize> = FxHashMap::default();
+        for commit in commits.by_ref().take(CHUNK_SIZE) {
+            // parents outside of this chunk are already part of the mapping
+            let level = commit
+                .parents()
+                .iter()
+                .filter_map(|parent| commit_levels.get(parent))
+                .max()
+                .map_or(0, |level| level + 1);
+            commit_levels.insert(commit.hash.clone(), level);
+            if level == levels.len() {
+                levels.push(Vec::new());
+            }
+            levels[level].push(commit);
+        }
+
+        if levels.is_empty() {
+            break;
+        }
+
+        for level in levels {
+            // the mapping stays consistent as long as levels are either done completely or not
+            if repository.is_cancelled() {
+                return Ok(rewritten_commits);
+            }
+
+            let outcomes: Vec<(CommitHash, Outcome)> = level
+                .into_par_iter()
+                .map(|commit| {
+                    let old_hash = commit.hash.clone();
+                    let outcome = rewrite_commit(
+                        writer,
+                        CommitEditable::create(commit),
+                        None,
+                        &rewritten_commits,
+                        &dropped_commits,
+                        progress.hash_index.as_ref(),
+                        &commit_cb,
+                    )?;
+                    Ok((old_hash, outcome))
+                })
+                .collect::<Result<_, GitRwError>>()?;
+
+            for (old_hash, outcome) in outcomes {
+                record(
+                    old_hash,
+                    outcome,
+                    &mut rewritten_commits,
+                    &mut dropped_commits,
+                    &mut progress,
+                )?;
+            }
+        }
+    }
+
+    Ok(rewritten_commits)
+}
+
+/// What happened to a single commit, recorded into the commit mapping by `record`.
+enum Outcome {
+    Unchanged,
+    /// Dropped, children continue on the given commit or become root commits
+    Dropped(Option<CommitHash>),
+    Written(CommitHash),
+}
+
+/// Writes the changed commits of a rewrite. Commits found in the commit cache of the
+/// repository are neither serialized nor hashed again.
+#[derive(Clone, Copy)]
+struct CommitWriter<'a> {
+    repository_path: &'a Path,
+    commit_cache: Option<&'a CommitCache>,
+    /// Applied to every commit before the commit callback, see `CommitWriter::prepare`
+    options: &'a RewriteOptions,
+    dry_run: bool,
+}
+
+impl<'a> CommitWriter<'a> {
+    fn create(repository: &'a Repository, options: &'a RewriteOptions) -> Self {
+        CommitWriter {
+            repository_path: repository.path.as_path(),
+            commit_cache: repository.commit_cache.as_deref(),
+            options,
+            dry_run: options.dry_run,
+        }
+    }
+
+    /// Applies the options that concern every commit of a rewrite. Returns whether the commit
+    /// callback may modify the commit, see `RewriteOptions::select_commit`.
+    fn prepare(&self, commit: &mut CommitEditable) -> bool {
+        commit.set_strip_signature(self.options.strip_signatures);
+        commit.set_refresh_committer_date(self.options.refresh_committer_date);
+        self.options.select_commit(commit)
+    }
+
+    /// Returns the hash of the changed commit, which is only written if it differs from the
+    /// hash of the base commit.
+    fn write(&self, commit: CommitEditable) -> Result<CommitHash, GitRwError> {
+        if self.dry_run {
+            let base = commit.base();
+            for (old, new) in [
+                (base.author(), commit.author_bytes()),
+                (base.committer(), commit.committer_bytes()),
+            ] {
+                if old != new {
+                    dry_run::capture_contributor(self.repository_path, old, new);
+                }
+            }
+        }
+
+        let old_hash = commit.base_hash().clone();
+        let cached = self
+            .commit_cache
+            .map(|commit_cache| (commit_cache, commit.edit_key()));
+        if let Some((commit_cache, edit_key)) = &cached {
+            if let Some(new_hash) = commit_cache.get(&old_hash, edit_key, self.dry_run) {
+                return Ok(new_hash);
+            }
+        }
+
+        let write_object: WriteObject = commit.into();
+        let new_hash: CommitHash = write_object.hash.clone().into();
+        if new_hash != old_hash {
+            Repository::write(
+                self.repository_path.to_path_buf(),
+                write_object,
+                self.dry_run,
+            )?;
+        }
+
+        if let Some((commit_cache, edit_key)) = cached {
+            commit_cache.insert(old_hash, edit_key, new_hash.clone(), self.dry_run);
+        }
+        Ok(new_hash)
+    }
+}
+
+fn update_commit(
+    writer: CommitWriter,
+    commit: CommitEditable,
+    new_tree: Option<TreeHash>,
+    rewritten_commits: &mut FxHashMap<CommitHash, CommitHash>,
+    dropped_commits: &mut FxHashSet<CommitHash>,
+    progress: &mut Progress,
+    commit_cb: &mut impl FnMut(&mut CommitEditable) -> CommitAction,
+) -> Result<(), GitRwError> {
+    let old_hash = commit.base_hash().clone();
+    let outcome = rewrite_commit(
+        writer,
+        commit,
+        new_tree,
+        rewritten_commits,
+        dropped_commits,
+        progress.hash_index.as_ref(),
+        commit_cb,
+    )?;
+    record(
+        old_hash,
+        outcome,
+        rewritten_commits,
+        dropped_commits,
+        progress,
+    )
+}
+
+fn rewrite_commit(
+    writer: CommitWriter,
+    mut commit: CommitEditable,
+    new_tree: Option<TreeHash>,
+    rewritten_commits: &FxHashMap<CommitHash, CommitHash>,
+    dropped_commits: &FxHashSet<CommitHash>,
+    hash_index: Option<&HashIndex>,
+    commit_cb: impl FnOnce(&mut CommitEditable) -> CommitAction,
+) -> Result<Outcome, GitRwError> {
+    let old_hash = commit.base_hash().clone();
+
+    update_parents(&mut commit, rewritten_commits, dropped_commits);
+    if let Some(hash_index) = hash_index {
+        hash_index.update(&mut commit);
+    }
+    if let Some(new_tree) = new_tree {
+        if new_tree != commit.tree() {
+            commit.set_tree(new_tree);
+        }
+    }
+
+    if writer.prepare(&mut commit) && commit_cb(&mut commit) == CommitAction::Drop {
+        return Ok(Outcome::Dropped(commit.parents().into_iter().next()));
+    }
+
+    if commit.has_changes() {
+        let new_hash = writer.write(commit)?;
+        if new_hash != old_hash {
+            return Ok(Outcome::Written(new_hash));
+        }
+    }
+
+    Ok(Outcome::Unchanged)
+}
+
+fn record(
+    old_hash: CommitHash,
+    outcome: Outcome,
+    rewritten_commits: &mut FxHashMap<CommitHash, CommitHash>,
+    dropped_commits: &mut FxHashSet<CommitHash>,
+    progress: &mut Progress,
+) -> Result<(), GitRwError> {
+    match outcome {
+        Outcome::Unchanged => (),
+        Outcome::Dropped(None) => {
+            progress.dropped(&old_hash)?;
+            dropped_commits.insert(old_hash);
+        }
+        Outcome::Dropped(Some(new_hash)) | Outcome::Written(new_hash) => {
+            progress.rewritten(&old_hash, &new_hash)?;
+            rewritten_commits.insert(old_hash, new_hash);
+        }
+    }
+    Ok(())
+}
+
+/// Follows the commit mapping of a rewrite besides the mapping itself.
+struct Progress {
+    hash_index: Option<HashIndex>,
+    /// Path of the state file of `Repository::set_state_file` and the writer appending to it,
+    /// flushed when dropped
+    state: Option<(PathBuf, CommitMapWriter<File>)>,
+}
+
+impl Progress {
+    /// Returns the mapping and the dropped commits to start from, with the commits of the state
+    /// file if there is one. The state file is not written with `dry_run`.
+    #[allow(clippy::type_complexity)]
+    fn create(
+        repository: &Repository,
+        dry_run: bool,
+    ) -> Result<
+        (
+            FxHashMap<CommitHash, CommitHash>,
+            FxHashSet<CommitHash>,
+            Progress,
+        ),
+        GitRwError,
+    > {
+        let mut progress = Progress {
+            hash_index: repository.message_hashes.map(HashIndex::new),
+            state: None,
+        };
+        let (rewritten_commits, dropped_commits) = repository
+            .rewrite_state
+            .as_ref()
+            .map(|state| (state.rewritten.clone(), state.dropped.clone()))
+            .unwrap_or_default();
+        if let Some(hash_index) = progress.hash_index.as_mut() {
+            for (old_hash, new_hash) in rewritten_commits.iter() {
+                hash_index.insert(old_hash, new_hash);
+            }
+        }
+        if let Some(state) = repository.rewrite_state.as_ref().filter(|_| !dry_run) {
+            let path = state.path().to_path_buf();
+            let appender = state.appender().map_err(GitRwError::io(&path))?;
+            progress.state = Some((path, appender));
+        }
+
+        Ok((rewritten_commits, dropped_commits, progress))
+    }
+
+    fn rewritten(
+        &mut self,
+        old_hash: &CommitHash,
+        new_hash: &CommitHash,
+    ) -> Result<(), GitRwError> {
+        if let Some(hash_index) = self.hash_index.as_mut() {
+            hash_index.insert(old_hash, new_hash);
+        }
+        if let Some((path, state)) = self.state.as_mut() {
+            state
+                .write(old_hash, new_hash)
+                .map_err(GitRwError::io(path))?;
+        }
+        Ok(())
+    }
+
+    /// Records a commit dropped without a parent, there is nothing for the message hashes.
+    fn dropped(&mut self, old_hash: &CommitHash) -> Result<(), GitRwError> {
+        if let Some((path, state)) = self.state.as_mut() {
+            state
+                .write(old_hash, &DROPPED.into())
+                .map_err(GitRwError::io(path))?;
+        }
+        Ok(())
+    }
+}
+
+fn update_parents(
+    commit: &mut CommitEditable,
+    rewritten_commits: &FxHashMap<CommitHash, CommitHash>,
+    dropped_commits: &FxHashSet<CommitHash>,
+) {
+    let parents = commit.parents();
+    if parents
+        .iter()
+        .any(|parent| dropped_commits.contains(parent))
+    {
+        let parents = parents
+            .into_iter()
+            .filter(|parent| !dropped_commits.contains(parent))
+            .map(|parent| rewritten_commits.get(&parent).cloned().unwrap_or(parent))
+            .collect();
+        commit.set_parents(parents);
+        return;
+    }
+
+    for (i, parent) in parents.iter().enumerate() {
+        if let Some(new_parent) = rewritten_commits.get(parent) {
+            if new_parent != parent {
+                commit.set_parent(i, new_parent.clone());
+            }
+        }
+    }
+}
+
+/// Number of problems listed by the error of `verify_unchanged`.
+const LISTED_PROBLEMS: usize = 10;
+
+/// Checks the commits that a rewrite keeps: their content has to hash to their id, as they end up
+/// in the new history byte by byte, and none of their parents may have been rewritten. Returns
+/// the number of checked commits.
+pub(crate) fn verify_unchanged<T: BuildHasher + Sync>(
+    repository: &Repository,
+    rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
+) -> Result<usize, GitRwError> {
+    let cancel = repository.cancel.clone();
+    let checked: Vec<Option<String>> = repository
+        .commits_topo()
+        .take_while(|_| !cancel.is_cancelled())
+        .filter(|commit| !rewritten_commits.contains_key(&commit.hash))
+        .par_bridge()
+        .map(|commit| {
+            let hash = crate::calculate_hash(commit.content(), b"commit");
+            if hash != commit.hash.0 {
+                return Some(format!("{} hashes to {hash}", commit.hash));
+            }
+
+            commit
+                .parents()
+                .into_iter()
+                .find(|parent| rewritten_commits.contains_key(parent))
+                .map(|parent| {
+                    format!(
+                        "{} is kept, but its parent {parent} was rewritten",
+                        commit.hash
+                    )
+                })
+        })
+        .collect();
+
+    if cancel.is_cancelled() {
+        return Err(GitRwError::Cancelled(String::from(
+            "Verifying the unchanged commits cancelled",
+        )));
+    }
+
+    let problems: Vec<String> = checked.iter().flatten().cloned().collect();
+    if !problems.is_empty() {
+        return Err(GitRwError::corrupt(
+            &repository.path,
+            format!(
+                "{} unchanged commits would not be kept as they are, refs were not updated:\n{}",
+                problems.len(),
+                problems[..problems.len().min(LISTED_PROBLEMS)].join("\n")
+            ),
+        ));
+    }
+
+    Ok(checked.len())
+}
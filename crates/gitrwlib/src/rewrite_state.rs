@@ -0,0 +1,147 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use bstr::ByteSlice;
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{
+    commit_map::{CommitMapFormat, CommitMapWriter, ZSTD_MAGIC},
+    error::GitRwError,
+    objs::CommitHash,
+    shared::ObjectHash,
+};
+
+/// The commits an earlier, interrupted rewrite already wrote, see `RewriteOptions::state_file`.
+pub(crate) struct RewriteState {
+    path: PathBuf,
+    /// Length of the complete lines, an interrupted write can leave an incomplete one behind
+    length: u64,
+    pub(crate) rewritten: FxHashMap<CommitHash, CommitHash>,
+    /// Commits dropped without a parent their children could continue on, stored with
+    /// `DROPPED` as new hash
+    pub(crate) dropped: FxHashSet<CommitHash>,
+}
+
+/// New hash of the dropped commits in the state file.
+pub(crate) const DROPPED: ObjectHash = ObjectHash { bytes: [0; 20] };
+
+impl RewriteState {
+    /// Reads the text mapping at `path`, a missing file is an empty state.
+    pub(crate) fn load(path: &Path) -> Result<Self, GitRwError> {
+        let mut state = RewriteState {
+            path: path.to_path_buf(),
+            length: 0,
+            rewritten: FxHashMap::default(),
+            dropped: FxHashSet::default(),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(state),
+            Err(e) => {
+                return Err(GitRwError::Io {
+                    path: path.to_owned(),
+                    source: e,
+                })
+            }
+        };
+
+        let io = GitRwError::io(path);
+        let mut reader = BufReader::new(file);
+        if reader.fill_buf().map_err(io)?.starts_with(&ZSTD_MAGIC) {
+            return Err(GitRwError::corrupt(
+                path,
+                "is a zstd commit map, the state file has to be a text one",
+            ));
+        }
+
+        let mut line = Vec::with_capacity(82);
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line).map_err(io)?;
+            if read == 0 || !line.ends_with(b"\n") {
+                break;
+            }
+
+            let parse = |hash: &[u8]| ObjectHash::try_from(hash.as_bstr()).ok();
+            let entry = line
+                .trim_end()
+                .split_once_str(" ")
+                .and_then(|(old, new)| Some((parse(old)?, parse(new)?)));
+            let Some((old, new)) = entry else {
+                return Err(GitRwError::corrupt(
+                    path,
+                    format!("malformed line {}", line.trim_end().as_bstr()),
+                ));
+            };
+            if new == DROPPED {
+                state.dropped.insert(old.into());
+            } else {
+                state.rewritten.insert(old.into(), new.into());
+            }
+            state.length += read as u64;
+        }
+
+        Ok(state)
+    }
+
+    /// Whether the earlier rewrite already handled the commit.
+    pub(crate) fn contains(&self, hash: &CommitHash) -> bool {
+        self.rewritten.contains_key(hash) || self.dropped.contains(hash)
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Opens the file to append the commits rewritten from now on, after cutting off an
+    /// incomplete last line.
+    pub(crate) fn appender(&self) -> std::io::Result<CommitMapWriter<File>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.path)?;
+        file.set_len(self.length)?;
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        CommitMapWriter::create(file, CommitMapFormat::Text)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::{objs::CommitHash, shared::ObjectHash};
+
+    use super::RewriteState;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn interrupted_state_is_continued() {
+        let hash = |b: u8| -> CommitHash { ObjectHash::from([b; 20]).into() };
+        let path = std::env::temp_dir().join(format!("gitrw-rewrite-state-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let empty = RewriteState::load(&path).unwrap();
+        let mut appender = empty.appender().unwrap();
+        appender.write(&hash(1), &hash(2)).unwrap();
+        let mut file = appender.finish().unwrap();
+        // a write that was cut off
+        file.write_all(&[b'a'; 30]).unwrap();
+        drop(file);
+
+        let state = RewriteState::load(&path).unwrap();
+        let mut appender = state.appender().unwrap();
+        appender.write(&hash(3), &hash(4)).unwrap();
+        appender.finish().unwrap();
+        let resumed = RewriteState::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(1, state.rewritten.len());
+        assert_eq!(2, resumed.rewritten.len());
+        assert_eq!(Some(&hash(4)), resumed.rewritten.get(&hash(3)));
+    }
+}
@@ -0,0 +1,128 @@
+use std::{fs, io, path::Path};
+
+use crate::{error::GitRwError, refs::RefInfo, Repository};
+
+const INFO_REFS: &str = "info/refs";
+const INFO_PACKS: &str = "objects/info/packs";
+
+/// Repositories served over the dumb HTTP protocol have `info/refs` or `objects/info/packs`, which
+/// go stale as soon as refs or packs change.
+pub(crate) fn exists(repository_path: &Path) -> bool {
+    repository_path.join(INFO_REFS).is_file() || repository_path.join(INFO_PACKS).is_file()
+}
+
+/// Regenerates both files like `git update-server-info`.
+pub(crate) fn update(repository: &mut Repository) -> Result<(), GitRwError> {
+    let refs = format_refs(&repository.ref_infos()?);
+    let packs = format_packs(&pack_names(&repository.path)?);
+
+    write(&repository.path.join(INFO_REFS), refs.as_bytes())?;
+    write(&repository.path.join(INFO_PACKS), packs.as_bytes())?;
+    Ok(())
+}
+
+/// One `<target>\t<name>` line per ref, annotated tags are followed by the commit they peel to
+/// with `^{}` appended to the name.
+fn format_refs(refs: &[RefInfo]) -> String {
+    let mut info_refs = String::new();
+    for r in refs {
+        info_refs.push_str(&format!("{}\t{}\n", r.target, r.name));
+        if let Some(peeled) = &r.peeled {
+            info_refs.push_str(&format!("{peeled}\t{}^{{}}\n", r.name));
+        }
+    }
+
+    info_refs
+}
+
+/// One `P <pack>` line per pack, terminated by an empty line.
+fn format_packs(packs: &[String]) -> String {
+    let mut info_packs = String::new();
+    for pack in packs {
+        info_packs.push_str(&format!("P {pack}\n"));
+    }
+    info_packs.push('\n');
+
+    info_packs
+}
+
+/// File names of all packs that have an index, sorted.
+fn pack_names(repository_path: &Path) -> Result<Vec<String>, GitRwError> {
+    let pack_dir = repository_path.join("objects/pack");
+    let entries = match fs::read_dir(&pack_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(GitRwError::Io {
+                path: pack_dir.to_owned(),
+                source: e,
+            })
+        }
+    };
+
+    let mut packs = Vec::new();
+    for entry in entries {
+        let name = entry.map_err(GitRwError::io(&pack_dir))?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+
+        if let Some(stem) = name.strip_suffix(".pack") {
+            if pack_dir.join(format!("{stem}.idx")).is_file() {
+                packs.push(name.to_owned());
+            }
+        }
+    }
+
+    packs.sort_unstable();
+    Ok(packs)
+}
+
+/// Replaces the file in one step, so a server never hands out half of it.
+fn write(path: &Path, content: &[u8]) -> Result<(), GitRwError> {
+    let directory = path.parent().unwrap();
+    fs::create_dir_all(directory).map_err(GitRwError::io(directory))?;
+
+    let temp_path = path.with_extension("gitrw-tmp");
+    fs::write(&temp_path, content)
+        .and_then(|_| fs::rename(&temp_path, path))
+        .map_err(GitRwError::io(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_packs, format_refs};
+    use crate::{refs::RefInfo, ObjectHash};
+
+    #[test]
+    fn server_info_format() {
+        let commit = "1".repeat(40);
+        let tag = "2".repeat(40);
+        let refs = [
+            RefInfo {
+                name: String::from("refs/heads/main"),
+                target: commit.clone(),
+                target_type: Some("commit"),
+                peeled: None,
+            },
+            RefInfo {
+                name: String::from("refs/tags/v1"),
+                target: tag.clone(),
+                target_type: Some("tag"),
+                peeled: Some(ObjectHash::from([0x11; 20]).into()),
+            },
+        ];
+
+        assert_eq!(
+            format!(
+                "{commit}\trefs/heads/main\n{tag}\trefs/tags/v1\n{commit}\trefs/tags/v1^{{}}\n"
+            ),
+            format_refs(&refs)
+        );
+        assert_eq!(
+            "P pack-a.pack\nP pack-b.pack\n\n",
+            format_packs(&[String::from("pack-a.pack"), String::from("pack-b.pack")])
+        );
+        assert_eq!("\n", format_packs(&[]));
+    }
+}
@@ -1,18 +1,20 @@
+use std::{mem::MaybeUninit, sync::Arc};
+
 pub(crate) mod object_hash;
 
-#[derive(Eq, PartialEq, Clone, Hash)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Hash)]
 pub struct ObjectHash {
     pub(crate) bytes: [u8; 20],
 }
 
-#[derive(Debug)]
-pub enum RefSlice<T> {
+#[derive(Clone, Debug)]
+pub(crate) enum RefSlice<T> {
     Referenced(SliceIndexes),
     Owned(Vec<T>),
 }
 
-#[derive(Debug)]
-pub struct SliceIndexes {
+#[derive(Clone, Debug)]
+pub(crate) struct SliceIndexes {
     position: usize,
     len: usize,
 }
@@ -34,6 +36,10 @@ impl SliceIndexes {
     pub fn get<T>(&self, data: &[T]) -> &[T] {
         unsafe { std::slice::from_raw_parts(data.as_ptr().add(self.position), self.len) }
     }
+
+    pub fn range(&self) -> std::ops::Range<usize> {
+        self.position..self.position + self.len
+    }
 }
 
 impl<T> RefSlice<T> {
@@ -58,3 +64,12 @@ impl<T> From<Vec<T>> for RefSlice<T> {
         RefSlice::Owned(value)
     }
 }
+
+/// Allocates object content that is shared instead of copied, `fill` has to overwrite all `len`
+/// bytes. Filling a `Vec` first would need another copy into the `Arc`.
+pub(crate) fn shared_bytes(len: usize, fill: impl FnOnce(&mut [u8])) -> Arc<[u8]> {
+    let mut bytes = Arc::<[u8]>::new_uninit_slice(len);
+    let target = Arc::get_mut(&mut bytes).unwrap();
+    fill(unsafe { &mut *(target as *mut [MaybeUninit<u8>] as *mut [u8]) });
+    unsafe { bytes.assume_init() }
+}
@@ -30,6 +30,16 @@ impl ObjectHash {
 
         Ok(ObjectHash::from(bytes).into())
     }
+
+    /// Whether the hex representation of the hash starts with `prefix`, ignoring case.
+    pub fn has_hex_prefix(&self, prefix: &str) -> bool {
+        prefix.len() <= 40
+            && prefix.bytes().enumerate().all(|(i, c)| {
+                let byte = self.bytes[i / 2];
+                let nibble = if i % 2 == 0 { byte >> 4 } else { byte & 0xf };
+                (c as char).to_digit(16) == Some(nibble as u32)
+            })
+    }
 }
 
 impl TryFrom<&[u8]> for ObjectHash {
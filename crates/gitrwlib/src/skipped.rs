@@ -0,0 +1,115 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+/// Number of objects listed per reason in a `SkipReport`.
+const LISTED_OBJECTS: usize = 5;
+
+/// Collects the objects that commit walks leave out, e.g. refs pointing to trees or parents
+/// missing in shallow clones, see `Repository::set_skip_tracker`. Objects skipped again by later
+/// walks are only counted once. All clones share the same state.
+#[derive(Clone, Debug, Default)]
+pub struct SkipTracker {
+    skipped: Arc<Mutex<BTreeMap<&'static str, BTreeSet<String>>>>,
+}
+
+impl SkipTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, reason: &'static str, object: impl Display) {
+        let mut skipped = self.skipped.lock().unwrap();
+        skipped
+            .entry(reason)
+            .or_default()
+            .insert(object.to_string());
+    }
+
+    pub fn report(&self) -> SkipReport {
+        let skipped = self.skipped.lock().unwrap();
+        let skipped = skipped
+            .iter()
+            .map(|(reason, objects)| {
+                let objects = SkippedObjects {
+                    count: objects.len(),
+                    examples: objects.iter().take(LISTED_OBJECTS).cloned().collect(),
+                };
+                (*reason, objects)
+            })
+            .collect();
+
+        SkipReport { skipped }
+    }
+}
+
+/// Objects skipped for the same reason.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SkippedObjects {
+    pub count: usize,
+    /// The first few of them in lexicographic order
+    pub examples: Vec<String>,
+}
+
+/// What was skipped so far, by reason.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SkipReport {
+    pub skipped: BTreeMap<&'static str, SkippedObjects>,
+}
+
+impl SkipReport {
+    pub fn is_empty(&self) -> bool {
+        self.skipped.is_empty()
+    }
+
+    pub fn total(&self) -> usize {
+        self.skipped.values().map(|objects| objects.count).sum()
+    }
+}
+
+impl Display for SkipReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.total() {
+            1 => write!(f, "1 object was skipped:")?,
+            total => write!(f, "{total} objects were skipped:")?,
+        }
+        for (reason, objects) in self.skipped.iter() {
+            write!(
+                f,
+                "\n  {reason}: {} ({}",
+                objects.count,
+                objects.examples.join(", ")
+            )?;
+            if objects.count > objects.examples.len() {
+                write!(f, ", ...")?;
+            }
+            write!(f, ")")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SkipTracker;
+
+    #[test]
+    fn skipped_objects_by_reason() {
+        let tracker = SkipTracker::new();
+        let clone = tracker.clone();
+        for i in (0..7).chain(0..3) {
+            clone.record("missing parent commit", i);
+        }
+        tracker.record("ref does not point to a commit", "refs/tags/tree");
+
+        let report = tracker.report();
+        assert_eq!(8, report.total());
+        assert_eq!(
+            "8 objects were skipped:\n  missing parent commit: 7 (0, 1, 2, 3, 4, ...)\n  ref does not point to a commit: 1 (refs/tags/tree)",
+            report.to_string()
+        );
+    }
+}
@@ -0,0 +1,83 @@
+use std::{fs, path::Path};
+
+use crate::{error::GitRwError, refs, reftable};
+
+/// Creates the bare repository `target` with the objects, refs and settings a rewrite needs from
+/// the repository at `source`. Pack files and loose objects are hard linked where possible, they
+/// never change once written. Everything that gets rewritten in place is copied.
+pub(crate) fn copy_repository(
+    source: &Path,
+    git_dir: &Path,
+    target: &Path,
+) -> Result<(), GitRwError> {
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(GitRwError::io(parent))?;
+    }
+    fs::create_dir(target).map_err(GitRwError::io(target))?;
+
+    copy_dir(&source.join("objects"), &target.join("objects"), true)?;
+    for dir in ["refs", "reftable", "info"] {
+        if source.join(dir).is_dir() {
+            copy_dir(&source.join(dir), &target.join(dir), false)?;
+        }
+    }
+    for dir in ["refs/heads", "refs/tags"] {
+        fs::create_dir_all(target.join(dir)).map_err(GitRwError::io(&target.join(dir)))?;
+    }
+    for file in ["packed-refs", "shallow"] {
+        if source.join(file).is_file() {
+            copy_file(&source.join(file), &target.join(file), false)?;
+        }
+    }
+
+    let config = if reftable::is_reftable(source) {
+        "[core]\n\trepositoryformatversion = 1\n\tbare = true\n[extensions]\n\trefStorage = reftable\n"
+    } else {
+        "[core]\n\trepositoryformatversion = 0\n\tbare = true\n"
+    };
+    let config_path = target.join("config");
+    fs::write(&config_path, config).map_err(GitRwError::io(&config_path))?;
+
+    // the tables of a reftable repository were copied with the symbolic HEAD in them
+    let head = match refs::default_branch(source, git_dir)? {
+        Some(branch) if !reftable::is_reftable(source) => format!("ref: {branch}\n"),
+        _ => {
+            let head = git_dir.join("HEAD");
+            fs::read_to_string(&head).map_err(GitRwError::io(&head))?
+        }
+    };
+    let head_path = target.join("HEAD");
+    fs::write(&head_path, head).map_err(GitRwError::io(&head_path))?;
+
+    Ok(())
+}
+
+/// Copies the directory recursively, `link_objects` hard links the files in `objects/pack` and
+/// the loose object directories instead.
+fn copy_dir(source: &Path, target: &Path, link_objects: bool) -> Result<(), GitRwError> {
+    fs::create_dir_all(target).map_err(GitRwError::io(target))?;
+    for entry in fs::read_dir(source).map_err(GitRwError::io(source))? {
+        let entry = entry.map_err(GitRwError::io(source))?;
+        let path = entry.path();
+        let file_type = entry.file_type().map_err(GitRwError::io(&path))?;
+        if file_type.is_dir() {
+            // objects/info keeps the commit-graph and files update-server-info rewrites
+            let link = link_objects && entry.file_name() != "info";
+            copy_dir(&path, &target.join(entry.file_name()), link)?;
+        } else {
+            copy_file(&path, &target.join(entry.file_name()), link_objects)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_file(source: &Path, target: &Path, link: bool) -> Result<(), GitRwError> {
+    if link && fs::hard_link(source, target).is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(source, target)
+        .map(|_| ())
+        .map_err(GitRwError::io(target))
+}
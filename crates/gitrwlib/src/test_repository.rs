@@ -0,0 +1,125 @@
+//! Small bare repositories built with git for the tests, so they do not depend on the history of
+//! the checkout they run in.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::Repository;
+
+/// A bare repository in the temp directory, removed again when it is dropped.
+pub(crate) struct TestRepository {
+    pub(crate) path: PathBuf,
+}
+
+impl TestRepository {
+    /// An empty bare repository.
+    pub(crate) fn empty(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("gitrw-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        let repository = TestRepository { path };
+        repository.git(&["init", "--quiet", "--bare", "--initial-branch=main"]);
+        repository
+    }
+
+    /// A packed repository with this history on `main`, `feature` and two tags, plus one loose
+    /// blob:
+    ///
+    /// ```text
+    /// initial - second - third - merge feature   <- main, v1 (annotated)
+    ///                 \                /
+    ///                  feature --------          <- feature
+    /// ```
+    ///
+    /// `light` is a lightweight tag on `second`.
+    pub(crate) fn create(name: &str) -> Self {
+        let repository = Self::empty(name);
+        let work = repository.path.with_extension("work");
+        let _ = std::fs::remove_dir_all(&work);
+        std::fs::create_dir_all(work.join("src")).unwrap();
+
+        let git = |args: &[&str], day: u32| run_git(&work, args, Some(day));
+        git(&["init", "--quiet", "--initial-branch=main"], 1);
+        let commit = |files: &[(&str, &str)], message: &str, day: u32| {
+            for (file, content) in files {
+                std::fs::write(work.join(file), content).unwrap();
+            }
+            git(&["add", "--all"], day);
+            git(&["commit", "--quiet", "-m", message], day);
+        };
+        commit(
+            &[("README.md", "# test\n"), ("src/main.rs", "fn main() {}\n")],
+            "initial",
+            1,
+        );
+        commit(&[("README.md", "# test\n\nsecond\n")], "second", 2);
+        git(&["tag", "light"], 2);
+        git(&["checkout", "--quiet", "-b", "feature"], 3);
+        commit(&[("src/feat.rs", "pub fn feat() {}\n")], "feature", 3);
+        git(&["checkout", "--quiet", "main"], 4);
+        commit(&[("README.md", "# test\n\nthird\n")], "third", 4);
+        git(
+            &[
+                "merge",
+                "--quiet",
+                "--no-ff",
+                "-m",
+                "merge feature",
+                "feature",
+            ],
+            5,
+        );
+        git(&["tag", "-a", "v1", "-m", "v1"], 5);
+
+        let repository_path = repository.path.to_str().unwrap();
+        git(&["push", "--quiet", "--all", repository_path], 5);
+        git(&["push", "--quiet", "--tags", repository_path], 5);
+        std::fs::remove_dir_all(&work).unwrap();
+
+        repository.git(&["gc", "--quiet"]);
+        repository.git(&["hash-object", "-w", "--stdin"]);
+        repository
+    }
+
+    /// Runs git in the repository and returns its trimmed output.
+    pub(crate) fn git(&self, args: &[&str]) -> String {
+        run_git(&self.path, args, None)
+    }
+
+    pub(crate) fn open(&self) -> Repository {
+        Repository::open(self.path.clone()).unwrap()
+    }
+}
+
+impl Drop for TestRepository {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Commits made on `day` of January 2020 get the same dates and hashes in every run.
+fn run_git(dir: &Path, args: &[&str], day: Option<u32>) -> String {
+    let mut command = Command::new("git");
+    command
+        .args([
+            "-c",
+            "user.name=gitrw",
+            "-c",
+            "user.email=gitrw@example.com",
+        ])
+        .args(["-c", "commit.gpgsign=false", "-c", "tag.gpgsign=false"])
+        .args(args)
+        .current_dir(dir);
+    if let Some(day) = day {
+        let date = format!("2020-01-{day:02}T00:00:00+0000");
+        command
+            .env("GIT_AUTHOR_DATE", &date)
+            .env("GIT_COMMITTER_DATE", &date);
+    }
+
+    let output = command.output().unwrap();
+    assert!(output.status.success(), "git {args:?}: {output:?}");
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+}
@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+
+use bstr::{BStr, BString, ByteSlice};
+use rustc_hash::FxHashSet;
+
+use crate::{objs::TreeHash, ObjectHash};
+
+/// Files removed from a single directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RemovedFiles {
+    pub files: usize,
+    /// Uncompressed size of the removed files
+    pub bytes: usize,
+}
+
+/// What the tree callback of a rewrite changed, filled by the callback while it rewrites the
+/// trees. Every distinct blob is only counted once, in the first directory it was removed from,
+/// so the bytes add up to the content that is gone from the history.
+#[derive(Debug, Default)]
+pub struct TreeRewriteReport {
+    /// Trees that were written with a different content
+    pub trees_rewritten: usize,
+    /// Directory entries that were removed from the rewritten trees as a whole
+    pub directories_removed: usize,
+    /// Removed files by directory, e.g. `/src/generated/`
+    pub directories: BTreeMap<BString, RemovedFiles>,
+    recorded: FxHashSet<ObjectHash>,
+}
+
+impl TreeRewriteReport {
+    pub fn record_rewritten_tree(&mut self) {
+        self.trees_rewritten += 1;
+    }
+
+    /// Counts a removed directory. Returns false if its tree was recorded before, its content
+    /// does not have to be visited again then.
+    pub fn record_removed_directory(&mut self, tree: &TreeHash) -> bool {
+        self.directories_removed += 1;
+        self.recorded.insert(tree.0.clone())
+    }
+
+    /// For the trees inside of a removed directory, returns false if the tree was recorded before.
+    pub fn record_removed_subtree(&mut self, tree: &TreeHash) -> bool {
+        self.recorded.insert(tree.0.clone())
+    }
+
+    pub fn record_removed_file(&mut self, directory: &[u8], blob: &ObjectHash, size: usize) {
+        if !self.recorded.insert(blob.clone()) {
+            return;
+        }
+
+        let removed = self
+            .directories
+            .entry(directory.as_bstr().to_owned())
+            .or_default();
+        removed.files += 1;
+        removed.bytes += size;
+    }
+
+    pub fn files_removed(&self) -> usize {
+        self.directories.values().map(|removed| removed.files).sum()
+    }
+
+    pub fn bytes_removed(&self) -> usize {
+        self.directories.values().map(|removed| removed.bytes).sum()
+    }
+
+    /// The `count` directories with the most removed bytes, biggest first.
+    pub fn top_directories(&self, count: usize) -> Vec<(&BStr, &RemovedFiles)> {
+        let mut directories: Vec<(&BStr, &RemovedFiles)> = self
+            .directories
+            .iter()
+            .map(|(directory, removed)| (directory.as_bstr(), removed))
+            .collect();
+        directories.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(b.0)));
+        directories.truncate(count);
+        directories
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TreeRewriteReport;
+    use crate::ObjectHash;
+
+    #[test]
+    fn blobs_are_counted_once() {
+        let first = ObjectHash::from([1u8; 20]);
+        let second = ObjectHash::from([2u8; 20]);
+
+        let mut report = TreeRewriteReport::default();
+        report.record_removed_file(b"/assets/", &first, 100);
+        report.record_removed_file(b"/copy/", &first, 100);
+        report.record_removed_file(b"/src/", &second, 10);
+
+        assert_eq!(2, report.files_removed());
+        assert_eq!(110, report.bytes_removed());
+        let top: Vec<_> = report
+            .top_directories(1)
+            .into_iter()
+            .map(|(directory, removed)| (directory.to_string(), removed.bytes))
+            .collect();
+        assert_eq!(vec![("/assets/".to_owned(), 100)], top);
+    }
+}
@@ -0,0 +1,199 @@
+use std::{error::Error, fmt::Display, path::Path};
+
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+use crate::{
+    calculate_hash,
+    cancel::CancellationToken,
+    compression::Decompression,
+    error::GitRwError,
+    packreader::{object_type_name, PackReader},
+    shared::ObjectHash,
+};
+
+/// Default for the number of corrupt objects listed by reports, the others are only counted.
+pub const DEFAULT_MAX_ERRORS: usize = 100;
+
+/// Controls the checks of `Repository::verify_packs`.
+#[derive(Clone, Debug)]
+pub struct VerifyOptions {
+    /// Compare the raw bytes of every entry with the CRC32 stored in the idx file before
+    /// decoding it, which pinpoints corruption inside of compressed data
+    pub crc: bool,
+    /// Number of corrupt objects to list in the report, all further ones are only counted
+    pub max_errors: usize,
+}
+
+impl Default for VerifyOptions {
+    fn default() -> Self {
+        VerifyOptions {
+            crc: false,
+            max_errors: DEFAULT_MAX_ERRORS,
+        }
+    }
+}
+
+/// Result of `Repository::verify_packs`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub packs: usize,
+    pub objects: usize,
+    pub corrupt: CorruptObjects,
+}
+
+/// A pack entry that failed verification, either damaged itself or a delta whose base is
+/// damaged or missing.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CorruptObject {
+    pub pack_file: String,
+    pub hash: String,
+    pub offset: usize,
+    pub reason: String,
+}
+
+impl Display for CorruptObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pack_name = Path::new(&self.pack_file)
+            .file_name()
+            .map_or(self.pack_file.clone(), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        write!(
+            f,
+            "{pack_name}: object {} at offset {}: {}",
+            self.hash, self.offset, self.reason
+        )
+    }
+}
+
+impl Error for CorruptObject {}
+
+/// The corrupt objects found by a scan, only the first ones are listed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CorruptObjects {
+    pub listed: Vec<CorruptObject>,
+    /// Number of corrupt objects found after the list was full
+    pub unlisted: usize,
+}
+
+impl CorruptObjects {
+    pub(crate) fn push(&mut self, corrupt: CorruptObject, max_errors: usize) {
+        if self.listed.len() < max_errors {
+            self.listed.push(corrupt);
+        } else {
+            self.unlisted += 1;
+        }
+    }
+
+    /// Number of corrupt objects, listed or not.
+    pub fn len(&self) -> usize {
+        self.listed.len() + self.unlisted
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+pub(crate) fn verify_packs(
+    pack_reader: &PackReader,
+    options: &VerifyOptions,
+    cancel: &CancellationToken,
+) -> Result<VerifyReport, GitRwError> {
+    let mut report = VerifyReport::default();
+    let mut result = Ok(());
+    pack_reader.for_each_pack(|pack_id, pack_file, mmap, offsets| {
+        if result.is_err() || cancel.is_cancelled() {
+            return;
+        }
+
+        let crcs = if options.crc {
+            match pack_reader.pack_crcs(pack_id) {
+                Ok(crcs) => Some(crcs),
+                Err(e) => {
+                    result = Err(e);
+                    return;
+                }
+            }
+        } else {
+            None
+        };
+
+        // the pack ends with the checksum of its content
+        let end = mmap.len().saturating_sub(20);
+        let entries: Vec<_> = offsets
+            .iter()
+            .enumerate()
+            .map(|(i, (hash, offset))| {
+                let next_offset = offsets.get(i + 1).map_or(end, |(_, next)| *next);
+                (hash, *offset, next_offset)
+            })
+            .collect();
+
+        let corrupt = |hash: &ObjectHash, offset: usize, reason: String| CorruptObject {
+            pack_file: pack_file.to_owned(),
+            hash: hash.to_string(),
+            offset,
+            reason,
+        };
+
+        let problems: Vec<CorruptObject> = entries
+            .par_iter()
+            .map_init(
+                Decompression::default,
+                |decompression, (hash, offset, next)| {
+                    if cancel.is_cancelled() {
+                        return None;
+                    }
+
+                    let Some(raw) = mmap.get(*offset..*next) else {
+                        return Some(corrupt(hash, *offset, "outside of the pack".to_owned()));
+                    };
+                    if let Some(crcs) = &crcs {
+                        let expected = crcs.get(*hash).copied().unwrap_or_default();
+                        let actual = crc32fast::hash(raw);
+                        if actual != expected {
+                            return Some(corrupt(
+                                hash,
+                                *offset,
+                                format!(
+                                    "CRC32 mismatch, expected {expected:08x}, got {actual:08x}"
+                                ),
+                            ));
+                        }
+                    }
+
+                    let (bytes, object_type) =
+                        match pack_reader.read_checked(decompression, mmap, *offset) {
+                            Ok(read) => read,
+                            Err(reason) => return Some(corrupt(hash, *offset, reason)),
+                        };
+                    let read_hash =
+                        calculate_hash(&bytes, object_type_name(object_type).as_bytes());
+                    (&read_hash != *hash)
+                        .then(|| corrupt(hash, *offset, format!("content hashes to {read_hash}")))
+                },
+            )
+            .flatten()
+            .collect();
+
+        if cancel.is_cancelled() {
+            return;
+        }
+
+        report.packs += 1;
+        report.objects += entries.len();
+        for problem in problems {
+            report.corrupt.push(problem, options.max_errors);
+        }
+    });
+
+    result?;
+    if cancel.is_cancelled() {
+        return Err(GitRwError::Cancelled(String::from(
+            "Verification cancelled",
+        )));
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,129 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::error::GitRwError;
+
+/// Directory inside the repository directory with the state gitrw keeps between runs: the
+/// journal of a ref update, the mappings of cancelled rewrites and temporary files.
+const WORKSPACE_DIR: &str = "gitrw";
+
+/// How long entries are kept by `Repository::expire_workspace` unless configured otherwise.
+pub const DEFAULT_EXPIRY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// Files in the workspace that are never expired because losing them loses data.
+const KEPT_FILES: [&str; 1] = [crate::ref_journal::JOURNAL_FILE];
+
+pub(crate) fn path(repository_path: &Path) -> PathBuf {
+    repository_path.join(WORKSPACE_DIR)
+}
+
+/// The path of `name` inside the workspace, which is created if it does not exist yet.
+pub(crate) fn file(repository_path: &Path, name: &str) -> Result<PathBuf, GitRwError> {
+    let workspace = path(repository_path);
+    fs::create_dir_all(&workspace).map_err(GitRwError::io(&workspace))?;
+    Ok(workspace.join(name))
+}
+
+/// The files `Repository::expire_workspace` removed, or would have removed in a dry run.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ExpiredFiles {
+    pub files: Vec<PathBuf>,
+    pub bytes: u64,
+}
+
+/// Removes the files last modified more than `max_age` ago and the directories that become
+/// empty. The journal of an interrupted ref update is kept, it is needed for `recover-refs`.
+pub(crate) fn expire(
+    repository_path: &Path,
+    max_age: Duration,
+    dry_run: bool,
+) -> Result<ExpiredFiles, GitRwError> {
+    let workspace = path(repository_path);
+    let mut expired = ExpiredFiles::default();
+    if workspace.is_dir() {
+        let cutoff = SystemTime::now()
+            .checked_sub(max_age)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        expire_dir(&workspace, &workspace, cutoff, dry_run, &mut expired)?;
+    }
+
+    expired.files.sort_unstable();
+    Ok(expired)
+}
+
+fn expire_dir(
+    workspace: &Path,
+    dir: &Path,
+    cutoff: SystemTime,
+    dry_run: bool,
+    expired: &mut ExpiredFiles,
+) -> Result<(), GitRwError> {
+    for entry in fs::read_dir(dir).map_err(GitRwError::io(dir))? {
+        let entry = entry.map_err(GitRwError::io(dir))?;
+        let entry_path = entry.path();
+        let io = GitRwError::io(&entry_path);
+        let metadata = entry.metadata().map_err(io)?;
+        if metadata.is_dir() {
+            expire_dir(workspace, &entry_path, cutoff, dry_run, expired)?;
+            if !dry_run {
+                // fails for directories that still contain files
+                let _ = fs::remove_dir(&entry_path);
+            }
+            continue;
+        }
+
+        let kept = dir == workspace
+            && KEPT_FILES
+                .iter()
+                .any(|name| entry.file_name() == std::ffi::OsStr::new(name));
+        if kept || metadata.modified().map_err(io)? >= cutoff {
+            continue;
+        }
+
+        if !dry_run {
+            fs::remove_file(&entry_path).map_err(io)?;
+        }
+        expired.bytes += metadata.len();
+        expired.files.push(entry_path);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, time::Duration};
+
+    use crate::ref_journal::JOURNAL_FILE;
+
+    #[test]
+    fn expire_keeps_journal() {
+        let dir = std::env::temp_dir().join(format!("gitrw-workspace-{}", std::process::id()));
+        let map = super::file(&dir, "object-id-map.old-new.txt.partial").unwrap();
+        fs::write(&map, "a b\n").unwrap();
+        fs::create_dir_all(super::path(&dir).join("tmp")).unwrap();
+        fs::write(super::path(&dir).join("tmp/pack"), "pack").unwrap();
+        fs::write(
+            super::file(&dir, JOURNAL_FILE).unwrap(),
+            "a b refs/heads/main\n",
+        )
+        .unwrap();
+
+        let fresh = super::expire(&dir, Duration::from_secs(3600), false).unwrap();
+        let dry_run = super::expire(&dir, Duration::ZERO, true).unwrap();
+        let expired = super::expire(&dir, Duration::ZERO, false).unwrap();
+        let journal_kept = super::path(&dir).join(JOURNAL_FILE).is_file();
+        let tmp_removed = !super::path(&dir).join("tmp").exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(super::ExpiredFiles::default(), fresh);
+        assert_eq!(2, dry_run.files.len());
+        assert_eq!(dry_run, expired);
+        assert_eq!(8, expired.bytes);
+        assert!(journal_kept);
+        assert!(tmp_removed);
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use gitrwlib::{PackEntry, PackStats};
+use serde_json::{json, Value};
+
+use crate::output::{self, OutputFormat, Table};
+
+/// Prints the statistics of every pack, sizes are in KiB. The table only has the totals of every
+/// pack, JSON also lists the worst compressed objects, the deepest delta chains and the entries
+/// with damaged headers. Fails after printing if any entry is damaged.
+pub fn analyze(
+    repository_path: PathBuf,
+    top: usize,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let repository = crate::open_for_reading(repository_path)?;
+
+    let all_stats = repository.pack_stats(top)?;
+    let corrupt: usize = all_stats.iter().map(|stats| stats.corrupt.len()).sum();
+    write_stats(&all_stats, format)?;
+
+    match corrupt {
+        0 => Ok(()),
+        1 => Err("1 damaged pack entry, gitrw verify checks the content of all entries".into()),
+        n => Err(format!(
+            "{n} damaged pack entries, gitrw verify checks the content of all entries"
+        )
+        .into()),
+    }
+}
+
+fn write_stats(all_stats: &[PackStats], format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    let mut handle = BufWriter::new(stdout().lock());
+    match format {
+        OutputFormat::Plain => {}
+        OutputFormat::Table | OutputFormat::Csv => {
+            let mut table = Table::new(&[
+                "pack",
+                "kib",
+                "objects",
+                "commits",
+                "trees",
+                "blobs",
+                "tags",
+                "deltas",
+                "max depth",
+                "avg depth",
+                "compression %",
+                "corrupt",
+            ]);
+            for stats in all_stats.iter() {
+                table.push(vec![
+                    json!(pack_name(stats)),
+                    json!(stats.pack_size / 1024),
+                    json!(stats.objects()),
+                    json!(stats.commits),
+                    json!(stats.trees),
+                    json!(stats.blobs),
+                    json!(stats.tags),
+                    json!(stats.deltas),
+                    json!(stats.max_delta_depth),
+                    json!(one_decimal(stats.average_delta_depth())),
+                    json!(one_decimal(stats.compression_ratio() * 100.0)),
+                    json!(stats.corrupt.len()),
+                ]);
+            }
+            table.write(format, &mut handle)?;
+            return Ok(());
+        }
+        OutputFormat::Json => {
+            let packs: Vec<Value> = all_stats.iter().map(pack_json).collect();
+            output::write_json(&Value::Array(packs), &mut handle)?;
+            return Ok(());
+        }
+    }
+
+    if all_stats.is_empty() {
+        writeln!(handle, "No packs, all objects are stored loose")?;
+    }
+
+    for stats in all_stats {
+        writeln!(
+            handle,
+            "{}: {} KiB",
+            pack_name(stats),
+            stats.pack_size / 1024
+        )?;
+        writeln!(
+            handle,
+            "  objects: {} (commits: {}, trees: {}, blobs: {}, tags: {})",
+            stats.objects(),
+            stats.commits,
+            stats.trees,
+            stats.blobs,
+            stats.tags
+        )?;
+        writeln!(
+            handle,
+            "  deltas: {}, max chain depth: {}, average chain depth: {:.1}",
+            stats.deltas,
+            stats.max_delta_depth,
+            stats.average_delta_depth()
+        )?;
+        writeln!(
+            handle,
+            "  compression: {} KiB stored, {} KiB inflated ({:.1}%)",
+            stats.stored_size / 1024,
+            stats.inflated_size / 1024,
+            stats.compression_ratio() * 100.0
+        )?;
+
+        if !stats.worst_compressed.is_empty() {
+            writeln!(handle, "  worst compressed:")?;
+        }
+        for entry in stats.worst_compressed.iter() {
+            let delta = if entry.delta_depth > 0 {
+                format!(
+                    ", delta depth {} for {} bytes",
+                    entry.delta_depth, entry.object_size
+                )
+            } else {
+                String::new()
+            };
+            writeln!(
+                handle,
+                "    {} {}: {} of {} bytes ({:.1}%{delta})",
+                entry.hash,
+                entry.object_type,
+                entry.stored_size,
+                entry.inflated_size,
+                entry.compression_ratio() * 100.0
+            )?;
+        }
+
+        if !stats.deepest_chains.is_empty() {
+            writeln!(handle, "  deepest delta chains:")?;
+        }
+        for entry in stats.deepest_chains.iter() {
+            writeln!(
+                handle,
+                "    {} {}: depth {}, {} bytes",
+                entry.hash, entry.object_type, entry.delta_depth, entry.object_size
+            )?;
+        }
+
+        if !stats.corrupt.is_empty() {
+            writeln!(handle, "  damaged entries:")?;
+        }
+        for corrupt in stats.corrupt.listed.iter() {
+            writeln!(
+                handle,
+                "    {} at offset {}: {}",
+                corrupt.hash, corrupt.offset, corrupt.reason
+            )?;
+        }
+        if stats.corrupt.unlisted > 0 {
+            writeln!(handle, "    ... and {} more", stats.corrupt.unlisted)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn pack_name(stats: &PackStats) -> String {
+    Path::new(&stats.pack_file)
+        .file_name()
+        .map_or(stats.pack_file.clone(), |name| {
+            name.to_string_lossy().into_owned()
+        })
+}
+
+fn one_decimal(value: f64) -> f64 {
+    (value * 10.0).round() / 10.0
+}
+
+fn pack_json(stats: &PackStats) -> Value {
+    let entry_json = |entry: &PackEntry| {
+        json!({
+            "hash": entry.hash.to_string(),
+            "type": entry.object_type,
+            "delta_depth": entry.delta_depth,
+            "stored_size": entry.stored_size,
+            "inflated_size": entry.inflated_size,
+            "object_size": entry.object_size,
+        })
+    };
+
+    json!({
+        "pack": pack_name(stats),
+        "size_kib": stats.pack_size / 1024,
+        "objects": stats.objects(),
+        "commits": stats.commits,
+        "trees": stats.trees,
+        "blobs": stats.blobs,
+        "tags": stats.tags,
+        "deltas": stats.deltas,
+        "max_delta_depth": stats.max_delta_depth,
+        "average_delta_depth": one_decimal(stats.average_delta_depth()),
+        "stored_kib": stats.stored_size / 1024,
+        "inflated_kib": stats.inflated_size / 1024,
+        "worst_compressed": stats.worst_compressed.iter().map(entry_json).collect::<Vec<_>>(),
+        "deepest_chains": stats.deepest_chains.iter().map(entry_json).collect::<Vec<_>>(),
+        "corrupt": stats.corrupt.listed.iter().map(|corrupt| json!({
+            "hash": corrupt.hash,
+            "offset": corrupt.offset,
+            "reason": corrupt.reason,
+        })).collect::<Vec<_>>(),
+        "unlisted_corrupt": stats.corrupt.unlisted,
+    })
+}
@@ -0,0 +1,108 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+use bstr::ByteSlice;
+use gitrwlib::{objs::GitObject, ReadOnly, Repository};
+use tar::{Builder, EntryType, Header};
+
+fn commit_time(repository: &mut Repository<ReadOnly>, rev: &str) -> u64 {
+    let object = repository
+        .peel_to_commit(rev)
+        .and_then(|commit| repository.read_object(commit.into()));
+
+    match object {
+        Some(GitObject::Commit(commit)) => commit
+            .committer_time()
+            .split_str(" ")
+            .next()
+            .and_then(|time| time.to_str().ok()?.parse().ok())
+            .unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Writes the tree of `rev` into a tar archive. Blobs are read and appended one at a time so
+/// only a single file is held in memory.
+pub fn archive(
+    repository_path: PathBuf,
+    rev: &str,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let mut repository = Repository::open_read_only(repository_path)?;
+
+    let hash = repository
+        .resolve(rev)
+        .ok_or_else(|| format!("Unknown revision: {rev}"))?;
+    let tree = match repository.lookup_path(hash, b"") {
+        Some(GitObject::Tree(tree)) => tree,
+        _ => return Err(format!("{rev} does not point to a tree").into()),
+    };
+    let mtime = commit_time(&mut repository, rev);
+
+    let writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(stdout().lock())),
+    };
+    let mut builder = Builder::new(writer);
+
+    // the first failure skips the rest of the walk
+    let mut result: Result<(), Box<dyn Error>> = Ok(());
+    repository.walk_tree(tree.hash().clone(), &mut |repository, path, line| {
+        if result.is_err() {
+            return;
+        }
+
+        let mut header = Header::new_gnu();
+        header.set_mtime(mtime);
+        header.set_size(0);
+
+        let appended = match line.mode() {
+            b"40000" | b"160000" => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                let path = [path, b"/"].concat();
+                builder.append_data(&mut header, path.to_path().unwrap(), io::empty())
+            }
+            mode => {
+                let blob = match repository.read_object(line.hash.as_ref().clone().into()) {
+                    Some(GitObject::Blob(blob)) => blob,
+                    Some(_) => {
+                        result =
+                            Err(format!("{}: {} is no blob", path.as_bstr(), line.hash).into());
+                        return;
+                    }
+                    None => {
+                        result = Err(
+                            format!("{}: blob {} is missing", path.as_bstr(), line.hash).into()
+                        );
+                        return;
+                    }
+                };
+
+                if mode == b"120000" {
+                    header.set_entry_type(EntryType::Symlink);
+                    header.set_mode(0o777);
+                    builder.append_link(
+                        &mut header,
+                        path.to_path().unwrap(),
+                        blob.content().to_path().unwrap(),
+                    )
+                } else {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_mode(if mode == b"100755" { 0o755 } else { 0o644 });
+                    header.set_size(blob.content().len() as u64);
+                    builder.append_data(&mut header, path.to_path().unwrap(), blob.content())
+                }
+            }
+        };
+        result = appended.map_err(Into::into);
+    })?;
+    result?;
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
@@ -0,0 +1,45 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+
+use gitrwlib::{GitRwError, Repository};
+
+/// Writes a bundle of the refs selected by `revs` into `file`, see `Repository::write_bundle`.
+/// The file is removed again if writing the bundle fails.
+pub fn create(
+    repository_path: PathBuf,
+    file: PathBuf,
+    revs: Vec<String>,
+) -> Result<(), Box<dyn Error>> {
+    let repository = Repository::open_read_only(repository_path)?;
+
+    let revs: Vec<&str> = revs.iter().map(String::as_str).collect();
+    let mut writer = BufWriter::new(File::create(&file)?);
+    let result = repository
+        .write_bundle(&revs, &mut writer)
+        .and_then(|counts| {
+            writer.flush().map_err(|source| GitRwError::Io {
+                path: file.clone(),
+                source,
+            })?;
+            Ok(counts)
+        });
+
+    match result {
+        Ok((refs, objects)) => {
+            println!(
+                "Bundled {refs} refs and {objects} objects into {}",
+                file.display()
+            );
+            Ok(())
+        }
+        Err(e) => {
+            drop(writer);
+            std::fs::remove_file(&file)?;
+            Err(e.into())
+        }
+    }
+}
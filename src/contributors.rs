@@ -1,14 +1,52 @@
 use std::{
-    collections::HashMap, error::Error, io::stdin, path::PathBuf, sync::mpsc::channel,
-    thread::spawn,
+    borrow::Cow,
+    collections::BTreeMap,
+    error::Error,
+    fs::File,
+    io::{stdin, stdout, BufRead, BufReader, BufWriter},
+    path::{Path, PathBuf},
 };
 
-use bstr::{io::BufReadExt, BString, ByteSlice};
-use gitrwlib::{
-    objs::{CommitEditable, CommitHash},
-    Repository, WriteObject,
-};
-use rustc_hash::{FxHashMap, FxHashSet};
+use bstr::{io::BufReadExt, BStr, BString, ByteSlice};
+use gitrwlib::{objs::CommitBase, CommitAction, RewriteOptions};
+use regex::bytes::Regex;
+use rustc_hash::FxHashMap;
+use serde_json::json;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::output::{OutputFormat, Table};
+
+/// Replacement identities have to look like `Name <email>` unless another pattern is passed.
+const IDENTITY_PATTERN: &str = r"^[^<>\n]*[^<>\s] <[^<>\n]*>$";
+
+/// How the old identities of the mappings are compared with the ones in the commits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IdentityMatching {
+    pub ignore_case: bool,
+    /// Compares the NFC forms, e.g. an 'é' stored as 'e' and a combining accent by old clients
+    pub normalize_unicode: bool,
+}
+
+impl IdentityMatching {
+    /// The form of `identity` mappings are looked up with. Identities that are not valid UTF-8
+    /// are never normalized.
+    fn key<'a>(&self, identity: &'a [u8]) -> Cow<'a, [u8]> {
+        let mut key = Cow::Borrowed(identity);
+        if self.normalize_unicode {
+            if let Ok(identity) = std::str::from_utf8(identity) {
+                if !unicode_normalization::is_nfc(identity) {
+                    key = Cow::Owned(identity.nfc().collect::<String>().into_bytes());
+                }
+            }
+        }
+
+        if self.ignore_case {
+            key = Cow::Owned(key.to_lowercase());
+        }
+
+        key
+    }
+}
 
 fn split_index(line: &[u8]) -> Option<usize> {
     for (pos, c) in line.iter().enumerate() {
@@ -20,79 +58,381 @@ fn split_index(line: &[u8]) -> Option<usize> {
     None
 }
 
-fn get_mappings() -> Result<FxHashMap<Vec<u8>, Vec<u8>>, Box<dyn Error>> {
-    let mut mappings = FxHashMap::default();
+/// Reads the `old = new` lines, blank lines and lines starting with '#' are ignored. Fails with
+/// the numbers of all lines without '=' and of all new identities that do not match
+/// `identity_pattern`. Identities mapped to different new ones more than once are reported and
+/// the last line wins. The old identities are stored in the form `matching` looks them up.
+fn get_mappings(
+    input: impl BufRead,
+    identity_pattern: &Regex,
+    matching: IdentityMatching,
+) -> Result<FxHashMap<Vec<u8>, Vec<u8>>, Box<dyn Error>> {
+    let mut mappings: FxHashMap<Vec<u8>, Vec<u8>> = FxHashMap::default();
+    let mut malformed = Vec::new();
+    let mut unmatched = Vec::new();
 
-    for line in stdin().lock().byte_lines() {
+    for (index, line) in input.byte_lines().enumerate() {
         let line = line?;
-        let split_pos = split_index(&line).ok_or("Line is malformed. Pattern: old = new")?;
+        if line.trim().is_empty() || line.trim_start().starts_with(b"#") {
+            continue;
+        }
 
-        let old = line[0..split_pos].trim().to_owned();
+        let Some(split_pos) = split_index(&line) else {
+            malformed.push(format!("line {}: {}", index + 1, line.as_bstr()));
+            continue;
+        };
+
+        let old = line[0..split_pos].trim();
         let new = line[split_pos + 1..].trim().to_owned();
 
-        if old != new {
-            mappings.insert(old, new);
+        if old == new {
+            continue;
+        }
+        let old = matching.key(old).into_owned();
+
+        if !identity_pattern.is_match(&new) {
+            unmatched.push(format!("line {}: {}", index + 1, new.as_bstr()));
         }
+
+        if let Some(previous) = mappings.get(&old).filter(|previous| **previous != new) {
+            eprintln!(
+                "warning: {} is mapped to {} and {}, using the latter",
+                old.as_bstr(),
+                previous.as_bstr(),
+                new.as_bstr()
+            );
+        }
+        mappings.insert(old, new);
+    }
+
+    let mut errors = Vec::new();
+    if !malformed.is_empty() {
+        errors.push(format!(
+            "Lines not matching the pattern old = new:\n{}",
+            malformed.join("\n")
+        ));
+    }
+    if !unmatched.is_empty() {
+        errors.push(format!(
+            "New identities not matching {}:\n{}",
+            identity_pattern.as_str(),
+            unmatched.join("\n")
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
     }
 
     Ok(mappings)
 }
 
-pub fn rewrite(repository_path: PathBuf, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
-    let mappings = get_mappings()?;
+/// Reads the mappings from `mapping_file`, or from stdin if it is omitted or `-`.
+fn read_mappings(
+    mapping_file: Option<&Path>,
+    identity_pattern: &Regex,
+    matching: IdentityMatching,
+) -> Result<FxHashMap<Vec<u8>, Vec<u8>>, Box<dyn Error>> {
+    match mapping_file {
+        Some(path) if path != Path::new("-") => {
+            let file =
+                File::open(path).map_err(|e| format!("Reading {} failed: {e}", path.display()))?;
+            get_mappings(BufReader::new(file), identity_pattern, matching)
+                .map_err(|e| format!("{}: {e}", path.display()).into())
+        }
+        _ => get_mappings(stdin().lock(), identity_pattern, matching),
+    }
+}
 
-    let (tx, rx) = channel();
-    let write_path = repository_path.clone();
-    let write_thread =
-        spawn(move || Repository::write_commits(write_path, rx.into_iter(), dry_run));
+/// The identities of a commit the mappings are applied to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentityFields {
+    Both,
+    /// Keeps the committer as it is, e.g. because it has to name whoever pushed the commit
+    AuthorOnly,
+    CommitterOnly,
+}
 
-    let mut repository = Repository::create(repository_path);
-    let mut rewritten_commits: HashMap<CommitHash, CommitHash, _> = FxHashMap::default();
-    for mut commit in repository.commits_topo().map(CommitEditable::create) {
-        if let Some(new_author) = mappings.get(commit.author_bytes()) {
-            commit.set_author(new_author.clone());
+#[allow(clippy::too_many_arguments)]
+pub fn rewrite(
+    repository_path: PathBuf,
+    mapping_file: Option<PathBuf>,
+    committer_from_author: bool,
+    only_refs: Option<Vec<String>>,
+    identity_pattern: Option<String>,
+    fields: IdentityFields,
+    matching: IdentityMatching,
+    options: &RewriteOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let identity_pattern = Regex::new(identity_pattern.as_deref().unwrap_or(IDENTITY_PATTERN))?;
+    let mappings = read_mappings(mapping_file.as_deref(), &identity_pattern, matching)?;
+
+    let mut repository = crate::open_for_rewrite(repository_path)?;
+    let selected_commits = match only_refs {
+        Some(patterns) => Some(repository.commits_unique_to_refs(&patterns)?),
+        None => None,
+    };
+
+    let ref_updates = repository.rewrite_history_parallel(options, |commit| {
+        if let Some(selected_commits) = &selected_commits {
+            if !selected_commits.contains(commit.base_hash()) {
+                return CommitAction::Keep;
+            }
         }
 
-        if let Some(new_committer) = mappings.get(commit.committer_bytes()) {
-            commit.set_committer(new_committer.clone());
+        if fields != IdentityFields::CommitterOnly {
+            if let Some(new_author) = mappings.get(matching.key(commit.author_bytes()).as_ref()) {
+                commit.set_author(new_author.clone());
+            }
         }
 
-        for (i, parent) in commit.parents().iter().enumerate() {
-            if let Some(new_commit_hash) = rewritten_commits.get(parent) {
-                commit.set_parent(i, new_commit_hash.clone());
+        if fields != IdentityFields::AuthorOnly {
+            if let Some(new_committer) =
+                mappings.get(matching.key(commit.committer_bytes()).as_ref())
+            {
+                commit.set_committer(new_committer.clone());
             }
         }
 
-        if commit.has_changes() {
-            let old_hash = commit.base_hash().clone();
-            let w: WriteObject = commit.into();
-            rewritten_commits.insert(old_hash, CommitHash::from(w.hash.clone()));
-            tx.send(w).unwrap();
+        if committer_from_author {
+            let author = commit.author_bytes().to_vec();
+            if commit.committer_bytes() != author {
+                commit.set_committer(author);
+            }
+
+            let author_time = commit.author_time().to_vec();
+            if commit.committer_time() != author_time {
+                commit.set_committer_time(author_time);
+            }
         }
-    }
 
-    drop(tx);
-    write_thread.join().expect("Failed to write commits");
+        CommitAction::Keep
+    })?;
+    crate::print_ref_updates(&ref_updates, options)?;
 
-    if !rewritten_commits.is_empty() {
-        repository.update_refs(&rewritten_commits, dry_run);
-        Repository::write_rewritten_commits_file(rewritten_commits, dry_run);
+    Ok(())
+}
+
+/// Prints the contributors one per line. Tables, CSV and JSON have the name and the email
+/// address as separate columns, followed by the statistics of the contributor.
+pub fn list_contributors(
+    repository_path: PathBuf,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let contributors = get_contributors(repository_path)?;
+    if format == OutputFormat::Plain {
+        return crate::print_locked(contributors.keys());
+    }
+
+    let mut table = Table::new(&["name", "email", "authored", "committed", "first", "last"]);
+    for (contributor, stats) in contributors.iter() {
+        let (name, email) = match contributor.rfind(" <") {
+            Some(i) => (
+                &contributor[..i],
+                contributor[i + 2..].trim_end_with(|c| c == '>'),
+            ),
+            None => (contributor.as_slice(), &b""[..]),
+        };
+        table.push(vec![
+            json!(name.to_str_lossy()),
+            json!(email.to_str_lossy()),
+            json!(stats.authored),
+            json!(stats.committed),
+            json!(stats.first.as_ref().map(|(_, date)| date.to_string())),
+            json!(stats.last.as_ref().map(|(_, date)| date.to_string())),
+        ]);
     }
 
+    let mut handle = BufWriter::new(stdout().lock());
+    table.write(format, &mut handle)?;
     Ok(())
 }
 
-pub fn get_contributors(repository_path: PathBuf) -> Result<Vec<BString>, Box<dyn Error>> {
-    let mut committers = FxHashSet::default();
-    let repository = Repository::create(repository_path);
+/// How often an identity appears in the commits and when.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ContributorStats {
+    /// Commits with the identity as author
+    pub authored: usize,
+    /// Commits with the identity as committer
+    pub committed: usize,
+    /// The earliest date the identity appears with, as seconds and as stored in the commit.
+    /// Author dates count for authors, committer dates for committers.
+    pub first: Option<(i64, BString)>,
+    /// The latest date the identity appears with
+    pub last: Option<(i64, BString)>,
+}
+
+impl ContributorStats {
+    fn add_date(&mut self, date: &BStr, timestamp: Option<i64>) {
+        let Some(timestamp) = timestamp else {
+            return;
+        };
+        if self
+            .first
+            .as_ref()
+            .is_none_or(|(first, _)| timestamp < *first)
+        {
+            self.first = Some((timestamp, date.to_owned()));
+        }
+        if self.last.as_ref().is_none_or(|(last, _)| timestamp > *last) {
+            self.last = Some((timestamp, date.to_owned()));
+        }
+    }
+}
+
+fn add_commit(contributors: &mut FxHashMap<BString, ContributorStats>, commit: &CommitBase) {
+    let author = contributors.entry(commit.author().to_owned()).or_default();
+    author.authored += 1;
+    author.add_date(commit.author_time(), commit.author_timestamp());
+
+    let committer = contributors
+        .entry(commit.committer().to_owned())
+        .or_default();
+    committer.committed += 1;
+    committer.add_date(commit.committer_time(), commit.committer_timestamp());
+}
+
+/// All authors and committers of the commits reachable from the refs, or of every commit in a
+/// pack or bundle file, sorted by identity.
+pub fn get_contributors(
+    repository_path: PathBuf,
+) -> Result<BTreeMap<BString, ContributorStats>, Box<dyn Error>> {
+    let mut contributors = FxHashMap::default();
+    let pack_file = repository_path.is_file();
+    let repository = crate::open_for_reading(repository_path)?;
+
+    let commits: Box<dyn Iterator<Item = CommitBase>> = if pack_file {
+        Box::new(repository.commits_in_packs())
+    } else {
+        Box::new(repository.commits_lifo())
+    };
+    for commit in commits {
+        add_commit(&mut contributors, &commit);
+    }
+
+    Ok(contributors.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use bstr::BString;
+    use gitrwlib::{objs::CommitBase, ObjectHash};
+    use regex::bytes::Regex;
+    use rustc_hash::FxHashMap;
+
+    use super::{add_commit, get_mappings, IdentityMatching, IDENTITY_PATTERN};
+
+    #[test]
+    fn new_identities_are_validated() {
+        let pattern = Regex::new(IDENTITY_PATTERN).unwrap();
+        let input = b"Old <old@x.org> = New <new@x.org>\nA <a@x.org> = B <b@x.org>\nA <a@x.org> = C <c@x.org>\n";
+        let mappings = get_mappings(&input[..], &pattern, IdentityMatching::default()).unwrap();
+        assert_eq!(2, mappings.len());
+        assert_eq!(b"C <c@x.org>".to_vec(), mappings[&b"A <a@x.org>".to_vec()]);
 
-    for commit in repository.commits_lifo() {
-        committers.insert(commit.committer().to_owned());
-        committers.insert(commit.author().to_owned());
+        for malformed in [
+            "new@x.org",
+            "New new@x.org>",
+            "New <new@x.org",
+            " <new@x.org>",
+            "New <a<b>",
+        ] {
+            let input = format!("Old <old@x.org> = {malformed}\n");
+            let error =
+                get_mappings(input.as_bytes(), &pattern, IdentityMatching::default()).unwrap_err();
+            assert!(error.to_string().contains("line 1"), "{malformed}: {error}");
+        }
+
+        let anything = Regex::new(".*").unwrap();
+        assert!(get_mappings(
+            &b"Old <old@x.org> = new@x.org\n"[..],
+            &anything,
+            IdentityMatching::default()
+        )
+        .is_ok());
     }
 
-    let mut committers: Vec<_> = committers.into_iter().collect();
-    committers.sort();
+    #[test]
+    fn comments_and_malformed_lines() {
+        let pattern = Regex::new(IDENTITY_PATTERN).unwrap();
+        let input = b"# team renames\n\nOld <old@x.org> = New <new@x.org>\n  # indented\n";
+        let mappings = get_mappings(&input[..], &pattern, IdentityMatching::default()).unwrap();
+        assert_eq!(1, mappings.len());
+
+        let input = b"Old <old@x.org> New <new@x.org>\nA <a@x.org> = b@x.org\n\nC <c@x.org>\n";
+        let error = get_mappings(&input[..], &pattern, IdentityMatching::default())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("line 1: Old <old@x.org> New <new@x.org>"),
+            "{error}"
+        );
+        assert!(error.contains("line 2: b@x.org"), "{error}");
+        assert!(error.contains("line 4: C <c@x.org>"), "{error}");
+    }
 
-    Ok(committers)
+    #[test]
+    fn case_and_normalization_matching() {
+        let pattern = Regex::new(IDENTITY_PATTERN).unwrap();
+        // the old identity is decomposed, 'e' followed by a combining acute accent
+        let input = "Jose\u{301} <J@X.org> = José <j@x.org>\n";
+        let matching = IdentityMatching {
+            ignore_case: true,
+            normalize_unicode: true,
+        };
+        let mappings = get_mappings(input.as_bytes(), &pattern, matching).unwrap();
+
+        for identity in ["José <j@x.org>", "JOSÉ <J@X.ORG>", "Jose\u{301} <j@x.org>"] {
+            let key = matching.key(identity.as_bytes());
+            assert!(mappings.contains_key(key.as_ref()), "{identity}");
+        }
+
+        let exact = get_mappings(input.as_bytes(), &pattern, IdentityMatching::default()).unwrap();
+        assert!(!exact.contains_key("José <j@x.org>".as_bytes()));
+        assert_eq!(
+            b"\xff <a@b>".as_slice(),
+            matching.key(b"\xff <A@B>").as_ref()
+        );
+    }
+
+    #[test]
+    fn contributor_stats() {
+        let commit = |hash: &str, author_time: &str, committer_time: &str| {
+            let bytes = format!("tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nauthor A <a@x.org> {author_time}\ncommitter C <c@x.org> {committer_time}\n\nSubject\n");
+            let hash: ObjectHash = bstr::BStr::new(hash).try_into().unwrap();
+            CommitBase::create(hash.into(), bytes.into_bytes(), false)
+        };
+        let mut contributors = FxHashMap::default();
+        add_commit(
+            &mut contributors,
+            &commit(
+                "1111111111111111111111111111111111111111",
+                "1600000000 +0200",
+                "1600000100 +0000",
+            ),
+        );
+        add_commit(
+            &mut contributors,
+            &commit(
+                "2222222222222222222222222222222222222222",
+                "1500000000 -0100",
+                "1700000000 +0000",
+            ),
+        );
+
+        let author = &contributors[&BString::from("A <a@x.org>")];
+        assert_eq!((2, 0), (author.authored, author.committed));
+        assert_eq!("1500000000 -0100", author.first.as_ref().unwrap().1);
+        assert_eq!("1600000000 +0200", author.last.as_ref().unwrap().1);
+
+        let committer = &contributors[&BString::from("C <c@x.org>")];
+        assert_eq!((0, 2), (committer.authored, committer.committed));
+        assert_eq!(
+            Some(1600000100),
+            committer.first.as_ref().map(|(time, _)| *time)
+        );
+        assert_eq!(
+            Some(1700000000),
+            committer.last.as_ref().map(|(time, _)| *time)
+        );
+    }
 }
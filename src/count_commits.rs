@@ -0,0 +1,38 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+use gitrwlib::Repository;
+
+/// Prints the number of commits in `range`, or how many commits it is ahead of and behind
+/// `ahead_behind`. Without a range the commits of every ref pointing to a commit are counted
+/// on their own, which allows comparing the counts before and after a rewrite.
+pub fn count_commits(
+    repository_path: PathBuf,
+    range: Option<String>,
+    ahead_behind: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    let mut repository = Repository::open_read_only(repository_path)?;
+    repository.set_skip_tracker(crate::skip_tracker());
+
+    let mut handle = BufWriter::new(stdout().lock());
+    match (range, ahead_behind) {
+        (Some(rev), Some(other)) => {
+            let (ahead, behind) = repository.ahead_behind(&rev, &other)?;
+            writeln!(handle, "{ahead} ahead, {behind} behind")?;
+        }
+        (Some(range), None) => writeln!(handle, "{}", repository.count_commits(&range)?)?,
+        (None, _) => {
+            for info in repository.ref_infos()? {
+                if info.target_type == Some("commit") || info.peeled.is_some() {
+                    let count = repository.count_commits(&info.name)?;
+                    writeln!(handle, "{count} {}", info.name)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+use gitrwlib::Repository;
+use serde_json::{json, Value};
+
+use crate::output::{self, OutputFormat, Table};
+
+/// Prints the object statistics in the style of `git count-objects -v`, sizes are in KiB.
+pub fn count_objects(repository_path: PathBuf, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    let repository = Repository::open_read_only(repository_path)?;
+    let counts = repository.count_objects();
+
+    let statistics = [
+        ("count", counts.loose as u64),
+        ("size", counts.loose_size / 1024),
+        ("in-pack", counts.packed() as u64),
+        ("packs", counts.packs as u64),
+        ("size-pack", counts.pack_size / 1024),
+        ("commits", counts.commits as u64),
+        ("trees", counts.trees as u64),
+        ("blobs", counts.blobs as u64),
+        ("tags", counts.tags as u64),
+    ];
+
+    let mut handle = BufWriter::new(stdout().lock());
+    match format {
+        OutputFormat::Plain => {
+            for (name, value) in statistics {
+                writeln!(handle, "{name}: {value}")?;
+            }
+        }
+        OutputFormat::Table | OutputFormat::Csv => {
+            let mut table = Table::new(&["statistic", "value"]);
+            for (name, value) in statistics {
+                table.push(vec![json!(name), json!(value)]);
+            }
+            table.write(format, &mut handle)?;
+        }
+        OutputFormat::Json => {
+            let object = statistics
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), json!(value)))
+                .collect();
+            output::write_json(&Value::Object(object), &mut handle)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+use bstr::ByteSlice;
+use gitrwlib::{objs::GitObject, Repository, TreeChange};
+
+/// Prints the paths that differ between the trees of `a` and `b` in `git diff --name-status`
+/// style, followed by the number of commits unique to each side.
+pub fn diff_refs(repository_path: PathBuf, a: &str, b: &str) -> Result<(), Box<dyn Error>> {
+    let mut repository = Repository::open_read_only(repository_path)?;
+
+    let a_hash = repository
+        .resolve(a)
+        .ok_or_else(|| format!("Unknown revision: {a}"))?;
+    let b_hash = repository
+        .resolve(b)
+        .ok_or_else(|| format!("Unknown revision: {b}"))?;
+
+    let mut root_tree = |rev: &str, hash| match repository.lookup_path(hash, b"") {
+        Some(GitObject::Tree(tree)) => Ok(tree.hash().clone()),
+        _ => Err(format!("{rev} does not point to a tree")),
+    };
+    let a_tree = root_tree(a, a_hash)?;
+    let b_tree = root_tree(b, b_hash)?;
+
+    let (only_in_a, only_in_b) = repository.ahead_behind(a, b)?;
+
+    let mut handle = BufWriter::new(stdout().lock());
+    for change in repository.diff_trees(Some(a_tree), Some(b_tree)) {
+        let status = match change {
+            TreeChange::Added(_) => 'A',
+            TreeChange::Removed(_) => 'D',
+            TreeChange::Modified(_) => 'M',
+        };
+        writeln!(handle, "{status}\t{}", change.path().as_bstr())?;
+    }
+
+    writeln!(
+        handle,
+        "{only_in_a} commits only in {a}, {only_in_b} commits only in {b}"
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,290 @@
+use std::{
+    error::Error,
+    fs,
+    io::{stdout, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use gitrwlib::{GitRwError, ReadOnly, Repository};
+use serde_json::json;
+
+use crate::output::{OutputFormat, Table};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Ok,
+    Info,
+    Warning,
+    Error,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Info => "info",
+            Status::Warning => "warning",
+            Status::Error => "error",
+        }
+    }
+}
+
+struct Finding {
+    check: &'static str,
+    status: Status,
+    message: String,
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn add(&mut self, check: &'static str, status: Status, message: impl Into<String>) {
+        self.0.push(Finding {
+            check,
+            status,
+            message: message.into(),
+        });
+    }
+}
+
+/// Checks whether gitrw can work on the repository and prints what to do about the problems it
+/// finds. Fails if one of the checks reports an error.
+pub fn doctor(repository_path: PathBuf, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    let mut findings = Findings::default();
+    match Repository::open_read_only(repository_path.clone()) {
+        Ok(mut repository) => check_repository(&mut repository, &mut findings),
+        Err(e) => {
+            let hint = match e {
+                GitRwError::Corrupt { .. } | GitRwError::Io { .. } => {
+                    ". git fsck shows which files are damaged"
+                }
+                _ => "",
+            };
+            findings.add("repository", Status::Error, format!("{e}{hint}"));
+        }
+    }
+
+    let mut handle = BufWriter::new(stdout().lock());
+    if format == OutputFormat::Plain {
+        for finding in findings.0.iter() {
+            writeln!(
+                handle,
+                "{:<7} {}: {}",
+                finding.status.name(),
+                finding.check,
+                finding.message
+            )?;
+        }
+    } else {
+        let mut table = Table::new(&["check", "status", "message"]);
+        for finding in findings.0.iter() {
+            table.push(vec![
+                json!(finding.check),
+                json!(finding.status.name()),
+                json!(finding.message),
+            ]);
+        }
+        table.write(format, &mut handle)?;
+    }
+    handle.flush()?;
+
+    let errors = findings
+        .0
+        .iter()
+        .filter(|finding| finding.status == Status::Error)
+        .count();
+    match errors {
+        0 => Ok(()),
+        1 => Err("1 check failed".into()),
+        n => Err(format!("{n} checks failed").into()),
+    }
+}
+
+fn check_repository(repository: &mut Repository<ReadOnly>, findings: &mut Findings) {
+    let path = repository.path().to_path_buf();
+    match repository.work_tree() {
+        Some(work_tree) => findings.add(
+            "repository",
+            Status::Ok,
+            format!(
+                "working copy at {}, check out the rewritten branches afterwards",
+                work_tree.display()
+            ),
+        ),
+        None => findings.add(
+            "repository",
+            Status::Ok,
+            format!("bare repository at {}", path.display()),
+        ),
+    }
+
+    if path.join("shallow").is_file() {
+        findings.add(
+            "shallow",
+            Status::Warning,
+            "shallow clone, rewrites only cover the fetched history. Run git fetch --unshallow first",
+        );
+    }
+
+    if fs::metadata(path.join("objects")).is_ok_and(|metadata| metadata.permissions().readonly()) {
+        findings.add(
+            "permissions",
+            Status::Error,
+            format!(
+                "{} is read-only, only --dry-run rewrites are possible",
+                path.join("objects").display()
+            ),
+        );
+    }
+
+    check_packs(repository, &path, findings);
+    check_refs(repository, findings);
+
+    if repository.has_commit_graph() {
+        findings.add("commit-graph", Status::Ok, "present");
+    } else {
+        findings.add(
+            "commit-graph",
+            Status::Info,
+            "missing, git commit-graph write --reachable speeds up walking the history",
+        );
+    }
+
+    check_disk_space(repository, &path, findings);
+}
+
+fn check_packs(repository: &Repository<ReadOnly>, path: &Path, findings: &mut Findings) {
+    let pack_dir = path.join("objects").join("pack");
+    for entry in fs::read_dir(&pack_dir).into_iter().flatten().flatten() {
+        let pack = entry.path();
+        if pack.extension().is_some_and(|e| e == "pack") && !pack.with_extension("idx").is_file() {
+            findings.add(
+                "packs",
+                Status::Warning,
+                format!(
+                    "{} has no idx file, its objects are ignored. Run git index-pack on it",
+                    pack.display()
+                ),
+            );
+        }
+    }
+
+    match repository.pack_stats(0) {
+        Ok(stats) if stats.iter().any(|stats| !stats.corrupt.is_empty()) => {
+            let corrupt: usize = stats.iter().map(|stats| stats.corrupt.len()).sum();
+            findings.add(
+                "packs",
+                Status::Error,
+                format!("{corrupt} pack entries have damaged headers, gitrw verify lists them"),
+            );
+        }
+        Ok(stats) => {
+            let entries: usize = stats
+                .iter()
+                .map(|stats| stats.commits + stats.trees + stats.blobs + stats.tags)
+                .sum();
+            findings.add(
+                "packs",
+                Status::Ok,
+                format!(
+                    "{} packs with {entries} entries, gitrw verify also checks their content",
+                    stats.len()
+                ),
+            );
+        }
+        Err(e) => findings.add(
+            "packs",
+            Status::Error,
+            format!("{e}. gitrw verify or git fsck show the damage"),
+        ),
+    }
+}
+
+fn check_refs(repository: &mut Repository<ReadOnly>, findings: &mut Findings) {
+    match repository.ref_journal() {
+        Ok(Some(entries)) => findings.add(
+            "ref journal",
+            Status::Error,
+            format!(
+                "a rewrite was interrupted while updating {} refs, finish it with gitrw recover-refs",
+                entries.len()
+            ),
+        ),
+        Ok(None) => {}
+        Err(e) => findings.add("ref journal", Status::Error, e.to_string()),
+    }
+
+    match repository.ref_infos() {
+        Ok(infos) => {
+            let missing: Vec<_> = infos
+                .iter()
+                .filter(|info| info.target_type.is_none())
+                .collect();
+            for info in missing.iter() {
+                findings.add(
+                    "refs",
+                    Status::Warning,
+                    format!(
+                        "{} points to the missing object {}, delete or fix it before a rewrite",
+                        info.name, info.target
+                    ),
+                );
+            }
+            if missing.is_empty() {
+                findings.add("refs", Status::Ok, format!("{} refs", infos.len()));
+            }
+        }
+        Err(e) => findings.add(
+            "refs",
+            Status::Error,
+            format!("{e}. Fix or delete the broken ref"),
+        ),
+    }
+}
+
+/// A rewrite writes the new commits and trees as loose objects, in the worst case as much as
+/// the repository already takes up.
+fn check_disk_space(repository: &Repository<ReadOnly>, path: &Path, findings: &mut Findings) {
+    let counts = repository.count_objects();
+    let needed = counts.pack_size + counts.loose_size;
+    match free_space(path) {
+        Some(free) if free < needed => findings.add(
+            "disk space",
+            Status::Warning,
+            format!(
+                "{} KiB free, a rewrite of the whole history can need up to {} KiB",
+                free / 1024,
+                needed / 1024
+            ),
+        ),
+        Some(free) => findings.add(
+            "disk space",
+            Status::Ok,
+            format!("{} KiB free", free / 1024),
+        ),
+        None => findings.add(
+            "disk space",
+            Status::Info,
+            "free space unknown on this platform",
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn free_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> Option<u64> {
+    None
+}
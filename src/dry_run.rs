@@ -0,0 +1,135 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+};
+
+use gitrwlib::{DryRunReport, RefStatus, RefUpdate};
+use serde_json::{json, Value};
+
+use crate::output::{self, OutputFormat};
+
+/// Prints what a dry run would have changed: the number of rewritten commits, the paths the
+/// delete patterns matched, the rewritten contributors and the refs that would move. Nothing is
+/// printed but the objects if no rewrite ran. `show_written` adds every object that would have
+/// been written.
+pub fn print_report(
+    report: &DryRunReport,
+    format: OutputFormat,
+    show_written: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut handle = BufWriter::new(stdout().lock());
+    let Some(commits) = report.commits else {
+        if show_written {
+            write_objects(report, &mut handle)?;
+        }
+        return Ok(());
+    };
+
+    if format == OutputFormat::Json {
+        let mut value = json!({
+            "commits": commits,
+            "removed_paths": report.removed_paths.iter().map(|path| path.to_string()).collect::<Vec<_>>(),
+            "contributors": report.contributors.iter().map(|(old, new)| json!({
+                "old": old.to_string(),
+                "new": new.to_string(),
+            })).collect::<Vec<_>>(),
+            "refs": report.refs.iter().map(ref_json).collect::<Vec<_>>(),
+        });
+        if show_written {
+            value["written"] = report
+                .objects
+                .iter()
+                .map(|object| {
+                    json!({
+                        "hash": object.hash.to_string(),
+                        "type": object.object_type,
+                        "size": object.size,
+                    })
+                })
+                .collect();
+        }
+        output::write_json(&value, &mut handle)?;
+        return Ok(());
+    }
+
+    writeln!(handle, "Dry run, nothing was changed")?;
+    writeln!(handle, "{commits} commits would be rewritten")?;
+
+    if !report.removed_paths.is_empty() {
+        writeln!(
+            handle,
+            "{} paths match the delete patterns:",
+            report.removed_paths.len()
+        )?;
+    }
+    for path in report.removed_paths.iter() {
+        writeln!(handle, "  {path}")?;
+    }
+
+    if !report.contributors.is_empty() {
+        writeln!(
+            handle,
+            "{} contributors would be rewritten:",
+            report.contributors.len()
+        )?;
+    }
+    for (old, new) in report.contributors.iter() {
+        writeln!(handle, "  {old} -> {new}")?;
+    }
+
+    let moved: Vec<&RefUpdate> = report
+        .refs
+        .iter()
+        .filter(|update| {
+            matches!(
+                update.status,
+                RefStatus::Updated(_) | RefStatus::Created(_) | RefStatus::Deleted
+            )
+        })
+        .collect();
+    match moved.len() {
+        0 => writeln!(handle, "0 refs would move")?,
+        n => writeln!(handle, "{n} refs would move:")?,
+    }
+    for update in moved {
+        writeln!(handle, "  {update}")?;
+    }
+    for update in report
+        .refs
+        .iter()
+        .filter(|update| matches!(update.status, RefStatus::Unmapped(_)))
+    {
+        writeln!(handle, "  {update}")?;
+    }
+
+    if show_written {
+        write_objects(report, &mut handle)?;
+    }
+
+    Ok(())
+}
+
+fn write_objects(report: &DryRunReport, handle: &mut impl Write) -> Result<(), Box<dyn Error>> {
+    for object in report.objects.iter() {
+        writeln!(handle, "{object}")?;
+    }
+    Ok(())
+}
+
+fn ref_json(update: &RefUpdate) -> Value {
+    let (status, new_target) = match &update.status {
+        RefStatus::Updated(target) => ("updated", Some(target)),
+        RefStatus::Created(target) => ("created", Some(target)),
+        RefStatus::Unchanged => ("unchanged", None),
+        RefStatus::Deleted => ("deleted", None),
+        RefStatus::Skipped => ("skipped", None),
+        RefStatus::Unmapped(_) => ("unmapped", None),
+    };
+
+    json!({
+        "name": update.name,
+        "status": status,
+        "old_target": update.old_target,
+        "new_target": new_target,
+    })
+}
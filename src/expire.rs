@@ -0,0 +1,28 @@
+use std::{error::Error, path::PathBuf, time::Duration};
+
+use gitrwlib::Repository;
+
+/// Removes the workspace files older than `days`, or than the configured expiry, and lists them.
+pub fn expire(
+    repository_path: PathBuf,
+    days: Option<u64>,
+    dry_run: bool,
+) -> Result<(), Box<dyn Error>> {
+    let repository = Repository::open(repository_path)?;
+    let max_age = days.map_or_else(
+        || repository.workspace_expiry(),
+        |days| Duration::from_secs(days * 24 * 60 * 60),
+    );
+
+    let expired = repository.expire_workspace(max_age, dry_run)?;
+    crate::print_locked(expired.files.iter().map(|path| path.display()))?;
+
+    let verb = if dry_run { "Would remove" } else { "Removed" };
+    println!(
+        "{verb} {} files, {} KiB",
+        expired.files.len(),
+        expired.bytes / 1024
+    );
+
+    Ok(())
+}
@@ -0,0 +1,33 @@
+use std::{error::Error, fs, path::PathBuf};
+
+use gitrwlib::{objs::GitObject, Repository};
+use rustc_hash::FxHashSet;
+
+/// Writes every distinct blob that was ever stored at `path` into `out`, named by the blob hash.
+/// Prints one line per version with the blob hash and the first commit it was found in.
+pub fn extract(repository_path: PathBuf, path: &str, out: PathBuf) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(&out)?;
+
+    let repository = Repository::open_read_only(repository_path)?;
+    let mut lookup_repository = repository.clone();
+    let mut seen_blobs = FxHashSet::default();
+
+    for commit in repository.commits_lifo() {
+        let blob = match lookup_repository.lookup_path(commit.tree().into(), path.as_bytes()) {
+            Some(GitObject::Blob(blob)) => blob,
+            _ => continue,
+        };
+
+        let blob_hash = blob.hash().to_string();
+        if seen_blobs.insert(blob_hash.clone()) {
+            fs::write(out.join(&blob_hash), blob.content())?;
+            println!("{blob_hash} {}", commit.hash);
+        }
+    }
+
+    if seen_blobs.is_empty() {
+        return Err(format!("Path '{path}' was not found in any commit").into());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use bstr::ByteSlice;
+use gitrwlib::{objs::CommitBase, Repository};
+use serde_json::json;
+
+use crate::output::{OutputFormat, Table};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    Hash,
+    Author,
+    Committer,
+    Date,
+    Subject,
+}
+
+impl Field {
+    const ALL: [(&'static str, Field); 5] = [
+        ("hash", Field::Hash),
+        ("author", Field::Author),
+        ("committer", Field::Committer),
+        ("date", Field::Date),
+        ("subject", Field::Subject),
+    ];
+
+    fn value(self, commit: &CommitBase) -> String {
+        match self {
+            Field::Hash => commit.hash.to_string(),
+            Field::Author => commit.author().to_string(),
+            Field::Committer => commit.committer().to_string(),
+            Field::Date => commit.author_time().to_string(),
+            Field::Subject => commit
+                .message()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .to_str_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// A line printed per commit, e.g. `{hash} {subject}`. The placeholders are `{hash}`,
+/// `{author}` and `{committer}` with name and email, `{date}` with the author date as stored in
+/// the commit and `{subject}`, the first line of the message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template(Vec<Part>);
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("missing }} after {}", &rest[start..]))?;
+            let name = &rest[start + 1..start + end];
+            let field = Field::ALL
+                .iter()
+                .find(|(field_name, _)| *field_name == name)
+                .map(|(_, field)| *field)
+                .ok_or_else(|| {
+                    format!("unknown placeholder {{{name}}}, expected one of hash, author, committer, date, subject")
+                })?;
+
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_owned()));
+            }
+            parts.push(Part::Field(field));
+            rest = &rest[start + end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_owned()));
+        }
+        Ok(Template(parts))
+    }
+}
+
+impl Template {
+    fn expand(&self, commit: &CommitBase) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                Part::Text(text) => text.clone(),
+                Part::Field(field) => field.value(commit),
+            })
+            .collect()
+    }
+}
+
+/// Lists the commits in the order gitrw walks them when rewriting, only their hashes unless a
+/// template or a table or JSON format is given.
+pub fn log(
+    repository_path: PathBuf,
+    template: Option<Template>,
+    max_count: Option<usize>,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let repository = Repository::open_read_only(repository_path)?;
+    let commits = repository
+        .commits_topo()
+        .take(max_count.unwrap_or(usize::MAX));
+
+    let mut handle = BufWriter::new(stdout().lock());
+    if format != OutputFormat::Plain {
+        let names: Vec<&'static str> = Field::ALL.iter().map(|(name, _)| *name).collect();
+        let mut table = Table::new(&names);
+        for commit in commits {
+            table.push(
+                Field::ALL
+                    .iter()
+                    .map(|(_, field)| json!(field.value(&commit)))
+                    .collect(),
+            );
+        }
+        table.write(format, &mut handle)?;
+        return Ok(());
+    }
+
+    let template = template.unwrap_or_else(|| Template(vec![Part::Field(Field::Hash)]));
+    for commit in commits {
+        writeln!(handle, "{}", template.expand(&commit))?;
+    }
+
+    handle.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use gitrwlib::{objs::CommitBase, ObjectHash};
+
+    use super::Template;
+
+    #[test]
+    fn templates() {
+        let bytes = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\nauthor A <a@x.org> 1600000000 +0200\ncommitter C <c@x.org> 1600000001 +0200\n\nSubject line\n\nBody\n";
+        let hash: ObjectHash = bstr::BStr::new("1234567890123456789012345678901234567890")
+            .try_into()
+            .unwrap();
+        let commit = CommitBase::create(hash.into(), bytes.as_bytes(), false);
+
+        let template: Template = "{hash} [{author}] {date}: {subject}!".parse().unwrap();
+        assert_eq!(
+            "1234567890123456789012345678901234567890 [A <a@x.org>] 1600000000 +0200: Subject line!",
+            template.expand(&commit)
+        );
+        assert_eq!(
+            "C <c@x.org>",
+            "{committer}".parse::<Template>().unwrap().expand(&commit)
+        );
+        assert!("{message}".parse::<Template>().is_err());
+        assert!("{hash".parse::<Template>().is_err());
+    }
+}
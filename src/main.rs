@@ -1,14 +1,51 @@
-use std::{error::Error, fmt::Display, io::BufWriter, path::PathBuf};
+use std::{
+    error::Error,
+    fmt::Display,
+    io::BufWriter,
+    path::PathBuf,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use clap::{ArgGroup, Parser, Subcommand};
+use gitrwlib::{
+    CancellationToken, CommitFilter, CommitMapFormat, DanglingRefs, MessageHashes, ReadOnly,
+    RefOptions, RefRename, RefUpdate, Repository, RewriteOptions, SkipTracker,
+};
 #[cfg(not(test))]
 use mimalloc::MiMalloc;
+use output::OutputFormat;
+use signal_hook::consts::SIGINT;
 
 use std::io::Write;
 
+mod analyze;
+mod archive;
+mod bundle;
 mod contributors;
+mod count_commits;
+mod count_objects;
+mod diff_refs;
+mod doctor;
+mod dry_run;
+mod expire;
+mod extract;
+mod log;
+mod map_compose;
+mod map_lookup;
+mod output;
+mod pack_objects;
 mod prune;
+mod range_stats;
+mod recover_refs;
+mod refs;
 mod remove;
+mod rename;
+mod replace_text;
+mod show;
+mod subdir_filter;
+mod timezone;
+mod verify;
 
 #[cfg(not(test))]
 #[global_allocator]
@@ -17,15 +54,111 @@ static GLOBAL: MiMalloc = MiMalloc;
 /// CLI tool for reading and rewriting history information of a git repository
 #[derive(clap::Parser)]
 struct Cli {
-    /// Path to the mirrored/bare repository (do not use on a repository with a working copy)
+    /// Path to the mirrored/bare repository (do not use on a repository with a working copy). analyze and contributor list also accept a .pack or bundle file
     repository: Option<String>,
 
     #[command(subcommand)]
     command: Commands,
 
-    /// Do not change the repository.
+    /// Do not change the repository, print a report instead: the number of commits that would be rewritten, the paths matched by delete patterns, the rewritten contributors and the refs that would move
     #[arg(short, long)]
     dry_run: bool,
+
+    /// Record the original hash of every rewritten commit as a git note in refs/notes/gitrw
+    #[arg(long, global = true)]
+    write_notes: bool,
+
+    /// Ref that must not be rewritten without --allow-protected, '*' matches any sequence of characters. Values of gitrw.protect in the repository config are protected as well. Argument can be specified multiple times
+    #[arg(long, global = true)]
+    protect: Vec<String>,
+
+    /// Allow rewriting protected refs
+    #[arg(long, global = true)]
+    allow_protected: bool,
+
+    /// Rewrite even if grafts, replace refs or a shallow clone make git show another history than the stored commits, which are rewritten as they are
+    #[arg(long, global = true)]
+    allow_altered_history: bool,
+
+    /// Leave remote-tracking refs (refs/remotes/*) unchanged. This is the default
+    #[arg(long, global = true, overrides_with = "include_remotes")]
+    skip_remotes: bool,
+
+    /// Also rewrite remote-tracking refs (refs/remotes/*)
+    #[arg(long, global = true, overrides_with = "skip_remotes")]
+    include_remotes: bool,
+
+    /// Delete refs matching the pattern while updating the refs, e.g. 'refs/pull/*'. Argument can be specified multiple times
+    #[arg(long, global = true)]
+    delete_refs: Vec<String>,
+
+    /// What to do with refs that point to missing objects: keep them as they are, delete them, or fail before rewriting anything
+    #[arg(long, global = true, default_value = "keep")]
+    dangling_refs: DanglingRefs,
+
+    /// Publish the rewritten refs whose name starts with OLD_PREFIX under NEW_PREFIX instead and leave the original refs unchanged, e.g. 'refs/heads/:refs/heads/archive/' to review a rewrite before switching over. Argument can be specified multiple times
+    #[arg(long, global = true, value_name = "OLD_PREFIX:NEW_PREFIX")]
+    ref_rename: Vec<RefRename>,
+
+    /// Leave all refs unchanged and create this ref pointing to the rewritten HEAD instead, e.g. refs/heads/cleaned to review or test a rewrite before touching any existing ref
+    #[arg(long, global = true, value_name = "REF", conflicts_with = "ref_rename")]
+    output_ref: Option<String>,
+
+    /// Leave the repository unchanged and write the rewritten history to a new bare repository at DIR instead. Objects are hard linked where possible, HEAD points to the rewritten default branch so the result can be cloned right away
+    #[arg(long, global = true, value_name = "DIR")]
+    target: Option<PathBuf>,
+
+    /// Print hash, type and size of every object the dry run would have written
+    #[arg(long, requires = "dry_run")]
+    show_written: bool,
+
+    /// Print the report of the dry run as JSON, same as --format json
+    #[arg(long, requires = "dry_run")]
+    json: bool,
+
+    /// Format of the file mapping old to new commits: text writes object-id-map.old-new.txt, zstd the much smaller object-id-map.old-new.bin.zst
+    #[arg(long, global = true, default_value = "text")]
+    map_format: CommitMapFormat,
+
+    /// Before updating the refs, re-hash every commit that stays unchanged and check that none of its parents was rewritten
+    #[arg(long, global = true)]
+    verify_unchanged: bool,
+
+    /// Regenerate info/refs and objects/info/packs after updating the refs, for repositories served over the dumb HTTP protocol. Happens automatically if one of them exists
+    #[arg(long, global = true)]
+    update_server_info: bool,
+
+    /// Remove the gpgsig and gpgsig-sha256 signatures of rewritten commits, which do not match them anymore. Commits that stay the same keep their signatures
+    #[arg(long, global = true)]
+    strip_signatures: bool,
+
+    /// Set the committer date of rewritten commits to the time the rewrite started, instead of keeping it. The timezone offset is kept, commits that stay the same keep their dates
+    #[arg(long, global = true)]
+    refresh_committer_date: bool,
+
+    /// Only modify the commits matching the expression, e.g. "author =~ 'bot@' && date < 2020-01-01". Fields are author, committer, message and hash (=~ !~ == !=) and date, author-date, committer-date and parents (== != < <= > >=), combined with &&, || and !. Other commits keep their content and only get rewritten parents
+    #[arg(long, global = true, value_name = "EXPR")]
+    commit_filter: Option<CommitFilter>,
+
+    /// Update hashes of rewritten commits mentioned in commit messages, e.g. "This reverts commit abcdef12": replace writes the new hash abbreviated to the same length, annotate also adds "[formerly <old>]"
+    #[arg(long, global = true, value_name = "MODE")]
+    message_hashes: Option<MessageHashes>,
+
+    /// Continue an interrupted rewrite: the commits listed in this commit mapping file (text format, e.g. a copy of object-id-map.old-new.txt) are taken as they are and every newly rewritten commit is appended as soon as it is written. Root commits that were dropped are listed with the all-zero hash. Created if missing. Only use it with the same command and options as the interrupted run
+    #[arg(long, global = true, value_name = "FILE")]
+    state_file: Option<PathBuf>,
+
+    /// How contributor list, analyze, refs, count-objects, log, doctor, verify and the dry run report print their results
+    #[arg(long, global = true, value_enum, default_value_t)]
+    format: OutputFormat,
+}
+
+impl Cli {
+    /// Remote-tracking refs are skipped unless the last of `--include-remotes` and
+    /// `--skip-remotes` includes them.
+    fn include_remotes(&self) -> bool {
+        self.include_remotes && !self.skip_remotes
+    }
 }
 
 #[derive(Subcommand)]
@@ -50,56 +183,700 @@ enum Commands {
         /// Regex to remove files. Matches on the whole path including the filename, which makes it a little more expensive than the file or directory options. Argument can be specified multiple times
         #[arg(short, long, group = "input")]
         regex: Option<Vec<String>>,
+
+        /// Regex to remove files, matched against the filename only. Argument can be specified multiple times
+        #[arg(long, group = "input")]
+        filename_regex: Option<Vec<String>>,
+
+        /// Regex of files to keep, every file whose whole path does not match any of these is removed. Argument can be specified multiple times
+        #[arg(long, group = "input")]
+        regex_keep: Option<Vec<String>>,
+
+        /// Remove files bigger than the given size, optionally only files matching a file pattern: <SIZE>[:<PATTERN>], e.g. 5M:*.json or 100K. Sizes are read from the object headers without inflating the blobs. Argument can be specified multiple times
+        #[arg(long, visible_alias = "blobs-bigger-than", group = "input")]
+        bigger_than: Option<Vec<remove::SizeLimit>>,
+
+        /// File with one regex per line, handled like --regex. Use '-' to read the patterns from stdin
+        #[arg(long, group = "input")]
+        regex_from: Option<String>,
+
+        /// Keep the files and directories the other arguments match and remove everything else, e.g. to split a repository. Directories are removed once nothing in them is kept
+        #[arg(long, conflicts_with = "regex_keep")]
+        invert: bool,
+
+        /// Run the rewrite a second time without writing and verify that all new commit hashes match before updating refs
+        #[arg(long)]
+        check_reproducible: bool,
+
+        /// After a reproducible rewrite, delete the loose objects of the original commits and their trees that are not reachable from any ref anymore. Reflog entries pointing to them become invalid
+        #[arg(long, requires = "check_reproducible")]
+        prune_loose: bool,
+
+        /// What to do with commits whose whole content was removed
+        #[arg(long, value_enum, default_value_t)]
+        on_empty_tree: remove::EmptyTree,
+
+        /// Print how many trees were rewritten and the directories with the most removed bytes. Every file is counted once, with its uncompressed size
+        #[arg(long)]
+        report: bool,
+
+        /// Before updating refs, re-read every rewritten root tree and compare it with the original: everything the filters match has to be gone and everything else unchanged
+        #[arg(long)]
+        verify_trees: bool,
     },
 
-    /// Remove empty commits that are no merge commits
-    PruneEmpty,
+    /// Remove empty commits that are no merge commits. Duplicate parents of merges are removed, merges left with a single parent are removed as well if they do not change the tree
+    PruneEmpty {
+        /// Drop parents of octopus merges that became duplicates or ancestors of another parent
+        #[arg(long)]
+        simplify_octopus: bool,
+
+        /// Drop parents of all merges that are ancestors of another parent, including the first parent, like git filter-repo does for degenerate merges
+        #[arg(long)]
+        prune_degenerate: bool,
+    },
+
+    /// Write every distinct version of a file found in history into a directory
+    Extract {
+        /// Path of the file inside the repository, e.g. config/secrets.txt
+        #[arg(short, long)]
+        path: String,
+
+        /// Output directory, files are named by their blob hash
+        #[arg(short, long)]
+        out: PathBuf,
+    },
+
+    /// Print an object. Use <rev>:<path> to print the file content or directory listing at a path
+    #[command(visible_alias = "cat")]
+    Show {
+        /// Object hash, abbreviated hash or ref name, optionally followed by :<path>, e.g. main:src/main.rs
+        object: String,
+
+        /// Print the type of the object instead of its content
+        #[arg(short = 't', long = "type", conflicts_with_all = ["size", "location"])]
+        object_type: bool,
+
+        /// Print the size of the object in bytes instead of its content
+        #[arg(short, long, conflicts_with = "location")]
+        size: bool,
+
+        /// Print every place the object is stored in instead of its content: the packs with the offset of its entry and the loose file
+        #[arg(long)]
+        location: bool,
+
+        /// Print the object content as stored: binary blobs and trees in their binary format
+        #[arg(long, conflicts_with_all = ["object_type", "size", "location"])]
+        raw: bool,
+    },
+
+    /// Write the tree of a commit into a tar archive
+    Archive {
+        /// Commit hash or ref name
+        rev: String,
+
+        /// Output file, writes to stdout if omitted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Summarize the differences between two refs: changed paths and commits unique to each side
+    DiffRefs {
+        /// Commit hash or ref name of the original side
+        a: String,
+
+        /// Commit hash or ref name of the compared side
+        b: String,
+    },
+
+    /// Normalize the timezone offsets of authors and committers, the timestamps themselves stay unchanged
+    Timezone {
+        /// Offset all dates are set to
+        #[arg(long, default_value = "+0000")]
+        offset: timezone::Offset,
+
+        /// File with lines of the format: User <user.mail> = +0200. Listed contributors get their own offset instead of --offset
+        #[arg(long)]
+        zones: Option<PathBuf>,
+
+        /// Only rewrite offsets that are not valid, e.g. +51800
+        #[arg(long)]
+        invalid_only: bool,
+    },
+
+    /// Count packs, packed objects by type and loose objects, sizes are reported in KiB
+    CountObjects,
+
+    /// Report statistics of every pack: objects by type, delta chain depths and compression, sizes are reported in KiB
+    Analyze {
+        /// Number of entries to list per pack for the worst compression and the deepest delta chains
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+
+    /// Read every packed object and check that its content matches its hash
+    Verify {
+        /// Also check the CRC32 of every raw pack entry against the idx file, which pinpoints corruption in compressed data
+        #[arg(long)]
+        crc: bool,
+
+        /// Number of corrupt objects to list, all further ones are only counted
+        #[arg(long, default_value_t = gitrwlib::DEFAULT_MAX_ERRORS)]
+        max_errors: usize,
+    },
+
+    /// Show the ref updates of a rewrite that was interrupted while updating refs, or finish them
+    RecoverRefs {
+        /// Point all refs to their rewritten targets
+        #[arg(long, conflicts_with = "rollback")]
+        complete: bool,
+
+        /// Point all refs back to their original targets
+        #[arg(long)]
+        rollback: bool,
+    },
+
+    /// List every ref with its target, the type of the target and the commit annotated tags peel to
+    Refs {
+        /// Print the refs as a JSON array, same as --format json
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Count the commits of a range, of every ref, or how far two revisions diverged
+    CountCommits {
+        /// Revision or range like v1.0..main, every ref is counted on its own if omitted
+        range: Option<String>,
+
+        /// Print how many commits RANGE is ahead of and behind this revision instead
+        #[arg(long, value_name = "REV", requires = "range")]
+        ahead_behind: Option<String>,
+    },
+
+    /// Look up what rewritten commits became, using the commit mapping file of a rewrite
+    MapLookup {
+        /// Old commit hashes, abbreviated to at least 4 hex digits
+        #[arg(required = true)]
+        hashes: Vec<String>,
+
+        /// Commit mapping file, defaults to the one written into the current directory
+        #[arg(long)]
+        map: Option<PathBuf>,
+
+        /// Look up new hashes and print the commits they were rewritten from
+        #[arg(short, long)]
+        reverse: bool,
+    },
+
+    /// Write a pack of all objects reachable from the given revisions, e.g. to pipe a rewritten history into git index-pack --stdin
+    PackObjects {
+        /// Revisions to pack, ^REV excludes the commits reachable from REV and A..B is short for ^A B. Packs every ref if omitted
+        revs: Vec<String>,
+
+        /// Output file, writes to stdout if omitted
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Bundle related actions like create
+    #[command(subcommand)]
+    Bundle(BundleArgs),
+
+    /// Replace text in every file of the history, e.g. passwords. Binary files are left alone
+    ReplaceText {
+        /// File with one rule per line: TEXT, TEXT==>REPLACEMENT, regex:PATTERN or regex:PATTERN==>REPLACEMENT. Text without replacement becomes ***REMOVED***, regex replacements can use $1 for capture groups. Lines starting with '#' are ignored
+        rules: PathBuf,
+    },
+
+    /// Move files and directories to another path in every commit, like git filter-repo --path-rename. Directories moved onto existing ones are merged into them
+    Rename {
+        /// Path to move, argument can be specified multiple times
+        #[arg(long, required = true)]
+        from: Vec<String>,
+
+        /// New path of the --from argument at the same position
+        #[arg(long, required = true)]
+        to: Vec<String>,
+    },
+
+    /// Make a subdirectory the root of every commit, e.g. to extract a component into its own repository. Commits without the directory and commits that are no merges and leave it unchanged are dropped
+    SubdirFilter {
+        /// Directory that becomes the new root, relative to the repository root
+        directory: String,
+    },
+
+    /// Count the commits, trees and blobs each range introduced and the size of the blobs in bytes, e.g. to find the release that bloated the repository. Trees and blobs count if no parent of a commit has them at the same path
+    RangeStats {
+        /// Revisions or ranges like v1.0..v2.0, every commit reachable from a single revision is counted
+        #[arg(required = true)]
+        ranges: Vec<String>,
+    },
+
+    /// Delete old files of the gitrw workspace (gitrw/ in the repository directory): mappings of cancelled rewrites and temporary files. The journal of an interrupted ref update is kept. Rewrites do this automatically
+    Expire {
+        /// Delete files older than this many days, 0 deletes all. Defaults to gitrw.expireDays of the repository config or 14
+        #[arg(long)]
+        days: Option<u64>,
+    },
+
+    /// Commit mapping related actions like compose
+    #[command(subcommand)]
+    Map(MapArgs),
+
+    /// List the commits in the order gitrw walks them when rewriting, starting from all refs
+    #[command(visible_alias = "rev-list")]
+    Log {
+        /// Line printed per commit instead of its hash, with the placeholders {hash}, {author}, {committer}, {date} (the author date as stored), {subject}, e.g. "{hash} {subject}". Only used by the plain format
+        #[arg(long, value_name = "TEMPLATE")]
+        pretty: Option<log::Template>,
+
+        /// Stop after this many commits
+        #[arg(short = 'n', long)]
+        max_count: Option<usize>,
+    },
+
+    /// Check whether the repository is ready for a rewrite: packs and refs readable, no interrupted ref update, commit-graph present and enough disk space. Fails if a check finds an error
+    #[command(after_help = "Examples:\n  gitrw doctor\n  gitrw --format json doctor")]
+    Doctor,
+}
+
+impl Commands {
+    /// Whether the command rewrites the history, the commands `--target` applies to.
+    fn rewrites(&self) -> bool {
+        matches!(
+            self,
+            Commands::Contributor(ContributorArgs::Rewrite { .. })
+                | Commands::Remove { .. }
+                | Commands::PruneEmpty { .. }
+                | Commands::Timezone { .. }
+                | Commands::ReplaceText { .. }
+                | Commands::Rename { .. }
+                | Commands::SubdirFilter { .. }
+        )
+    }
 }
 
 #[derive(Subcommand)]
 enum ContributorArgs {
     /// Lists all authors and committers
+    ///
+    /// With --format table, csv or json also the number of commits authored and committed by each of them and the dates of their first and last commit
+    #[command(
+        after_help = "Examples:\n  gitrw contributor list\n  gitrw --format csv contributor list > contributors.csv"
+    )]
     List,
-    /// Allows to rewrite contributors. Expects stdin input lines with the format: Old User <old@user.mail> = New User <new@user.mail>
-    Rewrite,
+    /// Allows to rewrite contributors. Expects mapping lines with the format: Old User <old@user.mail> = New User <new@user.mail>
+    Rewrite {
+        /// File with the mapping lines, read from stdin if omitted or '-'. Blank lines and lines starting with '#' are ignored
+        mapping_file: Option<PathBuf>,
+
+        /// Set the committer and commit date of every commit to its (rewritten) author and author date
+        #[arg(long, conflicts_with_all = ["author_only", "committer_only"])]
+        committer_from_author: bool,
+
+        /// Only apply the mapping to authors, committers stay unchanged
+        #[arg(long, conflicts_with = "committer_only")]
+        author_only: bool,
+
+        /// Only apply the mapping to committers, authors stay unchanged
+        #[arg(long)]
+        committer_only: bool,
+
+        /// Only change commits that are reachable from refs matching the pattern but not from any other ref, e.g. the commits unique to a fork. '*' matches any sequence of characters, e.g. refs/heads/fork-*. Argument can be specified multiple times
+        #[arg(long)]
+        only_refs: Option<Vec<String>>,
+
+        /// Regex every new identity has to match, by default they have to look like Name <email>
+        #[arg(long, value_name = "REGEX")]
+        identity_pattern: Option<String>,
+
+        /// Match the old identities of the mapping case-insensitively
+        #[arg(long)]
+        ignore_case: bool,
+
+        /// Match the old identities in Unicode normalization form C, so e.g. an 'é' written as 'e' and a combining accent by older clients is matched by the same mapping line
+        #[arg(long)]
+        normalize_unicode: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum MapArgs {
+    /// Combine the commit mapping files of consecutive rewrites into one mapping the original commits to the final ones, e.g. to translate references after several runs of gitrw. Fails if a rewrite did not run on the result of the one before
+    Compose {
+        /// Commit mapping files in the order the rewrites ran
+        #[arg(required = true, num_args = 2..)]
+        maps: Vec<PathBuf>,
+
+        /// Output file, writes to stdout if omitted. Written in the --map-format
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum BundleArgs {
+    /// Write a bundle of refs that can be cloned or fetched from like a remote, e.g. to share a rewritten repository
+    Create {
+        /// Bundle file to write
+        file: PathBuf,
+
+        /// Refs to bundle, ^REV excludes the commits reachable from REV and A..B is short for ^A B. The receiving repository must already have the excluded commits. Bundles every ref and HEAD if omitted
+        revs: Vec<String>,
+    },
 }
 
 fn main() {
-    let cli = Cli::parse();
-    let repository_path = PathBuf::from(cli.repository.unwrap_or(String::from(".")));
+    let result = run(Cli::parse());
+
+    let skipped = skip_tracker().report();
+    if !skipped.is_empty() {
+        eprintln!("warning: {skipped}");
+    }
+
+    if let Err(e) = result {
+        eprintln!("error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let include_remotes = cli.include_remotes();
+    let mut repository_path = PathBuf::from(cli.repository.unwrap_or(String::from(".")));
+    // a dry run reports the same changes for the repository itself, no copy is needed
+    if let Some(target) = cli.target.filter(|_| !cli.dry_run) {
+        if !cli.command.rewrites() {
+            return Err("--target only applies to commands that rewrite the history".into());
+        }
+        Repository::open_read_only(repository_path)?.copy_to(&target)?;
+        repository_path = target;
+    }
+    let rewrite_options = RewriteOptions {
+        dry_run: cli.dry_run,
+        write_notes: cli.write_notes,
+        ref_options: RefOptions {
+            protected: cli.protect,
+            allow_protected: cli.allow_protected,
+            include_remotes,
+            delete: cli.delete_refs,
+            dangling: cli.dangling_refs,
+            allow_altered_history: cli.allow_altered_history,
+            rename: cli.ref_rename,
+            output_ref: cli.output_ref,
+        },
+        map_format: cli.map_format,
+        verify_unchanged: cli.verify_unchanged,
+        update_server_info: cli.update_server_info,
+        strip_signatures: cli.strip_signatures,
+        refresh_committer_date: cli.refresh_committer_date.then(|| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            now.as_secs() as i64
+        }),
+        commit_filter: cli.commit_filter,
+        message_hashes: cli.message_hashes,
+        state_file: cli.state_file,
+    };
+
+    // paths of pack files can not be captured, nothing gets rewritten there anyway
+    let capture = match Repository::open_read_only(repository_path.clone()) {
+        Ok(repository) if cli.dry_run => {
+            repository.capture_dry_run();
+            Some(repository)
+        }
+        _ => None,
+    };
 
     match cli.command {
         Commands::Contributor(args) => match args {
             ContributorArgs::List => {
-                print_locked(
-                    contributors::get_contributors(repository_path)
-                        .unwrap()
-                        .iter(),
-                )
-                .unwrap();
+                contributors::list_contributors(repository_path, cli.format)?;
             }
-            ContributorArgs::Rewrite => {
-                contributors::rewrite(repository_path, cli.dry_run).unwrap();
+            ContributorArgs::Rewrite {
+                mapping_file,
+                committer_from_author,
+                author_only,
+                committer_only,
+                only_refs,
+                identity_pattern,
+                ignore_case,
+                normalize_unicode,
+            } => {
+                let fields = if author_only {
+                    contributors::IdentityFields::AuthorOnly
+                } else if committer_only {
+                    contributors::IdentityFields::CommitterOnly
+                } else {
+                    contributors::IdentityFields::Both
+                };
+
+                contributors::rewrite(
+                    repository_path,
+                    mapping_file,
+                    committer_from_author,
+                    only_refs,
+                    identity_pattern,
+                    fields,
+                    contributors::IdentityMatching {
+                        ignore_case,
+                        normalize_unicode,
+                    },
+                    &rewrite_options,
+                )?;
             }
         },
         Commands::Remove {
             file,
             directory,
             regex,
+            filename_regex,
+            regex_keep,
+            bigger_than,
+            regex_from,
+            invert,
+            check_reproducible,
+            prune_loose,
+            on_empty_tree,
+            report,
+            verify_trees,
         } => {
+            let mut regexes = regex.unwrap_or_default();
+            if let Some(source) = regex_from {
+                regexes.append(&mut remove::read_regex_patterns(&source)?);
+            }
+
+            let filters = remove::Filters {
+                files: file.unwrap_or_default(),
+                directories: directory.unwrap_or_default(),
+                regexes,
+                filename_regexes: filename_regex.unwrap_or_default(),
+                keep_regexes: regex_keep.unwrap_or_default(),
+                size_limits: bigger_than.unwrap_or_default(),
+                invert,
+            };
+
             remove::remove(
                 repository_path,
-                file.unwrap_or_default(),
-                directory.unwrap_or_default(),
-                regex.unwrap_or_default(),
-                cli.dry_run,
-            );
+                filters,
+                &rewrite_options,
+                check_reproducible,
+                prune_loose,
+                on_empty_tree,
+                report,
+                verify_trees,
+            )?;
         }
 
-        Commands::PruneEmpty => {
-            prune::remove_empty_commits(repository_path, cli.dry_run).unwrap();
+        Commands::PruneEmpty {
+            simplify_octopus,
+            prune_degenerate,
+        } => {
+            prune::remove_empty_commits(
+                repository_path,
+                &rewrite_options,
+                simplify_octopus,
+                prune_degenerate,
+            )?;
         }
+
+        Commands::Archive { rev, output } => {
+            archive::archive(repository_path, &rev, output)?;
+        }
+
+        Commands::DiffRefs { a, b } => {
+            diff_refs::diff_refs(repository_path, &a, &b)?;
+        }
+
+        Commands::Extract { path, out } => {
+            extract::extract(repository_path, &path, out)?;
+        }
+
+        Commands::Show {
+            object,
+            object_type,
+            size,
+            location,
+            raw,
+        } => {
+            show::show(repository_path, &object, object_type, size, location, raw)?;
+        }
+
+        Commands::Timezone {
+            offset,
+            zones,
+            invalid_only,
+        } => {
+            timezone::normalize(
+                repository_path,
+                offset,
+                zones,
+                invalid_only,
+                &rewrite_options,
+            )?;
+        }
+
+        Commands::CountObjects => {
+            count_objects::count_objects(repository_path, cli.format)?;
+        }
+
+        Commands::Analyze { top } => {
+            analyze::analyze(repository_path, top, cli.format)?;
+        }
+
+        Commands::Verify { crc, max_errors } => {
+            verify::verify(repository_path, crc, max_errors, cli.format)?;
+        }
+
+        Commands::RecoverRefs { complete, rollback } => {
+            recover_refs::recover_refs(repository_path, complete, rollback)?;
+        }
+
+        Commands::Refs { json } => {
+            let format = if json { OutputFormat::Json } else { cli.format };
+            refs::list_refs(repository_path, format)?;
+        }
+
+        Commands::CountCommits {
+            range,
+            ahead_behind,
+        } => {
+            count_commits::count_commits(repository_path, range, ahead_behind)?;
+        }
+
+        Commands::MapLookup {
+            hashes,
+            map,
+            reverse,
+        } => {
+            map_lookup::map_lookup(hashes, map, reverse)?;
+        }
+
+        Commands::PackObjects { revs, output } => {
+            pack_objects::pack_objects(repository_path, revs, output)?;
+        }
+
+        Commands::Bundle(args) => match args {
+            BundleArgs::Create { file, revs } => {
+                bundle::create(repository_path, file, revs)?;
+            }
+        },
+
+        Commands::ReplaceText { rules } => {
+            replace_text::replace_text(repository_path, rules, &rewrite_options)?;
+        }
+
+        Commands::Rename { from, to } => {
+            if from.len() != to.len() {
+                return Err("Every --from needs a matching --to".into());
+            }
+
+            let renames = from
+                .iter()
+                .zip(to.iter())
+                .map(|(from, to)| rename::PathRename::new(from, to))
+                .collect::<Result<Vec<_>, _>>()?;
+            rename::rename(repository_path, renames, &rewrite_options)?;
+        }
+
+        Commands::SubdirFilter { directory } => {
+            subdir_filter::subdir_filter(repository_path, &directory, &rewrite_options)?;
+        }
+
+        Commands::RangeStats { ranges } => {
+            range_stats::range_stats(repository_path, &ranges, cli.format)?;
+        }
+
+        Commands::Expire { days } => {
+            expire::expire(repository_path, days, cli.dry_run)?;
+        }
+
+        Commands::Map(args) => match args {
+            MapArgs::Compose { maps, output } => {
+                map_compose::compose(maps, output, cli.map_format)?;
+            }
+        },
+
+        Commands::Log { pretty, max_count } => {
+            log::log(repository_path, pretty, max_count, cli.format)?;
+        }
+
+        Commands::Doctor => {
+            doctor::doctor(repository_path, cli.format)?;
+        }
+    };
+
+    if let Some(repository) = capture {
+        let format = if cli.json {
+            OutputFormat::Json
+        } else {
+            cli.format
+        };
+        dry_run::print_report(&repository.dry_run_report(), format, cli.show_written)?;
+    }
+
+    Ok(())
+}
+
+/// Opens the repository for commands that rewrite history. Working copies are refused, their
+/// checked out files and index would not match the rewritten commits anymore.
+fn open_for_rewrite(repository_path: PathBuf) -> Result<Repository, Box<dyn Error>> {
+    let display_path = repository_path.display().to_string();
+    let mut repository = Repository::open(repository_path)?;
+    if !repository.is_bare() {
+        return Err(format!(
+            "{display_path} is a working copy, rewriting it would leave the checked out files behind. Run gitrw on a bare or mirrored clone instead, e.g. git clone --mirror {display_path} repo.git"
+        )
+        .into());
+    }
+
+    repository.set_cancellation(interrupt_token()?);
+    repository.set_skip_tracker(skip_tracker());
+    Ok(repository)
+}
+
+/// Opens the repository for read-only commands that can also inspect a standalone pack or bundle
+/// file, e.g. an upload that was not accepted yet.
+fn open_for_reading(repository_path: PathBuf) -> Result<Repository<ReadOnly>, Box<dyn Error>> {
+    let mut repository = if repository_path.is_file() {
+        Repository::open_pack_file(repository_path)?
+    } else {
+        Repository::open_read_only(repository_path)?
     };
+
+    repository.set_skip_tracker(skip_tracker());
+    Ok(repository)
+}
+
+/// Collects the refs and parents skipped by the commit walks of all repositories opened by the
+/// command, reported once it finishes.
+fn skip_tracker() -> SkipTracker {
+    static TRACKER: OnceLock<SkipTracker> = OnceLock::new();
+    TRACKER.get_or_init(SkipTracker::new).clone()
+}
+
+/// Cancelled by the first Ctrl-C: rewrites stop walking commits, write the mapping of the
+/// commits rewritten so far and leave the refs untouched. A second Ctrl-C terminates right away.
+fn interrupt_token() -> Result<CancellationToken, Box<dyn Error>> {
+    static TOKEN: OnceLock<CancellationToken> = OnceLock::new();
+    if let Some(token) = TOKEN.get() {
+        return Ok(token.clone());
+    }
+
+    let token = CancellationToken::new();
+    // handlers run in the order they were registered, so this only exits on the second Ctrl-C
+    signal_hook::flag::register_conditional_shutdown(SIGINT, 130, token.flag())?;
+    signal_hook::flag::register(SIGINT, token.flag())?;
+    Ok(TOKEN.get_or_init(|| token).clone())
+}
+
+/// Prints what happened to the refs, in a dry run the report lists them instead.
+fn print_ref_updates(
+    ref_updates: &[RefUpdate],
+    options: &RewriteOptions,
+) -> Result<(), Box<dyn Error>> {
+    if options.dry_run {
+        return Ok(());
+    }
+    print_locked(ref_updates.iter())
 }
 
 fn print_locked<T: Display>(items: impl Iterator<Item = T>) -> Result<(), Box<dyn Error>> {
@@ -118,7 +895,10 @@ mod tests {
     use std::sync::mpsc::channel;
 
     use bstr::ByteSlice;
-    use libgitrw::objs::{CommitBase, CommitEditable, CommitHash};
+    use clap::{CommandFactory, Parser};
+    use gitrwlib::objs::{CommitBase, CommitEditable, CommitHash};
+
+    use super::Cli;
 
     const BYTES: &[u8] = b"tree 31aa860596f003d69b896943677e9fe5ff208233\nparent 5eec99927bb6058c8180e5dac871c89c7d01b0ab\nauthor Tim Heinrich <2929650+TimHeinrich@users.noreply.github.com> 1688207675 +0200\ncommitter Tim Heinrich <2929650+TimHeinrich@users.noreply.github.com> 1688209149 +0200\n\nChanging of commit data\n";
 
@@ -129,8 +909,7 @@ mod tests {
             .try_into()
             .unwrap();
 
-        let mut commit =
-            CommitEditable::create(CommitBase::create(object_hash, BYTES.into(), false));
+        let mut commit = CommitEditable::create(CommitBase::create(object_hash, BYTES, false));
 
         let author = commit.author().to_owned();
         commit.set_author(b"Test user".to_vec());
@@ -151,4 +930,23 @@ mod tests {
 
         thread.join().unwrap();
     }
+
+    #[test]
+    fn cli_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn last_remotes_flag_wins() {
+        let parse = |args: &[&str]| {
+            Cli::try_parse_from(["gitrw"].iter().chain(args).chain(&["refs"]))
+                .unwrap()
+                .include_remotes()
+        };
+
+        assert!(!parse(&[]));
+        assert!(parse(&["--include-remotes"]));
+        assert!(!parse(&["--include-remotes", "--skip-remotes"]));
+        assert!(parse(&["--skip-remotes", "--include-remotes"]));
+    }
 }
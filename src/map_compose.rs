@@ -0,0 +1,41 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{stdout, Write},
+    path::PathBuf,
+};
+
+use gitrwlib::{map_compose, CommitMapFormat, CommitMapReader, CommitMapWriter};
+
+/// Combines the commit mapping files of consecutive rewrites, oldest first, into the mapping of
+/// the original to the final commits, see `map_compose`. Writes it to `output` or stdout.
+pub fn compose(
+    maps: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    format: CommitMapFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut composed = CommitMapReader::open(&maps[0])
+        .map_err(|e| format!("{}: {e}", maps[0].display()))?
+        .read_map()?;
+    for map in &maps[1..] {
+        let next = CommitMapReader::open(map)
+            .and_then(CommitMapReader::read_map)
+            .map_err(|e| format!("{}: {e}", map.display()))?;
+        composed = map_compose(&composed, &next).map_err(|e| format!("{}: {e}", map.display()))?;
+    }
+
+    let writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(stdout().lock()),
+    };
+    let mut sorted: Vec<_> = composed.iter().collect();
+    sorted.sort_unstable();
+    let mut writer = CommitMapWriter::create(writer, format)?;
+    for (old, new) in sorted {
+        writer.write(old, new)?;
+    }
+    writer.finish()?.flush()?;
+
+    eprintln!("Composed {} rewritten commits", composed.len());
+    Ok(())
+}
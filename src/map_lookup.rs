@@ -0,0 +1,60 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use gitrwlib::{CommitMapFormat, CommitMapReader, ObjectHash};
+
+/// Prints `<old> <new>` for every rewritten commit matching one of the (abbreviated) `hashes`,
+/// `<new> <old>` with `reverse`. Without `map` the mapping file of the last rewrite in the
+/// current directory is read. Fails if any of the hashes has no mapping.
+pub fn map_lookup(
+    hashes: Vec<String>,
+    map: Option<PathBuf>,
+    reverse: bool,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(hash) = hashes.iter().find(|hash| {
+        hash.len() < 4 || hash.len() > 40 || !hash.bytes().all(|c| c.is_ascii_hexdigit())
+    }) {
+        return Err(
+            format!("'{hash}' is no commit hash, at least 4 hex digits are required").into(),
+        );
+    }
+
+    let map = match map {
+        Some(map) => map,
+        None => [CommitMapFormat::Zstd, CommitMapFormat::Text]
+            .iter()
+            .map(|format| PathBuf::from(format.file_name()))
+            .find(|path| path.is_file())
+            .ok_or("No commit mapping file found in the current directory, pass it with --map")?,
+    };
+
+    let prefixes: Vec<&str> = hashes.iter().map(String::as_str).collect();
+    let found = CommitMapReader::open(Path::new(&map))?.find(&prefixes, reverse)?;
+
+    let mut handle = BufWriter::new(stdout().lock());
+    let mut missing = Vec::new();
+    for prefix in prefixes {
+        let mut matched = false;
+        for (old, new) in found.iter() {
+            let (from, to) = if reverse { (new, old) } else { (old, new) };
+            if ObjectHash::from(from.clone()).has_hex_prefix(prefix) {
+                writeln!(handle, "{from} {to}")?;
+                matched = true;
+            }
+        }
+
+        if !matched {
+            missing.push(prefix);
+        }
+    }
+    handle.flush()?;
+
+    if !missing.is_empty() {
+        return Err(format!("No mapping for {}", missing.join(", ")).into());
+    }
+
+    Ok(())
+}
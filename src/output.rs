@@ -0,0 +1,194 @@
+use std::io::{self, Write};
+
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    /// The line format of the command, meant to be read by people
+    #[default]
+    Plain,
+    /// Aligned columns with a header line
+    Table,
+    /// JSON, meant to be read by scripts
+    Json,
+    /// Comma separated values with a header line, for spreadsheets. Commands without a table,
+    /// like the dry run report, print their plain format instead
+    Csv,
+}
+
+/// Rows with named columns, printed as aligned columns, as CSV or as a JSON array of objects
+/// with the column names as keys.
+pub struct Table {
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn new(columns: &[&'static str]) -> Self {
+        Table {
+            columns: columns.to_vec(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Value>) {
+        assert_eq!(self.columns.len(), row.len(), "one value per column");
+        self.rows.push(row);
+    }
+
+    /// Plain prints the values of a row separated by spaces, without a header.
+    pub fn write(&self, format: OutputFormat, handle: &mut impl Write) -> io::Result<()> {
+        match format {
+            OutputFormat::Plain => {
+                for row in self.rows.iter() {
+                    let cells: Vec<String> = row.iter().map(cell).collect();
+                    writeln!(handle, "{}", cells.join(" "))?;
+                }
+                Ok(())
+            }
+            OutputFormat::Table => self.write_aligned(handle),
+            OutputFormat::Csv => self.write_csv(handle),
+            OutputFormat::Json => {
+                let objects: Vec<Value> = self
+                    .rows
+                    .iter()
+                    .map(|row| {
+                        let object: Map<String, Value> = self
+                            .columns
+                            .iter()
+                            .map(|column| column.to_string())
+                            .zip(row.iter().cloned())
+                            .collect();
+                        Value::Object(object)
+                    })
+                    .collect();
+                write_json(&Value::Array(objects), handle)
+            }
+        }
+    }
+
+    /// Numbers are aligned to the right, everything else to the left.
+    fn write_aligned(&self, handle: &mut impl Write) -> io::Result<()> {
+        let cells: Vec<Vec<String>> = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(cell).collect())
+            .collect();
+
+        let mut widths: Vec<usize> = self.columns.iter().map(|c| c.chars().count()).collect();
+        for row in cells.iter() {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let header: Vec<String> = self
+            .columns
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, &width)| format!("{:width$}", column.to_uppercase()))
+            .collect();
+        writeln!(handle, "{}", header.join("  ").trim_end())?;
+
+        for (row, values) in cells.iter().zip(self.rows.iter()) {
+            let line: Vec<String> = row
+                .iter()
+                .zip(values)
+                .zip(widths.iter())
+                .map(|((cell, value), &width)| match value {
+                    Value::Number(_) => format!("{cell:>width$}"),
+                    _ => format!("{cell:width$}"),
+                })
+                .collect();
+            writeln!(handle, "{}", line.join("  ").trim_end())?;
+        }
+
+        Ok(())
+    }
+
+    /// Fields with commas, quotes or line breaks are quoted as in RFC 4180, missing values are
+    /// left empty.
+    fn write_csv(&self, handle: &mut impl Write) -> io::Result<()> {
+        let header: Vec<String> = self.columns.iter().map(|c| csv_field(c)).collect();
+        writeln!(handle, "{}", header.join(","))?;
+
+        for row in self.rows.iter() {
+            let fields: Vec<String> = row
+                .iter()
+                .map(|value| match value {
+                    Value::Null => String::new(),
+                    value => csv_field(&cell(value)),
+                })
+                .collect();
+            writeln!(handle, "{}", fields.join(","))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Strings without quotes, missing values as `-`.
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("-"),
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Pretty printed, followed by a newline.
+pub fn write_json(value: &Value, handle: &mut impl Write) -> io::Result<()> {
+    serde_json::to_writer_pretty(&mut *handle, value)?;
+    writeln!(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{OutputFormat, Table};
+
+    fn written(table: &Table, format: OutputFormat) -> String {
+        let mut out = Vec::new();
+        table.write(format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn table_formats() {
+        let mut table = Table::new(&["name", "count", "peeled"]);
+        table.push(vec![json!("refs/heads/main"), json!(6), json!(null)]);
+        table.push(vec![json!("refs/tags/v1"), json!(12), json!("a,\"b\"")]);
+
+        assert_eq!(
+            "refs/heads/main 6 -\nrefs/tags/v1 12 a,\"b\"\n",
+            written(&table, OutputFormat::Plain)
+        );
+        assert_eq!(
+            "NAME             COUNT  PEELED\nrefs/heads/main      6  -\nrefs/tags/v1        12  a,\"b\"\n",
+            written(&table, OutputFormat::Table)
+        );
+        assert_eq!(
+            "name,count,peeled\nrefs/heads/main,6,\nrefs/tags/v1,12,\"a,\"\"b\"\"\"\n",
+            written(&table, OutputFormat::Csv)
+        );
+
+        let parsed: serde_json::Value =
+            serde_json::from_str(&written(&table, OutputFormat::Json)).unwrap();
+        assert_eq!(
+            json!([
+                {"name": "refs/heads/main", "count": 6, "peeled": null},
+                {"name": "refs/tags/v1", "count": 12, "peeled": "a,\"b\""},
+            ]),
+            parsed
+        );
+    }
+}
@@ -0,0 +1,34 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{stdout, BufWriter, IsTerminal, Write},
+    path::PathBuf,
+};
+
+use gitrwlib::Repository;
+
+/// Writes a pack of the objects selected by `revs`, see `Repository::write_pack`. The number of
+/// packed objects goes to stderr so the pack itself can be piped from stdout.
+pub fn pack_objects(
+    repository_path: PathBuf,
+    revs: Vec<String>,
+    output: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let repository = Repository::open_read_only(repository_path)?;
+
+    let writer: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None if stdout().is_terminal() => {
+            return Err(
+                "Refusing to write a pack to a terminal, redirect stdout or use --output".into(),
+            )
+        }
+        None => Box::new(BufWriter::new(stdout().lock())),
+    };
+
+    let revs: Vec<&str> = revs.iter().map(String::as_str).collect();
+    let count = repository.write_pack(&revs, writer)?;
+    eprintln!("Packed {count} objects");
+
+    Ok(())
+}
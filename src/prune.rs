@@ -1,94 +1,237 @@
-use std::{
-    collections::HashMap,
-    error::Error,
-    hash::BuildHasher,
-    path::PathBuf,
-    sync::mpsc::{channel, Sender},
-    thread,
-};
+use std::{collections::HashMap, error::Error, hash::BuildHasher, path::PathBuf};
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 use gitrwlib::{
-    objs::{CommitEditable, CommitHash, TreeHash},
-    Repository, WriteObject,
+    objs::{CommitHash, TreeHash},
+    CommitAction, RewriteOptions,
 };
 
-fn get_parent_if_empty_commit<T: BuildHasher>(
-    commit: &CommitEditable,
-    rewritten_commits: &HashMap<CommitHash, CommitHash, T>,
-    commit_trees: &HashMap<CommitHash, TreeHash, T>,
-) -> Option<CommitHash> {
-    let parents = commit.parents();
-    if parents.len() == 1 {
-        let commit_tree = commit.tree();
-        let parent = parents.first().unwrap();
-        let parent = rewritten_commits.get(parent).unwrap_or(parent).clone();
-
-        let parent_tree = &commit_trees[&parent];
-        if parent_tree == &commit_tree {
-            Some(parent)
-        } else {
-            None
-        }
-    } else {
-        None
-    }
+/// The kept parents and generation number of a commit. Like git's topological levels root
+/// commits have generation 1 and every other commit one more than its highest parent, so an
+/// ancestor always has a lower generation than its descendants.
+struct Parents {
+    parents: Vec<CommitHash>,
+    generation: u32,
 }
 
-fn find_empty_commits(
-    repository: &mut Repository,
-    tx: Sender<WriteObject>,
-) -> FxHashMap<CommitHash, CommitHash> {
-    let mut rewritten_commits: FxHashMap<CommitHash, CommitHash> = FxHashMap::default();
-    let mut commit_trees: FxHashMap<CommitHash, TreeHash> = FxHashMap::default();
+fn generation<T: BuildHasher>(
+    commit: &CommitHash,
+    commit_parents: &HashMap<CommitHash, Parents, T>,
+) -> u32 {
+    commit_parents
+        .get(commit)
+        .map_or(0, |parents| parents.generation)
+}
 
-    for mut commit in repository.commits_topo().map(CommitEditable::create) {
-        if let Some(parent) = get_parent_if_empty_commit(&commit, &rewritten_commits, &commit_trees)
-        {
-            rewritten_commits.insert(commit.base_hash().clone(), parent);
-            continue;
+/// Walks the parents of `commit` but not below the generation of `ancestor`, which only visits
+/// the commits between both instead of the whole history.
+fn is_ancestor<T: BuildHasher>(
+    ancestor: &CommitHash,
+    commit: &CommitHash,
+    commit_parents: &HashMap<CommitHash, Parents, T>,
+) -> bool {
+    let min_generation = generation(ancestor, commit_parents);
+    let mut visited: FxHashSet<&CommitHash> = FxHashSet::default();
+    let mut stack = vec![commit];
+    while let Some(current) = stack.pop() {
+        if current == ancestor {
+            return true;
         }
+        if visited.insert(current) {
+            if let Some(parents) = commit_parents.get(current) {
+                stack.extend(
+                    parents
+                        .parents
+                        .iter()
+                        .filter(|parent| generation(parent, commit_parents) >= min_generation),
+                );
+            }
+        }
+    }
 
-        let base_hash = commit.base_hash().clone();
-        commit
-            .parents()
-            .iter()
-            .map(|parent| rewritten_commits.get(parent).unwrap_or(parent).clone())
-            .enumerate()
-            .for_each(|(i, parent)| commit.set_parent(i, parent));
-
-        let commit_tree = commit.tree();
-        let w: WriteObject = commit.into();
-
-        let new_hash: CommitHash = w.hash.clone().into();
-        commit_trees.insert(new_hash.clone(), commit_tree);
+    false
+}
 
-        if base_hash != new_hash {
-            rewritten_commits.insert(base_hash, new_hash.clone());
-            tx.send(w).unwrap();
+/// Removes duplicate parents and, with `drop_ancestors`, parents that are ancestors of one of the
+/// other parents. The first parent is only removed as an ancestor if `keep_first` is not set.
+fn simplify_parents<T: BuildHasher>(
+    parents: &[CommitHash],
+    commit_parents: &HashMap<CommitHash, Parents, T>,
+    drop_ancestors: bool,
+    keep_first: bool,
+) -> Vec<CommitHash> {
+    let mut simplified: Vec<CommitHash> = Vec::with_capacity(parents.len());
+    for (i, parent) in parents.iter().enumerate() {
+        if simplified.contains(parent) {
+            continue;
+        }
+        let redundant = drop_ancestors
+            && !(i == 0 && keep_first)
+            && parents
+                .iter()
+                .filter(|other| *other != parent)
+                .any(|other| is_ancestor(parent, other, commit_parents));
+        if !redundant {
+            simplified.push(parent.clone());
         }
     }
 
-    rewritten_commits
+    simplified
 }
 
-pub fn remove_empty_commits(repository_path: PathBuf, dry_run: bool) -> Result<(), Box<dyn Error>> {
-    let write_path = repository_path.clone();
-    let (tx, rx) = channel();
+pub fn remove_empty_commits(
+    repository_path: PathBuf,
+    options: &RewriteOptions,
+    simplify_octopus_merges: bool,
+    prune_degenerate: bool,
+) -> Result<(), Box<dyn Error>> {
+    // all maps are keyed by the original commit hashes: a dropped commit is represented by the
+    // kept commit it was folded into, and has the same tree
+    let mut commit_trees: FxHashMap<CommitHash, TreeHash> = FxHashMap::default();
+    let mut representatives: FxHashMap<CommitHash, CommitHash> = FxHashMap::default();
+    let mut commit_parents: FxHashMap<CommitHash, Parents> = FxHashMap::default();
+
+    let mut repository = crate::open_for_rewrite(repository_path)?;
+    let ref_updates = repository.rewrite_history(
+        options,
+        |commit| {
+            let base_hash = commit.base_hash().clone();
+            let parents: Vec<CommitHash> = commit
+                .base()
+                .parents()
+                .into_iter()
+                .map(|parent| representatives.get(&parent).cloned().unwrap_or(parent))
+                .collect();
+
+            let simplified = if prune_degenerate && parents.len() > 1 {
+                simplify_parents(&parents, &commit_parents, true, false)
+            } else {
+                let octopus = simplify_octopus_merges && parents.len() > 2;
+                simplify_parents(&parents, &commit_parents, octopus, true)
+            };
+            if simplified.len() != parents.len() {
+                let rewritten_parents = commit.parents();
+                let new_parents = simplified
+                    .iter()
+                    .map(|parent| {
+                        let index = parents.iter().position(|p| p == parent).unwrap();
+                        rewritten_parents[index].clone()
+                    })
+                    .collect();
+                commit.set_parents(new_parents);
+            }
+            let parents = simplified;
+
+            // also merges whose parents collapsed into one
+            if parents.len() == 1 && commit_trees.get(&parents[0]) == Some(&commit.tree()) {
+                representatives.insert(base_hash.clone(), parents[0].clone());
+                commit_trees.insert(base_hash, commit.tree());
+                return CommitAction::Drop;
+            }
+
+            commit_trees.insert(base_hash.clone(), commit.tree());
+            if simplify_octopus_merges || prune_degenerate {
+                let generation = parents
+                    .iter()
+                    .map(|parent| generation(parent, &commit_parents))
+                    .max()
+                    .unwrap_or(0)
+                    + 1;
+                commit_parents.insert(
+                    base_hash,
+                    Parents {
+                        parents,
+                        generation,
+                    },
+                );
+            }
+
+            CommitAction::Keep
+        },
+        |_, _| Ok(None),
+    )?;
+    crate::print_ref_updates(&ref_updates, options)?;
 
-    let thread =
-        thread::spawn(move || Repository::write_commits(write_path, rx.into_iter(), dry_run));
+    Ok(())
+}
 
-    let mut repository = Repository::create(repository_path);
-    let rewritten_commits = find_empty_commits(&mut repository, tx);
+#[cfg(test)]
+mod test {
+    use gitrwlib::{objs::CommitHash, ObjectHash};
+    use rustc_hash::FxHashMap;
 
-    thread.join().unwrap();
+    use super::{generation, is_ancestor, simplify_parents, Parents};
 
-    if !rewritten_commits.is_empty() {
-        repository.update_refs(&rewritten_commits, dry_run);
-        Repository::write_rewritten_commits_file(rewritten_commits, dry_run);
+    fn hash(n: u8) -> CommitHash {
+        ObjectHash::from([n; 20]).into()
     }
 
-    Ok(())
+    fn add(commit_parents: &mut FxHashMap<CommitHash, Parents>, n: u8, parents: &[u8]) {
+        let parents: Vec<CommitHash> = parents.iter().map(|parent| hash(*parent)).collect();
+        let generation = parents
+            .iter()
+            .map(|parent| generation(parent, commit_parents))
+            .max()
+            .unwrap_or(0)
+            + 1;
+        commit_parents.insert(
+            hash(n),
+            Parents {
+                parents,
+                generation,
+            },
+        );
+    }
+
+    #[test]
+    fn degenerate_parents() {
+        // 1 <- 2 <- 3, 4 is unrelated
+        let mut commit_parents = FxHashMap::default();
+        add(&mut commit_parents, 1, &[]);
+        add(&mut commit_parents, 2, &[1]);
+        add(&mut commit_parents, 3, &[2]);
+        add(&mut commit_parents, 4, &[]);
+
+        let duplicates = [hash(3), hash(3), hash(1)];
+        assert_eq!(
+            vec![hash(3), hash(1)],
+            simplify_parents(&duplicates, &commit_parents, false, true)
+        );
+        assert_eq!(
+            vec![hash(3)],
+            simplify_parents(&duplicates, &commit_parents, true, true)
+        );
+
+        let first_is_ancestor = [hash(1), hash(3), hash(4)];
+        assert_eq!(
+            vec![hash(1), hash(3), hash(4)],
+            simplify_parents(&first_is_ancestor, &commit_parents, true, true)
+        );
+        assert_eq!(
+            vec![hash(3), hash(4)],
+            simplify_parents(&first_is_ancestor, &commit_parents, true, false)
+        );
+    }
+
+    #[test]
+    fn ancestors_across_branches() {
+        // 1 <- 2 <- 3 <- 5 and 1 <- 4 <- 5, 6 merges 5 and 2
+        let mut commit_parents = FxHashMap::default();
+        add(&mut commit_parents, 1, &[]);
+        add(&mut commit_parents, 2, &[1]);
+        add(&mut commit_parents, 3, &[2]);
+        add(&mut commit_parents, 4, &[1]);
+        add(&mut commit_parents, 5, &[3, 4]);
+        add(&mut commit_parents, 6, &[5, 2]);
+
+        assert!(is_ancestor(&hash(4), &hash(6), &commit_parents));
+        assert!(is_ancestor(&hash(1), &hash(5), &commit_parents));
+        assert!(!is_ancestor(&hash(3), &hash(4), &commit_parents));
+        assert!(!is_ancestor(&hash(5), &hash(2), &commit_parents));
+        assert_eq!(
+            vec![hash(5)],
+            simplify_parents(&[hash(5), hash(2)], &commit_parents, true, true)
+        );
+    }
 }
@@ -0,0 +1,36 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter},
+    path::PathBuf,
+};
+
+use gitrwlib::Repository;
+use serde_json::json;
+
+use crate::output::{OutputFormat, Table};
+
+/// Prints the commits, trees and blobs every range introduced and the size of the blobs in
+/// bytes, one row per range.
+pub fn range_stats(
+    repository_path: PathBuf,
+    ranges: &[String],
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let mut repository = Repository::open_read_only(repository_path)?;
+    repository.set_skip_tracker(crate::skip_tracker());
+
+    let mut table = Table::new(&["range", "commits", "trees", "blobs", "blob_size"]);
+    for range in ranges {
+        let stats = repository.range_stats(range)?;
+        table.push(vec![
+            json!(range),
+            json!(stats.commits),
+            json!(stats.trees),
+            json!(stats.blobs),
+            json!(stats.blob_size),
+        ]);
+    }
+
+    table.write(format, &mut BufWriter::new(stdout().lock()))?;
+    Ok(())
+}
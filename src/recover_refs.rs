@@ -0,0 +1,30 @@
+use std::{error::Error, path::PathBuf};
+
+use gitrwlib::Repository;
+
+/// Lists the ref updates of an interrupted rewrite, or completes or rolls them back.
+pub fn recover_refs(
+    repository_path: PathBuf,
+    complete: bool,
+    rollback: bool,
+) -> Result<(), Box<dyn Error>> {
+    let repository = Repository::open(repository_path)?;
+
+    if complete {
+        let entries = repository.complete_ref_journal()?;
+        println!("Completed the update of {} refs", entries.len());
+    } else if rollback {
+        let entries = repository.rollback_ref_journal()?;
+        println!("Restored {} refs", entries.len());
+    } else {
+        match repository.ref_journal()? {
+            Some(entries) => {
+                crate::print_locked(entries.iter())?;
+                println!("Use --complete or --rollback to finish the interrupted ref update");
+            }
+            None => println!("No interrupted ref update found"),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+use gitrwlib::Repository;
+use serde_json::json;
+
+use crate::output::{OutputFormat, Table};
+
+/// Prints every ref with its target and the type of the target, annotated tags also with the
+/// commit they peel to.
+pub fn list_refs(repository_path: PathBuf, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    let mut repository = Repository::open_read_only(repository_path)?;
+    let infos = repository.ref_infos()?;
+
+    let mut handle = BufWriter::new(stdout().lock());
+    if format != OutputFormat::Plain {
+        let mut table = Table::new(&["name", "target", "type", "peeled"]);
+        for info in infos {
+            table.push(vec![
+                json!(info.name),
+                json!(info.target),
+                json!(info.target_type),
+                json!(info.peeled.map(|commit| commit.to_string())),
+            ]);
+        }
+        table.write(format, &mut handle)?;
+        return Ok(());
+    }
+
+    for info in infos {
+        let target_type = info.target_type.unwrap_or("missing");
+        match info.peeled {
+            Some(peeled) => writeln!(
+                handle,
+                "{} {target_type} {} -> {peeled}",
+                info.target, info.name
+            )?,
+            None => writeln!(handle, "{} {target_type} {}", info.target, info.name)?,
+        }
+    }
+
+    Ok(())
+}
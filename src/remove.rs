@@ -1,23 +1,28 @@
-use core::panic;
 use std::{
     borrow::Cow,
-    cmp::Reverse,
-    collections::{BinaryHeap, HashMap},
+    collections::HashMap,
+    error::Error,
+    fs::File,
     hash::BuildHasher,
+    io::{stdin, BufRead, BufReader},
     ops::Deref,
-    path::{Path, PathBuf},
-    sync::{mpsc::channel, RwLock},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Mutex, RwLock},
 };
 
 use bstr::ByteSlice;
 
 use gitrwlib::{
-    objs::{CommitBase, CommitEditable, CommitHash, Tree, TreeHash},
-    Repository, WriteObject,
+    objs::{CommitEditable, CommitHash, Tree, TreeHash, TreeLine},
+    path_matcher::{
+        AllOf, AnyOf, BiggerThan, Exact, InDirectory, Not, PathEntry, PathMatcher, PathRegex,
+        Prefix, Suffix,
+    },
+    CommitAction, GitRwError, ObjectStore, Repository, RewriteOptions, TreeRewriteReport,
 };
 use rayon::prelude::*;
-use regex::bytes::RegexSet;
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHashSet};
 
 macro_rules! b {
     ( $x:expr ) => {
@@ -34,133 +39,235 @@ fn last_index_of(path: &[u8], needle: u8) -> Option<usize> {
     None
 }
 
-type DynFn<'a> = Box<dyn Fn(&[u8]) -> bool + Sync + Send + 'a>;
-type DynFn2<'a> = Box<dyn Fn(&[u8], &[u8]) -> bool + Sync + Send + 'a>;
-
-fn build_folder_delete_patterns(folders: &[String]) -> DynFn {
-    let mut delete_folder: DynFn = Box::new(|_path| false);
+fn build_folder_delete_patterns(folders: &[String]) -> Box<dyn PathMatcher> {
+    let mut matchers: Vec<Box<dyn PathMatcher>> = Vec::new();
 
     for folder in folders.iter().map(|f| f.as_bytes()) {
+        // directory paths always end with a slash, the pattern does not have to
+        let mut with_slash = folder.to_vec();
+        if folder[folder.len() - 1] != b'/' {
+            with_slash.push(b'/');
+        }
+
         if folder[0] == b'*' {
-            if folder[folder.len() - 1] == b'/' {
-                delete_folder = b!(move |path| delete_folder(path) || path.ends_with(&folder[1..]));
-            } else {
-                // handles trailing slash
-                delete_folder = b!(move |path| delete_folder(path)
-                    || path[0..path.len() - 1].ends_with(&folder[1..]));
-            }
+            matchers.push(b!(Suffix(with_slash[1..].to_vec())));
         } else if folder[folder.len() - 1] == b'*' {
-            delete_folder =
-                b!(move |path| delete_folder(path)
-                    || path.starts_with(&folder[0..folder.len() - 1]));
+            matchers.push(b!(Prefix(folder[0..folder.len() - 1].to_vec())));
         } else if folder[0] == b'/' {
             // absolute path, no wildcard
-            if folder[folder.len() - 1] == b'/' {
-                delete_folder = b!(move |path| delete_folder(path) || path.eq(folder));
-            } else {
-                // handles missing trailing slash
-                delete_folder = b!(move |path| delete_folder(path)
-                    || path.len() == folder.len() + 1 && path[0..path.len() - 1].eq(folder));
-            }
+            matchers.push(b!(Exact(with_slash)));
         } else {
             // relative path, no wildcard
-            let mut folder: Vec<u8> = folder.to_owned();
-            if folder[folder.len() - 1] != b'/' {
-                folder.push(b'/');
-            }
-            if folder[0] != b'/' {
-                folder.insert(0, b'/');
-            }
-
-            delete_folder = b!(move |path| delete_folder(path) || path.ends_with(&folder));
+            with_slash.insert(0, b'/');
+            matchers.push(b!(Suffix(with_slash)));
         }
     }
 
-    delete_folder
+    b!(AnyOf(matchers))
+}
+
+fn build_regex_pattern(patterns: &[String]) -> Result<Box<dyn PathMatcher>, GitRwError> {
+    if patterns.is_empty() {
+        return Ok(b!(AnyOf(Vec::new())));
+    }
+
+    Ok(b!(PathRegex::path(patterns)?))
+}
+
+fn build_keep_regex_pattern(patterns: &[String]) -> Result<Box<dyn PathMatcher>, GitRwError> {
+    if patterns.is_empty() {
+        return Ok(b!(AnyOf(Vec::new())));
+    }
+
+    Ok(b!(Not(b!(PathRegex::path(patterns)?))))
 }
 
-fn build_regex_pattern(patterns: &[String]) -> DynFn2 {
+fn build_filename_regex_pattern(patterns: &[String]) -> Result<Box<dyn PathMatcher>, GitRwError> {
     if patterns.is_empty() {
-        return b!(|_, _| false);
+        return Ok(b!(AnyOf(Vec::new())));
     }
 
-    let regexes = RegexSet::new(patterns).unwrap();
-    b!(move |folder, file| {
-        let path = [folder, file].concat();
-        regexes.is_match(&path)
-    })
+    Ok(b!(PathRegex::name(patterns)?))
 }
 
-fn build_file_delete_patterns(files: &[String]) -> DynFn2 {
-    let mut delete_file: DynFn2 = b!(|_path, _filename| false);
+/// Reads newline separated regex patterns from a file, or from stdin if `source` is `-`.
+/// Empty lines are ignored.
+pub fn read_regex_patterns(source: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let reader: Box<dyn BufRead> = if source == "-" {
+        Box::new(stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(source)?))
+    };
+
+    let mut patterns = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if !line.is_empty() {
+            patterns.push(line.to_owned());
+        }
+    }
+
+    Ok(patterns)
+}
+
+fn build_file_delete_patterns(files: &[String]) -> Result<Box<dyn PathMatcher>, GitRwError> {
+    let mut matchers: Vec<Box<dyn PathMatcher>> = Vec::new();
     for file in files.iter().map(|f| f.as_bytes()) {
+        if file.is_empty() {
+            return Err(GitRwError::InvalidInput(String::from(
+                "File patterns must not be empty",
+            )));
+        }
+
         if file[0] == b'*' {
-            match last_index_of(file, b'/') {
-                // */bin/test.txt
-                Some(last_slash) => {
-                    delete_file = b!(move |path, filename| delete_file(path, filename)
-                        || (path.ends_with(&file[1..last_slash + 1])
-                            && filename.eq(&file[last_slash + 1..])));
-                }
-                // *mytest.txt
-                None => {
-                    delete_file = b!(move |path, filename| delete_file(path, filename)
-                        || filename.ends_with(&file[1..]));
-                }
-            }
+            // */bin/test.txt or *mytest.txt
+            matchers.push(b!(Suffix(file[1..].to_vec())));
         } else if file[file.len() - 1] == b'*' {
-            match last_index_of(file, b'/') {
+            let prefix = regex::escape(&String::from_utf8_lossy(&file[0..file.len() - 1]));
+            let matcher = match last_index_of(file, b'/') {
                 // /some/folder/file_to_delete*
-                Some(last_slash) => {
-                    delete_file = b!(move |path, filename| delete_file(path, filename)
-                        || (path.eq(&file[0..last_slash + 1])
-                            && filename.starts_with(&file[last_slash + 1..file.len() - 1])));
-                }
+                Some(_) => PathRegex::path(&[format!("^{prefix}[^/]*$")]),
                 // file_to_delete*
-                None => {
-                    delete_file = b!(move |path, filename| delete_file(path, filename)
-                        || filename.starts_with(&file[0..file.len() - 1]));
-                }
-            }
+                None => PathRegex::name(&[format!("^{prefix}")]),
+            };
+            matchers.push(b!(matcher?));
         } else if file[0] == b'/' {
             // absolute path: /some/folder/file_to_delete.txt
-            let last_slash = last_index_of(file, b'/').unwrap();
-            delete_file = b!(move |path, filename| delete_file(path, filename)
-                || (path.len() + filename.len() == file.len()
-                    && path.eq(&file[0..last_slash + 1])
-                    && filename.eq(&file[last_slash + 1..])));
+            matchers.push(b!(Exact(file.to_vec())));
         } else {
             // simple file name, should not contain any slashes: file_to_delete.txt
             if last_index_of(file, b'/').is_some() {
-                panic!("Unknown pattern: {}", file.as_bstr());
+                return Err(GitRwError::InvalidInput(format!(
+                    "Unknown file pattern {}: use a name without slashes, an absolute path or a * at the start or end",
+                    file.as_bstr()
+                )));
             }
 
-            delete_file =
-                b!(move |path, filename| delete_file(path, filename) || filename.eq(file));
+            matchers.push(b!(Suffix([b"/", file].concat())));
+        }
+    }
+
+    Ok(b!(AnyOf(matchers)))
+}
+
+struct Matchers {
+    delete_file: Box<dyn PathMatcher>,
+    delete_folder: Box<dyn PathMatcher>,
+    blob_sizes: RwLock<FxHashMap<TreeHash, usize>>,
+}
+
+impl Matchers {
+    /// Fails if a file pattern or regex of `filters` is invalid.
+    fn create(filters: &Filters) -> Result<Self, GitRwError> {
+        // the cheap path checks come first, sizes are only looked up if they do not match
+        let mut delete_file = vec![
+            build_file_delete_patterns(&filters.files)?,
+            build_regex_pattern(&filters.regexes)?,
+            build_filename_regex_pattern(&filters.filename_regexes)?,
+            build_keep_regex_pattern(&filters.keep_regexes)?,
+        ];
+        for limit in filters.size_limits.iter() {
+            let bigger_than: Box<dyn PathMatcher> = b!(BiggerThan(limit.bytes));
+            delete_file.push(match &limit.pattern {
+                Some(pattern) => b!(AllOf(vec![
+                    build_file_delete_patterns(std::slice::from_ref(pattern))?,
+                    bigger_than
+                ])),
+                None => bigger_than,
+            });
+        }
+
+        if filters.invert {
+            // directories are only removed once nothing in them is kept
+            let keep = AnyOf(vec![
+                b!(AnyOf(delete_file)),
+                b!(InDirectory(build_folder_delete_patterns(
+                    &filters.directories
+                ))),
+            ]);
+            return Ok(Matchers {
+                delete_file: b!(Not(b!(keep))),
+                delete_folder: b!(AnyOf(Vec::new())),
+                blob_sizes: RwLock::new(FxHashMap::default()),
+            });
+        }
+
+        Ok(Matchers {
+            delete_file: b!(AnyOf(delete_file)),
+            delete_folder: build_folder_delete_patterns(&filters.directories),
+            blob_sizes: RwLock::new(FxHashMap::default()),
+        })
+    }
+
+    fn should_delete_file(
+        &self,
+        repository: &mut Repository,
+        path: &[u8],
+        line: &TreeLine,
+    ) -> Result<bool, GitRwError> {
+        let mut failure = None;
+        let mut size = || {
+            self.blob_size(repository, line).unwrap_or_else(|e| {
+                failure = Some(e);
+                0
+            })
+        };
+        let matches = self
+            .delete_file
+            .matches(&PathEntry::file(path, line.filename()).with_size(&mut size));
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(matches),
         }
     }
 
-    delete_file
+    /// Size of the blob of a file entry, 0 for submodules whose commits are not part of the
+    /// repository.
+    fn blob_size(&self, repository: &mut Repository, line: &TreeLine) -> Result<usize, GitRwError> {
+        if line.mode() == b"160000" {
+            return Ok(0);
+        }
+
+        let hash = line.hash.deref();
+        if let Some(size) = self.blob_sizes.read().unwrap().get(hash) {
+            return Ok(*size);
+        }
+
+        let size = match repository.object_header(&hash.clone().into()) {
+            Ok(("blob", size)) => size,
+            Ok((object_type, _)) => {
+                return Err(GitRwError::Corrupt {
+                    path: repository.path().to_owned(),
+                    reason: format!("{hash} is a {object_type}, expected a blob"),
+                })
+            }
+            Err(e) => {
+                return Err(GitRwError::Corrupt {
+                    path: repository.path().to_owned(),
+                    reason: format!("cannot read the size of blob {hash}: {e}"),
+                })
+            }
+        };
+        self.blob_sizes.write().unwrap().insert(hash.clone(), size);
+        Ok(size)
+    }
 }
 
 fn update_tree<T: BuildHasher + Sync + Send>(
     tree_hash: TreeHash,
     path: &[u8],
     repository: &mut Repository,
-    should_delete_file: &DynFn2,
-    should_delete_folder: &DynFn,
-    should_remove: &DynFn2,
+    matchers: &Matchers,
     rewritten_trees: &RwLock<HashMap<TreeHash, Option<TreeHash>, T>>,
-    write_tree: &(impl Fn(Tree) + Sync + Send),
-) -> Option<TreeHash> {
+    report: Option<&Mutex<TreeRewriteReport>>,
+    store: &ObjectStore,
+) -> Result<Option<TreeHash>, GitRwError> {
     if let Some(rewritten_hash_option) = rewritten_trees.read().unwrap().get(&tree_hash) {
-        return rewritten_hash_option.clone();
+        return Ok(rewritten_hash_option.clone());
     }
 
-    let tree: Tree = match repository.read_object(tree_hash.into()).unwrap() {
-        gitrwlib::objs::GitObject::Tree(tree) => tree,
-        _ => panic!("Expected a tree, found something else"),
-    };
+    let tree: Tree = repository.read_tree(tree_hash)?;
 
     let old_hash = tree.hash();
 
@@ -170,7 +277,19 @@ fn update_tree<T: BuildHasher + Sync + Send>(
         if line.is_tree() {
             let full_path = [path, line.filename(), b"/"].concat();
 
-            if should_delete_folder(&full_path) {
+            if matchers
+                .delete_folder
+                .matches(&PathEntry::directory(path, line.filename()))
+            {
+                store.record_removed(&full_path);
+                if let Some(report) = report {
+                    let tree_hash = line.hash.deref();
+                    if report.lock().unwrap().record_removed_directory(tree_hash) {
+                        record_removed_content(
+                            tree_hash, &full_path, repository, matchers, report,
+                        )?;
+                    }
+                }
                 tree_changed = true;
                 continue;
             }
@@ -179,24 +298,33 @@ fn update_tree<T: BuildHasher + Sync + Send>(
                 line.hash.deref().clone(),
                 &full_path,
                 repository,
-                should_delete_file,
-                should_delete_folder,
-                should_remove,
+                matchers,
                 rewritten_trees,
-                write_tree,
-            ) {
+                report,
+                store,
+            )? {
                 tree_changed = true;
+                if new_tree_hash.is_empty_tree() {
+                    // everything below was removed, drop the directory as well
+                    continue;
+                }
+
                 line.hash = Cow::Owned(new_tree_hash);
             }
-        } else {
-            if should_delete_file(path, line.filename()) {
-                tree_changed = true;
-                continue;
+        } else if matchers.should_delete_file(repository, path, &line)? {
+            if store.is_dry_run() {
+                store.record_removed(&[path, line.filename()].concat());
             }
-            if should_remove(path, line.filename()) {
-                tree_changed = true;
-                continue;
+            if let Some(report) = report {
+                let size = matchers.blob_size(repository, &line)?;
+                report.lock().unwrap().record_removed_file(
+                    path,
+                    &line.hash.deref().clone().into(),
+                    size,
+                );
             }
+            tree_changed = true;
+            continue;
         }
 
         filtered_lines.push(line);
@@ -207,7 +335,7 @@ fn update_tree<T: BuildHasher + Sync + Send>(
             .write()
             .unwrap()
             .insert(old_hash.clone(), None);
-        None
+        Ok(None)
     } else {
         let tree: Tree = filtered_lines.into_iter().collect();
         let new_hash = tree.hash().clone();
@@ -215,184 +343,462 @@ fn update_tree<T: BuildHasher + Sync + Send>(
             .write()
             .unwrap()
             .insert(old_hash.clone(), Some(new_hash.clone()));
-        write_tree(tree);
-        Some(new_hash)
+        if let Some(report) = report {
+            report.lock().unwrap().record_rewritten_tree();
+        }
+        store.write(tree)?;
+        Ok(Some(new_hash))
     }
 }
 
-struct OrderedCommit {
-    commit: CommitBase,
-    index: usize,
+/// Records every file below a removed directory, trees recorded before are skipped.
+fn record_removed_content(
+    tree_hash: &TreeHash,
+    path: &[u8],
+    repository: &mut Repository,
+    matchers: &Matchers,
+    report: &Mutex<TreeRewriteReport>,
+) -> Result<(), GitRwError> {
+    let tree: Tree = repository.read_tree(tree_hash.clone())?;
+    for line in tree.lines() {
+        if line.is_tree() {
+            if report.lock().unwrap().record_removed_subtree(&line.hash) {
+                let full_path = [path, line.filename(), b"/"].concat();
+                record_removed_content(&line.hash, &full_path, repository, matchers, report)?;
+            }
+        } else {
+            let size = matchers.blob_size(repository, &line)?;
+            report.lock().unwrap().record_removed_file(
+                path,
+                &line.hash.deref().clone().into(),
+                size,
+            );
+        }
+    }
+    Ok(())
 }
 
-impl Eq for OrderedCommit {}
+/// What to do with a commit whose root tree is empty after filtering.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EmptyTree {
+    /// Keep the commit, pointing at the empty tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904
+    #[default]
+    Keep,
+    /// Drop the commit, its children are attached to its first parent
+    Drop,
+}
 
-impl PartialEq for OrderedCommit {
-    fn eq(&self, other: &Self) -> bool {
-        self.index == other.index
-    }
+#[derive(Default)]
+pub struct Filters {
+    pub files: Vec<String>,
+    pub directories: Vec<String>,
+    pub regexes: Vec<String>,
+    pub filename_regexes: Vec<String>,
+    pub keep_regexes: Vec<String>,
+    pub size_limits: Vec<SizeLimit>,
+    /// Keep what the other filters match and remove everything else
+    pub invert: bool,
 }
 
-impl PartialOrd for OrderedCommit {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.index.cmp(&other.index))
-    }
+/// Removes files bigger than `bytes`, optionally only those matching a file pattern.
+/// Parsed from `<SIZE>[:<PATTERN>]`, e.g. `5M:*.json` or `100K`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SizeLimit {
+    pub bytes: usize,
+    pub pattern: Option<String>,
 }
 
-impl Ord for OrderedCommit {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.index.cmp(&other.index)
+impl FromStr for SizeLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (size, pattern) = match s.split_once(':') {
+            Some((size, pattern)) => (size, Some(pattern.to_owned())),
+            None => (s, None),
+        };
+
+        let (number, multiplier) = match size.as_bytes().last() {
+            Some(b'K' | b'k') => (&size[..size.len() - 1], 1024),
+            Some(b'M' | b'm') => (&size[..size.len() - 1], 1024 * 1024),
+            Some(b'G' | b'g') => (&size[..size.len() - 1], 1024 * 1024 * 1024),
+            _ => (size, 1),
+        };
+
+        let number: usize = number
+            .parse()
+            .map_err(|_| format!("Invalid size '{size}', expected e.g. 500, 100K, 5M or 1G"))?;
+
+        Ok(SizeLimit {
+            bytes: number * multiplier,
+            pattern,
+        })
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn remove(
     repository_path: PathBuf,
-    files: Vec<String>,
-    directories: Vec<String>,
-    regexes: Vec<String>,
-    dry_run: bool,
-) {
-    let mut rewritten_commits: HashMap<CommitHash, CommitHash, _> = FxHashMap::default();
-    let rewritten_trees: RwLock<HashMap<TreeHash, Option<TreeHash>, _>> =
-        RwLock::new(FxHashMap::default());
-
-    let mut repository = rayon::scope(|scope| {
-        let (tx, rx) = channel::<OrderedCommit>();
-        scope.spawn(|_| {
-            let mut heap: BinaryHeap<Reverse<OrderedCommit>> = BinaryHeap::new();
-            let mut commit_index = 0usize;
-            for ordered_commit in rx.into_iter() {
-                if ordered_commit.index == commit_index {
-                    commit_index += 1;
-
-                    let commit = CommitEditable::create(ordered_commit.commit);
-                    let (old_hash, new_hash) = update_commit(
-                        &repository_path,
-                        commit,
-                        &rewritten_commits,
-                        &rewritten_trees,
-                        dry_run,
-                    );
-                    if old_hash != new_hash {
-                        rewritten_commits.insert(old_hash, new_hash);
-                    }
+    filters: Filters,
+    options: &RewriteOptions,
+    check_reproducible: bool,
+    prune_loose: bool,
+    on_empty_tree: EmptyTree,
+    report: bool,
+    verify_trees: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut repository = crate::open_for_rewrite(repository_path)?;
+    let tree_report = report.then(Mutex::default);
+    let root_trees = Mutex::default();
+    let rewriter = TreeRewriter::create(
+        &repository,
+        &filters,
+        on_empty_tree,
+        options.dry_run,
+        tree_report.as_ref(),
+        Some(&root_trees),
+    )?;
+    let mut superseded: Vec<CommitHash> = Vec::new();
 
-                    while let Some(commit) = heap.pop() {
-                        if commit.0.index == commit_index {
-                            commit_index += 1;
-
-                            let commit = CommitEditable::create(commit.0.commit);
-                            let (old_hash, new_hash) = update_commit(
-                                &repository_path,
-                                commit,
-                                &rewritten_commits,
-                                &rewritten_trees,
-                                dry_run,
-                            );
-                            if old_hash != new_hash {
-                                rewritten_commits.insert(old_hash, new_hash);
-                            }
-                        } else {
-                            heap.push(commit);
-                            break;
-                        }
-                    }
+    let ref_updates = repository.rewrite_history_checked(
+        options,
+        |commit| rewriter.commit_action(commit),
+        |repository, tree_hash| rewriter.rewrite_tree(repository, tree_hash),
+        |repository, rewritten_commits| {
+            if check_reproducible {
+                check_reproducible_rewrite(
+                    repository,
+                    &filters,
+                    on_empty_tree,
+                    options,
+                    rewritten_commits,
+                )?;
+            }
+
+            if verify_trees {
+                if options.dry_run {
+                    println!(
+                        "Trees are not verified in a dry run, the rewritten ones were not written"
+                    );
                 } else {
-                    heap.push(Reverse(ordered_commit));
+                    let root_trees = root_trees.lock().unwrap();
+                    verify_root_trees(repository, &filters, &root_trees)?;
+                    println!("Verified {} root trees", root_trees.len());
                 }
             }
+
+            if prune_loose {
+                superseded = rewritten_commits.keys().cloned().collect();
+            }
+            Ok(())
+        },
+    )?;
+    crate::print_ref_updates(&ref_updates, options)?;
+
+    if prune_loose {
+        let pruned = repository.prune_superseded(superseded, options.dry_run)?;
+        println!("Pruned {} superseded loose objects", pruned.len());
+    }
+
+    if let Some(tree_report) = tree_report {
+        print_report(&tree_report.into_inner().unwrap());
+    }
+
+    Ok(())
+}
+
+/// Rewrites the history a second time as a dry run and fails if any commit gets another hash
+/// than in the first pass.
+fn check_reproducible_rewrite(
+    repository: &Repository,
+    filters: &Filters,
+    on_empty_tree: EmptyTree,
+    options: &RewriteOptions,
+    rewritten_commits: &FxHashMap<CommitHash, CommitHash>,
+) -> Result<(), GitRwError> {
+    let dry_run = RewriteOptions {
+        dry_run: true,
+        ..options.clone()
+    };
+    let second_pass = rewrite_commits(repository, filters, on_empty_tree, &dry_run)?;
+
+    let mismatches = rewritten_commits
+        .iter()
+        .filter(|(old, new)| second_pass.get(*old) != Some(*new))
+        .count()
+        + second_pass
+            .keys()
+            .filter(|old| !rewritten_commits.contains_key(*old))
+            .count();
+
+    if mismatches > 0 {
+        return Err(GitRwError::Corrupt {
+            path: repository.path().to_owned(),
+            reason: format!(
+                "Rewrite is not reproducible: {mismatches} commits got different hashes in the second pass, refs were not updated"
+            ),
         });
+    }
 
-        let repository = Repository::create(repository_path.clone());
-        let file_delete_patterns = build_file_delete_patterns(&files);
-        let folder_delete_patterns = build_folder_delete_patterns(&directories);
-        let should_remove_line = build_regex_pattern(&regexes);
-        repository
-            .commits_topo()
-            .enumerate()
-            .map(|(index, commit)| OrderedCommit { index, commit })
-            .par_bridge()
-            .for_each_with(repository.clone(), |repository, commit| {
-                let old_tree_hash = commit.commit.tree();
-                update_tree(
-                    old_tree_hash,
-                    b"/",
-                    repository,
-                    &file_delete_patterns,
-                    &folder_delete_patterns,
-                    &should_remove_line,
-                    &rewritten_trees,
-                    &|tree| {
-                        if !dry_run {
-                            // TODO write out on different thread
-                            Repository::write(repository_path.clone(), tree.into(), dry_run);
-                        }
-                    },
-                );
+    println!("Rewrite is reproducible");
+    Ok(())
+}
 
-                tx.send(commit).unwrap();
-            });
+/// Number of directories listed by `--report`.
+const REPORT_DIRECTORIES: usize = 10;
 
-        std::mem::drop(tx);
+fn print_report(report: &TreeRewriteReport) {
+    println!(
+        "Rewrote {} trees, removed {} directories and {} files with {} KiB",
+        report.trees_rewritten,
+        report.directories_removed,
+        report.files_removed(),
+        report.bytes_removed() / 1024
+    );
+    for (directory, removed) in report.top_directories(REPORT_DIRECTORIES) {
+        println!(
+            "  {:>10} KiB {:>6} files  {directory}",
+            removed.bytes / 1024,
+            removed.files
+        );
+    }
+}
 
-        repository
-    });
+/// The commit and tree callbacks of a rewrite removing what `filters` match. Root trees are
+/// recorded in `root_trees` with their replacement, or `None` if unchanged.
+struct TreeRewriter<'a> {
+    matchers: Matchers,
+    on_empty_tree: EmptyTree,
+    store: ObjectStore,
+    rewritten_trees: RwLock<FxHashMap<TreeHash, Option<TreeHash>>>,
+    report: Option<&'a Mutex<TreeRewriteReport>>,
+    root_trees: Option<&'a Mutex<FxHashMap<TreeHash, Option<TreeHash>>>>,
+}
+
+impl<'a> TreeRewriter<'a> {
+    fn create(
+        repository: &Repository,
+        filters: &Filters,
+        on_empty_tree: EmptyTree,
+        dry_run: bool,
+        report: Option<&'a Mutex<TreeRewriteReport>>,
+        root_trees: Option<&'a Mutex<FxHashMap<TreeHash, Option<TreeHash>>>>,
+    ) -> Result<Self, GitRwError> {
+        Ok(TreeRewriter {
+            matchers: Matchers::create(filters)?,
+            on_empty_tree,
+            store: repository.object_store(dry_run),
+            rewritten_trees: RwLock::new(FxHashMap::default()),
+            report,
+            root_trees,
+        })
+    }
+
+    fn commit_action(&self, commit: &mut CommitEditable) -> CommitAction {
+        // only commits that became empty by the filtering are dropped
+        if self.on_empty_tree == EmptyTree::Drop
+            && commit.tree().is_empty_tree()
+            && !commit.base().tree().is_empty_tree()
+        {
+            CommitAction::Drop
+        } else {
+            CommitAction::Keep
+        }
+    }
 
-    repository.update_refs(&rewritten_commits, dry_run);
-    Repository::write_rewritten_commits_file(rewritten_commits, dry_run);
+    fn rewrite_tree(
+        &self,
+        repository: &mut Repository,
+        tree_hash: TreeHash,
+    ) -> Result<Option<TreeHash>, GitRwError> {
+        let new_hash = update_tree(
+            tree_hash.clone(),
+            b"/",
+            repository,
+            &self.matchers,
+            &self.rewritten_trees,
+            self.report,
+            &self.store,
+        )?;
+        if let Some(root_trees) = self.root_trees {
+            root_trees
+                .lock()
+                .unwrap()
+                .insert(tree_hash, new_hash.clone());
+        }
+        Ok(new_hash)
+    }
+}
+
+/// Rewrites the commits without touching the refs.
+fn rewrite_commits(
+    repository: &Repository,
+    filters: &Filters,
+    on_empty_tree: EmptyTree,
+    options: &RewriteOptions,
+) -> Result<FxHashMap<CommitHash, CommitHash>, GitRwError> {
+    let rewriter = TreeRewriter::create(
+        repository,
+        filters,
+        on_empty_tree,
+        options.dry_run,
+        None,
+        None,
+    )?;
+    repository.rewrite_commits(
+        options,
+        |commit| rewriter.commit_action(commit),
+        |repository, tree_hash| rewriter.rewrite_tree(repository, tree_hash),
+    )
 }
 
-fn update_commit(
-    repo_path: &Path,
-    mut commit: CommitEditable,
-    rewritten_commits: &HashMap<
-        CommitHash,
-        CommitHash,
-        std::hash::BuildHasherDefault<rustc_hash::FxHasher>,
-    >,
-    rewritten_trees: &RwLock<
-        HashMap<TreeHash, Option<TreeHash>, std::hash::BuildHasherDefault<rustc_hash::FxHasher>>,
-    >,
-    dry_run: bool,
-) -> (CommitHash, CommitHash) {
-    let old_hash = commit.base_hash().clone();
-
-    update_parents(&mut commit, rewritten_commits);
-    // update tree
-    if let Some(Some(new_tree_hash)) = rewritten_trees.read().unwrap().get(&commit.tree()) {
-        commit.set_tree(new_tree_hash.clone());
-    }
-
-    if commit.has_changes() {
-        let write_object: WriteObject = commit.into();
-        let new_hash = write_object.hash.clone();
-        Repository::write(repo_path.into(), write_object, dry_run);
-        return (old_hash, new_hash.into());
-    }
-
-    (old_hash.clone(), old_hash)
+/// Trees already verified: the original, the rewritten one and the path.
+type VerifiedTrees = RwLock<FxHashSet<(TreeHash, Option<TreeHash>, Vec<u8>)>>;
+
+/// Compares every rewritten root tree with its original, independently of the memoized rewrite
+/// and with fresh matchers, before any ref is moved.
+fn verify_root_trees(
+    repository: &Repository,
+    filters: &Filters,
+    root_trees: &FxHashMap<TreeHash, Option<TreeHash>>,
+) -> Result<(), GitRwError> {
+    let matchers = Matchers::create(filters)?;
+    let verified = VerifiedTrees::default();
+
+    root_trees
+        .par_iter()
+        .map_with(repository.clone(), |repository, (old, new)| {
+            let new = new.as_ref().unwrap_or(old);
+            verify_tree(repository, &matchers, b"/", old, Some(new), &verified)
+        })
+        .collect::<Result<(), String>>()
+        .map_err(|e| GitRwError::Corrupt {
+            path: repository.path().to_owned(),
+            reason: format!("Tree verification failed, refs were not updated: {e}"),
+        })
 }
 
-fn update_parents(
-    commit: &mut CommitEditable,
-    rewritten_commits: &HashMap<
-        CommitHash,
-        CommitHash,
-        std::hash::BuildHasherDefault<rustc_hash::FxHasher>,
-    >,
-) {
-    for (i, parent) in commit.parents().iter().enumerate() {
-        if let Some(new_parent) = rewritten_commits.get(parent) {
-            if new_parent != parent {
-                commit.parents[i] = Some(new_parent.clone());
+/// Every entry the filters match has to be gone from `new`, everything else has to be unchanged
+/// apart from directories that only contained removed entries. `new` is `None` for a directory
+/// that was removed because of that.
+fn verify_tree(
+    repository: &mut Repository,
+    matchers: &Matchers,
+    path: &[u8],
+    old: &TreeHash,
+    new: Option<&TreeHash>,
+    verified: &VerifiedTrees,
+) -> Result<(), String> {
+    let key = (old.clone(), new.cloned(), path.to_vec());
+    if verified.read().unwrap().contains(&key) {
+        return Ok(());
+    }
+
+    let old_tree = repository
+        .read_tree(old.clone())
+        .map_err(|e| format!("{}: {e}", path.as_bstr()))?;
+    let mut new_entries: FxHashMap<Vec<u8>, (Vec<u8>, TreeHash)> = FxHashMap::default();
+    if let Some(new) = new {
+        let new_tree = repository
+            .read_tree(new.clone())
+            .map_err(|e| format!("{}: {e}", path.as_bstr()))?;
+        for line in new_tree.lines() {
+            let entry = (line.mode().to_vec(), line.hash.deref().clone());
+            new_entries.insert(line.filename().to_vec(), entry);
+        }
+    }
+
+    for line in old_tree.lines() {
+        let name = line.filename();
+        let full_path = [path, name].concat();
+        let new_entry = new_entries.remove(name);
+
+        if line.is_tree() {
+            if matchers
+                .delete_folder
+                .matches(&PathEntry::directory(path, name))
+            {
+                if new_entry.is_some() {
+                    return Err(format!("{}/ was not removed", full_path.as_bstr()));
+                }
+                continue;
+            }
+
+            let new_hash = match new_entry {
+                Some((mode, _)) if mode != line.mode() => {
+                    return Err(format!("{} changed its mode", full_path.as_bstr()))
+                }
+                Some((_, hash)) => Some(hash),
+                None => None,
+            };
+            let directory = [full_path.as_slice(), b"/"].concat();
+            verify_tree(
+                repository,
+                matchers,
+                &directory,
+                &line.hash,
+                new_hash.as_ref(),
+                verified,
+            )?;
+        } else {
+            let removed = matchers
+                .should_delete_file(repository, path, &line)
+                .map_err(|e| e.to_string())?;
+            match new_entry {
+                Some(_) if removed => {
+                    return Err(format!("{} was not removed", full_path.as_bstr()))
+                }
+                None if !removed => {
+                    return Err(format!(
+                        "{} was removed although no filter matches it",
+                        full_path.as_bstr()
+                    ))
+                }
+                Some((mode, hash)) if mode != line.mode() || hash != *line.hash => {
+                    return Err(format!("{} was changed", full_path.as_bstr()))
+                }
+                _ => {}
             }
         }
     }
+
+    if let Some(name) = new_entries.keys().next() {
+        return Err(format!("{}{} was added", path.as_bstr(), name.as_bstr()));
+    }
+
+    verified.write().unwrap().insert(key);
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use super::build_folder_delete_patterns;
+    use std::{path::Path, time::SystemTime};
+
+    use gitrwlib::{PathEntry, PathMatcher, Repository, RewriteOptions};
+
+    use super::{build_folder_delete_patterns, rewrite_commits, EmptyTree, Filters};
+
+    /// Every file below `path` with its size and modification time.
+    fn snapshot(path: &Path, files: &mut Vec<(std::path::PathBuf, u64, SystemTime)>) {
+        for entry in std::fs::read_dir(path).unwrap() {
+            let entry = entry.unwrap();
+            let metadata = entry.metadata().unwrap();
+            if metadata.is_dir() {
+                snapshot(&entry.path(), files);
+            }
+            files.push((entry.path(), metadata.len(), metadata.modified().unwrap()));
+        }
+    }
+
+    /// Splits `/a/b/` into the directory `/a/` and the name `b`.
+    fn folder_matches(matcher: &dyn PathMatcher, path: &[u8]) -> bool {
+        let path = &path[..path.len() - 1];
+        let split = path.iter().rposition(|c| *c == b'/').unwrap() + 1;
+        matcher.matches(&PathEntry::directory(&path[..split], &path[split..]))
+    }
+
+    fn file_matches(matcher: &dyn PathMatcher, directory: &[u8], name: &[u8]) -> bool {
+        matcher.matches(&PathEntry::file(directory, name))
+    }
 
     #[test]
     pub fn folder_deletion_patterns() {
@@ -406,7 +812,8 @@ mod test {
             "foo/bar/".into(),
         ];
 
-        let matches = build_folder_delete_patterns(&patterns);
+        let matcher = build_folder_delete_patterns(&patterns);
+        let matches = |path: &[u8]| folder_matches(matcher.as_ref(), path);
 
         assert!(matches(b"/some/folder/"));
         assert!(matches(b"/another/folder/"));
@@ -438,7 +845,9 @@ mod test {
             "/var/opt/myfile*".into(),
             "thisfile*".into(),
         ];
-        let should_delete = super::build_file_delete_patterns(&patterns);
+        let matcher = super::build_file_delete_patterns(&patterns).unwrap();
+        let should_delete =
+            |directory: &[u8], name: &[u8]| file_matches(matcher.as_ref(), directory, name);
 
         assert!(should_delete(b"/some/folder/", b"removeme.txt"));
         assert!(!should_delete(b"/some/folder/", b"1removeme.txt"));
@@ -473,4 +882,158 @@ mod test {
         assert!(!should_delete(b"/", b"test.txt1"));
         assert!(!should_delete(b"/hello/world", b"1test.txt"));
     }
+
+    #[test]
+    pub fn filename_regex_patterns() {
+        let patterns = vec!["secret".into(), r"\.pem$".into()];
+        let path_matcher = super::build_regex_pattern(&patterns).unwrap();
+        let filename_matcher = super::build_filename_regex_pattern(&patterns).unwrap();
+        let path_matches =
+            |directory: &[u8], name: &[u8]| file_matches(path_matcher.as_ref(), directory, name);
+        let filename_matches = |name: &[u8]| file_matches(filename_matcher.as_ref(), b"/", name);
+
+        assert!(path_matches(b"/secret_dir/", b"notes.txt"));
+        assert!(!filename_matches(b"notes.txt"));
+
+        assert!(filename_matches(b"my_secret.txt"));
+        assert!(filename_matches(b"key.pem"));
+        assert!(!filename_matches(b"key.pem.txt"));
+    }
+
+    #[test]
+    pub fn keep_regex_patterns() {
+        let patterns = vec!["^/src/".into(), r"\.md$".into()];
+        let matcher = super::build_keep_regex_pattern(&patterns).unwrap();
+        let should_delete =
+            |directory: &[u8], name: &[u8]| file_matches(matcher.as_ref(), directory, name);
+
+        assert!(!should_delete(b"/src/", b"main.rs"));
+        assert!(!should_delete(b"/src/bin/", b"tool.rs"));
+        assert!(!should_delete(b"/", b"README.md"));
+        assert!(should_delete(b"/", b"secrets.txt"));
+        assert!(should_delete(b"/docs/src/", b"a.rs"));
+
+        let nothing_kept = super::build_keep_regex_pattern(&[]).unwrap();
+        assert!(!file_matches(nothing_kept.as_ref(), b"/", b"secrets.txt"));
+    }
+
+    #[test]
+    pub fn invalid_patterns_are_reported() {
+        let filters = Filters {
+            regexes: vec!["(unclosed".into()],
+            ..Default::default()
+        };
+        assert!(super::Matchers::create(&filters).is_err());
+
+        assert!(super::build_file_delete_patterns(&["some/file.txt".into()]).is_err());
+        assert!(super::build_file_delete_patterns(&["".into()]).is_err());
+    }
+
+    #[test]
+    pub fn size_limit_parsing() {
+        use super::SizeLimit;
+
+        let limit: SizeLimit = "5M:*.json".parse().unwrap();
+        assert_eq!(5 * 1024 * 1024, limit.bytes);
+        assert_eq!(Some("*.json".to_owned()), limit.pattern);
+
+        let limit: SizeLimit = "100k".parse().unwrap();
+        assert_eq!(100 * 1024, limit.bytes);
+        assert_eq!(None, limit.pattern);
+
+        assert_eq!(42, "42".parse::<SizeLimit>().unwrap().bytes);
+        assert!("5X:*.png".parse::<SizeLimit>().is_err());
+        assert!("M".parse::<SizeLimit>().is_err());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn missing_blob_fails_the_size_filter() {
+        use std::borrow::Cow;
+
+        use bstr::ByteSlice;
+        use gitrwlib::objs::{TreeHash, TreeLine};
+
+        let path =
+            std::env::temp_dir().join(format!("gitrw-remove-missing-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let initialized = std::process::Command::new("git")
+            .args(["init", "--bare", "--quiet"])
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(initialized.success());
+        let mut repository = Repository::open(path.clone()).unwrap();
+        let filters = Filters {
+            size_limits: vec!["1K".parse().unwrap()],
+            ..Default::default()
+        };
+        let matchers = super::Matchers::create(&filters).unwrap();
+        let hash =
+            TreeHash::try_from(b"0123456789abcdef0123456789abcdef01234567".as_bstr()).unwrap();
+        let line = TreeLine {
+            hash: Cow::Owned(hash),
+            text: b"100644 missing.bin".as_bstr(),
+        };
+
+        assert!(matchers
+            .should_delete_file(&mut repository, b"/", &line)
+            .is_err());
+        // a submodule commit is no blob of this repository
+        let line = TreeLine {
+            text: b"160000 submodule".as_bstr(),
+            ..line
+        };
+        assert!(!matchers
+            .should_delete_file(&mut repository, b"/", &line)
+            .unwrap());
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn dry_run_leaves_repository_untouched() {
+        // a throwaway copy, the dry run must not touch it but any bug would
+        let path =
+            std::env::temp_dir().join(format!("gitrw-remove-dry-run-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let cloned = std::process::Command::new("git")
+            .args(["clone", "--bare", "--quiet", "--no-local"])
+            .arg(Path::new(env!("CARGO_MANIFEST_DIR")).join(".git"))
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(cloned.success());
+        let mut repository = Repository::open(path.clone()).unwrap();
+        let map_file = Path::new(gitrwlib::CommitMapFormat::Text.file_name());
+        let map_file_existed = map_file.exists();
+        let mut before = Vec::new();
+        snapshot(&path, &mut before);
+
+        let filters = Filters {
+            files: vec!["Cargo.toml".into()],
+            ..Default::default()
+        };
+        let options = RewriteOptions {
+            dry_run: true,
+            write_notes: true,
+            update_server_info: true,
+            ..Default::default()
+        };
+        repository.capture_dry_run();
+        let rewritten_commits =
+            rewrite_commits(&repository, &filters, EmptyTree::Drop, &options).unwrap();
+        let written = repository.dry_run_objects();
+        assert!(!rewritten_commits.is_empty());
+        repository
+            .apply_rewrite(rewritten_commits, &options)
+            .unwrap();
+
+        let mut after = Vec::new();
+        snapshot(&path, &mut after);
+        std::fs::remove_dir_all(&path).unwrap();
+        assert_eq!(before, after);
+        assert!(!written.is_empty());
+        assert_eq!(map_file_existed, map_file.exists());
+    }
 }
@@ -0,0 +1,273 @@
+use std::{
+    borrow::Cow, collections::BTreeMap, error::Error, ops::Deref, path::PathBuf, sync::RwLock,
+};
+
+use bstr::ByteSlice;
+use gitrwlib::{
+    objs::{Tree, TreeHash, TreeLine},
+    CommitAction, GitRwError, ObjectStore, Repository, RewriteOptions,
+};
+use rustc_hash::FxHashMap;
+
+const TREE_MODE: &[u8] = b"40000";
+
+/// A file or directory moved to another path, given as `old/path` and `new/path`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PathRename {
+    from: Vec<Vec<u8>>,
+    to: Vec<Vec<u8>>,
+}
+
+fn components(path: &str) -> Result<Vec<Vec<u8>>, String> {
+    let components: Vec<Vec<u8>> = path
+        .trim_matches('/')
+        .split('/')
+        .map(|component| component.as_bytes().to_vec())
+        .collect();
+
+    if components
+        .iter()
+        .any(|c| c.is_empty() || c == b"." || c == b"..")
+    {
+        return Err(format!("Invalid path '{path}'"));
+    }
+
+    Ok(components)
+}
+
+impl PathRename {
+    pub fn new(from: &str, to: &str) -> Result<PathRename, String> {
+        let rename = PathRename {
+            from: components(from)?,
+            to: components(to)?,
+        };
+
+        if rename.to.starts_with(&rename.from) || rename.from.starts_with(&rename.to) {
+            return Err(format!(
+                "Cannot move '{from}' to '{to}', one contains the other"
+            ));
+        }
+
+        Ok(rename)
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    mode: Vec<u8>,
+    hash: TreeHash,
+}
+
+impl Entry {
+    fn is_tree(&self) -> bool {
+        self.mode == TREE_MODE
+    }
+}
+
+/// The entries of a tree by name.
+type Entries = BTreeMap<Vec<u8>, Entry>;
+
+/// Git orders tree entries by name, directories as if their name ended with a slash.
+fn sort_key(name: &[u8], entry: &Entry) -> Vec<u8> {
+    let mut key = name.to_vec();
+    if entry.is_tree() {
+        key.push(b'/');
+    }
+    key
+}
+
+struct Renamer<'a> {
+    renames: &'a [PathRename],
+    rewritten_trees: RwLock<FxHashMap<TreeHash, Option<TreeHash>>>,
+    store: ObjectStore,
+}
+
+impl Renamer<'_> {
+    fn read_entries(
+        &self,
+        repository: &mut Repository,
+        hash: &TreeHash,
+    ) -> Result<Entries, GitRwError> {
+        let tree = repository.read_tree(hash.clone())?;
+        Ok(tree
+            .lines()
+            .map(|line| {
+                let entry = Entry {
+                    mode: line.mode().to_vec(),
+                    hash: line.hash.deref().clone(),
+                };
+                (line.filename().to_vec(), entry)
+            })
+            .collect())
+    }
+
+    fn write_entries(&self, entries: &Entries) -> Result<TreeHash, GitRwError> {
+        let mut sorted: Vec<(Vec<u8>, &Entry, Vec<u8>)> = entries
+            .iter()
+            .map(|(name, entry)| {
+                let text = [entry.mode.as_slice(), b" ", name].concat();
+                (sort_key(name, entry), entry, text)
+            })
+            .collect();
+        sorted.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let tree: Tree = sorted
+            .iter()
+            .map(|(_, entry, text)| TreeLine {
+                hash: Cow::Borrowed(&entry.hash),
+                text: text.as_bstr(),
+            })
+            .collect();
+        let hash = tree.hash().clone();
+        self.store.write(tree)?;
+        Ok(hash)
+    }
+
+    /// Removes the entry at `path` below `entries`, directories that become empty are removed
+    /// as well.
+    fn take(
+        &self,
+        repository: &mut Repository,
+        entries: &mut Entries,
+        path: &[Vec<u8>],
+    ) -> Result<Option<Entry>, GitRwError> {
+        let Some((name, rest)) = path.split_first() else {
+            return Ok(None);
+        };
+        if rest.is_empty() {
+            return Ok(entries.remove(name));
+        }
+
+        let Some(directory) = entries.get(name).filter(|entry| entry.is_tree()).cloned() else {
+            return Ok(None);
+        };
+        let mut children = self.read_entries(repository, &directory.hash)?;
+        let Some(taken) = self.take(repository, &mut children, rest)? else {
+            return Ok(None);
+        };
+        if children.is_empty() {
+            entries.remove(name);
+        } else {
+            let hash = self.write_entries(&children)?;
+            entries.insert(name.clone(), Entry { hash, ..directory });
+        }
+
+        Ok(Some(taken))
+    }
+
+    /// Adds `entry` at `path` below `entries`, creating the directories on the way. A directory
+    /// moved onto an existing one is merged into it, everything else replaces what was there.
+    fn insert(
+        &self,
+        repository: &mut Repository,
+        entries: &mut Entries,
+        path: &[Vec<u8>],
+        entry: Entry,
+    ) -> Result<(), GitRwError> {
+        let (name, rest) = path.split_first().unwrap();
+        let existing = entries
+            .get(name)
+            .filter(|existing| existing.is_tree())
+            .cloned();
+        if rest.is_empty() && !(entry.is_tree() && existing.is_some()) {
+            entries.insert(name.clone(), entry);
+            return Ok(());
+        }
+
+        let mut children = match existing {
+            Some(existing) => self.read_entries(repository, &existing.hash)?,
+            None => Entries::new(),
+        };
+        if rest.is_empty() {
+            for (child_name, child) in self.read_entries(repository, &entry.hash)? {
+                self.insert(repository, &mut children, &[child_name], child)?;
+            }
+        } else {
+            self.insert(repository, &mut children, rest, entry)?;
+        }
+
+        let hash = self.write_entries(&children)?;
+        entries.insert(
+            name.clone(),
+            Entry {
+                mode: TREE_MODE.to_vec(),
+                hash,
+            },
+        );
+        Ok(())
+    }
+
+    fn update_tree(
+        &self,
+        tree_hash: TreeHash,
+        repository: &mut Repository,
+    ) -> Result<Option<TreeHash>, GitRwError> {
+        if let Some(rewritten) = self.rewritten_trees.read().unwrap().get(&tree_hash) {
+            return Ok(rewritten.clone());
+        }
+
+        let mut entries = self.read_entries(repository, &tree_hash)?;
+        let mut changed = false;
+        for rename in self.renames {
+            if let Some(entry) = self.take(repository, &mut entries, &rename.from)? {
+                self.insert(repository, &mut entries, &rename.to, entry)?;
+                changed = true;
+            }
+        }
+
+        let new_hash = if changed {
+            Some(self.write_entries(&entries)?)
+        } else {
+            None
+        };
+        self.rewritten_trees
+            .write()
+            .unwrap()
+            .insert(tree_hash, new_hash.clone());
+        Ok(new_hash)
+    }
+}
+
+/// Moves files and directories in every commit, the renames are applied in the given order.
+pub fn rename(
+    repository_path: PathBuf,
+    renames: Vec<PathRename>,
+    options: &RewriteOptions,
+) -> Result<(), Box<dyn Error>> {
+    let mut repository = crate::open_for_rewrite(repository_path)?;
+    let renamer = Renamer {
+        renames: &renames,
+        rewritten_trees: RwLock::new(FxHashMap::default()),
+        store: repository.object_store(options.dry_run),
+    };
+    let ref_updates = repository.rewrite_history(
+        options,
+        |_| CommitAction::Keep,
+        |repository, tree_hash| renamer.update_tree(tree_hash, repository),
+    )?;
+    crate::print_ref_updates(&ref_updates, options)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::PathRename;
+
+    #[test]
+    fn renames_are_validated() {
+        let rename = PathRename::new("/src/old/", "lib/new").unwrap();
+        assert_eq!(vec![b"src".to_vec(), b"old".to_vec()], rename.from);
+        assert_eq!(vec![b"lib".to_vec(), b"new".to_vec()], rename.to);
+
+        for (from, to) in [
+            ("src", ""),
+            ("a//b", "c"),
+            ("../a", "b"),
+            ("src", "src/sub"),
+            ("src/sub", "src"),
+        ] {
+            assert!(PathRename::new(from, to).is_err(), "{from} -> {to}");
+        }
+    }
+}
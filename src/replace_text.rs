@@ -0,0 +1,237 @@
+use std::{borrow::Cow, error::Error, fs, ops::Deref, path::PathBuf, sync::RwLock};
+
+use gitrwlib::{
+    objs::{Blob, GitObject, Tree, TreeHash},
+    CommitAction, GitRwError, ObjectStore, Repository, RewriteOptions,
+};
+use regex::bytes::{NoExpand, Regex};
+use rustc_hash::FxHashMap;
+
+/// Replacement of rules without `==>`, same as the one of the BFG.
+const DEFAULT_REPLACEMENT: &str = "***REMOVED***";
+
+/// Files with a NUL byte in this many leading bytes are binary and left alone, like git does.
+const BINARY_CHECK_LEN: usize = 8000;
+
+/// Mode of submodule entries, their hash is a commit of another repository.
+const GITLINK_MODE: &[u8] = b"160000";
+
+struct Rule {
+    pattern: Regex,
+    replacement: Vec<u8>,
+    /// Literal rules insert the replacement as is, regex rules expand `$1` and `${name}`
+    literal: bool,
+}
+
+/// Parses one rule per line: `<literal>`, `<literal>==><replacement>` or `regex:<pattern>` with an
+/// optional `==><replacement>`. Empty lines and lines starting with '#' are skipped.
+fn parse_rules(rules: &str) -> Result<Vec<Rule>, Box<dyn Error>> {
+    let mut parsed = Vec::new();
+    for line in rules.lines() {
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (pattern, replacement) = line
+            .rsplit_once("==>")
+            .unwrap_or((line, DEFAULT_REPLACEMENT));
+        let (pattern, literal) = match pattern.strip_prefix("regex:") {
+            Some(pattern) => (Regex::new(pattern)?, false),
+            None => (Regex::new(&regex::escape(pattern))?, true),
+        };
+
+        parsed.push(Rule {
+            pattern,
+            replacement: replacement.as_bytes().to_vec(),
+            literal,
+        });
+    }
+
+    if parsed.is_empty() {
+        return Err("The rules file contains no rules".into());
+    }
+
+    Ok(parsed)
+}
+
+struct Replacer {
+    rules: Vec<Rule>,
+    rewritten_blobs: RwLock<FxHashMap<TreeHash, Option<TreeHash>>>,
+    rewritten_trees: RwLock<FxHashMap<TreeHash, Option<TreeHash>>>,
+}
+
+impl Replacer {
+    /// Applies all rules in order, `None` if the content stays the same.
+    fn replace(&self, content: &[u8]) -> Option<Vec<u8>> {
+        if memchr::memchr(0, &content[..content.len().min(BINARY_CHECK_LEN)]).is_some() {
+            return None;
+        }
+
+        let mut result = Cow::Borrowed(content);
+        for rule in self.rules.iter() {
+            let replaced = if rule.literal {
+                rule.pattern
+                    .replace_all(&result, NoExpand(&rule.replacement))
+            } else {
+                rule.pattern
+                    .replace_all(&result, rule.replacement.as_slice())
+            };
+
+            if let Cow::Owned(replaced) = replaced {
+                result = Cow::Owned(replaced);
+            }
+        }
+
+        match result {
+            Cow::Borrowed(_) => None,
+            Cow::Owned(result) => Some(result),
+        }
+    }
+
+    fn update_blob(
+        &self,
+        blob_hash: &TreeHash,
+        repository: &mut Repository,
+        store: &ObjectStore,
+    ) -> Result<Option<TreeHash>, GitRwError> {
+        if let Some(rewritten) = self.rewritten_blobs.read().unwrap().get(blob_hash) {
+            return Ok(rewritten.clone());
+        }
+
+        let new_blob = match repository.read_object(blob_hash.clone().into()) {
+            Some(GitObject::Blob(blob)) => self
+                .replace(blob.content())
+                .map(Blob::from_content)
+                // a replacement can also produce the same content again
+                .filter(|new_blob| new_blob.hash() != blob.hash()),
+            _ => None,
+        };
+        let new_hash: Option<TreeHash> = match new_blob {
+            Some(new_blob) => {
+                let new_hash = new_blob.hash().clone().into();
+                store.write(new_blob)?;
+                Some(new_hash)
+            }
+            None => None,
+        };
+
+        self.rewritten_blobs
+            .write()
+            .unwrap()
+            .insert(blob_hash.clone(), new_hash.clone());
+        Ok(new_hash)
+    }
+
+    fn update_tree(
+        &self,
+        tree_hash: TreeHash,
+        repository: &mut Repository,
+        store: &ObjectStore,
+    ) -> Result<Option<TreeHash>, GitRwError> {
+        if let Some(rewritten) = self.rewritten_trees.read().unwrap().get(&tree_hash) {
+            return Ok(rewritten.clone());
+        }
+
+        let tree: Tree = repository.read_tree(tree_hash.clone())?;
+        let mut lines = Vec::new();
+        let mut tree_changed = false;
+        for mut line in tree.lines() {
+            let new_hash = if line.is_tree() {
+                self.update_tree(line.hash.deref().clone(), repository, store)?
+            } else if line.mode() != GITLINK_MODE {
+                self.update_blob(&line.hash, repository, store)?
+            } else {
+                None
+            };
+
+            if let Some(new_hash) = new_hash {
+                line.hash = Cow::Owned(new_hash);
+                tree_changed = true;
+            }
+            lines.push(line);
+        }
+
+        let new_hash = if tree_changed {
+            let tree: Tree = lines.into_iter().collect();
+            let new_hash = tree.hash().clone();
+            store.write(tree)?;
+            Some(new_hash)
+        } else {
+            None
+        };
+
+        self.rewritten_trees
+            .write()
+            .unwrap()
+            .insert(tree_hash, new_hash.clone());
+        Ok(new_hash)
+    }
+}
+
+pub fn replace_text(
+    repository_path: PathBuf,
+    rules_file: PathBuf,
+    options: &RewriteOptions,
+) -> Result<(), Box<dyn Error>> {
+    let rules = fs::read_to_string(&rules_file)
+        .map_err(|e| format!("Reading {} failed: {e}", rules_file.display()))?;
+    let replacer = Replacer {
+        rules: parse_rules(&rules)?,
+        rewritten_blobs: RwLock::new(FxHashMap::default()),
+        rewritten_trees: RwLock::new(FxHashMap::default()),
+    };
+
+    let mut repository = crate::open_for_rewrite(repository_path)?;
+    let store = repository.object_store(options.dry_run);
+    let ref_updates = repository.rewrite_history(
+        options,
+        |_| CommitAction::Keep,
+        |repository, tree_hash| replacer.update_tree(tree_hash, repository, &store),
+    )?;
+    crate::print_ref_updates(&ref_updates, options)?;
+
+    let replaced = replacer
+        .rewritten_blobs
+        .into_inner()
+        .unwrap()
+        .values()
+        .filter(|new_hash| new_hash.is_some())
+        .count();
+    println!("Replaced text in {replaced} blobs");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::RwLock;
+
+    use rustc_hash::FxHashMap;
+
+    use super::{parse_rules, Replacer};
+
+    fn replacer(rules: &str) -> Replacer {
+        Replacer {
+            rules: parse_rules(rules).unwrap(),
+            rewritten_blobs: RwLock::new(FxHashMap::default()),
+            rewritten_trees: RwLock::new(FxHashMap::default()),
+        }
+    }
+
+    #[test]
+    pub fn literal_and_regex_rules() {
+        let replacer = replacer(
+            "# comment\nPASSWORD==>REMOVED\nhunter2\nregex:key=(\\w)\\w*==>key=$1...\n$HOME==>~\n",
+        );
+
+        let replaced = replacer
+            .replace(b"PASSWORD: hunter2, key=secret, dir=$HOME")
+            .unwrap();
+        assert_eq!(
+            b"REMOVED: ***REMOVED***, key=s..., dir=~".as_slice(),
+            replaced.as_slice()
+        );
+        assert_eq!(None, replacer.replace(b"nothing to see"));
+        assert_eq!(None, replacer.replace(b"PASSWORD\0binary"));
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+use gitrwlib::{objs::GitObject, ObjectHash, ReadOnly, Repository};
+
+/// Hash of the entry at `path` in the tree of `hash`, only the trees along the path get read.
+fn lookup_hash(
+    repository: &mut Repository<ReadOnly>,
+    hash: ObjectHash,
+    path: &str,
+) -> Option<ObjectHash> {
+    let path = path.trim_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+    match repository.lookup_path(hash, parent.as_bytes())? {
+        GitObject::Tree(tree) if name.is_empty() => Some(tree.hash().clone().into()),
+        GitObject::Tree(tree) => tree
+            .lines()
+            .find(|line| line.filename() == name.as_bytes())
+            .map(|line| line.hash.as_ref().clone().into()),
+        _ => None,
+    }
+}
+
+/// Like git, content with a NUL byte in its first 8000 bytes counts as binary.
+fn is_binary(content: &[u8]) -> bool {
+    content[..content.len().min(8000)].contains(&0)
+}
+
+pub fn show(
+    repository_path: PathBuf,
+    object: &str,
+    object_type: bool,
+    size: bool,
+    location: bool,
+    raw: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut repository = Repository::open_read_only(repository_path)?;
+
+    let (rev, path) = match object.split_once(':') {
+        Some((rev, path)) => (rev, Some(path)),
+        None => (object, None),
+    };
+
+    let hash = match repository.resolve(rev) {
+        Some(hash) => hash,
+        // explains why an abbreviation did not resolve
+        None if rev.bytes().all(|c| c.is_ascii_hexdigit()) => repository.resolve_prefix(rev)?,
+        None => return Err(format!("Unknown revision: {rev}").into()),
+    };
+
+    if object_type || size || location {
+        let hash = match path {
+            Some(path) => lookup_hash(&mut repository, hash, path)
+                .ok_or_else(|| format!("Path '{path}' does not exist in '{rev}'"))?,
+            None => hash,
+        };
+        if location {
+            let locations = repository.object_location(&hash);
+            if locations.is_empty() {
+                return Err(format!("Object {hash} is missing").into());
+            }
+            return crate::print_locked(locations.iter());
+        }
+
+        let (header_type, header_size) = repository.object_header(&hash)?;
+        if object_type {
+            println!("{header_type}");
+        } else {
+            println!("{header_size}");
+        }
+        return Ok(());
+    }
+
+    let git_object = match path {
+        Some(path) => repository
+            .lookup_path(hash, path.as_bytes())
+            .ok_or_else(|| format!("Path '{path}' does not exist in '{rev}'"))?,
+        None => repository
+            .read_object(hash)
+            .ok_or_else(|| format!("Object {rev} not found"))?,
+    };
+
+    let mut handle = BufWriter::new(stdout().lock());
+    match git_object {
+        GitObject::Blob(blob) if !raw && is_binary(blob.content()) => writeln!(
+            handle,
+            "Binary blob {} of {} bytes, use --raw to print it",
+            blob.hash(),
+            blob.content().len()
+        )?,
+        GitObject::Blob(blob) => handle.write_all(blob.content())?,
+        GitObject::Tree(tree) if raw => handle.write_all(tree.content())?,
+        GitObject::Tree(tree) => {
+            for line in tree.lines() {
+                writeln!(handle, "{line}")?;
+            }
+        }
+        GitObject::Commit(commit) => handle.write_all(commit.content())?,
+        GitObject::Tag(tag) => handle.write_all(&tag.to_bytes())?,
+    }
+
+    handle.flush()?;
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::{error::Error, path::PathBuf};
+
+use gitrwlib::{
+    objs::{CommitHash, GitObject, TreeHash},
+    CommitAction, RewriteOptions,
+};
+use rustc_hash::FxHashMap;
+
+/// Makes `directory` the root of every commit. Commits without the directory are dropped, like
+/// commits that are no merges and leave it unchanged.
+pub fn subdir_filter(
+    repository_path: PathBuf,
+    directory: &str,
+    options: &RewriteOptions,
+) -> Result<(), Box<dyn Error>> {
+    let directory = directory.trim_matches('/');
+    if directory.is_empty() {
+        return Err("The directory must not be the repository root".into());
+    }
+
+    // keyed by the original commit hashes: the new tree of a commit, or of the commit a dropped
+    // one was folded into, `None` for dropped commits without a parent
+    let mut commit_trees: FxHashMap<CommitHash, Option<TreeHash>> = FxHashMap::default();
+
+    let mut repository = crate::open_for_rewrite(repository_path)?;
+    let ref_updates = repository.rewrite_history(
+        options,
+        |commit| {
+            let parents = commit.base().parents();
+            let parent_tree = match parents.as_slice() {
+                [parent] => commit_trees.get(parent).cloned().flatten(),
+                _ => None,
+            };
+
+            let tree = commit.tree();
+            let drop =
+                tree.is_empty_tree() || (parents.len() == 1 && parent_tree.as_ref() == Some(&tree));
+            let base_hash = commit.base_hash().clone();
+            if drop {
+                commit_trees.insert(base_hash, parent_tree);
+                CommitAction::Drop
+            } else {
+                commit_trees.insert(base_hash, Some(tree));
+                CommitAction::Keep
+            }
+        },
+        |repository, tree_hash| match repository.lookup_path(tree_hash.into(), directory.as_bytes())
+        {
+            Some(GitObject::Tree(tree)) => Ok(Some(tree.hash().clone())),
+            // commits with the empty tree are dropped
+            _ => Ok(Some(TreeHash::empty())),
+        },
+    )?;
+    crate::print_ref_updates(&ref_updates, options)?;
+
+    Ok(())
+}
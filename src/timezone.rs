@@ -0,0 +1,134 @@
+use std::{error::Error, fs, path::PathBuf, str::FromStr};
+
+use bstr::ByteSlice;
+use gitrwlib::{CommitAction, RewriteOptions};
+use rustc_hash::FxHashMap;
+
+/// A timezone offset in the format git uses: a sign followed by hours and minutes, e.g. +0200.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Offset(String);
+
+impl FromStr for Offset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if is_valid_offset(s.as_bytes()) {
+            Ok(Offset(s.to_string()))
+        } else {
+            Err(format!(
+                "invalid timezone offset '{s}', expected e.g. +0200"
+            ))
+        }
+    }
+}
+
+fn is_valid_offset(offset: &[u8]) -> bool {
+    if offset.len() != 5
+        || !(offset[0] == b'+' || offset[0] == b'-')
+        || !offset[1..].iter().all(u8::is_ascii_digit)
+    {
+        return false;
+    }
+
+    let hours = (offset[1] - b'0') * 10 + (offset[2] - b'0');
+    let minutes = (offset[3] - b'0') * 10 + (offset[4] - b'0');
+    hours <= 14 && minutes < 60
+}
+
+fn read_zones(path: &PathBuf) -> Result<FxHashMap<Vec<u8>, Offset>, Box<dyn Error>> {
+    let mut zones = FxHashMap::default();
+    for line in fs::read(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let split_pos = line
+            .rfind_byte(b'=')
+            .ok_or("Line is malformed. Pattern: User <user.mail> = +0200")?;
+        let contributor = line[0..split_pos].trim().to_owned();
+        let offset: Offset = line[split_pos + 1..].trim().to_str()?.parse()?;
+        zones.insert(contributor, offset);
+    }
+
+    Ok(zones)
+}
+
+/// Returns the new time field if the offset of `time` (e.g. `1600000000 +0200`) has to change.
+fn normalize_time(time: &[u8], offset: &Offset, invalid_only: bool) -> Option<Vec<u8>> {
+    let split_pos = time.rfind_byte(b' ')?;
+    let current = &time[split_pos + 1..];
+    if current == offset.0.as_bytes() || (invalid_only && is_valid_offset(current)) {
+        return None;
+    }
+
+    let mut result = time[..=split_pos].to_vec();
+    result.extend_from_slice(offset.0.as_bytes());
+    Some(result)
+}
+
+pub fn normalize(
+    repository_path: PathBuf,
+    offset: Offset,
+    zones: Option<PathBuf>,
+    invalid_only: bool,
+    options: &RewriteOptions,
+) -> Result<(), Box<dyn Error>> {
+    let zones = match zones {
+        Some(path) => read_zones(&path)?,
+        None => FxHashMap::default(),
+    };
+
+    let mut repository = crate::open_for_rewrite(repository_path)?;
+    let ref_updates = repository.rewrite_history(
+        options,
+        |commit| {
+            let author_offset = zones.get(commit.author_bytes()).unwrap_or(&offset);
+            if let Some(time) = normalize_time(commit.author_time(), author_offset, invalid_only) {
+                commit.set_author_time(time);
+            }
+
+            let committer_offset = zones.get(commit.committer_bytes()).unwrap_or(&offset);
+            if let Some(time) =
+                normalize_time(commit.committer_time(), committer_offset, invalid_only)
+            {
+                commit.set_committer_time(time);
+            }
+
+            CommitAction::Keep
+        },
+        |_, _| Ok(None),
+    )?;
+    crate::print_ref_updates(&ref_updates, options)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize_time, Offset};
+
+    #[test]
+    fn offset_parsing() {
+        assert!("+0200".parse::<Offset>().is_ok());
+        assert!("-1130".parse::<Offset>().is_ok());
+        assert!("+51800".parse::<Offset>().is_err());
+        assert!("0200".parse::<Offset>().is_err());
+        assert!("+0260".parse::<Offset>().is_err());
+    }
+
+    #[test]
+    fn time_normalization() {
+        let utc: Offset = "+0000".parse().unwrap();
+        assert_eq!(
+            Some(b"1600000000 +0000".to_vec()),
+            normalize_time(b"1600000000 +0200", &utc, false)
+        );
+        assert_eq!(None, normalize_time(b"1600000000 +0000", &utc, false));
+        assert_eq!(None, normalize_time(b"1600000000 +0200", &utc, true));
+        assert_eq!(
+            Some(b"1600000000 +0000".to_vec()),
+            normalize_time(b"1600000000 +51800", &utc, true)
+        );
+    }
+}
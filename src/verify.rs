@@ -0,0 +1,88 @@
+use std::{
+    error::Error,
+    io::{stdout, BufWriter, Write},
+    path::PathBuf,
+};
+
+use bstr::ByteSlice;
+use gitrwlib::{CorruptObject, ObjectHash, ObjectLocation, ReadOnly, Repository, VerifyOptions};
+use serde_json::json;
+
+use crate::output::{OutputFormat, Table};
+
+/// Verifies all packed objects and prints a summary. Corrupt objects don't stop the scan, up to
+/// `max_errors` of them are listed with the intact copies of the object in other packs or loose
+/// files, if any. Fails if any object is corrupt.
+pub fn verify(
+    repository_path: PathBuf,
+    crc: bool,
+    max_errors: usize,
+    format: OutputFormat,
+) -> Result<(), Box<dyn Error>> {
+    let repository = Repository::open_read_only(repository_path)?;
+    let report = repository.verify_packs(&VerifyOptions { crc, max_errors })?;
+
+    let mut handle = BufWriter::new(stdout().lock());
+    if format == OutputFormat::Plain {
+        for corrupt in report.corrupt.listed.iter() {
+            writeln!(handle, "{corrupt}")?;
+            for location in other_copies(&repository, corrupt) {
+                writeln!(handle, "  also {location}")?;
+            }
+        }
+        if report.corrupt.unlisted > 0 {
+            writeln!(
+                handle,
+                "... and {} more corrupt objects",
+                report.corrupt.unlisted
+            )?;
+        }
+        writeln!(
+            handle,
+            "{} objects in {} packs verified",
+            report.objects, report.packs
+        )?;
+    } else {
+        let mut table = Table::new(&["pack", "hash", "offset", "reason", "also"]);
+        for corrupt in report.corrupt.listed.iter() {
+            let others: Vec<String> = other_copies(&repository, corrupt)
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            table.push(vec![
+                json!(corrupt.pack_file),
+                json!(corrupt.hash),
+                json!(corrupt.offset),
+                json!(corrupt.reason),
+                match others.is_empty() {
+                    true => json!(null),
+                    false => json!(others.join(", ")),
+                },
+            ]);
+        }
+        table.write(format, &mut handle)?;
+    }
+    handle.flush()?;
+
+    match report.corrupt.len() {
+        0 => Ok(()),
+        1 => Err("1 corrupt object".into()),
+        n => Err(format!("{n} corrupt objects").into()),
+    }
+}
+
+/// The copies of `corrupt` in other packs or loose files.
+fn other_copies(repository: &Repository<ReadOnly>, corrupt: &CorruptObject) -> Vec<ObjectLocation> {
+    let Ok(hash) = ObjectHash::try_from(corrupt.hash.as_bytes().as_bstr()) else {
+        return Vec::new();
+    };
+
+    repository
+        .object_location(&hash)
+        .into_iter()
+        .filter(|location| {
+            !matches!(location, ObjectLocation::Packed { pack_file, offset }
+                if *pack_file == corrupt.pack_file && *offset == corrupt.offset)
+        })
+        .collect()
+}